@@ -142,9 +142,13 @@ You have access to the following tools:
 ## Rhai Scripting
 
 Rhai is an embedded scripting language. Key features:
-- Access `payload` (JSON object), `metadata` (map), `protocol`, `destination`
-- Built-in functions: `parse_json()`, `to_json()`, `uuid()`, `timestamp()`, `now_utc()`
-- Modify `payload` to transform data
+- Access `payload` (JSON object), `metadata` (map), `protocol`, `destination`, and `input` (the payload as text)
+- Built-in functions: `parse_json()`, `to_json()`, `uuid()`, `timestamp()`, `now_utc()`, `upper()`, `lower()`,
+  `wrap_xml()`, `celsius_to_fahrenheit()`
+- Integration helpers: `json_path()`, `parse_xml()`, `to_xml()`, `base64_encode()`, `sha256()`, `hmac_sha256()`,
+  `url_encode()`, `parse_date()`, `format_date()`, `regex_replace()`, `parse_csv()`, `to_csv()`, `format_currency()`
+- Modify `payload` to transform data, or assign text to `output` to replace it
+- The same scripts run unchanged in the gateway's transform filter and the console's dry runs
 
 Example Rhai script:
 ```rhai
@@ -2,14 +2,20 @@
 //!
 //! Provides safe, embedded scripting for data transformations.
 //! The AI generates Rhai scripts that can transform messages between protocols.
+//!
+//! Scripts run on the gateway's shared [`scripting`](gateway_core::scripting)
+//! runtime, with the same helpers, context and [`ScriptLimits`] as route
+//! scripts and transforms, so a script validated here runs unchanged in the
+//! gateway.
 
-use rhai::{Dynamic, Engine, ImmutableString, Scope, AST};
+use gateway_core::config::ScriptLimits;
+use gateway_core::scripting::{Script, ScriptContext, ScriptEngine, ScriptError};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
-use tracing::{debug, error, info, warn};
+use tracing::debug;
 
 /// Rhai engine errors
 #[derive(Debug, Error)]
@@ -25,6 +31,24 @@ pub enum RhaiError {
 
     #[error("Validation failed: {0}")]
     ValidationError(String),
+
+    #[error("Script timed out")]
+    Timeout,
+
+    #[error("Script limit exceeded: {0}")]
+    LimitExceeded(String),
+}
+
+impl From<ScriptError> for RhaiError {
+    fn from(e: ScriptError) -> Self {
+        match e {
+            ScriptError::CompileError(msg) => RhaiError::CompileError(msg),
+            ScriptError::ExecutionError(msg) => RhaiError::ExecutionError(msg),
+            ScriptError::Timeout => RhaiError::Timeout,
+            ScriptError::LimitExceeded(msg) => RhaiError::LimitExceeded(msg),
+            ScriptError::OutputError(msg) => RhaiError::ConversionError(msg),
+        }
+    }
 }
 
 /// Script validation result
@@ -51,93 +75,45 @@ pub struct TransformContext {
     pub destination: String,
 }
 
+impl TransformContext {
+    /// The context as scripts see it; `input` holds the payload as text
+    fn to_script_context(&self) -> ScriptContext {
+        ScriptContext {
+            metadata: self.metadata.clone(),
+            protocol: self.protocol.clone(),
+            destination: self.destination.clone(),
+            ..ScriptContext::from_payload(self.payload.clone())
+        }
+    }
+}
+
 /// Rhai scripting engine for data transformations
 pub struct RhaiEngine {
-    /// The Rhai engine instance
-    engine: Engine,
+    /// The shared scripting engine
+    engine: ScriptEngine,
 
     /// Compiled script cache
-    script_cache: HashMap<String, Arc<AST>>,
+    script_cache: HashMap<String, Arc<Script>>,
 }
 
 impl RhaiEngine {
-    /// Create a new Rhai engine with NaseejMesh functions
+    /// Create a new Rhai engine with NaseejMesh functions and the default
+    /// limits
     pub fn new() -> Self {
-        let mut engine = Engine::new();
-
-        // Set safety limits
-        engine.set_max_operations(100_000);
-        engine.set_max_expr_depths(64, 64);
-        engine.set_max_string_size(1024 * 1024); // 1MB
-        engine.set_max_array_size(10_000);
-        engine.set_max_map_size(10_000);
-
-        // Register custom functions for transformations
-        Self::register_functions(&mut engine);
+        Self::with_limits(ScriptLimits::default())
+    }
 
+    /// Create a new Rhai engine whose script runs are bounded by `limits`
+    pub fn with_limits(limits: ScriptLimits) -> Self {
         Self {
-            engine,
+            engine: ScriptEngine::new(limits),
             script_cache: HashMap::new(),
         }
     }
 
-    /// Register custom functions available in scripts
-    fn register_functions(engine: &mut Engine) {
-        // JSON parsing
-        engine.register_fn("parse_json", |s: &str| -> Dynamic {
-            match serde_json::from_str::<JsonValue>(s) {
-                Ok(v) => json_to_dynamic(&v),
-                Err(_) => Dynamic::UNIT,
-            }
-        });
-
-        // JSON stringification
-        engine.register_fn("to_json", |d: Dynamic| -> ImmutableString {
-            match dynamic_to_json(&d) {
-                Ok(v) => serde_json::to_string(&v).unwrap_or_default().into(),
-                Err(_) => "{}".into(),
-            }
-        });
-
-        // String manipulation
-        engine.register_fn("trim", |s: &str| -> ImmutableString {
-            s.trim().into()
-        });
-
-        engine.register_fn("uppercase", |s: &str| -> ImmutableString {
-            s.to_uppercase().into()
-        });
-
-        engine.register_fn("lowercase", |s: &str| -> ImmutableString {
-            s.to_lowercase().into()
-        });
-
-        // Date/time functions
-        engine.register_fn("now_utc", || -> ImmutableString {
-            chrono::Utc::now().to_rfc3339().into()
-        });
-
-        engine.register_fn("timestamp", || -> i64 {
-            chrono::Utc::now().timestamp()
-        });
-
-        // Logging
-        engine.register_fn("log_debug", |msg: &str| {
-            debug!(rhai = true, "{}", msg);
-        });
-
-        engine.register_fn("log_info", |msg: &str| {
-            info!(rhai = true, "{}", msg);
-        });
-
-        engine.register_fn("log_warn", |msg: &str| {
-            warn!(rhai = true, "{}", msg);
-        });
-
-        // UUID generation
-        engine.register_fn("uuid", || -> ImmutableString {
-            uuid::Uuid::new_v4().to_string().into()
-        });
+    /// Limits on each script run
+    pub fn limits(&self) -> &ScriptLimits {
+        self.engine.limits()
     }
 
     /// Validate a script without executing it
@@ -151,12 +127,12 @@ impl RhaiEngine {
         }
 
         // Try to compile
-        match self.engine.compile(script) {
-            Ok(_) => {
+        match self.engine.validate(script) {
+            Ok(()) => {
                 debug!("Script validation passed");
             }
             Err(e) => {
-                errors.push(format!("Compilation error: {}", e));
+                errors.push(e.to_string());
             }
         }
 
@@ -169,11 +145,9 @@ impl RhaiEngine {
 
     /// Compile and cache a script
     pub fn compile(&mut self, script_id: &str, script: &str) -> Result<(), RhaiError> {
-        let ast = self.engine
-            .compile(script)
-            .map_err(|e| RhaiError::CompileError(e.to_string()))?;
+        let script = self.engine.compile(script)?.named(format!("engine.{}", script_id));
 
-        self.script_cache.insert(script_id.to_string(), Arc::new(ast));
+        self.script_cache.insert(script_id.to_string(), Arc::new(script));
         debug!(script_id = %script_id, "Script compiled and cached");
 
         Ok(())
@@ -185,12 +159,9 @@ impl RhaiEngine {
         script: &str,
         ctx: &TransformContext,
     ) -> Result<TransformContext, RhaiError> {
-        // Compile the script
-        let ast = self.engine
-            .compile(script)
-            .map_err(|e| RhaiError::CompileError(e.to_string()))?;
+        let script = self.engine.compile(script)?.named("engine");
 
-        self.execute_ast(&ast, ctx)
+        Self::run(&script, ctx)
     }
 
     /// Execute a cached script
@@ -199,51 +170,22 @@ impl RhaiEngine {
         script_id: &str,
         ctx: &TransformContext,
     ) -> Result<TransformContext, RhaiError> {
-        let ast = self.script_cache
+        let script = self.script_cache
             .get(script_id)
             .ok_or_else(|| RhaiError::ExecutionError(format!("Script not found: {}", script_id)))?;
 
-        self.execute_ast(ast, ctx)
+        Self::run(script, ctx)
     }
 
-    /// Execute an AST with context
-    fn execute_ast(
-        &self,
-        ast: &AST,
-        ctx: &TransformContext,
-    ) -> Result<TransformContext, RhaiError> {
-        let mut scope = Scope::new();
-
-        // Inject context variables
-        scope.push("payload", json_to_dynamic(&ctx.payload));
-        scope.push("metadata", ctx.metadata.clone());
-        scope.push("protocol", ctx.protocol.clone());
-        scope.push("destination", ctx.destination.clone());
-
-        // Execute
-        self.engine
-            .run_ast_with_scope(&mut scope, ast)
-            .map_err(|e| RhaiError::ExecutionError(e.to_string()))?;
-
-        // Extract modified context
-        let payload = scope
-            .get_value::<Dynamic>("payload")
-            .map(|d| dynamic_to_json(&d).unwrap_or(JsonValue::Null))
-            .unwrap_or(ctx.payload.clone());
-
-        let metadata = scope
-            .get_value::<HashMap<String, String>>("metadata")
-            .unwrap_or_else(|| ctx.metadata.clone());
-
-        let destination = scope
-            .get_value::<String>("destination")
-            .unwrap_or_else(|| ctx.destination.clone());
+    /// Run a compiled script with context
+    fn run(script: &Script, ctx: &TransformContext) -> Result<TransformContext, RhaiError> {
+        let outcome = script.run(&ctx.to_script_context())?;
 
         Ok(TransformContext {
-            payload,
-            metadata,
+            payload: outcome.payload,
+            metadata: outcome.metadata,
             protocol: ctx.protocol.clone(),
-            destination,
+            destination: outcome.destination,
         })
     }
 
@@ -259,79 +201,10 @@ impl Default for RhaiEngine {
     }
 }
 
-/// Convert JSON value to Rhai Dynamic
-fn json_to_dynamic(value: &JsonValue) -> Dynamic {
-    match value {
-        JsonValue::Null => Dynamic::UNIT,
-        JsonValue::Bool(b) => Dynamic::from(*b),
-        JsonValue::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                Dynamic::from(i)
-            } else if let Some(f) = n.as_f64() {
-                Dynamic::from(f)
-            } else {
-                Dynamic::UNIT
-            }
-        }
-        JsonValue::String(s) => Dynamic::from(s.clone()),
-        JsonValue::Array(arr) => {
-            let vec: Vec<Dynamic> = arr.iter().map(json_to_dynamic).collect();
-            Dynamic::from(vec)
-        }
-        JsonValue::Object(obj) => {
-            let map: rhai::Map = obj
-                .iter()
-                .map(|(k, v)| (k.clone().into(), json_to_dynamic(v)))
-                .collect();
-            Dynamic::from(map)
-        }
-    }
-}
-
-/// Convert Rhai Dynamic to JSON value
-fn dynamic_to_json(value: &Dynamic) -> Result<JsonValue, RhaiError> {
-    if value.is_unit() {
-        return Ok(JsonValue::Null);
-    }
-
-    if let Some(b) = value.clone().try_cast::<bool>() {
-        return Ok(JsonValue::Bool(b));
-    }
-
-    if let Some(i) = value.clone().try_cast::<i64>() {
-        return Ok(JsonValue::Number(i.into()));
-    }
-
-    if let Some(f) = value.clone().try_cast::<f64>() {
-        return Ok(serde_json::Number::from_f64(f)
-            .map(JsonValue::Number)
-            .unwrap_or(JsonValue::Null));
-    }
-
-    if let Some(s) = value.clone().try_cast::<ImmutableString>() {
-        return Ok(JsonValue::String(s.to_string()));
-    }
-
-    if let Some(arr) = value.clone().try_cast::<Vec<Dynamic>>() {
-        let json_arr: Result<Vec<JsonValue>, _> = arr.iter().map(dynamic_to_json).collect();
-        return Ok(JsonValue::Array(json_arr?));
-    }
-
-    if let Some(map) = value.clone().try_cast::<rhai::Map>() {
-        let json_obj: Result<serde_json::Map<String, JsonValue>, _> = map
-            .iter()
-            .map(|(k, v)| dynamic_to_json(v).map(|jv| (k.to_string(), jv)))
-            .collect();
-        return Ok(JsonValue::Object(json_obj?));
-    }
-
-    // Fallback to string representation
-    Ok(JsonValue::String(format!("{:?}", value)))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use gateway_core::scripting::{script_metrics, ScriptLimit};
 
     #[test]
     fn test_validate_valid_script() {
@@ -387,6 +260,59 @@ mod tests {
         assert!(result.payload.get("id").is_some());
     }
 
+    #[test]
+    fn test_gateway_script() {
+        // Scripts written for the gateway's `input`/`output` contract and
+        // helper names run here too
+        let engine = RhaiEngine::new();
+        let ctx = TransformContext {
+            payload: serde_json::json!({"name": "sensor"}),
+            metadata: HashMap::from([("unit".to_string(), "c".to_string())]),
+            protocol: "mqtt".to_string(),
+            destination: "/sensors".to_string(),
+        };
+
+        let script = r#"
+            let data = parse_json(input);
+            data["name"] = upper(data["name"]);
+            data["unit"] = metadata.unit;
+            data["seen"] = now_iso();
+            output = to_json(data);
+        "#;
+
+        let result = engine.execute(script, &ctx).unwrap();
+        assert_eq!(result.payload["name"], "SENSOR");
+        assert_eq!(result.payload["unit"], "c");
+        assert!(result.payload["seen"].is_string());
+        assert_eq!(result.destination, "/sensors");
+    }
+
+    #[test]
+    fn test_limits() {
+        let ctx = TransformContext {
+            payload: serde_json::json!({}),
+            metadata: HashMap::new(),
+            protocol: "http".to_string(),
+            destination: "/api".to_string(),
+        };
+
+        let engine = RhaiEngine::with_limits(ScriptLimits {
+            timeout_ms: 20,
+            max_operations: 0,
+            ..Default::default()
+        });
+        let result = engine.execute("loop { }", &ctx);
+        assert!(matches!(result, Err(RhaiError::Timeout)));
+
+        let engine = RhaiEngine::with_limits(ScriptLimits {
+            max_string_size: 16,
+            ..Default::default()
+        });
+        let result = engine.execute(r#"payload["s"] = "0123456789" + "0123456789";"#, &ctx);
+        assert!(matches!(result, Err(RhaiError::LimitExceeded(_))));
+        assert!(script_metrics().count("engine", ScriptLimit::Size) >= 1);
+    }
+
     #[test]
     fn test_json_functions() {
         let engine = RhaiEngine::new();
@@ -0,0 +1,58 @@
+//! Route matching at 10k routes: radix tree vs linear template scan.
+//!
+//! Run with `cargo bench -p gateway-core --bench router`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use gateway_core::config::Route;
+use gateway_core::router::{build_router_map, find_route, find_route_linear, RequestInfo};
+
+const ROUTES: usize = 10_000;
+
+/// A mix of static, parameterised, constrained and prefix routes, similar
+/// to a large multi-tenant deployment.
+fn routes(n: usize) -> Vec<Route> {
+    (0..n)
+        .map(|i| {
+            let path = match i % 4 {
+                0 => format!("/svc{}/health", i),
+                1 => format!("/svc{}/users/{{id}}", i),
+                2 => format!("/svc{}/orders/{{id:[0-9]+}}/items", i),
+                _ => format!("/svc{}/static/", i),
+            };
+            Route::new(i.to_string(), path, format!("http://svc{}:8080", i))
+        })
+        .collect()
+}
+
+fn bench_match(c: &mut Criterion) {
+    let map = build_router_map(routes(ROUTES));
+    let last = ROUTES - 1;
+
+    let paths = [
+        ("exact", format!("/svc{}/health", ROUTES - 4)),
+        ("param", format!("/svc{}/users/alice", ROUTES - 3)),
+        ("constrained", format!("/svc{}/orders/42/items", ROUTES - 2)),
+        ("prefix", format!("/svc{}/static/css/site.css", last)),
+        ("miss", "/unknown/path".to_string()),
+    ];
+
+    let mut group = c.benchmark_group("match_10k_routes");
+    for (name, path) in &paths {
+        group.bench_with_input(BenchmarkId::new("radix", name), path, |b, path| {
+            b.iter(|| find_route(black_box(path), &map))
+        });
+        group.bench_with_input(BenchmarkId::new("linear", name), path, |b, path| {
+            b.iter(|| find_route_linear(&RequestInfo::from_path(black_box(path)), &map))
+        });
+    }
+    group.finish();
+}
+
+fn bench_build(c: &mut Criterion) {
+    let routes = routes(ROUTES);
+    c.bench_function("build_10k_routes", |b| b.iter(|| build_router_map(black_box(routes.clone()))));
+}
+
+criterion_group!(benches, bench_match, bench_build);
+criterion_main!(benches);
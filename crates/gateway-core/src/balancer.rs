@@ -0,0 +1,398 @@
+//! Upstream target selection for multi-upstream routes.
+//!
+//! A route may list several weighted upstream targets. The `LoadBalancer`
+//! picks one per request according to the route's `LoadBalancing` policy and
+//! tracks in-flight requests per target so load-aware policies can use them.
+//!
+//! Balancer state lives in `GatewayState`, outside the hot-swapped routing
+//! table, so round-robin positions and in-flight counts survive reloads.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hyper::HeaderMap;
+use rand::Rng;
+
+use crate::config::{HashKey, LoadBalancing, Route, UpstreamTarget};
+
+/// Picks upstream targets for routes and tracks in-flight requests.
+#[derive(Default)]
+pub struct LoadBalancer {
+    /// Smooth weighted round-robin state, keyed by route id
+    round_robin: Mutex<HashMap<String, RoundRobinState>>,
+
+    /// In-flight request counters, keyed by upstream URL
+    in_flight: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+/// Per-route smooth weighted round-robin state.
+struct RoundRobinState {
+    /// Targets the weights below were computed for
+    targets: Vec<UpstreamTarget>,
+    /// Current effective weight of each target
+    current: Vec<i64>,
+}
+
+/// An upstream chosen for one request.
+///
+/// Counts as in flight on its target until dropped.
+pub struct SelectedUpstream {
+    /// Base URL of the chosen target
+    pub url: String,
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for SelectedUpstream {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadBalancer {
+    /// Create an empty load balancer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Select an upstream target for a request on `route`.
+    ///
+    /// `hash_key` is only used by the consistent-hash policy; without one,
+    /// that policy falls back to weighted round robin.
+    ///
+    /// Targets rejected by `is_available` (unhealthy or ejected) are skipped.
+    /// If that leaves nothing, all targets are considered again: sending
+    /// traffic to a possibly-bad target beats failing every request.
+    ///
+    /// Returns `None` when the route has no usable targets.
+    pub fn select(
+        &self,
+        route: &Route,
+        hash_key: Option<&str>,
+        is_available: impl Fn(&str) -> bool,
+    ) -> Option<SelectedUpstream> {
+        let configured = route.targets();
+        let available: Vec<UpstreamTarget> = configured
+            .iter()
+            .filter(|t| is_available(&t.url))
+            .cloned()
+            .collect();
+        let targets: &[UpstreamTarget] = if available.is_empty() { &configured } else { &available };
+
+        let index = match targets.len() {
+            0 => return None,
+            1 => 0,
+            _ => match (&route.load_balancing, hash_key) {
+                (LoadBalancing::WeightedRoundRobin, _) => self.round_robin(&route.id, targets),
+                (LoadBalancing::LeastOutstanding, _) => self.least_outstanding(targets),
+                (LoadBalancing::RandomTwoChoices, _) => self.random_two_choices(targets),
+                (LoadBalancing::ConsistentHash { .. }, Some(key)) => rendezvous(targets, key),
+                (LoadBalancing::ConsistentHash { .. }, None) => self.round_robin(&route.id, targets),
+            },
+        };
+
+        let url = targets[index].url.clone();
+        let counter = self.counter(&url);
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        tracing::trace!(
+            route_id = %route.id,
+            policy = route.load_balancing.name(),
+            upstream = %url,
+            "Selected upstream target"
+        );
+
+        Some(SelectedUpstream { url, counter })
+    }
+
+    /// Number of requests currently in flight to an upstream
+    pub fn in_flight(&self, url: &str) -> usize {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .get(url)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Snapshot of in-flight request counts for every known upstream
+    pub fn in_flight_snapshot(&self) -> HashMap<String, usize> {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(url, c)| (url.clone(), c.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn counter(&self, url: &str) -> Arc<AtomicUsize> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        match in_flight.get(url) {
+            Some(counter) => counter.clone(),
+            None => in_flight.entry(url.to_string()).or_default().clone(),
+        }
+    }
+
+    /// Smooth weighted round robin (as used by nginx).
+    ///
+    /// Spreads picks evenly over time instead of sending `weight` requests
+    /// in a row to the same target.
+    fn round_robin(&self, route_id: &str, targets: &[UpstreamTarget]) -> usize {
+        let mut states = self.round_robin.lock().unwrap();
+        let state = states
+            .entry(route_id.to_string())
+            .or_insert_with(|| RoundRobinState {
+                targets: Vec::new(),
+                current: Vec::new(),
+            });
+
+        // Targets changed on reload: start over
+        if state.targets != targets {
+            state.targets = targets.to_vec();
+            state.current = vec![0; targets.len()];
+        }
+
+        let total: i64 = targets.iter().map(|t| t.weight as i64).sum();
+        let mut best = 0;
+        for (i, target) in targets.iter().enumerate() {
+            state.current[i] += target.weight as i64;
+            if state.current[i] > state.current[best] {
+                best = i;
+            }
+        }
+        state.current[best] -= total;
+        best
+    }
+
+    /// Fewest in-flight requests relative to weight.
+    fn least_outstanding(&self, targets: &[UpstreamTarget]) -> usize {
+        let loads: Vec<usize> = targets.iter().map(|t| self.in_flight(&t.url)).collect();
+
+        (0..targets.len())
+            .min_by(|&a, &b| {
+                // Compare load_a / weight_a with load_b / weight_b without dividing
+                let lhs = loads[a] as u64 * targets[b].weight as u64;
+                let rhs = loads[b] as u64 * targets[a].weight as u64;
+                lhs.cmp(&rhs)
+                    .then_with(|| targets[b].weight.cmp(&targets[a].weight))
+            })
+            .unwrap_or(0)
+    }
+
+    /// Power of two random choices, weighted.
+    fn random_two_choices(&self, targets: &[UpstreamTarget]) -> usize {
+        let mut rng = rand::thread_rng();
+        let first = weighted_random(targets, &mut rng);
+        let second = weighted_random(targets, &mut rng);
+        self.lighter(targets, first, second)
+    }
+
+    /// The candidate with fewer in-flight requests, `first` on a tie.
+    fn lighter(&self, targets: &[UpstreamTarget], first: usize, second: usize) -> usize {
+        if self.in_flight(&targets[second].url) < self.in_flight(&targets[first].url) {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+/// Pick a target at random with probability proportional to its weight.
+fn weighted_random(targets: &[UpstreamTarget], rng: &mut impl Rng) -> usize {
+    let total: u64 = targets.iter().map(|t| t.weight as u64).sum();
+    let mut point = rng.gen_range(0..total.max(1));
+
+    for (i, target) in targets.iter().enumerate() {
+        if point < target.weight as u64 {
+            return i;
+        }
+        point -= target.weight as u64;
+    }
+    targets.len() - 1
+}
+
+/// Weighted rendezvous (highest random weight) hashing.
+///
+/// Every target scores the key independently and the highest score wins, so
+/// adding or removing a target only moves the keys that belonged to it.
+fn rendezvous(targets: &[UpstreamTarget], key: &str) -> usize {
+    targets
+        .iter()
+        .enumerate()
+        .map(|(i, target)| {
+            let hash = mix64(fnv1a(&[key.as_bytes(), b"\0", target.url.as_bytes()]));
+            // Map the hash into (0, 1) and apply the weight
+            let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            (i, target.weight as f64 / -unit.ln())
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// 64-bit FNV-1a, stable across processes and releases.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in parts.iter().flat_map(|p| p.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// SplitMix64 finalizer; FNV alone leaves the high bits poorly mixed.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Derive the consistent-hashing key for a request.
+///
+/// Returns `None` for other policies, or when neither the configured header
+/// nor the client address is available.
+pub fn hash_key(policy: &LoadBalancing, headers: &HeaderMap, client_ip: Option<IpAddr>) -> Option<String> {
+    let LoadBalancing::ConsistentHash { hash_on } = policy else {
+        return None;
+    };
+
+    if let HashKey::Header(name) = hash_on {
+        if let Some(value) = headers.get(name.as_str()).and_then(|v| v.to_str().ok()) {
+            return Some(value.to_string());
+        }
+    }
+
+    client_ip.map(|ip| ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(policy: LoadBalancing, weights: &[u32]) -> Route {
+        let targets = weights
+            .iter()
+            .enumerate()
+            .map(|(i, w)| UpstreamTarget::new(format!("http://backend-{}:8080", i), *w))
+            .collect();
+        Route::with_upstreams("lb", "/lb", targets, policy)
+    }
+
+    fn pick(lb: &LoadBalancer, route: &Route, key: Option<&str>) -> String {
+        lb.select(route, key, |_| true).unwrap().url.clone()
+    }
+
+    #[test]
+    fn test_single_upstream() {
+        let lb = LoadBalancer::new();
+        let route = Route::new("1", "/api", "http://svc:8080");
+        assert_eq!(pick(&lb, &route, None), "http://svc:8080");
+
+        let route = Route::new("2", "/api", "");
+        assert!(lb.select(&route, None, |_| true).is_none());
+    }
+
+    #[test]
+    fn test_skips_unavailable_targets() {
+        let lb = LoadBalancer::new();
+        let route = route(LoadBalancing::WeightedRoundRobin, &[1, 1, 1]);
+
+        for _ in 0..6 {
+            let selected = lb.select(&route, None, |url| url.contains("-1:")).unwrap();
+            assert!(selected.url.contains("-1:"));
+        }
+
+        // Nothing available: fail open rather than reject the request
+        assert!(lb.select(&route, None, |_| false).is_some());
+    }
+
+    #[test]
+    fn test_weighted_round_robin_is_smooth() {
+        let lb = LoadBalancer::new();
+        let route = route(LoadBalancing::WeightedRoundRobin, &[5, 1, 1]);
+
+        let picks: Vec<String> = (0..7).map(|_| pick(&lb, &route, None)).collect();
+        let count = |n: usize| picks.iter().filter(|u| u.contains(&format!("-{}:", n))).count();
+        assert_eq!((count(0), count(1), count(2)), (5, 1, 1));
+
+        // Smooth: the light targets are interleaved rather than left to the end
+        assert!(picks[..3].iter().any(|u| !u.contains("-0:")));
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let lb = LoadBalancer::new();
+        let route = route(LoadBalancing::LeastOutstanding, &[1, 1]);
+
+        let first = lb.select(&route, None, |_| true).unwrap();
+        let second = lb.select(&route, None, |_| true).unwrap();
+        assert_ne!(first.url, second.url);
+        assert_eq!(lb.in_flight(&first.url), 1);
+
+        drop(first);
+        let snapshot = lb.in_flight_snapshot();
+        assert_eq!(snapshot.values().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn test_random_two_choices_prefers_idle_target() {
+        let lb = LoadBalancer::new();
+        let route = route(LoadBalancing::RandomTwoChoices, &[1, 1]);
+
+        // Pin a request on backend-0 so backend-1 is always the lighter choice
+        let busy = loop {
+            let selected = lb.select(&route, None, |_| true).unwrap();
+            if selected.url.contains("-0:") {
+                break selected;
+            }
+        };
+        assert_eq!(lb.in_flight(&busy.url), 1);
+
+        // Whenever both targets are sampled, in either order, the idle one wins
+        assert_eq!(lb.lighter(&route.upstreams, 0, 1), 1);
+        assert_eq!(lb.lighter(&route.upstreams, 1, 0), 1);
+
+        // So the busy target is only picked when it is sampled twice
+        let busy_picks = (0..400).filter(|_| pick(&lb, &route, None).contains("-0:")).count();
+        assert!(busy_picks < 200, "busy target picked {} times out of 400", busy_picks);
+    }
+
+    #[test]
+    fn test_consistent_hash_is_sticky() {
+        let lb = LoadBalancer::new();
+        let policy = LoadBalancing::ConsistentHash { hash_on: HashKey::ClientIp };
+        let route3 = route(policy.clone(), &[1, 1, 1]);
+
+        let mut moved = 0;
+        for i in 0..100 {
+            let key = format!("10.0.0.{}", i);
+            let chosen = pick(&lb, &route3, Some(&key));
+            assert_eq!(chosen, pick(&lb, &route3, Some(&key)));
+
+            // Adding a fourth target only moves keys onto the new target
+            let route4 = route(policy.clone(), &[1, 1, 1, 1]);
+            let rehashed = pick(&lb, &route4, Some(&key));
+            if rehashed != chosen {
+                assert!(rehashed.contains("-3:"));
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 50);
+    }
+
+    #[test]
+    fn test_hash_key() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", "alice".parse().unwrap());
+        let ip: IpAddr = "192.168.1.10".parse().unwrap();
+
+        let by_header = LoadBalancing::ConsistentHash { hash_on: HashKey::Header("x-user".into()) };
+        assert_eq!(hash_key(&by_header, &headers, Some(ip)).as_deref(), Some("alice"));
+        assert_eq!(hash_key(&by_header, &HeaderMap::new(), Some(ip)).as_deref(), Some("192.168.1.10"));
+
+        let by_ip = LoadBalancing::ConsistentHash { hash_on: HashKey::ClientIp };
+        assert_eq!(hash_key(&by_ip, &headers, None), None);
+        assert_eq!(hash_key(&LoadBalancing::WeightedRoundRobin, &headers, Some(ip)), None);
+    }
+}
@@ -1,23 +1,354 @@
 //! Secure body handling with size limits.
 //!
 //! This module addresses the critical security vulnerability of unbounded
-//! body consumption. Using `http_body_util::Limited`, we enforce strict
-//! upper bounds on request body sizes to prevent OOM denial of service.
+//! body consumption. Request bodies are either streamed through with the
+//! limit enforced chunk by chunk ([`stream_limited`]), or buffered under
+//! the same limit when a route needs the whole payload
+//! ([`collect_body_limited`]). Responses on streaming routes are passed
+//! through [`idle_timeout`] instead of being held to a total deadline.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::Bytes;
-use http_body_util::{BodyExt, Limited};
-use hyper::body::Incoming;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use tokio::time::{Instant, Sleep};
 
 use crate::error::GatewayError;
 
-/// Default maximum body size: 2MB
-/// This can be overridden per-route in future phases.
+/// Default maximum request body size: 2MB
+/// Routes override it with `max_request_body_bytes`.
 pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
 
+/// Boxed error type carried by gateway bodies.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body type flowing through the proxy path.
+///
+/// Both buffered payloads (error responses, collected request bodies) and
+/// streamed upstream bodies are boxed into this single type so the handler
+/// can return either from the same code path.
+pub type GatewayBody = UnsyncBoxBody<Bytes, BoxError>;
+
+/// Create a `GatewayBody` from a complete buffer.
+pub fn full(data: impl Into<Bytes>) -> GatewayBody {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Create an empty `GatewayBody`.
+pub fn empty() -> GatewayBody {
+    Empty::<Bytes>::new()
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Box any compatible body into a `GatewayBody` without buffering it.
+pub fn boxed<B>(body: B) -> GatewayBody
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(Into::into).boxed_unsync()
+}
+
+/// Shared record of whether a streamed body hit its size limit.
+///
+/// Attached to the outgoing request as an extension, so the proxy path can
+/// tell a client that sent too much apart from a failing upstream.
+#[derive(Debug, Clone)]
+pub struct LimitStatus(Arc<LimitState>);
+
+#[derive(Debug)]
+struct LimitState {
+    limit: usize,
+    observed: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl LimitStatus {
+    fn new(limit: usize) -> Self {
+        Self(Arc::new(LimitState {
+            limit,
+            observed: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }))
+    }
+
+    /// Whether the body was cut off at its limit
+    pub fn exceeded(&self) -> bool {
+        self.0.exceeded.load(Ordering::Acquire)
+    }
+
+    /// Bytes seen so far, including the chunk that crossed the limit
+    pub fn observed(&self) -> u64 {
+        self.0.observed.load(Ordering::Acquire)
+    }
+
+    /// The enforced limit in bytes
+    pub fn limit(&self) -> usize {
+        self.0.limit
+    }
+
+    /// The error describing a cut-off request body
+    pub fn to_error(&self) -> GatewayError {
+        GatewayError::PayloadTooLarge {
+            size: self.observed(),
+            limit: self.limit() as u64,
+        }
+    }
+}
+
+/// Error yielded by a streamed body that went over its limit
+#[derive(Debug)]
+struct LimitExceeded(usize);
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "body exceeds limit of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Body wrapper counting data frames against a limit as they pass.
+struct StreamLimited {
+    inner: GatewayBody,
+    seen: u64,
+    status: LimitStatus,
+}
+
+impl Body for StreamLimited {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            other => return other,
+        };
+
+        if let Some(data) = frame.data_ref() {
+            self.seen += data.len() as u64;
+            let state = &self.status.0;
+            state.observed.store(self.seen, Ordering::Release);
+            if self.seen > state.limit as u64 {
+                state.exceeded.store(true, Ordering::Release);
+                return Poll::Ready(Some(Err(Box::new(LimitExceeded(state.limit)))));
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Stream a body through, failing it as soon as more than `max_size`
+/// bytes have passed.
+///
+/// Nothing is buffered: each chunk is handed on as it arrives, so the
+/// reader's pace sets the writer's (backpressure). The returned
+/// [`LimitStatus`] reports whether the body was cut off.
+pub fn stream_limited<B>(body: B, max_size: usize) -> (GatewayBody, LimitStatus)
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let status = LimitStatus::new(max_size);
+    let limited = StreamLimited {
+        inner: boxed(body),
+        seen: 0,
+        status: status.clone(),
+    };
+    (limited.boxed_unsync(), status)
+}
+
+/// Error yielded by a streamed body that went quiet for too long
+#[derive(Debug)]
+struct IdleTimedOut(Duration);
+
+impl std::fmt::Display for IdleTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no body data for {}ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for IdleTimedOut {}
+
+/// Body wrapper failing once no frame arrives within the idle timeout.
+struct IdleTimeout {
+    inner: GatewayBody,
+    idle: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl Body for IdleTimeout {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                let next = Instant::now() + self.idle;
+                self.deadline.as_mut().reset(next);
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(other) => Poll::Ready(other),
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Some(Err(Box::new(IdleTimedOut(self.idle))))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Stream a body through, failing it once no frame arrives for `idle`.
+///
+/// Frames are handed on one by one as they arrive, so each Server-Sent
+/// Event reaches the client as soon as the upstream sends it.
+pub fn idle_timeout<B>(body: B, idle: Duration) -> GatewayBody
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    IdleTimeout {
+        inner: boxed(body),
+        idle,
+        deadline: Box::pin(tokio::time::sleep(idle)),
+    }
+    .boxed_unsync()
+}
+
+/// Body wrapper keeping a value alive until the body is dropped.
+struct Held {
+    inner: GatewayBody,
+    _guard: Box<dyn Send>,
+}
+
+impl Body for Held {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Keep `guard` alive for as long as `body`, e.g. so a response counts
+/// as in flight until it has been streamed to the client.
+pub fn hold<G: Send + 'static>(body: GatewayBody, guard: G) -> GatewayBody {
+    Held {
+        inner: body,
+        _guard: Box::new(guard),
+    }
+    .boxed_unsync()
+}
+
+/// Reject a request up front when its declared `Content-Length` is over
+/// the limit, before any of the body is read.
+pub fn check_content_length(headers: &HeaderMap, max_size: usize) -> Result<(), GatewayError> {
+    match content_length(headers) {
+        Some(size) if size > max_size as u64 => Err(GatewayError::PayloadTooLarge {
+            size,
+            limit: max_size as u64,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The declared `Content-Length`, if present and valid
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Check the request media type against a route's allowlist.
+///
+/// An empty allowlist accepts anything, as does a request without a body.
+/// Entries are `type/subtype`, `type/*` or `*/*`; parameters such as
+/// `charset` are ignored and matching is case-insensitive.
+pub fn check_content_type(headers: &HeaderMap, allowed: &[String], has_body: bool) -> Result<(), GatewayError> {
+    if allowed.is_empty() || !has_body {
+        return Ok(());
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+
+    if media_type_matches(media_type, allowed) {
+        Ok(())
+    } else {
+        Err(GatewayError::UnsupportedMediaType {
+            content_type: content_type.to_string(),
+        })
+    }
+}
+
+/// Whether a media type (without parameters) matches any of `patterns`,
+/// given as `type/subtype`, `type/*` or `*/*`. An empty media type matches
+/// nothing.
+pub(crate) fn media_type_matches(media_type: &str, patterns: &[String]) -> bool {
+    !media_type.is_empty()
+        && patterns.iter().any(|pattern| {
+            let pattern = pattern.trim();
+            match pattern.strip_suffix("/*") {
+                Some("*") => true,
+                Some(main) => media_type
+                    .split_once('/')
+                    .is_some_and(|(m, _)| m.eq_ignore_ascii_case(main)),
+                None => media_type.eq_ignore_ascii_case(pattern),
+            }
+        })
+}
+
 /// Collect an incoming body with a size limit.
 ///
-/// This function wraps the body in a `Limited` wrapper that enforces
-/// the specified size limit. If the body exceeds the limit during
+/// This function wraps the body in a [`stream_limited`] wrapper that
+/// enforces the specified size limit. If the body exceeds the limit during
 /// streaming, collection fails immediately.
 ///
 /// # Security
@@ -34,21 +365,22 @@ pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
 /// # Returns
 ///
 /// * `Ok(Bytes)` - The collected body data
-/// * `Err(GatewayError::PayloadTooLarge)` - If the limit is exceeded
-pub async fn collect_body_limited(body: Incoming, max_size: usize) -> Result<Bytes, GatewayError> {
-    let limited = Limited::new(body, max_size);
+/// * `Err(GatewayError::PayloadTooLarge)` - If the limit is exceeded, with
+///   the number of bytes received before collection stopped
+pub async fn collect_body_limited<B>(body: B, max_size: usize) -> Result<Bytes, GatewayError>
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let (limited, status) = stream_limited(body, max_size);
 
     limited
         .collect()
         .await
         .map(|collected| collected.to_bytes())
         .map_err(|e| {
-            // Check if this was a limit error
-            if e.to_string().contains("length limit exceeded") {
-                GatewayError::PayloadTooLarge {
-                    size: 0, // We don't know the actual size
-                    limit: max_size as u64,
-                }
+            if status.exceeded() {
+                status.to_error()
             } else {
                 GatewayError::BodyReadError(e.to_string())
             }
@@ -97,7 +429,108 @@ pub mod zero_copy {
 #[cfg(test)]
 mod tests {
     use super::zero_copy::*;
-    use bytes::Bytes;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_limited_passes_small_bodies() {
+        let (body, status) = stream_limited(full("hello"), 5);
+        let collected = body.collect().await.unwrap().to_bytes();
+
+        assert_eq!(&collected[..], b"hello");
+        assert!(!status.exceeded());
+    }
+
+    #[tokio::test]
+    async fn test_stream_limited_cuts_off_large_bodies() {
+        let chunks = futures::stream::iter(
+            ["abc", "def", "ghi"].map(|c| Ok::<_, BoxError>(Frame::data(Bytes::from(c)))),
+        );
+        let (mut body, status) = stream_limited(http_body_util::StreamBody::new(chunks), 7);
+
+        // Chunks within the limit are forwarded as they arrive
+        assert_eq!(body.frame().await.unwrap().unwrap().into_data().unwrap(), "abc");
+        assert_eq!(body.frame().await.unwrap().unwrap().into_data().unwrap(), "def");
+        assert!(!status.exceeded());
+
+        assert!(body.frame().await.unwrap().is_err());
+        assert!(status.exceeded());
+    }
+
+    #[tokio::test]
+    async fn test_collect_reports_observed_size() {
+        let chunks = futures::stream::iter(
+            ["abcd", "efgh"].map(|c| Ok::<_, BoxError>(Frame::data(Bytes::from(c)))),
+        );
+        let result = collect_body_limited(http_body_util::StreamBody::new(chunks), 6).await;
+
+        assert!(matches!(result, Err(GatewayError::PayloadTooLarge { size: 8, limit: 6 })));
+    }
+
+    /// A body yielding one `x` after each delay
+    fn delayed_chunks(delays_ms: &'static [u64]) -> GatewayBody {
+        use futures::StreamExt;
+
+        let stream = futures::stream::iter(delays_ms).then(|&ms| async move {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok::<_, std::convert::Infallible>(Frame::data(Bytes::from_static(b"x")))
+        });
+        boxed(http_body_util::StreamBody::new(stream))
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_resets_on_data() {
+        let body = idle_timeout(delayed_chunks(&[30, 30, 30]), Duration::from_millis(100));
+        let collected = body.collect().await.unwrap().to_bytes();
+
+        assert_eq!(&collected[..], b"xxx");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_fails_quiet_bodies() {
+        let mut body = idle_timeout(delayed_chunks(&[10, 500]), Duration::from_millis(50));
+
+        assert!(body.frame().await.unwrap().is_ok());
+        let err = body.frame().await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "no body data for 50ms");
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        let allowed = vec!["application/json".to_string(), "image/*".to_string()];
+        let headers = |ct: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, ct.parse().unwrap());
+            headers
+        };
+
+        assert!(check_content_type(&headers("application/json"), &allowed, true).is_ok());
+        assert!(check_content_type(&headers("Application/JSON; charset=utf-8"), &allowed, true).is_ok());
+        assert!(check_content_type(&headers("image/png"), &allowed, true).is_ok());
+        assert!(matches!(
+            check_content_type(&headers("text/xml"), &allowed, true),
+            Err(GatewayError::UnsupportedMediaType { .. })
+        ));
+
+        // Missing content type only matters when there is a body
+        assert!(check_content_type(&HeaderMap::new(), &allowed, true).is_err());
+        assert!(check_content_type(&HeaderMap::new(), &allowed, false).is_ok());
+        assert!(check_content_type(&headers("text/xml"), &[], true).is_ok());
+    }
+
+    #[test]
+    fn test_check_content_length() {
+        let mut headers = HeaderMap::new();
+        assert!(check_content_length(&headers, 10).is_ok());
+
+        headers.insert(CONTENT_LENGTH, "10".parse().unwrap());
+        assert!(check_content_length(&headers, 10).is_ok());
+
+        headers.insert(CONTENT_LENGTH, "11".parse().unwrap());
+        assert!(matches!(
+            check_content_length(&headers, 10),
+            Err(GatewayError::PayloadTooLarge { size: 11, limit: 10 })
+        ));
+    }
 
     #[test]
     fn test_zero_copy_slice() {
@@ -0,0 +1,1021 @@
+//! In-process HTTP response cache.
+//!
+//! GET responses on routes with a [`CacheConfig`] are kept in a shared moka
+//! cache bounded by total size. What is stored, and for how long, follows
+//! the upstream's `Cache-Control` (`s-maxage`, `max-age`, `no-cache`,
+//! `no-store`, `private`, `public`, `stale-while-revalidate`) and `Expires`
+//! headers; `Vary` splits entries by request header. On top of that:
+//!
+//! - concurrent misses for the same key share one upstream request
+//! - within the stale-while-revalidate window a stale entry is served while
+//!   it is refreshed in the background
+//! - expired entries with an `ETag` or `Last-Modified` are revalidated with
+//!   a conditional request, and clients' own `If-None-Match` /
+//!   `If-Modified-Since` are answered from the cache
+//!
+//! Every response served through the cache carries an `X-Cache` header:
+//! `HIT`, `STALE`, `REVALIDATED`, `MISS` or `BYPASS`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, DATE, ETAG, EXPIRES,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, SET_COOKIE, VARY,
+};
+use hyper::http::request::Parts;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use moka::future::Cache;
+use moka::Expiry;
+use tokio::sync::watch;
+
+use crate::body::{boxed, content_length, empty, full, BoxError, GatewayBody};
+use crate::config::{CacheConfig, Route};
+use crate::error::GatewayError;
+use crate::headers::{strip_hop_by_hop, JwtClaims};
+
+/// Default total size of cached responses: 64MB
+pub const DEFAULT_CACHE_CAPACITY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Statuses that may be cached (heuristically cacheable, RFC 9110 section 15.1)
+const CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// Sends a request upstream through the normal proxy path.
+pub type Fetch =
+    Arc<dyn Fn(Request<GatewayBody>) -> BoxFuture<'static, Result<Response<GatewayBody>, GatewayError>> + Send + Sync>;
+
+/// Parsed `Cache-Control` directives.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub no_transform: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    /// Parse every `Cache-Control` header; unknown directives are ignored.
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+        let directives = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = || value.and_then(|v| v.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                "no-transform" => cc.no_transform = true,
+                "max-age" => cc.max_age = secs(),
+                "s-maxage" => cc.s_maxage = secs(),
+                "stale-while-revalidate" => cc.stale_while_revalidate = secs(),
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+/// Freshness lifetime of a response, before subtracting its `Age`.
+///
+/// `s-maxage` wins over `max-age`, which wins over `Expires` - `Date`; the
+/// route's default applies when none is given. `no-cache` responses are
+/// never fresh, and lifetimes are capped at the route's maximum.
+pub fn freshness_lifetime(headers: &HeaderMap, cc: &CacheControl, config: &CacheConfig) -> Duration {
+    if cc.no_cache {
+        return Duration::ZERO;
+    }
+
+    let lifetime = cc
+        .s_maxage
+        .or(cc.max_age)
+        .or_else(|| {
+            let expires = headers.get(EXPIRES)?;
+            // An invalid `Expires` means the response is already stale
+            let Some(expires) = http_date(expires) else {
+                return Some(0);
+            };
+            let date = headers.get(DATE).and_then(http_date).unwrap_or_else(SystemTime::now);
+            Some(expires.duration_since(date).map_or(0, |d| d.as_secs()))
+        })
+        .unwrap_or(config.default_ttl_secs);
+
+    Duration::from_secs(lifetime.min(config.max_ttl_secs))
+}
+
+fn http_date(value: &HeaderValue) -> Option<SystemTime> {
+    let date = chrono::DateTime::parse_from_rfc2822(value.to_str().ok()?).ok()?;
+    Some(date.into())
+}
+
+/// Cache key of a request: its path, then whatever the route's key config
+/// adds (query string, header values, JWT subject). There is none when the
+/// key includes the JWT subject and the request has no claims, so that
+/// anonymous callers never share per-caller responses.
+pub fn cache_key(parts: &Parts, config: &CacheConfig) -> Option<String> {
+    let mut key = parts.uri.path().to_string();
+
+    if config.key.query {
+        if let Some(query) = parts.uri.query() {
+            key.push('?');
+            key.push_str(query);
+        }
+    }
+    for name in &config.key.headers {
+        push_header(&mut key, &name.to_ascii_lowercase(), &parts.headers);
+    }
+    if config.key.jwt_subject {
+        let subject = parts
+            .extensions
+            .get::<JwtClaims>()
+            .and_then(|claims| claims.0.get("sub")?.as_str())?;
+        key.push_str("|sub=");
+        key.push_str(subject);
+    }
+    Some(key)
+}
+
+fn push_header(key: &mut String, name: &str, headers: &HeaderMap) {
+    let values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+    key.push('|');
+    key.push_str(name);
+    key.push('=');
+    key.push_str(&values.join(","));
+}
+
+/// A stored response.
+#[derive(Debug)]
+struct Entry {
+    route_id: String,
+    /// Cache key, including the values of `Vary` headers
+    key: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// When the response was generated upstream, going by its `Age`
+    born: Instant,
+    fresh_for: Duration,
+    stale_for: Duration,
+    /// How long the cache keeps the entry, counted from insertion
+    keep_for: Duration,
+}
+
+/// Where an entry is in its lifetime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Fresh,
+    /// Past its freshness but within the stale-while-revalidate window
+    Stale,
+    Expired,
+}
+
+impl Entry {
+    fn new(
+        route_id: &str,
+        key: String,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        config: &CacheConfig,
+    ) -> Self {
+        let cc = CacheControl::parse(&headers);
+        let age = headers
+            .get(AGE)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        let fresh_for = freshness_lifetime(&headers, &cc, config);
+        let stale_for = if cc.must_revalidate {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(
+                cc.stale_while_revalidate
+                    .unwrap_or(0)
+                    .max(config.stale_while_revalidate_secs),
+            )
+        };
+        // Entries that can be revalidated are kept a while longer
+        let validation = if headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED) {
+            Duration::from_secs(config.max_ttl_secs)
+        } else {
+            Duration::ZERO
+        };
+
+        let now = Instant::now();
+        Self {
+            route_id: route_id.to_string(),
+            key,
+            status,
+            headers,
+            body,
+            born: now.checked_sub(age).unwrap_or(now),
+            fresh_for,
+            stale_for,
+            keep_for: (fresh_for + stale_for + validation).saturating_sub(age),
+        }
+    }
+
+    fn age(&self) -> Duration {
+        self.born.elapsed()
+    }
+
+    fn state(&self) -> State {
+        let age = self.age();
+        if age < self.fresh_for {
+            State::Fresh
+        } else if age < self.fresh_for + self.stale_for {
+            State::Stale
+        } else {
+            State::Expired
+        }
+    }
+
+    fn has_validator(&self) -> bool {
+        self.headers.contains_key(ETAG) || self.headers.contains_key(LAST_MODIFIED)
+    }
+
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.key.len() + headers + self.body.len()
+    }
+
+    /// Whether a client's conditional headers match this entry
+    fn not_modified(&self, request: &HeaderMap) -> bool {
+        if self.status != StatusCode::OK {
+            return false;
+        }
+        if let Some(if_none_match) = request.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+            let Some(etag) = self.headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+                return false;
+            };
+            // Weak comparison (RFC 9110 section 8.8.3.2)
+            let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            return if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| weak(tag) == weak(etag));
+        }
+
+        let since = request.get(IF_MODIFIED_SINCE).and_then(http_date);
+        let modified = self.headers.get(LAST_MODIFIED).and_then(http_date);
+        matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+    }
+
+    /// Build the response for a request served from this entry.
+    fn respond(&self, request: &RequestHead, cache_status: &'static str) -> Response<GatewayBody> {
+        let mut headers = self.headers.clone();
+        headers.insert(AGE, HeaderValue::from(self.age().as_secs()));
+        headers.insert(X_CACHE, HeaderValue::from_static(cache_status));
+
+        let (status, body) = if self.not_modified(&request.headers) {
+            headers.remove(CONTENT_LENGTH);
+            (StatusCode::NOT_MODIFIED, empty())
+        } else if request.method == Method::HEAD {
+            (self.status, empty())
+        } else {
+            (self.status, full(self.body.clone()))
+        };
+
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        response
+    }
+
+    /// The entry refreshed by a `304 Not Modified` from upstream.
+    fn refreshed(&self, not_modified: &HeaderMap, config: &CacheConfig) -> Self {
+        let mut headers = self.headers.clone();
+        for name in not_modified.keys() {
+            if *name != CONTENT_LENGTH {
+                headers.remove(name);
+            }
+        }
+        for (name, value) in not_modified {
+            if name != CONTENT_LENGTH {
+                headers.append(name, value.clone());
+            }
+        }
+        strip_hop_by_hop(&mut headers);
+        Entry::new(&self.route_id, self.key.clone(), self.status, headers, self.body.clone(), config)
+    }
+}
+
+/// Expires entries after their `keep_for`
+struct KeepFor;
+
+impl Expiry<String, Arc<Entry>> for KeepFor {
+    fn expire_after_create(&self, _key: &String, entry: &Arc<Entry>, _created_at: Instant) -> Option<Duration> {
+        Some(entry.keep_for)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        entry: &Arc<Entry>,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(entry.keep_for)
+    }
+}
+
+/// The parts of a request the cache needs to replay it upstream.
+#[derive(Debug, Clone)]
+struct RequestHead {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+}
+
+impl RequestHead {
+    /// Upstream request for this head, conditional on `validators`' entry.
+    ///
+    /// The client's own conditional headers are dropped so a full response
+    /// comes back to fill the cache; they are answered from the entry.
+    fn to_request(&self, validators: Option<&Entry>) -> Request<GatewayBody> {
+        let mut req = Request::new(empty());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.headers_mut() = self.headers.clone();
+
+        let headers = req.headers_mut();
+        headers.remove(IF_NONE_MATCH);
+        headers.remove(IF_MODIFIED_SINCE);
+        if let Some(entry) = validators {
+            if let Some(etag) = entry.headers.get(ETAG) {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(modified) = entry.headers.get(LAST_MODIFIED) {
+                headers.insert(IF_MODIFIED_SINCE, modified.clone());
+            }
+        }
+        req
+    }
+}
+
+/// One request being served through the cache.
+struct Lookup<'a> {
+    head: RequestHead,
+    route: &'a Route,
+    config: &'a CacheConfig,
+    /// Cache key without `Vary` values
+    primary: String,
+    fetch: Fetch,
+}
+
+/// Shared response cache.
+///
+/// Cloning is cheap: all clones share the same entries.
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Cache<String, Arc<Entry>>,
+    /// `Vary` header names per route and primary key
+    vary: Cache<String, Arc<Vec<HeaderName>>>,
+    /// Misses being fetched; waiters are woken when the sender drops
+    pending: Arc<Mutex<HashMap<String, watch::Receiver<()>>>>,
+    /// Entries being refreshed in the background
+    refreshing: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Marks a miss as being fetched until dropped.
+struct PendingGuard {
+    pending: Arc<Mutex<HashMap<String, watch::Receiver<()>>>>,
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ResponseCache {
+    /// Create a cache holding up to `capacity_bytes` of responses
+    pub fn new(capacity_bytes: u64) -> Self {
+        let entries = Cache::builder()
+            .max_capacity(capacity_bytes)
+            .weigher(|key: &String, entry: &Arc<Entry>| u32::try_from(key.len() + entry.size()).unwrap_or(u32::MAX))
+            .expire_after(KeepFor)
+            .build();
+        let vary = Cache::builder()
+            .max_capacity(100_000)
+            .time_to_idle(Duration::from_secs(3_600))
+            .build();
+
+        Self {
+            entries,
+            vary,
+            pending: Default::default(),
+            refreshing: Default::default(),
+        }
+    }
+
+    /// Serve a GET or HEAD request from the cache, going upstream through
+    /// `fetch` when there is no usable entry.
+    ///
+    /// HEAD requests are answered from stored GET responses but never fill
+    /// the cache themselves.
+    pub async fn serve(
+        &self,
+        parts: &Parts,
+        route: &Route,
+        config: &CacheConfig,
+        fetch: Fetch,
+    ) -> Result<Response<GatewayBody>, GatewayError> {
+        let head = RequestHead {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            headers: parts.headers.clone(),
+        };
+        let request_cc = CacheControl::parse(&head.headers);
+        let primary = match cache_key(parts, config) {
+            Some(primary) if !request_cc.no_store => primary,
+            _ => {
+                let response = fetch(head.to_request(None)).await?;
+                return Ok(with_cache_status(response, "BYPASS"));
+            }
+        };
+
+        let lookup = Lookup {
+            primary,
+            head,
+            route,
+            config,
+            fetch,
+        };
+        let (storage_key, entry) = self.find(&lookup).await;
+
+        if let Some(entry) = entry {
+            let revalidate = request_cc.no_cache || request_cc.max_age == Some(0);
+            match entry.state() {
+                State::Fresh if !revalidate => return Ok(entry.respond(&lookup.head, "HIT")),
+                State::Stale if !revalidate => {
+                    self.refresh_in_background(&lookup, entry.clone());
+                    return Ok(entry.respond(&lookup.head, "STALE"));
+                }
+                _ if entry.has_validator() && lookup.head.method == Method::GET => {
+                    return self.fetch_and_store(&lookup, Some(&entry)).await;
+                }
+                _ => {}
+            }
+        }
+
+        if lookup.head.method != Method::GET {
+            let response = (lookup.fetch)(lookup.head.to_request(None)).await?;
+            return Ok(with_cache_status(response, "MISS"));
+        }
+        self.fetch_coalesced(&lookup, storage_key).await
+    }
+
+    /// Storage key and entry for a request, taking `Vary` into account.
+    async fn find(&self, lookup: &Lookup<'_>) -> (String, Option<Arc<Entry>>) {
+        let vary = self.vary.get(&vary_key(&lookup.route.id, &lookup.primary)).await;
+        let key = variant_key(&lookup.primary, vary.as_deref().map_or(&[], |v| v), &lookup.head.headers);
+        let storage_key = storage_key(&lookup.route.id, &key);
+        let entry = self.entries.get(&storage_key).await;
+        (storage_key, entry)
+    }
+
+    /// Fetch a miss, sharing one upstream request among concurrent misses
+    /// for the same key.
+    async fn fetch_coalesced(
+        &self,
+        lookup: &Lookup<'_>,
+        storage_key: String,
+    ) -> Result<Response<GatewayBody>, GatewayError> {
+        let waiting = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&storage_key) {
+                Some(done) => Err(done.clone()),
+                None => {
+                    let (done, waiter) = watch::channel(());
+                    pending.insert(storage_key.clone(), waiter);
+                    Ok(PendingGuard {
+                        pending: self.pending.clone(),
+                        key: storage_key,
+                        _done: done,
+                    })
+                }
+            }
+        };
+
+        match waiting {
+            Ok(_guard) => self.fetch_and_store(lookup, None).await,
+            Err(mut done) => {
+                // Resolves once the first request finishes and drops its sender
+                let wait = Duration::from_millis(lookup.route.response_timeout_ms());
+                let _ = tokio::time::timeout(wait, done.changed()).await;
+
+                if let (_, Some(entry)) = self.find(lookup).await {
+                    if entry.state() == State::Fresh {
+                        return Ok(entry.respond(&lookup.head, "HIT"));
+                    }
+                }
+                // Not cacheable after all: go upstream on our own
+                self.fetch_and_store(lookup, None).await
+            }
+        }
+    }
+
+    /// Refresh a stale entry in the background, unless already underway.
+    fn refresh_in_background(&self, lookup: &Lookup<'_>, entry: Arc<Entry>) {
+        let key = storage_key(&entry.route_id, &entry.key);
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+
+        let cache = self.clone();
+        let head = lookup.head.clone();
+        let route = lookup.route.clone();
+        let config = lookup.config.clone();
+        let primary = lookup.primary.clone();
+        let fetch = lookup.fetch.clone();
+        tokio::spawn(async move {
+            let lookup = Lookup {
+                head,
+                route: &route,
+                config: &config,
+                primary,
+                fetch,
+            };
+            let validators = Some(entry.as_ref()).filter(|e| e.has_validator());
+            if let Err(e) = cache.fetch_and_store(&lookup, validators).await {
+                tracing::debug!(route_id = %route.id, key = %entry.key, error = %e, "Background cache refresh failed");
+            }
+            cache.refreshing.lock().unwrap().remove(&key);
+        });
+    }
+
+    /// Go upstream, conditionally when `validators` is given, and store
+    /// the response if it may be cached.
+    async fn fetch_and_store(
+        &self,
+        lookup: &Lookup<'_>,
+        validators: Option<&Entry>,
+    ) -> Result<Response<GatewayBody>, GatewayError> {
+        let response = (lookup.fetch)(lookup.head.to_request(validators)).await?;
+
+        if let Some(entry) = validators.filter(|_| response.status() == StatusCode::NOT_MODIFIED) {
+            let entry = Arc::new(entry.refreshed(response.headers(), lookup.config));
+            self.entries
+                .insert(storage_key(&entry.route_id, &entry.key), entry.clone())
+                .await;
+            return Ok(entry.respond(&lookup.head, "REVALIDATED"));
+        }
+
+        self.store(lookup, response).await
+    }
+
+    /// Store a fresh upstream response if it may be cached, and return the
+    /// response for the client.
+    async fn store(
+        &self,
+        lookup: &Lookup<'_>,
+        response: Response<GatewayBody>,
+    ) -> Result<Response<GatewayBody>, GatewayError> {
+        let Some(vary) = storable(&response, &lookup.head, lookup.config) else {
+            return Ok(with_cache_status(response, "MISS"));
+        };
+        if content_length(response.headers()).is_some_and(|len| len > lookup.config.max_body_bytes as u64) {
+            return Ok(with_cache_status(response, "MISS"));
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let body = match collect_up_to(body, lookup.config.max_body_bytes).await {
+            Ok(Collected::Complete(body)) => body,
+            Ok(Collected::Overflow(prefix, rest)) => {
+                let prefix = futures::stream::once(async move { Ok::<_, BoxError>(Frame::data(prefix)) });
+                let body = boxed(StreamBody::new(prefix.chain(BodyStream::new(rest))));
+                return Ok(with_cache_status(Response::from_parts(parts, body), "MISS"));
+            }
+            Err(e) => {
+                return Err(GatewayError::UpstreamConnectionFailed {
+                    upstream: format!("route '{}'", lookup.route.id),
+                    reason: format!("failed to read response body: {}", e),
+                })
+            }
+        };
+
+        strip_hop_by_hop(&mut parts.headers);
+        let key = variant_key(&lookup.primary, &vary, &lookup.head.headers);
+        let entry = Arc::new(Entry::new(
+            &lookup.route.id,
+            key,
+            parts.status,
+            parts.headers,
+            body,
+            lookup.config,
+        ));
+
+        if entry.fresh_for + entry.stale_for > Duration::ZERO || entry.has_validator() {
+            self.vary
+                .insert(vary_key(&lookup.route.id, &lookup.primary), Arc::new(vary))
+                .await;
+            self.entries
+                .insert(storage_key(&entry.route_id, &entry.key), entry.clone())
+                .await;
+        }
+        Ok(entry.respond(&lookup.head, "MISS"))
+    }
+
+    /// Remove entries whose key starts with `prefix`, only from `route_id`
+    /// when given. Returns the number of entries removed.
+    pub async fn purge(&self, route_id: Option<&str>, prefix: &str) -> usize {
+        let doomed: Vec<Arc<String>> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| route_id.is_none_or(|id| entry.route_id == id) && entry.key.starts_with(prefix))
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in &doomed {
+            self.entries.invalidate(key.as_str()).await;
+        }
+        doomed.len()
+    }
+
+    /// Number of cached responses (approximate)
+    pub fn entry_count(&self) -> u64 {
+        self.entries.entry_count()
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY_BYTES)
+    }
+}
+
+fn storage_key(route_id: &str, key: &str) -> String {
+    format!("{}\n{}", route_id, key)
+}
+
+fn vary_key(route_id: &str, primary: &str) -> String {
+    format!("{}\n{}", route_id, primary)
+}
+
+/// Append the request's values of the `Vary` headers to a primary key
+fn variant_key(primary: &str, vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        push_header(&mut key, name.as_str(), headers);
+    }
+    key
+}
+
+/// The response's `Vary` header names if it may be stored.
+fn storable(response: &Response<GatewayBody>, request: &RequestHead, config: &CacheConfig) -> Option<Vec<HeaderName>> {
+    let headers = response.headers();
+    let cc = CacheControl::parse(headers);
+    let per_caller = config.key.jwt_subject;
+
+    if !CACHEABLE_STATUS.contains(&response.status().as_u16())
+        || cc.no_store
+        || (cc.private && !per_caller)
+        || headers.contains_key(SET_COOKIE)
+        || is_event_stream(headers)
+    {
+        return None;
+    }
+    // Shared caches may only reuse authorized responses when told so
+    // (RFC 9111 section 3.5)
+    if request.headers.contains_key(AUTHORIZATION)
+        && !(per_caller || cc.public || cc.s_maxage.is_some() || cc.must_revalidate)
+    {
+        return None;
+    }
+
+    let mut vary = Vec::new();
+    for name in headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+    {
+        if name == "*" {
+            return None;
+        }
+        vary.push(HeaderName::try_from(name).ok()?);
+    }
+    vary.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    vary.dedup();
+    Some(vary)
+}
+
+/// Whether a response is a Server-Sent Events stream
+pub(crate) fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/event-stream"))
+}
+
+fn with_cache_status(mut response: Response<GatewayBody>, status: &'static str) -> Response<GatewayBody> {
+    response.headers_mut().insert(X_CACHE, HeaderValue::from_static(status));
+    response
+}
+
+enum Collected {
+    Complete(Bytes),
+    /// The body went over the limit: what was read, and the rest
+    Overflow(Bytes, GatewayBody),
+}
+
+/// Read a body into memory, stopping once it goes over `max` bytes.
+async fn collect_up_to(mut body: GatewayBody, max: usize) -> Result<Collected, BoxError> {
+    let mut buf = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            buf.extend_from_slice(&data);
+            if buf.len() > max {
+                return Ok(Collected::Overflow(buf.freeze(), body));
+            }
+        }
+    }
+    Ok(Collected::Complete(buf.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fetch answering every request with `respond`, counting calls
+    fn fetch_with<F>(respond: F) -> (Fetch, Arc<AtomicUsize>)
+    where
+        F: Fn(&Request<GatewayBody>, usize) -> Response<GatewayBody> + Send + Sync + 'static,
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let fetch: Fetch = Arc::new(move |req| {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            let response = respond(&req, n);
+            async move { Ok(response) }.boxed()
+        });
+        (fetch, calls)
+    }
+
+    fn response(cache_control: &str, body: String) -> Response<GatewayBody> {
+        Response::builder()
+            .header(CACHE_CONTROL, cache_control)
+            .body(full(body))
+            .unwrap()
+    }
+
+    fn get(uri: &str) -> Parts {
+        Request::get(uri).body(()).unwrap().into_parts().0
+    }
+
+    async fn body_text(response: Response<GatewayBody>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn route() -> Route {
+        Route::new("items", "/items/*", "http://items:8080")
+    }
+
+    #[test]
+    fn test_cache_control_and_freshness() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=60, s-maxage=\"120\""));
+        let cc = CacheControl::parse(&headers);
+        assert!(cc.public && !cc.no_store);
+        assert_eq!((cc.max_age, cc.s_maxage), (Some(60), Some(120)));
+
+        let config = CacheConfig {
+            max_ttl_secs: 100,
+            ..Default::default()
+        };
+        assert_eq!(freshness_lifetime(&headers, &cc, &config), Duration::from_secs(100));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(DATE, HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"));
+        headers.insert(EXPIRES, HeaderValue::from_static("Sun, 06 Nov 1994 08:50:07 GMT"));
+        let cc = CacheControl::parse(&headers);
+        assert_eq!(freshness_lifetime(&headers, &cc, &config), Duration::from_secs(30));
+
+        headers.insert(EXPIRES, HeaderValue::from_static("0"));
+        assert_eq!(freshness_lifetime(&headers, &cc, &config), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let mut config = CacheConfig::default();
+        let mut parts = Request::get("/items/1?page=2")
+            .header("accept-language", "ar")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        parts.extensions.insert(JwtClaims(
+            serde_json::json!({"sub": "alice"}).as_object().unwrap().clone(),
+        ));
+        assert_eq!(cache_key(&parts, &config).unwrap(), "/items/1?page=2");
+
+        config.key.query = false;
+        config.key.headers = vec!["Accept-Language".to_string()];
+        config.key.jwt_subject = true;
+        assert_eq!(cache_key(&parts, &config).unwrap(), "/items/1|accept-language=ar|sub=alice");
+
+        // Without claims there is no caller to key on
+        parts.extensions.clear();
+        assert_eq!(cache_key(&parts, &config), None);
+    }
+
+    #[tokio::test]
+    async fn test_hit_after_miss() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+        let (fetch, calls) = fetch_with(|_, n| response("max-age=60", format!("v{}", n)));
+
+        let first = cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+        assert_eq!(first.headers()[X_CACHE], "MISS");
+        assert_eq!(body_text(first).await, "v0");
+
+        let second = cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+        assert_eq!(second.headers()[X_CACHE], "HIT");
+        assert_eq!(body_text(second).await, "v0");
+
+        // Other query, other entry
+        let other = cache.serve(&get("/items/1?x=1"), &route(), &config, fetch).await.unwrap();
+        assert_eq!(body_text(other).await, "v1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_uncacheable_responses() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+
+        for cache_control in ["no-store", "private, max-age=60", ""] {
+            let (fetch, calls) = fetch_with(move |_, _| response(cache_control, String::new()));
+            for _ in 0..2 {
+                let resp = cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+                assert_eq!(resp.headers()[X_CACHE], "MISS");
+            }
+            assert_eq!(calls.load(Ordering::SeqCst), 2, "{}", cache_control);
+        }
+
+        // Authorized requests need explicit permission
+        let (fetch, calls) = fetch_with(|_, _| response("max-age=60", String::new()));
+        let mut parts = get("/items/2");
+        parts.headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer t"));
+        cache.serve(&parts, &route(), &config, fetch.clone()).await.unwrap();
+        cache.serve(&parts, &route(), &config, fetch).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Per-caller entries are never shared by callers without claims
+        let mut config = CacheConfig::default();
+        config.key.jwt_subject = true;
+        let (fetch, calls) = fetch_with(|_, _| response("private, max-age=60", String::new()));
+        for _ in 0..2 {
+            let resp = cache.serve(&get("/items/3"), &route(), &config, fetch.clone()).await.unwrap();
+            assert_eq!(resp.headers()[X_CACHE], "BYPASS");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_vary_splits_entries() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+        let (fetch, calls) = fetch_with(|req, _| {
+            let lang = req.headers()["accept-language"].to_str().unwrap().to_string();
+            let mut resp = response("max-age=60", lang);
+            resp.headers_mut().insert(VARY, HeaderValue::from_static("Accept-Language"));
+            resp
+        });
+        let request = |lang: &'static str| {
+            let mut parts = get("/items/1");
+            parts.headers.insert("accept-language", HeaderValue::from_static(lang));
+            parts
+        };
+
+        for lang in ["en", "ar", "en", "ar"] {
+            let resp = cache.serve(&request(lang), &route(), &config, fetch.clone()).await.unwrap();
+            assert_eq!(body_text(resp).await, lang);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesces_concurrent_misses() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let fetch: Fetch = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(response("max-age=60", "shared".to_string()))
+            }
+            .boxed()
+        });
+
+        let route = route();
+        let parts = get("/items/1");
+        let requests = (0..10).map(|_| cache.serve(&parts, &route, &config, fetch.clone()));
+        for resp in futures::future::join_all(requests).await {
+            assert_eq!(body_text(resp.unwrap()).await, "shared");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig {
+            stale_while_revalidate_secs: 60,
+            ..Default::default()
+        };
+        let (fetch, calls) = fetch_with(|_, n| response("max-age=0", format!("v{}", n)));
+
+        cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+        let stale = cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+        assert_eq!(stale.headers()[X_CACHE], "STALE");
+        assert_eq!(body_text(stale).await, "v0");
+
+        // The background refresh replaces the entry
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let refreshed = cache.serve(&get("/items/1"), &route(), &config, fetch).await.unwrap();
+        assert_eq!(body_text(refreshed).await, "v1");
+    }
+
+    #[tokio::test]
+    async fn test_etag_revalidation() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+        let (fetch, calls) = fetch_with(|req, _| {
+            if req.headers().get(IF_NONE_MATCH).is_some_and(|v| v == "\"v1\"") {
+                return Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .header(ETAG, "\"v1\"")
+                    .body(empty())
+                    .unwrap();
+            }
+            let mut resp = response("no-cache", "body".to_string());
+            resp.headers_mut().insert(ETAG, HeaderValue::from_static("\"v1\""));
+            resp
+        });
+
+        cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+        let revalidated = cache.serve(&get("/items/1"), &route(), &config, fetch.clone()).await.unwrap();
+        assert_eq!(revalidated.headers()[X_CACHE], "REVALIDATED");
+        assert_eq!(body_text(revalidated).await, "body");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // A client holding the same version gets a 304
+        let mut parts = get("/items/1");
+        parts.headers.insert(IF_NONE_MATCH, HeaderValue::from_static("W/\"v1\""));
+        let not_modified = cache.serve(&parts, &route(), &config, fetch).await.unwrap();
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+        let (fetch, calls) = fetch_with(|_, _| response("max-age=60", String::new()));
+        let other = Route::new("other", "/other/*", "http://other:8080");
+
+        for path in ["/items/1", "/items/2", "/items/list"] {
+            cache.serve(&get(path), &route(), &config, fetch.clone()).await.unwrap();
+        }
+        cache.serve(&get("/items/1"), &other, &config, fetch.clone()).await.unwrap();
+
+        assert_eq!(cache.purge(Some("items"), "/items/1").await, 1);
+        assert_eq!(cache.purge(None, "/items/").await, 3);
+
+        cache.serve(&get("/items/list"), &route(), &config, fetch).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+}
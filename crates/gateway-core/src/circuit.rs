@@ -0,0 +1,454 @@
+//! Per-upstream circuit breakers.
+//!
+//! Each (route, upstream target) pair gets its own breaker, configured by
+//! the route's `CircuitBreaker` settings:
+//!
+//! - **Closed**: calls flow; outcomes are counted in a rolling window. When
+//!   the failure or slow-call rate crosses its threshold the breaker opens.
+//! - **Open**: calls are rejected with `GatewayError::CircuitOpen` until
+//!   `open_ms` has passed.
+//! - **Half-open**: a limited number of trial calls is let through. Any
+//!   failure re-opens the breaker; enough successes close it.
+//!
+//! State transitions are published as tracing events.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::CircuitBreaker;
+use crate::error::GatewayError;
+
+/// Number of buckets the rolling window is divided into
+const WINDOW_BUCKETS: u64 = 10;
+
+/// Circuit breaker state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls flow normally
+    Closed,
+    /// Calls are rejected until the given instant
+    Open { until: Instant },
+    /// Trial calls are being let through
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Short state name for logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open { .. } => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Breaker registry key: (route id, upstream URL)
+type BreakerKey = (String, String);
+
+/// All circuit breakers of the gateway, keyed by route id and upstream URL.
+#[derive(Default)]
+pub struct CircuitBreakers {
+    breakers: Mutex<HashMap<BreakerKey, Arc<Mutex<Breaker>>>>,
+}
+
+impl CircuitBreakers {
+    /// Create an empty breaker registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the breaker for this route and upstream currently rejects calls.
+    ///
+    /// Used to steer load balancing away from open targets.
+    pub fn is_open(&self, route_id: &str, upstream: &str) -> bool {
+        let breakers = self.breakers.lock().unwrap();
+        breakers
+            .get(&(route_id.to_string(), upstream.to_string()))
+            .is_some_and(|b| matches!(b.lock().unwrap().state, CircuitState::Open { until } if until > Instant::now()))
+    }
+
+    /// Current state of a breaker (closed if it was never used)
+    pub fn state(&self, route_id: &str, upstream: &str) -> CircuitState {
+        let breakers = self.breakers.lock().unwrap();
+        breakers
+            .get(&(route_id.to_string(), upstream.to_string()))
+            .map_or(CircuitState::Closed, |b| b.lock().unwrap().state)
+    }
+
+    /// Ask permission to call `upstream` on behalf of `route_id`.
+    ///
+    /// The returned permit must be completed with [`CallPermit::record`]
+    /// once the outcome is known.
+    pub fn acquire(
+        &self,
+        route_id: &str,
+        upstream: &str,
+        config: &CircuitBreaker,
+    ) -> Result<CallPermit, GatewayError> {
+        let breaker = {
+            let mut breakers = self.breakers.lock().unwrap();
+            breakers
+                .entry((route_id.to_string(), upstream.to_string()))
+                .or_insert_with(|| Arc::new(Mutex::new(Breaker::new(route_id, upstream))))
+                .clone()
+        };
+
+        // Read the clock under the lock so calls reach the window in order
+        let (trial, now) = {
+            let mut locked = breaker.lock().unwrap();
+            let now = Instant::now();
+            (locked.try_acquire(config, now)?, now)
+        };
+
+        Ok(CallPermit {
+            breaker,
+            config: config.clone(),
+            started: now,
+            trial,
+            recorded: false,
+        })
+    }
+}
+
+/// Permission to make one upstream call.
+///
+/// Dropping a permit without recording an outcome (e.g. the client went
+/// away) releases its half-open trial slot without counting a result.
+pub struct CallPermit {
+    breaker: Arc<Mutex<Breaker>>,
+    config: CircuitBreaker,
+    started: Instant,
+    trial: bool,
+    recorded: bool,
+}
+
+impl CallPermit {
+    /// Record the outcome of the call.
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        let mut breaker = self.breaker.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.started);
+        breaker.record(&self.config, success, elapsed, self.trial, now);
+    }
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            let mut breaker = self.breaker.lock().unwrap();
+            breaker.trials_in_flight = breaker.trials_in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// State of a single breaker.
+struct Breaker {
+    route_id: String,
+    upstream: String,
+    state: CircuitState,
+    window: RollingWindow,
+    /// Half-open trial calls that have not completed yet
+    trials_in_flight: u32,
+    /// Successful half-open trial calls
+    trial_successes: u32,
+}
+
+impl Breaker {
+    fn new(route_id: &str, upstream: &str) -> Self {
+        Self {
+            route_id: route_id.to_string(),
+            upstream: upstream.to_string(),
+            state: CircuitState::Closed,
+            window: RollingWindow::default(),
+            trials_in_flight: 0,
+            trial_successes: 0,
+        }
+    }
+
+    /// Returns whether the call is a half-open trial.
+    fn try_acquire(&mut self, config: &CircuitBreaker, now: Instant) -> Result<bool, GatewayError> {
+        if let CircuitState::Open { until } = self.state {
+            if now < until {
+                return Err(GatewayError::CircuitOpen {
+                    upstream: self.upstream.clone(),
+                });
+            }
+            self.transition(CircuitState::HalfOpen);
+        }
+
+        match self.state {
+            CircuitState::HalfOpen => {
+                if self.trials_in_flight + self.trial_successes >= config.half_open_calls.max(1) {
+                    return Err(GatewayError::CircuitOpen {
+                        upstream: self.upstream.clone(),
+                    });
+                }
+                self.trials_in_flight += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn record(&mut self, config: &CircuitBreaker, success: bool, elapsed: Duration, trial: bool, now: Instant) {
+        let slow = elapsed >= Duration::from_millis(config.slow_call_ms);
+
+        if trial {
+            self.trials_in_flight = self.trials_in_flight.saturating_sub(1);
+        }
+
+        match self.state {
+            CircuitState::HalfOpen if trial => {
+                if !success || slow {
+                    self.open(config, now);
+                } else {
+                    self.trial_successes += 1;
+                    if self.trial_successes >= config.half_open_calls.max(1) {
+                        self.transition(CircuitState::Closed);
+                    }
+                }
+            }
+            CircuitState::Closed => {
+                let bucket_ms = (config.window_ms / WINDOW_BUCKETS).max(1);
+                self.window.record(now, bucket_ms, success, slow);
+
+                let totals = self.window.totals(now, bucket_ms);
+                if totals.calls >= config.minimum_calls as u64
+                    && (totals.failures * 100 >= totals.calls * config.failure_rate_percent as u64
+                        || totals.slow * 100 >= totals.calls * config.slow_call_rate_percent as u64)
+                {
+                    tracing::debug!(
+                        calls = totals.calls,
+                        failures = totals.failures,
+                        slow = totals.slow,
+                        "Circuit breaker threshold reached"
+                    );
+                    self.open(config, now);
+                }
+            }
+            // Stragglers from before the breaker opened do not change its state
+            _ => {}
+        }
+    }
+
+    fn open(&mut self, config: &CircuitBreaker, now: Instant) {
+        self.transition(CircuitState::Open {
+            until: now + Duration::from_millis(config.open_ms),
+        });
+    }
+
+    fn transition(&mut self, to: CircuitState) {
+        let from = self.state;
+        self.state = to;
+        self.trial_successes = 0;
+        self.window = RollingWindow::default();
+
+        if matches!(to, CircuitState::Open { .. }) {
+            tracing::warn!(
+                route_id = %self.route_id,
+                upstream = %self.upstream,
+                from = from.name(),
+                to = to.name(),
+                "Circuit breaker state changed"
+            );
+        } else {
+            tracing::info!(
+                route_id = %self.route_id,
+                upstream = %self.upstream,
+                from = from.name(),
+                to = to.name(),
+                "Circuit breaker state changed"
+            );
+        }
+    }
+}
+
+/// Call outcomes over a rolling time window, split into fixed buckets.
+#[derive(Default)]
+struct RollingWindow {
+    /// Reference point for bucket numbering
+    origin: Option<Instant>,
+    buckets: [Bucket; WINDOW_BUCKETS as usize],
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bucket {
+    /// Absolute bucket number this slot currently holds
+    epoch: u64,
+    calls: u64,
+    failures: u64,
+    slow: u64,
+}
+
+impl RollingWindow {
+    fn epoch(&mut self, now: Instant, bucket_ms: u64) -> u64 {
+        let origin = *self.origin.get_or_insert(now);
+        now.duration_since(origin).as_millis() as u64 / bucket_ms
+    }
+
+    fn record(&mut self, now: Instant, bucket_ms: u64, success: bool, slow: bool) {
+        let epoch = self.epoch(now, bucket_ms);
+        let bucket = &mut self.buckets[(epoch % WINDOW_BUCKETS) as usize];
+        if bucket.epoch != epoch {
+            *bucket = Bucket {
+                epoch,
+                ..Bucket::default()
+            };
+        }
+
+        bucket.calls += 1;
+        bucket.failures += u64::from(!success);
+        bucket.slow += u64::from(slow);
+    }
+
+    fn totals(&mut self, now: Instant, bucket_ms: u64) -> Bucket {
+        let epoch = self.epoch(now, bucket_ms);
+        self.buckets
+            .iter()
+            .filter(|b| b.calls > 0 && epoch.saturating_sub(b.epoch) < WINDOW_BUCKETS)
+            .fold(Bucket::default(), |acc, b| Bucket {
+                epoch,
+                calls: acc.calls + b.calls,
+                failures: acc.failures + b.failures,
+                slow: acc.slow + b.slow,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAM: &str = "http://backend:8080";
+
+    fn config() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_rate_percent: 50,
+            minimum_calls: 4,
+            open_ms: 60_000,
+            half_open_calls: 2,
+            ..CircuitBreaker::default()
+        }
+    }
+
+    #[test]
+    fn test_opens_on_failure_rate() {
+        let breakers = CircuitBreakers::new();
+        let config = config();
+
+        for success in [true, false, true] {
+            breakers.acquire("r", UPSTREAM, &config).unwrap().record(success);
+        }
+        assert_eq!(breakers.state("r", UPSTREAM), CircuitState::Closed);
+
+        // 2 of 4 failed: 50% reaches the threshold
+        breakers.acquire("r", UPSTREAM, &config).unwrap().record(false);
+        assert!(breakers.is_open("r", UPSTREAM));
+
+        let err = breakers.acquire("r", UPSTREAM, &config).err().unwrap();
+        assert!(matches!(err, GatewayError::CircuitOpen { .. }));
+        assert_eq!(err.status_code(), 503);
+        assert_eq!(err.category(), "circuit_open");
+
+        // Breakers are per route
+        assert!(!breakers.is_open("other", UPSTREAM));
+    }
+
+    #[test]
+    fn test_opens_on_slow_calls() {
+        let mut breaker = Breaker::new("r", UPSTREAM);
+        let config = CircuitBreaker {
+            slow_call_rate_percent: 50,
+            slow_call_ms: 100,
+            ..config()
+        };
+        let now = Instant::now();
+
+        for elapsed in [10, 500, 10, 500] {
+            breaker.try_acquire(&config, now).unwrap();
+            breaker.record(&config, true, Duration::from_millis(elapsed), false, now);
+        }
+        assert!(matches!(breaker.state, CircuitState::Open { .. }));
+    }
+
+    #[test]
+    fn test_half_open_recovery() {
+        let mut breaker = Breaker::new("r", UPSTREAM);
+        let config = config();
+        let now = Instant::now();
+        breaker.open(&config, now);
+
+        let later = now + Duration::from_millis(config.open_ms);
+        assert!(breaker.try_acquire(&config, later).unwrap());
+        assert!(breaker.try_acquire(&config, later).unwrap());
+        // Only `half_open_calls` trials at once
+        assert!(breaker.try_acquire(&config, later).is_err());
+
+        breaker.record(&config, true, Duration::ZERO, true, later);
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        breaker.record(&config, true, Duration::ZERO, true, later);
+        assert_eq!(breaker.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_failure_reopens() {
+        let mut breaker = Breaker::new("r", UPSTREAM);
+        let config = config();
+        let now = Instant::now();
+        breaker.open(&config, now);
+
+        let later = now + Duration::from_millis(config.open_ms);
+        assert!(breaker.try_acquire(&config, later).unwrap());
+        breaker.record(&config, false, Duration::ZERO, true, later);
+        assert!(matches!(breaker.state, CircuitState::Open { until } if until > later));
+    }
+
+    #[test]
+    fn test_dropped_trial_releases_slot() {
+        let breakers = CircuitBreakers::new();
+        let config = CircuitBreaker {
+            open_ms: 0,
+            half_open_calls: 1,
+            ..config()
+        };
+        for _ in 0..4 {
+            breakers.acquire("r", UPSTREAM, &config).unwrap().record(false);
+        }
+
+        // open_ms = 0: the next call is immediately a half-open trial
+        let permit = breakers.acquire("r", UPSTREAM, &config).unwrap();
+        assert!(breakers.acquire("r", UPSTREAM, &config).is_err());
+        drop(permit);
+        assert!(breakers.acquire("r", UPSTREAM, &config).is_ok());
+    }
+
+    #[test]
+    fn test_window_forgets_old_calls() {
+        let mut breaker = Breaker::new("r", UPSTREAM);
+        let config = config();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            breaker.record(&config, false, Duration::ZERO, false, now);
+        }
+        // The failures have rolled out of the window by now
+        let later = now + Duration::from_millis(config.window_ms * 2);
+        breaker.record(&config, false, Duration::ZERO, false, later);
+        assert_eq!(breaker.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_window_tolerates_out_of_order_times() {
+        let mut window = RollingWindow::default();
+        let now = Instant::now();
+        window.record(now, 100, true, false);
+        window.record(now + Duration::from_millis(500), 100, false, false);
+
+        // A reading taken before the newest bucket was filled
+        let totals = window.totals(now + Duration::from_millis(100), 100);
+        assert_eq!((totals.calls, totals.failures), (2, 1));
+    }
+}
@@ -0,0 +1,394 @@
+//! Response compression and request body decoding.
+//!
+//! Responses on routes with a [`CompressionConfig`] are compressed on the
+//! fly with the encoding the client's `Accept-Encoding` prefers among the
+//! route's `encodings`. Responses are passed through untouched when the
+//! upstream already encoded them, when they are too small or of a media
+//! type the route does not list, when they are event streams or partial
+//! content, or when the upstream sent `Cache-Control: no-transform`. The
+//! handler never compresses responses on streaming routes.
+//!
+//! Request bodies go to the upstream as received, except on routes that
+//! buffer them for inspection: those are decoded ([`decode_body`]) so
+//! policies read the payload rather than its compressed form.
+
+use std::io;
+
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder, ZstdDecoder, ZstdEncoder,
+};
+use async_compression::Level;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Method, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::body::{boxed, content_length, media_type_matches, GatewayBody};
+use crate::cache::CacheControl;
+use crate::config::{CompressionConfig, ContentEncoding};
+use crate::error::GatewayError;
+
+/// Brotli quality for on-the-fly compression; the library default (11) is
+/// meant for static assets and far too slow per request.
+const BROTLI_LEVEL: Level = Level::Precise(4);
+
+/// Pick the response encoding from the request's `Accept-Encoding`.
+///
+/// The highest q-value among `offered` wins, ties going to the earlier
+/// entry of `offered`. `*` stands for any encoding not listed, and `q=0`
+/// rules an encoding out. Returns `None` when nothing acceptable is
+/// offered, including when the header is absent.
+pub fn negotiate(headers: &HeaderMap, offered: &[ContentEncoding]) -> Option<ContentEncoding> {
+    let mut listed = Vec::new();
+    let mut wildcard = None;
+
+    let items = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+    for item in items {
+        let mut params = item.split(';');
+        let token = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if token == "*" {
+            wildcard = Some(q);
+        } else if let Some(encoding) = ContentEncoding::from_token(token) {
+            listed.push((encoding, q));
+        }
+    }
+
+    let quality = |encoding: ContentEncoding| {
+        listed
+            .iter()
+            .find(|(e, _)| *e == encoding)
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for &encoding in offered {
+        let q = quality(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compress a response with `encoding` if the route's settings allow it.
+///
+/// Compressible responses get `Vary: Accept-Encoding` whether or not they
+/// are compressed, so shared caches downstream keep the variants apart.
+/// Compressing drops `Content-Length` and `Accept-Ranges` and weakens a
+/// strong `ETag`, since the bytes on the wire no longer match them.
+pub fn compress_response(
+    mut response: Response<GatewayBody>,
+    config: &CompressionConfig,
+    encoding: Option<ContentEncoding>,
+    method: &Method,
+) -> Response<GatewayBody> {
+    if !is_compressible(&response, config, method) {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    add_vary(headers);
+
+    let Some(encoding) = encoding else {
+        return response;
+    };
+
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(ACCEPT_RANGES);
+    if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|v| v.starts_with('"')) {
+        if let Ok(weak) = HeaderValue::try_from(format!("W/{}", etag)) {
+            headers.insert(ETAG, weak);
+        }
+    }
+
+    response.map(|body| encode(body, encoding))
+}
+
+fn is_compressible(response: &Response<GatewayBody>, config: &CompressionConfig, method: &Method) -> bool {
+    let status = response.status();
+    let headers = response.headers();
+
+    let body_allowed = *method != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED;
+    // Ranges refer to the unencoded representation
+    let partial = status == StatusCode::PARTIAL_CONTENT || headers.contains_key(CONTENT_RANGE);
+    let encoded = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .any(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"));
+
+    let media_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or_default()
+        .trim();
+    // Compressors hold data back until they have enough to work with,
+    // which would stall individual events
+    let event_stream = media_type.eq_ignore_ascii_case("text/event-stream");
+
+    body_allowed
+        && !partial
+        && !encoded
+        && !event_stream
+        && media_type_matches(media_type, &config.content_types)
+        && content_length(headers).is_none_or(|len| len >= config.min_size_bytes)
+        && !CacheControl::parse(headers).no_transform
+}
+
+/// Add `Accept-Encoding` to `Vary` unless it is already covered.
+fn add_vary(headers: &mut HeaderMap) {
+    let covered = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|name| name == "*" || name.eq_ignore_ascii_case("accept-encoding"));
+    if !covered {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+/// Compress a body as it streams through.
+fn encode(body: GatewayBody, encoding: ContentEncoding) -> GatewayBody {
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    match encoding {
+        ContentEncoding::Gzip => reader_body(GzipEncoder::new(reader)),
+        ContentEncoding::Deflate => reader_body(ZlibEncoder::new(reader)),
+        ContentEncoding::Brotli => reader_body(BrotliEncoder::with_quality(reader, BROTLI_LEVEL)),
+        ContentEncoding::Zstd => reader_body(ZstdEncoder::new(reader)),
+    }
+}
+
+fn reader_body<R>(reader: R) -> GatewayBody
+where
+    R: AsyncRead + Send + 'static,
+{
+    boxed(StreamBody::new(ReaderStream::new(reader).map_ok(Frame::data)))
+}
+
+/// Decode a buffered request body according to its `Content-Encoding`.
+///
+/// Codings are undone in reverse order of application. On success the
+/// `Content-Encoding` header is removed and `Content-Length` set to the
+/// decoded size. A decoded body larger than `max_size` fails with
+/// `PayloadTooLarge` as soon as it goes over, so small compressed payloads
+/// cannot expand without bound.
+pub async fn decode_body(headers: &mut HeaderMap, body: Bytes, max_size: usize) -> Result<Bytes, GatewayError> {
+    let values = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .map(|v| {
+            v.to_str().map_err(|_| GatewayError::UnsupportedEncoding {
+                encoding: String::from_utf8_lossy(v.as_bytes()).into_owned(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let codings = values
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|c| !c.is_empty() && !c.eq_ignore_ascii_case("identity"))
+        .map(|c| {
+            ContentEncoding::from_token(c).ok_or_else(|| GatewayError::UnsupportedEncoding {
+                encoding: c.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if codings.is_empty() {
+        return Ok(body);
+    }
+
+    let mut body = body;
+    for encoding in codings.into_iter().rev() {
+        body = decode(encoding, &body, max_size).await?;
+    }
+
+    headers.remove(CONTENT_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Ok(body)
+}
+
+async fn decode(encoding: ContentEncoding, data: &[u8], max_size: usize) -> Result<Bytes, GatewayError> {
+    let read = match encoding {
+        ContentEncoding::Gzip => {
+            let mut decoder = GzipDecoder::new(data);
+            decoder.multiple_members(true);
+            read_limited(decoder, max_size).await
+        }
+        ContentEncoding::Deflate => read_limited(ZlibDecoder::new(data), max_size).await,
+        ContentEncoding::Brotli => read_limited(BrotliDecoder::new(data), max_size).await,
+        ContentEncoding::Zstd => read_limited(ZstdDecoder::new(data), max_size).await,
+    };
+
+    let decoded = read.map_err(|e| GatewayError::BodyReadError(format!("invalid {} body: {}", encoding.as_str(), e)))?;
+    if decoded.len() > max_size {
+        return Err(GatewayError::PayloadTooLarge {
+            size: decoded.len() as u64,
+            limit: max_size as u64,
+        });
+    }
+    Ok(decoded.into())
+}
+
+/// Read up to one byte past `max_size`, enough to tell the limit was hit.
+async fn read_limited<R>(reader: R, max_size: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut out = Vec::new();
+    reader.take(max_size as u64 + 1).read_to_end(&mut out).await?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::full;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn response(content_type: &'static str, body: &'static str) -> Response<GatewayBody> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, body.len())
+            .header(ETAG, "\"v1\"")
+            .body(full(body))
+            .unwrap()
+    }
+
+    fn config(min_size_bytes: u64) -> CompressionConfig {
+        CompressionConfig {
+            min_size_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = CompressionConfig::default().encodings;
+        use ContentEncoding::*;
+
+        assert_eq!(negotiate(&HeaderMap::new(), &offered), None);
+        assert_eq!(negotiate(&accept("gzip, deflate, br, zstd"), &offered), Some(Zstd));
+        assert_eq!(negotiate(&accept("gzip;q=0.8, br;q=0.9"), &offered), Some(Brotli));
+        assert_eq!(negotiate(&accept("x-gzip"), &offered), Some(Gzip));
+        assert_eq!(negotiate(&accept("*;q=0.5, zstd;q=0, br;q=0"), &offered), Some(Gzip));
+        assert_eq!(negotiate(&accept("identity, gzip;q=0"), &offered), None);
+        assert_eq!(negotiate(&accept("br"), &[Gzip]), None);
+    }
+
+    #[tokio::test]
+    async fn test_compress_round_trip() {
+        let text = "{\"message\": \"hello hello hello hello\"}";
+        for encoding in CompressionConfig::default().encodings {
+            let compressed = compress_response(response("application/json", text), &config(0), Some(encoding), &Method::GET);
+
+            let headers = compressed.headers().clone();
+            assert_eq!(headers[CONTENT_ENCODING], encoding.as_str());
+            assert_eq!(headers[VARY], "accept-encoding");
+            assert_eq!(headers[ETAG], "W/\"v1\"");
+            assert!(!headers.contains_key(CONTENT_LENGTH));
+
+            let body = compressed.into_body().collect().await.unwrap().to_bytes();
+            let mut headers = headers;
+            let decoded = decode_body(&mut headers, body, 1024).await.unwrap();
+            assert_eq!(decoded, text);
+            assert!(!headers.contains_key(CONTENT_ENCODING));
+            assert_eq!(headers[CONTENT_LENGTH], text.len().to_string().as_str());
+        }
+    }
+
+    #[test]
+    fn test_skips_incompressible_responses() {
+        let compressed = |response: Response<GatewayBody>, method: Method| {
+            compress_response(response, &config(10), Some(ContentEncoding::Gzip), &method)
+                .headers()
+                .contains_key(CONTENT_ENCODING)
+        };
+        let long = "a long enough response body";
+
+        assert!(compressed(response("text/plain; charset=utf-8", long), Method::GET));
+        assert!(!compressed(response("text/plain", "short"), Method::GET));
+        assert!(!compressed(response("image/png", long), Method::GET));
+        assert!(!compressed(response("text/event-stream", long), Method::GET));
+        assert!(!compressed(response("text/plain", long), Method::HEAD));
+
+        let mut no_transform = response("text/plain", long);
+        no_transform.headers_mut().insert("cache-control", HeaderValue::from_static("public, no-transform"));
+        assert!(!compressed(no_transform, Method::GET));
+
+        // Already encoded upstream: passed through without a second Vary
+        let mut encoded = response("text/plain", long);
+        encoded.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        let encoded = compress_response(encoded, &config(10), Some(ContentEncoding::Gzip), &Method::GET);
+        assert_eq!(encoded.headers()[CONTENT_ENCODING], "br");
+        assert!(!encoded.headers().contains_key(VARY));
+
+        // Compressible but not accepted by the client
+        let identity = compress_response(response("text/plain", long), &config(10), None, &Method::GET);
+        assert!(!identity.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(identity.headers()[VARY], "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn test_decode_body_limits_and_errors() {
+        // 1MB of zeros compresses to about a kilobyte
+        let bomb = encode(full(vec![0u8; 1024 * 1024]), ContentEncoding::Gzip)
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        assert!(bomb.len() < 16 * 1024);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let err = decode_body(&mut headers.clone(), bomb, 4096).await.unwrap_err();
+        assert!(matches!(err, GatewayError::PayloadTooLarge { limit: 4096, .. }));
+
+        let err = decode_body(&mut headers, Bytes::from("not gzip"), 4096).await.unwrap_err();
+        assert_eq!(err.status_code(), 400);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("compress"));
+        let err = decode_body(&mut headers, Bytes::from("data"), 4096).await.unwrap_err();
+        assert_eq!(err.status_code(), 415);
+
+        // A value that isn't ASCII is not silently read as identity
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_bytes(b"gz\xefp").unwrap());
+        let err = decode_body(&mut headers, Bytes::from("data"), 4096).await.unwrap_err();
+        assert!(matches!(err, GatewayError::UnsupportedEncoding { .. }));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+        assert_eq!(decode_body(&mut headers, Bytes::from("plain"), 4096).await.unwrap(), "plain");
+    }
+}
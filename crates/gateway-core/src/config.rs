@@ -4,7 +4,11 @@
 //! serializable for SurrealDB storage.
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+
+use crate::body::DEFAULT_MAX_BODY_SIZE;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 /// A single routing rule mapping a path to an upstream service.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -15,14 +19,113 @@ pub struct Route {
     /// URL path pattern to match (e.g., "/api/v1/users")
     pub path: String,
 
+    /// Host, header, query and cookie conditions on top of the path.
+    /// Several routes may share a path as long as their conditions differ.
+    #[serde(default, skip_serializing_if = "MatchConditions::is_empty")]
+    pub conditions: MatchConditions,
+
     /// Upstream service URL (e.g., "http://user-service:8080")
+    ///
+    /// Used as the single target when `upstreams` is empty.
+    #[serde(default)]
     pub upstream: String,
 
-    /// Traffic weight for load balancing (0-100)
-    /// Reserved for Phase 2 load balancing implementation
+    /// Traffic weight of `upstream` when it is the only target (0-100)
     #[serde(default = "default_weight")]
     pub weight: u32,
 
+    /// Weighted upstream targets for load balancing.
+    /// Takes precedence over `upstream` when non-empty.
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamTarget>,
+
+    /// Policy used to pick a target from `upstreams`
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+
+    /// Active health probing of this route's targets (disabled when absent)
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+
+    /// Passive ejection of targets that keep failing real traffic
+    #[serde(default)]
+    pub outlier_detection: OutlierDetection,
+
+    /// Circuit breaker applied to each of this route's targets (disabled when absent)
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    /// Retry and hedging policy (no retries when absent)
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+
+    /// Maximum request body size in bytes (gateway default of 2MB when absent)
+    #[serde(default)]
+    pub max_request_body_bytes: Option<usize>,
+
+    /// Maximum upstream response body size in bytes (unlimited when absent)
+    #[serde(default)]
+    pub max_response_body_bytes: Option<usize>,
+
+    /// Accepted request media types, e.g. "application/json" or "image/*"
+    /// (any when empty). Requests without a body are always accepted.
+    #[serde(default)]
+    pub allowed_content_types: Vec<String>,
+
+    /// Buffer the whole request body before forwarding, for policies that
+    /// inspect the payload. Compressed bodies are then decoded and sent on
+    /// uncompressed. Otherwise bodies are streamed, unless `retry` needs
+    /// them for replay.
+    #[serde(default)]
+    pub buffer_body: bool,
+
+    /// Path rewrite applied before forwarding (path passed through when absent)
+    #[serde(default)]
+    pub rewrite: Option<PathRewrite>,
+
+    /// Changes to request headers before forwarding
+    #[serde(default, skip_serializing_if = "HeaderPolicy::is_empty")]
+    pub request_headers: HeaderPolicy,
+
+    /// Changes to response headers before returning to the client
+    #[serde(default, skip_serializing_if = "HeaderPolicy::is_empty")]
+    pub response_headers: HeaderPolicy,
+
+    /// Proxy WebSocket upgrades on this route (upgrades are forwarded as
+    /// plain requests when absent)
+    #[serde(default)]
+    pub websocket: Option<WebSocketConfig>,
+
+    /// Streaming mode for Server-Sent Events and long polling: `timeout_ms`
+    /// is replaced by an idle timeout (total request timeout when absent)
+    #[serde(default)]
+    pub streaming: Option<StreamingConfig>,
+
+    /// Cache GET responses in the gateway (nothing cached when absent)
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+
+    /// Compress responses the upstream left uncompressed (passed through
+    /// as is when absent, and always on `streaming` routes)
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+
+    /// TLS settings for `https://` upstreams: trusted CAs, client
+    /// certificate, SNI name (public web roots and no client certificate
+    /// when absent)
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+
+    /// Names of registered filters run around this route's requests, in
+    /// order, e.g. `["waf", "jwt", "ratelimit"]` (none when empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<String>,
+
+    /// Rhai scripts run on this route's requests before forwarding and on
+    /// upstream responses before returning them (none when absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<ScriptConfig>,
+
     /// Whether this route is active
     #[serde(default = "default_active")]
     pub active: bool,
@@ -58,8 +161,29 @@ impl Route {
         Self {
             id: id.into(),
             path: path.into(),
+            conditions: MatchConditions::default(),
             upstream: upstream.into(),
             weight: default_weight(),
+            upstreams: Vec::new(),
+            load_balancing: LoadBalancing::default(),
+            health_check: None,
+            outlier_detection: OutlierDetection::default(),
+            circuit_breaker: None,
+            retry: None,
+            max_request_body_bytes: None,
+            max_response_body_bytes: None,
+            allowed_content_types: Vec::new(),
+            buffer_body: false,
+            rewrite: None,
+            request_headers: HeaderPolicy::default(),
+            response_headers: HeaderPolicy::default(),
+            websocket: None,
+            streaming: None,
+            cache: None,
+            compression: None,
+            upstream_tls: None,
+            filters: Vec::new(),
+            script: None,
             active: default_active(),
             methods: Vec::new(),
             timeout_ms: default_timeout(),
@@ -67,15 +191,884 @@ impl Route {
         }
     }
 
+    /// Create a route balancing across several weighted upstreams
+    pub fn with_upstreams(
+        id: impl Into<String>,
+        path: impl Into<String>,
+        upstreams: Vec<UpstreamTarget>,
+        load_balancing: LoadBalancing,
+    ) -> Self {
+        let mut route = Self::new(id, path, String::new());
+        route.upstreams = upstreams;
+        route.load_balancing = load_balancing;
+        route
+    }
+
+    /// The effective upstream targets for this route.
+    ///
+    /// Routes configured with only `upstream` yield a single target carrying
+    /// the route's `weight`. Targets with zero weight are skipped.
+    pub fn targets(&self) -> Cow<'_, [UpstreamTarget]> {
+        if self.upstreams.is_empty() {
+            if self.upstream.is_empty() {
+                return Cow::Owned(Vec::new());
+            }
+            Cow::Owned(vec![UpstreamTarget::new(self.upstream.clone(), self.weight.max(1))])
+        } else if self.upstreams.iter().all(|t| t.weight > 0) {
+            Cow::Borrowed(&self.upstreams)
+        } else {
+            Cow::Owned(self.upstreams.iter().filter(|t| t.weight > 0).cloned().collect())
+        }
+    }
+
+    /// Effective maximum request body size in bytes
+    pub fn max_request_body(&self) -> usize {
+        self.max_request_body_bytes.unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
+    /// Whether the request body must be buffered rather than streamed
+    pub fn needs_buffered_body(&self) -> bool {
+        self.buffer_body || self.retry.is_some()
+    }
+
+    /// How long to wait for the upstream response head, in milliseconds
+    pub fn response_timeout_ms(&self) -> u64 {
+        self.streaming.as_ref().map_or(self.timeout_ms, |s| s.idle_timeout_ms)
+    }
+
     /// Check if a specific HTTP method is allowed
     pub fn allows_method(&self, method: &str) -> bool {
         self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
     }
 }
 
-/// The routing table - a map from path patterns to routes.
+/// Request conditions a route can require besides its path.
+///
+/// All configured conditions must hold for the route to match. Header
+/// names are case-insensitive; values are compared exactly.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MatchConditions {
+    /// Host name (e.g., "beta.example.com"), or "*.example.com" for any
+    /// subdomain of example.com
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+
+    /// Required request header values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+
+    /// Required query parameter values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+
+    /// Required cookie values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cookies: BTreeMap<String, String>,
+}
+
+impl MatchConditions {
+    /// Whether no conditions are configured
+    pub fn is_empty(&self) -> bool {
+        self.host.is_none() && self.headers.is_empty() && self.query.is_empty() && self.cookies.is_empty()
+    }
+
+    /// Precedence among routes sharing a path template: an exact host
+    /// beats a wildcard host (longer suffixes first), which beats no host;
+    /// then more header, query and cookie conditions win.
+    pub fn specificity(&self) -> (u8, usize, usize) {
+        let host = match self.host.as_deref() {
+            Some(h) if h.starts_with("*.") => (1, h.len()),
+            Some(_) => (2, 0),
+            None => (0, 0),
+        };
+        (host.0, host.1, self.headers.len() + self.query.len() + self.cookies.len())
+    }
+}
+
+/// A weighted upstream target behind a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct UpstreamTarget {
+    /// Upstream service URL (e.g., "http://user-service-1:8080")
+    pub url: String,
+
+    /// Relative traffic weight (0 disables the target)
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+impl UpstreamTarget {
+    /// Create a new upstream target
+    pub fn new(url: impl Into<String>, weight: u32) -> Self {
+        Self {
+            url: url.into(),
+            weight,
+        }
+    }
+}
+
+/// Load balancing policy for routes with several upstream targets.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum LoadBalancing {
+    /// Smooth weighted round robin
+    #[default]
+    WeightedRoundRobin,
+
+    /// Target with the fewest in-flight requests (ties broken by weight)
+    LeastOutstanding,
+
+    /// Pick two targets at random (by weight), keep the less loaded one
+    RandomTwoChoices,
+
+    /// Consistent hashing, so the same key sticks to the same target
+    ConsistentHash {
+        /// What the hash key is derived from
+        #[serde(default)]
+        hash_on: HashKey,
+    },
+}
+
+impl LoadBalancing {
+    /// Short policy name for logs and statistics
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoadBalancing::WeightedRoundRobin => "weighted_round_robin",
+            LoadBalancing::LeastOutstanding => "least_outstanding",
+            LoadBalancing::RandomTwoChoices => "random_two_choices",
+            LoadBalancing::ConsistentHash { .. } => "consistent_hash",
+        }
+    }
+}
+
+/// Source of the consistent-hashing key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    /// The client's IP address
+    #[default]
+    ClientIp,
+
+    /// The value of a request header (falls back to client IP when absent)
+    Header(String),
+}
+
+/// Active health check settings for a route's upstream targets.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// HTTP path probed with a GET request (e.g., "/health")
+    #[serde(default = "default_health_path")]
+    pub path: String,
+
+    /// Time between probes in milliseconds
+    #[serde(default = "default_health_interval")]
+    pub interval_ms: u64,
+
+    /// Probe timeout in milliseconds
+    #[serde(default = "default_health_timeout")]
+    pub timeout_ms: u64,
+
+    /// Consecutive successful probes before an unhealthy target is restored
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+
+    /// Consecutive failed probes before a target is marked unhealthy
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_health_path() -> String {
+    "/health".to_string()
+}
+
+fn default_health_interval() -> u64 {
+    10_000
+}
+
+fn default_health_timeout() -> u64 {
+    2_000
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: default_health_path(),
+            interval_ms: default_health_interval(),
+            timeout_ms: default_health_timeout(),
+            healthy_threshold: default_healthy_threshold(),
+            unhealthy_threshold: default_unhealthy_threshold(),
+        }
+    }
+}
+
+/// Passive outlier detection settings.
+///
+/// A target that returns 5xx responses or fails to connect
+/// `consecutive_errors` times in a row is ejected for `ejection_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OutlierDetection {
+    /// Consecutive failures before ejection (0 disables ejection)
+    #[serde(default = "default_consecutive_errors")]
+    pub consecutive_errors: u32,
+
+    /// How long an ejected target is skipped, in milliseconds
+    #[serde(default = "default_ejection_ms")]
+    pub ejection_ms: u64,
+}
+
+fn default_consecutive_errors() -> u32 {
+    5
+}
+
+fn default_ejection_ms() -> u64 {
+    30_000
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_errors: default_consecutive_errors(),
+            ejection_ms: default_ejection_ms(),
+        }
+    }
+}
+
+/// Circuit breaker settings.
+///
+/// Calls are tracked over a rolling time window. Once at least
+/// `minimum_calls` were made, the breaker opens when the share of failed or
+/// slow calls reaches its threshold. After `open_ms` it lets
+/// `half_open_calls` trial calls through; if all succeed it closes again.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// Failure rate (percent) at which the breaker opens
+    #[serde(default = "default_failure_rate")]
+    pub failure_rate_percent: u32,
+
+    /// Slow call rate (percent) at which the breaker opens (100 = only when all calls are slow)
+    #[serde(default = "default_slow_call_rate")]
+    pub slow_call_rate_percent: u32,
+
+    /// Calls taking at least this long (milliseconds) count as slow
+    #[serde(default = "default_slow_call_ms")]
+    pub slow_call_ms: u64,
+
+    /// Length of the rolling window in milliseconds
+    #[serde(default = "default_window_ms")]
+    pub window_ms: u64,
+
+    /// Minimum calls in the window before rates are evaluated
+    #[serde(default = "default_minimum_calls")]
+    pub minimum_calls: u32,
+
+    /// How long the breaker stays open before allowing trial calls
+    #[serde(default = "default_open_ms")]
+    pub open_ms: u64,
+
+    /// Number of trial calls allowed while half-open
+    #[serde(default = "default_half_open_calls")]
+    pub half_open_calls: u32,
+}
+
+fn default_failure_rate() -> u32 {
+    50
+}
+
+fn default_slow_call_rate() -> u32 {
+    100
+}
+
+fn default_slow_call_ms() -> u64 {
+    5_000
+}
+
+fn default_window_ms() -> u64 {
+    10_000
+}
+
+fn default_minimum_calls() -> u32 {
+    10
+}
+
+fn default_open_ms() -> u64 {
+    30_000
+}
+
+fn default_half_open_calls() -> u32 {
+    3
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failure_rate_percent: default_failure_rate(),
+            slow_call_rate_percent: default_slow_call_rate(),
+            slow_call_ms: default_slow_call_ms(),
+            window_ms: default_window_ms(),
+            minimum_calls: default_minimum_calls(),
+            open_ms: default_open_ms(),
+            half_open_calls: default_half_open_calls(),
+        }
+    }
+}
+
+/// Retry policy for upstream calls.
+///
+/// Retries also draw from the gateway-wide retry budget, so a policy here
+/// is an upper bound rather than a guarantee.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+
+    /// Upstream status codes that trigger a retry
+    #[serde(default = "default_retry_on_status")]
+    pub retry_on_status: Vec<u16>,
+
+    /// Backoff before the first retry, doubled for each further retry
+    #[serde(default = "default_base_backoff_ms")]
+    pub base_backoff_ms: u64,
+
+    /// Upper bound for the backoff
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+
+    /// Also retry non-idempotent methods such as POST
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
+
+    /// Send a second (hedged) GET/HEAD request if the first has not
+    /// answered after this many milliseconds
+    #[serde(default)]
+    pub hedge_delay_ms: Option<u64>,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_on_status() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_base_backoff_ms() -> u64 {
+    25
+}
+
+fn default_max_backoff_ms() -> u64 {
+    1_000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            retry_on_status: default_retry_on_status(),
+            base_backoff_ms: default_base_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            retry_non_idempotent: false,
+            hedge_delay_ms: None,
+        }
+    }
+}
+
+/// Rewrite of the request path before it is sent upstream.
+///
+/// Steps run in field order; each is optional. The query string is kept
+/// as is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PathRewrite {
+    /// Replace the whole path, substituting `{name}` with parameters
+    /// captured by the route's path template (e.g., "/v2/users/{id}")
+    #[serde(default)]
+    pub template: Option<String>,
+
+    /// Remove this prefix when it matches whole segments (e.g., "/api")
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+
+    /// Regex replacements, applied in order
+    #[serde(default)]
+    pub replace: Vec<RegexReplace>,
+
+    /// Prepend this prefix (e.g., "/internal")
+    #[serde(default)]
+    pub add_prefix: Option<String>,
+}
+
+/// A regex replacement on the request path.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegexReplace {
+    /// Regex matched against the path
+    pub pattern: String,
+
+    /// Replacement text; `$1` or `$name` refer to capture groups
+    pub replacement: String,
+}
+
+/// Header changes applied to a request or response.
+///
+/// Applied in field order. Values of `set` and `add` may use `${client_ip}`,
+/// `${route_id}`, `${trace_id}` and `${jwt.<claim>}` placeholders.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HeaderPolicy {
+    /// Rename headers (old name -> new name), keeping their values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+
+    /// Remove headers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+
+    /// Set headers, replacing existing values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+
+    /// Add header values, keeping existing ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+}
+
+impl HeaderPolicy {
+    /// Whether the policy changes nothing
+    pub fn is_empty(&self) -> bool {
+        self.rename.is_empty() && self.remove.is_empty() && self.set.is_empty() && self.add.is_empty()
+    }
+}
+
+/// WebSocket proxying settings for a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WebSocketConfig {
+    /// Close the connection after this long without a message in either
+    /// direction
+    #[serde(default = "default_ws_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+
+    /// Largest message, in bytes, accepted from either side. Larger
+    /// messages close the connection with status 1009.
+    #[serde(default = "default_ws_max_message_bytes")]
+    pub max_message_bytes: usize,
+}
+
+fn default_ws_idle_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_ws_max_message_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_ms: default_ws_idle_timeout_ms(),
+            max_message_bytes: default_ws_max_message_bytes(),
+        }
+    }
+}
+
+/// Streaming settings for routes serving Server-Sent Events or long polls.
+///
+/// Instead of a single deadline for the upstream's answer, the connection
+/// may stay open as long as data keeps arriving.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StreamingConfig {
+    /// Longest wait, in milliseconds, for the response head and then
+    /// between response body chunks
+    #[serde(default = "default_stream_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+}
+
+fn default_stream_idle_timeout_ms() -> u64 {
+    60_000
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_ms: default_stream_idle_timeout_ms(),
+        }
+    }
+}
+
+/// Response caching settings for a route.
+///
+/// Upstream `Cache-Control`, `Expires` and `Vary` headers decide what is
+/// stored and for how long; these settings fill in defaults and bounds.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Freshness lifetime in seconds for responses that do not state one
+    /// (0 = only cache responses with explicit freshness or a validator)
+    #[serde(default)]
+    pub default_ttl_secs: u64,
+
+    /// Upper bound in seconds for any freshness lifetime
+    #[serde(default = "default_cache_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+
+    /// Serve a stale entry for up to this many seconds past its expiry
+    /// while it is refreshed in the background. An upstream
+    /// `stale-while-revalidate` directive takes precedence when longer.
+    #[serde(default)]
+    pub stale_while_revalidate_secs: u64,
+
+    /// Largest response body, in bytes, that is stored
+    #[serde(default = "default_cache_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// What besides the route and path tells cache entries apart
+    #[serde(default)]
+    pub key: CacheKeyConfig,
+}
+
+fn default_cache_max_ttl_secs() -> u64 {
+    3_600
+}
+
+fn default_cache_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            default_ttl_secs: 0,
+            max_ttl_secs: default_cache_max_ttl_secs(),
+            stale_while_revalidate_secs: 0,
+            max_body_bytes: default_cache_max_body_bytes(),
+            key: CacheKeyConfig::default(),
+        }
+    }
+}
+
+/// Request parts that make up a cache key, on top of route and path.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CacheKeyConfig {
+    /// Include the query string
+    #[serde(default = "default_true")]
+    pub query: bool,
+
+    /// Include the values of these request headers
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    /// Include the caller's JWT subject, giving each caller their own
+    /// entries. Also allows caching `private` responses. Requests without
+    /// JWT claims then bypass the cache.
+    #[serde(default)]
+    pub jwt_subject: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for CacheKeyConfig {
+    fn default() -> Self {
+        Self {
+            query: true,
+            headers: Vec::new(),
+            jwt_subject: false,
+        }
+    }
+}
+
+/// Response compression settings for a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Encodings the gateway may apply, most preferred first. The client's
+    /// `Accept-Encoding` q-values win over this order.
+    #[serde(default = "default_compression_encodings")]
+    pub encodings: Vec<ContentEncoding>,
+
+    /// Smallest response, in bytes, worth compressing. Responses without a
+    /// `Content-Length` are always compressed.
+    #[serde(default = "default_compression_min_size_bytes")]
+    pub min_size_bytes: u64,
+
+    /// Response media types to compress, e.g. "application/json" or "text/*"
+    #[serde(default = "default_compression_content_types")]
+    pub content_types: Vec<String>,
+}
+
+fn default_compression_encodings() -> Vec<ContentEncoding> {
+    vec![ContentEncoding::Zstd, ContentEncoding::Brotli, ContentEncoding::Gzip, ContentEncoding::Deflate]
+}
+
+fn default_compression_min_size_bytes() -> u64 {
+    1024
+}
+
+fn default_compression_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "application/problem+json",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encodings: default_compression_encodings(),
+            min_size_bytes: default_compression_min_size_bytes(),
+            content_types: default_compression_content_types(),
+        }
+    }
+}
+
+/// An HTTP content coding the gateway can apply and decode.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    /// `gzip` (RFC 1952)
+    Gzip,
+
+    /// `deflate`: zlib-wrapped DEFLATE (RFC 1950)
+    Deflate,
+
+    /// `br` (RFC 7932)
+    #[serde(rename = "br")]
+    Brotli,
+
+    /// `zstd` (RFC 8878)
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Token used in `Content-Encoding` and `Accept-Encoding`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    /// Parse a content coding token, case-insensitively
+    pub fn from_token(token: &str) -> Option<Self> {
+        let token = token.trim();
+        [Self::Gzip, Self::Deflate, Self::Brotli, Self::Zstd]
+            .into_iter()
+            .find(|e| e.as_str().eq_ignore_ascii_case(token))
+            .or_else(|| token.eq_ignore_ascii_case("x-gzip").then_some(Self::Gzip))
+    }
+}
+
+/// A TLS certificate served to clients asking for one of its hostnames.
+///
+/// The chain and private key are given either inline as PEM, as stored in
+/// SurrealDB, or as paths to PEM files on disk; inline PEM wins when both
+/// are set.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CertificateConfig {
+    /// Unique identifier for the certificate
+    pub id: String,
+
+    /// SNI hostnames this certificate is served for. `*.example.com`
+    /// matches exactly one label in place of the `*`.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+
+    /// Serve this certificate when the client sends no SNI or one that no
+    /// certificate lists
+    #[serde(default)]
+    pub default: bool,
+
+    /// PEM certificate chain, leaf first
+    #[serde(default)]
+    pub cert_pem: Option<String>,
+
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    #[serde(default)]
+    pub key_pem: Option<String>,
+
+    /// Path to a PEM certificate chain, leaf first
+    #[serde(default)]
+    pub cert_path: Option<String>,
+
+    /// Path to a PEM private key
+    #[serde(default)]
+    pub key_path: Option<String>,
+
+    /// Whether this certificate is served
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+impl CertificateConfig {
+    /// A certificate read from PEM files on disk
+    pub fn from_files(id: impl Into<String>, cert_path: impl Into<String>, key_path: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            hostnames: Vec::new(),
+            default: false,
+            cert_pem: None,
+            key_pem: None,
+            cert_path: Some(cert_path.into()),
+            key_path: Some(key_path.into()),
+            active: default_active(),
+        }
+    }
+}
+
+/// Rhai hooks run on a route's live traffic.
+///
+/// The request script sees the request as a `request` map (`method`,
+/// `path`, `params`, `query`, `headers`, `body`) and may change it, pick
+/// the upstream by setting `request.upstream`, or answer the request itself
+/// by setting `response` to a `#{ status, headers, body }` map. The
+/// response script sees that `request` and the upstream's `response` and
+/// may change the latter. JSON bodies are parsed into maps and arrays.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScriptConfig {
+    /// Script run before forwarding
+    #[serde(default)]
+    pub request: Option<String>,
+
+    /// Script run on the upstream's response
+    #[serde(default)]
+    pub response: Option<String>,
+
+    /// Time, operation and size limits for each run of either script
+    #[serde(default)]
+    pub limits: ScriptLimits,
+}
+
+/// Limits on a single run of a Rhai script.
+///
+/// A script that goes over any of them is stopped and its run fails. The
+/// size limits cap each string, array and map on its own, not the total a
+/// script holds, so they only slow down a script building up memory; the
+/// operation budget and timeout are what end it. 0 turns a limit off.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct ScriptLimits {
+    /// Wall-clock time a run may take, in milliseconds (0 = unlimited)
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// Operations a run may perform (0 = unlimited)
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+
+    /// Longest string a script may build, in bytes (0 = unlimited)
+    #[serde(default = "default_max_string_size")]
+    pub max_string_size: usize,
+
+    /// Most elements in an array (0 = unlimited)
+    #[serde(default = "default_max_collection_size")]
+    pub max_array_size: usize,
+
+    /// Most entries in an object map (0 = unlimited)
+    #[serde(default = "default_max_collection_size")]
+    pub max_map_size: usize,
+
+    /// Deepest nesting of expressions, and of expressions inside function
+    /// bodies, a script may be written with (0 = unlimited)
+    #[serde(default = "default_max_expr_depth")]
+    pub max_expr_depth: usize,
+}
+
+fn default_script_timeout_ms() -> u64 {
+    100
+}
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+fn default_max_string_size() -> usize {
+    1024 * 1024 // 1MB
+}
+
+fn default_max_collection_size() -> usize {
+    10_000
+}
+
+fn default_max_expr_depth() -> usize {
+    64
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            timeout_ms: default_script_timeout_ms(),
+            max_operations: default_max_operations(),
+            max_string_size: default_max_string_size(),
+            max_array_size: default_max_collection_size(),
+            max_map_size: default_max_collection_size(),
+            max_expr_depth: default_max_expr_depth(),
+        }
+    }
+}
+
+impl ScriptLimits {
+    /// Wall-clock time a run may take, or `None` for no deadline
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_ms > 0).then(|| Duration::from_millis(self.timeout_ms))
+    }
+}
+
+/// TLS settings for connections to a route's `https://` upstreams.
+///
+/// PEM material is given inline or as paths to PEM files on the gateway
+/// host; inline PEM wins when both are set. A client certificate needs both
+/// the chain and its key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct UpstreamTlsConfig {
+    /// PEM CA certificates trusted for the upstream, replacing the public
+    /// web roots
+    #[serde(default)]
+    pub ca_pem: Option<String>,
+
+    /// Path to a PEM CA bundle
+    #[serde(default)]
+    pub ca_path: Option<String>,
+
+    /// PEM client certificate chain presented when the upstream asks for one
+    #[serde(default)]
+    pub client_cert_pem: Option<String>,
+
+    /// Path to a PEM client certificate chain
+    #[serde(default)]
+    pub client_cert_path: Option<String>,
+
+    /// PEM private key of the client certificate
+    #[serde(default)]
+    pub client_key_pem: Option<String>,
+
+    /// Path to the PEM private key of the client certificate
+    #[serde(default)]
+    pub client_key_path: Option<String>,
+
+    /// Name sent via SNI and checked against the upstream certificate,
+    /// instead of the host in the upstream URL
+    #[serde(default)]
+    pub server_name: Option<String>,
+
+    /// Accept any upstream certificate. Only for development against
+    /// self-signed upstreams.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// The routing table - routes keyed by path template.
 /// This structure is designed to be swapped atomically via ArcSwap.
-pub type RouterMap = HashMap<String, Route>;
+pub use crate::router::RouterMap;
 
 #[cfg(test)]
 mod tests {
@@ -105,4 +1098,65 @@ mod tests {
         assert!(route.allows_method("post")); // case insensitive
         assert!(!route.allows_method("DELETE"));
     }
+
+    #[test]
+    fn test_targets() {
+        let route = Route::new("test-1", "/api/users", "http://localhost:3000");
+        assert_eq!(
+            route.targets().as_ref(),
+            &[UpstreamTarget::new("http://localhost:3000", 100)]
+        );
+
+        let route = Route::with_upstreams(
+            "test-2",
+            "/api/users",
+            vec![
+                UpstreamTarget::new("http://a:3000", 3),
+                UpstreamTarget::new("http://b:3000", 0),
+            ],
+            LoadBalancing::LeastOutstanding,
+        );
+        assert_eq!(route.targets().as_ref(), &[UpstreamTarget::new("http://a:3000", 3)]);
+    }
+
+    #[test]
+    fn test_load_balancing_serde() {
+        let json = r#"{
+            "id": "lb",
+            "path": "/lb",
+            "upstreams": [{"url": "http://a:80", "weight": 2}, {"url": "http://b:80"}],
+            "load_balancing": {"policy": "consistent_hash", "hash_on": {"header": "x-user"}}
+        }"#;
+        let route: Route = serde_json::from_str(json).unwrap();
+        assert_eq!(route.upstreams[1].weight, 100);
+        assert_eq!(
+            route.load_balancing,
+            LoadBalancing::ConsistentHash { hash_on: HashKey::Header("x-user".into()) }
+        );
+
+        let route: Route = serde_json::from_str(r#"{"id": "a", "path": "/a", "upstream": "http://a"}"#).unwrap();
+        assert_eq!(route.load_balancing, LoadBalancing::WeightedRoundRobin);
+    }
+
+    #[test]
+    fn test_match_conditions() {
+        let json = r#"{
+            "id": "beta",
+            "path": "/api",
+            "upstream": "http://beta:80",
+            "conditions": {"host": "*.example.com", "headers": {"x-api-version": "2"}}
+        }"#;
+        let route: Route = serde_json::from_str(json).unwrap();
+        assert_eq!(route.conditions.host.as_deref(), Some("*.example.com"));
+        assert_eq!(route.conditions.headers["x-api-version"], "2");
+        assert!(route.conditions.query.is_empty());
+
+        let exact = MatchConditions {
+            host: Some("beta.example.com".into()),
+            ..Default::default()
+        };
+        assert!(exact.specificity() > route.conditions.specificity());
+        assert!(route.conditions.specificity() > MatchConditions::default().specificity());
+        assert!(MatchConditions::default().is_empty());
+    }
 }
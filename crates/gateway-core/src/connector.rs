@@ -0,0 +1,295 @@
+//! Upstream connections over plain TCP or TLS.
+//!
+//! [`HttpsConnector`] wraps hyper-util's `HttpConnector` and performs a TLS
+//! handshake on top of it for `https://` upstreams, so a single pooled
+//! client reaches both kinds of upstream.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::http::uri::Scheme;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tower::Service;
+
+use crate::body::BoxError;
+use crate::config::UpstreamTlsConfig;
+use crate::error::GatewayError;
+use crate::proxy::DEFAULT_CONNECT_TIMEOUT;
+use crate::tls::client_config;
+
+/// Connects to `http://` upstreams over TCP and to `https://` upstreams
+/// over TLS.
+#[derive(Clone)]
+pub struct HttpsConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+    /// Overrides the URL host as SNI name and verified name
+    server_name: Option<ServerName<'static>>,
+}
+
+impl HttpsConnector {
+    /// Create a connector with the given upstream TLS settings, or the
+    /// defaults (public web roots, no client certificate) when `None`.
+    pub fn new(tls: Option<&UpstreamTlsConfig>) -> Result<Self, GatewayError> {
+        let server_name = tls
+            .and_then(|tls| tls.server_name.as_deref())
+            .map(|name| {
+                ServerName::try_from(name.to_string()).map_err(|e| {
+                    GatewayError::ConfigError(format!("upstream TLS: invalid server name '{}': {}", name, e))
+                })
+            })
+            .transpose()?;
+
+        let mut http = HttpConnector::new();
+        http.set_nodelay(true);
+        http.set_connect_timeout(Some(DEFAULT_CONNECT_TIMEOUT));
+        http.enforce_http(false);
+
+        Ok(Self {
+            http,
+            tls: TlsConnector::from(client_config(tls)?),
+            server_name,
+        })
+    }
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = MaybeHttpsStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<MaybeHttpsStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let server_name = (uri.scheme() == Some(&Scheme::HTTPS)).then(|| match &self.server_name {
+            Some(name) => Ok(name.clone()),
+            None => {
+                let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+                ServerName::try_from(host.to_string())
+            }
+        });
+        let connecting = self.http.call(uri);
+        let tls = self.tls.clone();
+
+        Box::pin(async move {
+            let stream = connecting.await?;
+            match server_name {
+                None => Ok(MaybeHttpsStream::Http(stream)),
+                Some(name) => {
+                    let stream = tls.connect(name?, stream.into_inner()).await?;
+                    Ok(MaybeHttpsStream::Https(Box::new(TokioIo::new(stream))))
+                }
+            }
+        })
+    }
+}
+
+/// A connection to an upstream, with or without TLS.
+pub enum MaybeHttpsStream {
+    Http(TokioIo<TcpStream>),
+    Https(Box<TokioIo<TlsStream<TcpStream>>>),
+}
+
+impl Connection for MaybeHttpsStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Http(stream) => stream.connected(),
+            Self::Https(stream) => stream.inner().get_ref().0.connected(),
+        }
+    }
+}
+
+impl Read for MaybeHttpsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Http(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Https(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for MaybeHttpsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Http(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Https(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Http(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Https(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Http(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Https(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Http(stream) => stream.is_write_vectored(),
+            Self::Https(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Http(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Https(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::{Request, Response};
+
+    use crate::body;
+    use crate::config::{CertificateConfig, Route, UpstreamTlsConfig};
+    use crate::error::GatewayError;
+    use crate::proxy::UpstreamClient;
+    use crate::tls::{acceptor, CertStore, ClientAuth, TlsConnection};
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Start an HTTPS upstream for `localhost` that requires a client
+    /// certificate and answers with its common name.
+    async fn spawn_mtls_upstream() -> u16 {
+        let cert = CertificateConfig::from_files("localhost", testdata("localhost.pem"), testdata("localhost.key"));
+        let store = Arc::new(ArcSwap::from_pointee(CertStore::build(&[cert])));
+        let client_auth = ClientAuth {
+            ca_path: testdata("client-ca.pem"),
+            required: true,
+        };
+        let acceptor = acceptor(store, Some(&client_auth)).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let cn = TlsConnection::from_stream(&stream).client_cert.and_then(|c| c.common_name);
+                    let service = hyper::service::service_fn(move |_req: Request<Incoming>| {
+                        let cn = cn.clone().unwrap_or_default();
+                        async move { Ok::<_, Infallible>(Response::new(body::full(cn))) }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        port
+    }
+
+    fn client_identity() -> UpstreamTlsConfig {
+        UpstreamTlsConfig {
+            client_cert_path: Some(testdata("client.pem")),
+            client_key_path: Some(testdata("client.key")),
+            ..Default::default()
+        }
+    }
+
+    async fn call(client: &UpstreamClient, upstream: &str, tls: Option<UpstreamTlsConfig>) -> Result<String, GatewayError> {
+        let mut route = Route::new("mtls", "/", upstream);
+        route.upstream_tls = tls;
+        let req = Request::get("/whoami").body(body::empty()).unwrap();
+        let response = client.forward(&route, upstream, req).await?;
+        let bytes = response.into_body().collect().await.map_err(|e| GatewayError::InternalError(e.to_string()))?;
+        Ok(String::from_utf8_lossy(&bytes.to_bytes()).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_mutual_tls() {
+        let port = spawn_mtls_upstream().await;
+        let upstream = format!("https://localhost:{}", port);
+        let client = UpstreamClient::new();
+
+        // Private CA plus client certificate
+        let tls = UpstreamTlsConfig {
+            ca_path: Some(testdata("ca.pem")),
+            ..client_identity()
+        };
+        assert_eq!(call(&client, &upstream, Some(tls.clone())).await.unwrap(), "orders-service");
+
+        // SNI override: dialled by IP, verified as localhost
+        let by_ip = UpstreamTlsConfig {
+            server_name: Some("localhost".to_string()),
+            ..tls.clone()
+        };
+        let upstream_ip = format!("https://127.0.0.1:{}", port);
+        assert_eq!(call(&client, &upstream_ip, Some(by_ip)).await.unwrap(), "orders-service");
+
+        // The private CA is not in the public web roots
+        let result = call(&client, &upstream, Some(client_identity())).await;
+        assert!(matches!(result, Err(GatewayError::UpstreamConnectionFailed { .. })));
+
+        // ...unless verification is switched off
+        let insecure = UpstreamTlsConfig {
+            insecure_skip_verify: true,
+            ..client_identity()
+        };
+        assert_eq!(call(&client, &upstream, Some(insecure)).await.unwrap(), "orders-service");
+
+        // No client certificate: the upstream refuses the connection
+        let anonymous = UpstreamTlsConfig {
+            ca_path: Some(testdata("ca.pem")),
+            ..Default::default()
+        };
+        assert!(call(&client, &upstream, Some(anonymous)).await.is_err());
+    }
+
+    #[test]
+    fn test_invalid_upstream_tls() {
+        let half_identity = UpstreamTlsConfig {
+            client_cert_path: Some(testdata("client.pem")),
+            ..Default::default()
+        };
+        assert!(HttpsConnector::new(Some(&half_identity)).is_err());
+
+        let mismatched = UpstreamTlsConfig {
+            client_key_path: Some(testdata("localhost.key")),
+            ..client_identity()
+        };
+        assert!(HttpsConnector::new(Some(&mismatched)).is_err());
+
+        let missing_ca = UpstreamTlsConfig {
+            ca_path: Some(testdata("missing.pem")),
+            ..Default::default()
+        };
+        assert!(HttpsConnector::new(Some(&missing_ca)).is_err());
+    }
+}
@@ -21,6 +21,30 @@ pub enum GatewayError {
     #[error("Payload too large: received {size} bytes, limit is {limit} bytes")]
     PayloadTooLarge { size: u64, limit: u64 },
 
+    /// Request content type is not accepted by the route
+    #[error("Unsupported media type: {content_type}")]
+    UnsupportedMediaType { content_type: String },
+
+    /// Request body uses a content coding the gateway cannot decode
+    #[error("Unsupported content encoding: {encoding}")]
+    UnsupportedEncoding { encoding: String },
+
+    /// Upstream response body exceeds the route's limit
+    #[error("Upstream response too large: {size} bytes, limit is {limit} bytes")]
+    ResponseTooLarge { size: u64, limit: u64 },
+
+    /// Upstream response body could not be read or decoded
+    #[error("Invalid upstream response body: {0}")]
+    InvalidResponseBody(String),
+
+    /// Malformed WebSocket upgrade request
+    #[error("Invalid WebSocket upgrade: {0}")]
+    InvalidUpgrade(String),
+
+    /// Upstream answered a WebSocket upgrade with an invalid handshake
+    #[error("Invalid WebSocket upgrade from upstream: {0}")]
+    InvalidUpstreamUpgrade(String),
+
     /// Failed to connect to upstream service
     #[error("Upstream connection failed for {upstream}: {reason}")]
     UpstreamConnectionFailed { upstream: String, reason: String },
@@ -33,6 +57,10 @@ pub enum GatewayError {
     #[error("Request timeout after {timeout_ms}ms for upstream: {upstream}")]
     RequestTimeout { upstream: String, timeout_ms: u64 },
 
+    /// Circuit breaker for the upstream is open
+    #[error("Circuit breaker open for upstream: {upstream}")]
+    CircuitOpen { upstream: String },
+
     /// Configuration error
     #[error("Configuration error: {0}")]
     ConfigError(String),
@@ -52,6 +80,10 @@ pub enum GatewayError {
     /// Serialization error
     #[error("Serialization error: {0}")]
     SerializationError(String),
+
+    /// A transform script failed on the request or response
+    #[error("Transform failed: {0}")]
+    TransformFailed(String),
 }
 
 impl GatewayError {
@@ -61,14 +93,35 @@ impl GatewayError {
             GatewayError::RouteNotFound { .. } => 404,
             GatewayError::MethodNotAllowed { .. } => 405,
             GatewayError::PayloadTooLarge { .. } => 413,
+            GatewayError::UnsupportedMediaType { .. } => 415,
+            GatewayError::UnsupportedEncoding { .. } => 415,
+            GatewayError::ResponseTooLarge { .. } => 502,
+            GatewayError::InvalidResponseBody(_) => 502,
+            GatewayError::InvalidUpgrade(_) => 400,
+            GatewayError::InvalidUpstreamUpgrade(_) => 502,
             GatewayError::UpstreamConnectionFailed { .. } => 502,
             GatewayError::UpstreamError { status_code, .. } => *status_code,
             GatewayError::RequestTimeout { .. } => 504,
+            GatewayError::CircuitOpen { .. } => 503,
             GatewayError::ConfigError(_) => 500,
             GatewayError::DatabaseError(_) => 500,
             GatewayError::InternalError(_) => 500,
             GatewayError::BodyReadError(_) => 400,
             GatewayError::SerializationError(_) => 400,
+            GatewayError::TransformFailed(_) => 500,
+        }
+    }
+
+    /// Attribute an error from reading or decoding an upstream response
+    /// body to the upstream, so the client doesn't get a 4xx for it.
+    pub fn for_response_body(self) -> Self {
+        match self {
+            GatewayError::PayloadTooLarge { size, limit } => GatewayError::ResponseTooLarge { size, limit },
+            GatewayError::BodyReadError(reason) => GatewayError::InvalidResponseBody(reason),
+            GatewayError::UnsupportedEncoding { encoding } => {
+                GatewayError::InvalidResponseBody(format!("unsupported content encoding {}", encoding))
+            }
+            e => e,
         }
     }
 
@@ -88,14 +141,22 @@ impl GatewayError {
             GatewayError::RouteNotFound { .. } => "routing",
             GatewayError::MethodNotAllowed { .. } => "routing",
             GatewayError::PayloadTooLarge { .. } => "client_error",
+            GatewayError::UnsupportedMediaType { .. } => "unsupported_media_type",
+            GatewayError::UnsupportedEncoding { .. } => "unsupported_media_type",
+            GatewayError::ResponseTooLarge { .. } => "upstream",
+            GatewayError::InvalidResponseBody(_) => "upstream",
+            GatewayError::InvalidUpgrade(_) => "client_error",
+            GatewayError::InvalidUpstreamUpgrade(_) => "upstream",
             GatewayError::UpstreamConnectionFailed { .. } => "upstream",
             GatewayError::UpstreamError { .. } => "upstream",
             GatewayError::RequestTimeout { .. } => "upstream",
+            GatewayError::CircuitOpen { .. } => "circuit_open",
             GatewayError::ConfigError(_) => "config",
             GatewayError::DatabaseError(_) => "database",
             GatewayError::InternalError(_) => "internal",
             GatewayError::BodyReadError(_) => "client_error",
             GatewayError::SerializationError(_) => "client_error",
+            GatewayError::TransformFailed(_) => "transform",
         }
     }
 }
@@ -144,6 +205,39 @@ mod tests {
             .status_code(),
             504
         );
+        assert_eq!(
+            GatewayError::CircuitOpen {
+                upstream: "test".into()
+            }
+            .status_code(),
+            503
+        );
+        assert_eq!(
+            GatewayError::UnsupportedMediaType {
+                content_type: "text/xml".into()
+            }
+            .status_code(),
+            415
+        );
+        assert_eq!(
+            GatewayError::ResponseTooLarge { size: 100, limit: 50 }.status_code(),
+            502
+        );
+    }
+
+    #[test]
+    fn test_categories() {
+        assert_eq!(
+            GatewayError::UnsupportedMediaType {
+                content_type: "text/xml".into()
+            }
+            .category(),
+            "unsupported_media_type"
+        );
+        assert_eq!(
+            GatewayError::PayloadTooLarge { size: 100, limit: 50 }.category(),
+            "client_error"
+        );
     }
 
     #[test]
@@ -158,5 +252,10 @@ mod tests {
             path: "/test".into()
         }
         .is_retryable());
+
+        assert!(!GatewayError::CircuitOpen {
+            upstream: "test".into()
+        }
+        .is_retryable());
     }
 }
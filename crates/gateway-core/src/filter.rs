@@ -0,0 +1,530 @@
+//! Per-route request/response filter chains.
+//!
+//! A [`Filter`] sees each request before it is forwarded and each response
+//! before it is returned, and may end the request early with a response of
+//! its own (an authentication failure, a rate limit, ...). Filters are
+//! registered by name in the gateway's [`FilterRegistry`]; routes list the
+//! names they opt into in [`Route::filters`], e.g.
+//! `["waf", "jwt", "ratelimit", "transform"]`.
+//!
+//! Request phases run in the listed order and response phases in reverse,
+//! so the first filter of a chain sees the request first and the response
+//! last. Every filter whose request phase ran also sees the response, even
+//! when a later filter answered the request itself.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::future::{self, BoxFuture};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::http::Extensions;
+use hyper::{Method, Request, Response, StatusCode};
+
+use crate::body::{collect_body_limited, empty, full, GatewayBody, DEFAULT_MAX_BODY_SIZE};
+use crate::compression::decode_body;
+use crate::config::Route;
+use crate::error::GatewayError;
+use crate::handler::ClientAddr;
+use crate::headers::JwtClaims;
+use crate::path::PathParams;
+use crate::tls::{ClientCertificate, TlsConnection};
+use crate::transform::RhaiTransformer;
+
+/// What to do after a filter's request phase.
+pub enum FilterAction {
+    /// Pass the request on to the next filter, then the upstream
+    Continue,
+    /// Answer the request with this response instead of forwarding it
+    Respond(Response<GatewayBody>),
+}
+
+/// A processing step around a route's upstream requests.
+///
+/// Both phases default to doing nothing, so a filter implements only the
+/// ones it needs.
+pub trait Filter: Send + Sync {
+    /// Inspect or change the request before it is forwarded, or answer it.
+    fn on_request<'a>(
+        &'a self,
+        _req: &'a mut Request<GatewayBody>,
+        _ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+        Box::pin(future::ready(Ok(FilterAction::Continue)))
+    }
+
+    /// Inspect or change the response before it is returned to the client.
+    ///
+    /// Responses the gateway generated itself carry a [`GatewayResponse`]
+    /// extension; filters that rewrite bodies should leave those alone.
+    fn on_response<'a>(
+        &'a self,
+        _response: &'a mut Response<GatewayBody>,
+        _ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), GatewayError>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Request details shared by the filters of one chain, across both phases.
+pub struct FilterContext {
+    /// ID of the matched route
+    pub route_id: String,
+    /// Request method
+    pub method: Method,
+    /// Request path as received, before any rewrite
+    pub path: String,
+    /// IP address of the downstream client
+    pub client_ip: Option<IpAddr>,
+    /// Verified TLS client certificate, if the client presented one
+    pub client_cert: Option<ClientCertificate>,
+    /// Path parameters captured by the route
+    pub params: PathParams,
+    /// Largest request body filters may buffer
+    pub max_request_body: usize,
+    /// Largest response body filters may buffer
+    pub max_response_body: usize,
+    /// When the gateway started processing the request
+    pub started: Instant,
+    /// Values filters hand on to later filters and to their own response
+    /// phase, e.g. [`JwtClaims`] once a caller is authenticated
+    pub extensions: Extensions,
+}
+
+impl FilterContext {
+    /// Build the context for a request matched to `route`, after its path
+    /// parameters were attached.
+    pub fn new(route: &Route, path: &str, req: &Request<GatewayBody>) -> Self {
+        Self {
+            route_id: route.id.clone(),
+            method: req.method().clone(),
+            path: path.to_string(),
+            client_ip: req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip()),
+            client_cert: req.extensions().get::<TlsConnection>().and_then(|tls| tls.client_cert.clone()),
+            params: req.extensions().get::<PathParams>().cloned().unwrap_or_default(),
+            max_request_body: route.max_request_body(),
+            max_response_body: route.max_response_body_bytes.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            started: Instant::now(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// Who is calling, for keying limits and usage: the JWT subject, then
+    /// the client certificate's common name, then the client IP.
+    pub fn client_id(&self) -> String {
+        let subject = self
+            .extensions
+            .get::<JwtClaims>()
+            .and_then(|claims| claims.0.get("sub"))
+            .and_then(|sub| sub.as_str());
+        if let Some(subject) = subject {
+            return subject.to_string();
+        }
+        if let Some(cn) = self.client_cert.as_ref().and_then(|cert| cert.common_name.as_ref()) {
+            return cn.clone();
+        }
+        self.client_ip.map_or_else(|| "anonymous".to_string(), |ip| ip.to_string())
+    }
+}
+
+/// Filters available to routes, by name.
+#[derive(Default)]
+pub struct FilterRegistry {
+    filters: HashMap<String, Arc<dyn Filter>>,
+}
+
+impl FilterRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `filter` available to routes as `name`, replacing any filter
+    /// registered under that name before.
+    pub fn register(&mut self, name: impl Into<String>, filter: impl Filter + 'static) {
+        self.filters.insert(name.into(), Arc::new(filter));
+    }
+
+    /// Whether a filter is registered as `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.filters.contains_key(name)
+    }
+
+    /// Registered filter names, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.filters.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Resolve a route's filter names into a chain for one request.
+    ///
+    /// A name nobody registered fails the request rather than skipping the
+    /// filter, so a route asking for authentication is never served without.
+    pub fn chain(&self, names: &[String], ctx: FilterContext) -> Result<FilterChain, GatewayError> {
+        let filters = names
+            .iter()
+            .map(|name| match self.filters.get(name) {
+                Some(filter) => Ok((name.clone(), filter.clone())),
+                None => Err(GatewayError::ConfigError(format!(
+                    "Route '{}' uses unknown filter '{}'",
+                    ctx.route_id, name
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(FilterChain { filters, ran: 0, ctx })
+    }
+}
+
+/// A route's filters resolved for one request.
+pub struct FilterChain {
+    filters: Vec<(String, Arc<dyn Filter>)>,
+    /// How many request phases ran, so only those filters see the response
+    ran: usize,
+    ctx: FilterContext,
+}
+
+impl FilterChain {
+    /// Run the request phases in order. Returns the response of a filter
+    /// that answered the request itself, if one did.
+    pub async fn on_request(
+        &mut self,
+        req: &mut Request<GatewayBody>,
+    ) -> Result<Option<Response<GatewayBody>>, GatewayError> {
+        while let Some((name, filter)) = self.filters.get(self.ran) {
+            self.ran += 1;
+            if let FilterAction::Respond(response) = filter.on_request(req, &mut self.ctx).await? {
+                tracing::debug!(
+                    route_id = %self.ctx.route_id,
+                    filter = %name,
+                    status = response.status().as_u16(),
+                    "Filter answered request"
+                );
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Run the response phases of the filters that saw the request, in
+    /// reverse order.
+    pub async fn on_response(&mut self, response: &mut Response<GatewayBody>) -> Result<(), GatewayError> {
+        for (_, filter) in self.filters[..self.ran].iter().rev() {
+            filter.on_response(response, &mut self.ctx).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Buffer the request body so a filter can read it, leaving a copy in
+/// place for the upstream. A compressed body is decoded first.
+pub async fn read_request_body(req: &mut Request<GatewayBody>, ctx: &FilterContext) -> Result<Bytes, GatewayError> {
+    let body = std::mem::replace(req.body_mut(), empty());
+    let bytes = collect_body_limited(body, ctx.max_request_body).await?;
+    let bytes = decode_body(req.headers_mut(), bytes, ctx.max_request_body).await?;
+    *req.body_mut() = full(bytes.clone());
+    Ok(bytes)
+}
+
+/// Buffer the response body so a filter can read it, leaving a copy in
+/// place for the client. A compressed body is decoded first.
+pub async fn read_response_body(
+    response: &mut Response<GatewayBody>,
+    ctx: &FilterContext,
+) -> Result<Bytes, GatewayError> {
+    let body = std::mem::replace(response.body_mut(), empty());
+    let bytes = collect_body_limited(body, ctx.max_response_body)
+        .await
+        .map_err(GatewayError::for_response_body)?;
+    let bytes = decode_body(response.headers_mut(), bytes, ctx.max_response_body)
+        .await
+        .map_err(GatewayError::for_response_body)?;
+    *response.body_mut() = full(bytes.clone());
+    Ok(bytes)
+}
+
+/// Body for a rewritten payload, with the framing headers set to match.
+pub fn buffered_body(headers: &mut HeaderMap, bytes: impl Into<Bytes>) -> GatewayBody {
+    let bytes = bytes.into();
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    full(bytes)
+}
+
+/// Marks a response the gateway generated itself, such as an error or a
+/// filter turning a request away, rather than one from the upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct GatewayResponse;
+
+/// A JSON error response in the gateway's usual shape, for filters that
+/// turn requests away.
+pub fn reject(status: StatusCode, category: &'static str, message: &str) -> Response<GatewayBody> {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "category": category,
+            "retryable": false
+        }
+    });
+
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("X-Gateway-Error-Category", category)
+        .extension(GatewayResponse)
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
+        .unwrap()
+}
+
+/// Runs a Rhai script over request and/or response bodies: the body is the
+/// script's `input` and its `output`, or its changed `payload`, replaces the
+/// body. A run that changes neither leaves the body as it was. Responses
+/// the gateway generated itself are never transformed.
+pub struct TransformFilter {
+    request: Option<RhaiTransformer>,
+    response: Option<RhaiTransformer>,
+}
+
+impl TransformFilter {
+    /// Transform request bodies with `request` and response bodies with
+    /// `response`; either may be absent.
+    pub fn new(request: Option<RhaiTransformer>, response: Option<RhaiTransformer>) -> Self {
+        Self { request, response }
+    }
+
+    /// The new body, or `None` when the script left the message alone
+    fn run(transformer: &RhaiTransformer, input: &[u8], ctx: &FilterContext) -> Result<Option<String>, GatewayError> {
+        let input = String::from_utf8_lossy(input);
+        transformer
+            .execute_with_context(&input, &ctx.params, ctx.client_cert.as_ref())
+            .map(|result| Some(result.output).filter(|output| !output.is_empty()))
+            .map_err(|e| GatewayError::TransformFailed(e.to_string()))
+    }
+}
+
+impl Filter for TransformFilter {
+    fn on_request<'a>(
+        &'a self,
+        req: &'a mut Request<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+        Box::pin(async move {
+            if let Some(transformer) = &self.request {
+                let input = read_request_body(req, ctx).await?;
+                if let Some(output) = Self::run(transformer, &input, ctx)? {
+                    *req.body_mut() = buffered_body(req.headers_mut(), output);
+                }
+            }
+            Ok(FilterAction::Continue)
+        })
+    }
+
+    fn on_response<'a>(
+        &'a self,
+        response: &'a mut Response<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), GatewayError>> {
+        Box::pin(async move {
+            let generated = response.extensions().get::<GatewayResponse>().is_some();
+            if let (Some(transformer), false) = (&self.response, generated) {
+                let input = read_response_body(response, ctx).await?;
+                if let Some(output) = Self::run(transformer, &input, ctx)? {
+                    *response.body_mut() = buffered_body(response.headers_mut(), output);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use http_body_util::BodyExt;
+    use hyper::header::CONTENT_ENCODING;
+
+    /// Records its phases into a shared log, answering requests with 403
+    /// when `block` is set.
+    struct Recorder {
+        name: &'static str,
+        block: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Filter for Recorder {
+        fn on_request<'a>(
+            &'a self,
+            _req: &'a mut Request<GatewayBody>,
+            _ctx: &'a mut FilterContext,
+        ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+            self.log.lock().unwrap().push(format!("{} request", self.name));
+            let action = match self.block {
+                true => FilterAction::Respond(reject(StatusCode::FORBIDDEN, "blocked", "blocked")),
+                false => FilterAction::Continue,
+            };
+            Box::pin(future::ready(Ok(action)))
+        }
+
+        fn on_response<'a>(
+            &'a self,
+            _response: &'a mut Response<GatewayBody>,
+            _ctx: &'a mut FilterContext,
+        ) -> BoxFuture<'a, Result<(), GatewayError>> {
+            self.log.lock().unwrap().push(format!("{} response", self.name));
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    fn registry(log: &Arc<Mutex<Vec<String>>>) -> FilterRegistry {
+        let mut registry = FilterRegistry::new();
+        for (name, block) in [("first", false), ("second", false), ("deny", true)] {
+            let log = log.clone();
+            registry.register(name, Recorder { name, block, log });
+        }
+        registry
+    }
+
+    fn request(body: &str) -> Request<GatewayBody> {
+        Request::post("/orders").body(full(body.to_string())).unwrap()
+    }
+
+    fn context(req: &Request<GatewayBody>) -> FilterContext {
+        FilterContext::new(&Route::new("orders", "/orders", "http://orders"), "/orders", req)
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_chain_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let registry = registry(&log);
+        assert_eq!(registry.names(), ["deny", "first", "second"]);
+
+        let mut req = request("");
+        let mut chain = registry.chain(&names(&["first", "second"]), context(&req)).unwrap();
+        assert!(chain.on_request(&mut req).await.unwrap().is_none());
+        chain.on_response(&mut Response::new(empty())).await.unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["first request", "second request", "second response", "first response"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chain_short_circuit() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let registry = registry(&log);
+
+        let mut req = request("");
+        let mut chain = registry.chain(&names(&["first", "deny", "second"]), context(&req)).unwrap();
+        let mut response = chain.on_request(&mut req).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        chain.on_response(&mut response).await.unwrap();
+
+        // `second` never saw the request, so it does not see the response
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["first request", "deny request", "deny response", "first response"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_filter() {
+        let registry = registry(&Arc::new(Mutex::new(Vec::new())));
+        let req = request("");
+        let result = registry.chain(&names(&["first", "missing"]), context(&req));
+        assert!(matches!(result, Err(GatewayError::ConfigError(msg)) if msg.contains("'missing'")));
+    }
+
+    #[test]
+    fn test_client_id() {
+        let req = request("");
+        let mut ctx = context(&req);
+        assert_eq!(ctx.client_id(), "anonymous");
+
+        ctx.client_ip = Some("10.0.0.7".parse().unwrap());
+        assert_eq!(ctx.client_id(), "10.0.0.7");
+
+        ctx.client_cert = Some(ClientCertificate {
+            common_name: Some("orders-service".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ctx.client_id(), "orders-service");
+
+        let claims = serde_json::json!({"sub": "alice"}).as_object().unwrap().clone();
+        ctx.extensions.insert(JwtClaims(claims));
+        assert_eq!(ctx.client_id(), "alice");
+    }
+
+    #[tokio::test]
+    async fn test_transform_filter() {
+        let filter = TransformFilter::new(
+            Some(RhaiTransformer::new("output = upper(input);").unwrap()),
+            Some(RhaiTransformer::new(r#"output = wrap_xml("order", input);"#).unwrap()),
+        );
+
+        let mut req = request("widget");
+        let mut ctx = context(&req);
+        assert!(matches!(filter.on_request(&mut req, &mut ctx).await.unwrap(), FilterAction::Continue));
+        assert_eq!(req.headers()[CONTENT_LENGTH], "6");
+        assert_eq!(req.into_body().collect().await.unwrap().to_bytes(), "WIDGET");
+
+        let mut response = Response::new(full("42"));
+        filter.on_response(&mut response, &mut ctx).await.unwrap();
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "<order>42</order>");
+
+        // The gateway's own error responses keep their JSON body
+        let mut response = reject(StatusCode::UNAUTHORIZED, "auth", "missing token");
+        filter.on_response(&mut response, &mut ctx).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(br#"{"error":"#), "{:?}", body);
+
+        // A script that leaves the message alone keeps the body as it was
+        let conditional = TransformFilter::new(
+            Some(RhaiTransformer::new(r#"if input == "x" { output = "y"; }"#).unwrap()),
+            Some(RhaiTransformer::new(r#"log("seen");"#).unwrap()),
+        );
+        let mut req = request("widget");
+        conditional.on_request(&mut req, &mut ctx).await.unwrap();
+        assert_eq!(req.into_body().collect().await.unwrap().to_bytes(), "widget");
+        let mut response = Response::new(full(r#"{"n":1}"#));
+        conditional.on_response(&mut response, &mut ctx).await.unwrap();
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), r#"{"n":1}"#);
+
+        // Changing only the payload sends the payload as JSON
+        let payload = TransformFilter::new(None, Some(RhaiTransformer::new("payload.n += 1;").unwrap()));
+        let mut response = Response::new(full(r#"{"n":1}"#));
+        payload.on_response(&mut response, &mut ctx).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_LENGTH], "7");
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), r#"{"n":2}"#);
+
+        // Responses over the route's limit are not buffered
+        ctx.max_response_body = 4;
+        let mut response = Response::new(full("too long"));
+        let result = filter.on_response(&mut response, &mut ctx).await;
+        assert!(matches!(result, Err(GatewayError::ResponseTooLarge { .. })));
+
+        // A body the upstream encoded badly is the upstream's fault
+        ctx.max_response_body = DEFAULT_MAX_BODY_SIZE;
+        for encoding in ["gzip", "compress"] {
+            let mut response = Response::new(full("not compressed"));
+            response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            let error = filter.on_response(&mut response, &mut ctx).await.unwrap_err();
+            assert!(matches!(error, GatewayError::InvalidResponseBody(_)), "{}: {:?}", encoding, error);
+            assert_eq!(error.status_code(), 502);
+        }
+
+        let failing = TransformFilter::new(Some(RhaiTransformer::new("throw \"bad input\";").unwrap()), None);
+        let mut req = request("");
+        let result = failing.on_request(&mut req, &mut ctx).await;
+        assert!(matches!(result, Err(GatewayError::TransformFailed(_))));
+    }
+}
@@ -4,44 +4,85 @@
 //! "Clone-and-Move" pattern required for async service handlers.
 
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use arc_swap::ArcSwap;
 use bytes::Bytes;
-use http_body_util::Full;
-use hyper::body::Incoming;
-use hyper::{Method, Request, Response, StatusCode};
+use futures::future::{self, Either};
+use hyper::body::Body;
+use hyper::http::request::Parts;
+use hyper::{Method, Request, Response, StatusCode, Uri};
 
-use crate::config::RouterMap;
+use crate::balancer::hash_key;
+use crate::body::{
+    boxed, check_content_length, check_content_type, collect_body_limited, content_length, full, hold,
+    stream_limited, BoxError, GatewayBody, LimitStatus,
+};
+use crate::cache::Fetch;
+use crate::compression::{compress_response, decode_body, negotiate};
+use crate::config::{CacheConfig, RetryPolicy, Route};
 use crate::error::GatewayError;
-use crate::router::match_route;
+use crate::filter::{FilterContext, GatewayResponse};
+use crate::headers::{add_forwarding_headers, apply_policy, strip_hop_by_hop, trace_id, HeaderContext, JwtClaims};
+use crate::health::is_upstream_failure;
+use crate::retry::{backoff, should_retry};
+use crate::router::{find_route_for, RequestInfo};
+use crate::state::GatewayState;
+use crate::tls::TlsConnection;
+use crate::scripting::script_metrics;
+use crate::websocket::{self, UpgradeKind};
+
+/// Address of the downstream client, attached to requests as an extension
+/// by the server's accept loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
 
 /// Handle an incoming HTTP request.
 ///
 /// This is the main entry point for request processing. It performs:
 /// 1. Route matching against the current configuration
 /// 2. Method validation
-/// 3. Request forwarding preparation (Phase 2 will add actual forwarding)
+/// 3. Path rewriting and request header policies
+/// 4. Upstream target selection according to the route's balancing policy
+/// 5. Circuit breaker admission for the selected target
+/// 6. Forwarding to the selected upstream and streaming the response back,
+///    retrying or hedging according to the route's retry policy, or relaying
+///    WebSocket messages for upgrades on WebSocket routes. GET and HEAD
+///    requests on caching routes go through the response cache first.
+/// 7. Response compression negotiated from `Accept-Encoding`
+/// 8. Response header policies
+///
+/// Routes with [`Route::filters`] run their filter chain's request phases
+/// between path rewriting and request header policies, and its response
+/// phases before step 7. A filter may answer the request itself, skipping
+/// steps 4 to 6.
+///
+/// Routes with a [`Route::script`] run its request script after the
+/// filters, where it may change the request, pick the upstream or answer
+/// the request itself, and its response script on the upstream's response
+/// before the filters see it.
 ///
 /// # Arguments
 ///
 /// * `req` - The incoming HTTP request
-/// * `config` - ArcSwap-wrapped routing configuration for wait-free access
+/// * `state` - Shared gateway state holding the ArcSwap-wrapped routing
+///   configuration and the pooled upstream client
 ///
 /// # Returns
 ///
 /// Always returns `Ok(Response)` - errors are converted to HTTP error responses.
-pub async fn handle_request(
-    req: Request<Incoming>,
-    config: Arc<ArcSwap<RouterMap>>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    // Load current configuration (wait-free read)
-    let router_map = config.load();
-
+pub async fn handle_request<B>(
+    mut req: Request<B>,
+    state: Arc<GatewayState>,
+) -> Result<Response<GatewayBody>, Infallible>
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
     // Extract request details
     let method = req.method().clone();
     let path = req.uri().path().to_string();
-    let _headers = req.headers().clone();
 
     tracing::debug!(
         method = %method,
@@ -49,59 +90,424 @@ pub async fn handle_request(
         "Processing request"
     );
 
-    // Match route
-    let result = match match_route(&path, &router_map) {
-        Some(route) => {
-            // Check method
-            if !route.allows_method(method.as_str()) {
-                Err(GatewayError::MethodNotAllowed {
-                    method: method.to_string(),
-                    path: path.clone(),
-                })
+    // Match route against the current configuration (wait-free read).
+    // The route is cloned so the guard is not held across upstream I/O.
+    let matched = find_route_for(&RequestInfo::from_request(&req), &state.config.load())
+        .map(|m| (m.route.clone(), m.params, m.rewriter.cloned(), m.hooks.cloned()));
+
+    let response = match matched {
+        // Before filters and scripts, so a disallowed method uses up no
+        // quota and can't be answered by a script
+        Some((route, ..)) if !route.allows_method(method.as_str()) => {
+            build_error_response(GatewayError::MethodNotAllowed {
+                method: method.to_string(),
+                path: path.clone(),
+            })
+        }
+        Some((mut route, params, rewriter, hooks)) => {
+            if let Some(rewriter) = rewriter {
+                let rewritten = rewriter.rewrite(&path, &params);
+                tracing::debug!(path = %path, rewritten = %rewritten, "Rewrote request path");
+                *req.uri_mut() = rewrite_path(req.uri(), &rewritten);
+            }
+
+            // Captured path parameters travel with the request
+            req.extensions_mut().insert(params);
+
+            // Request filters run first: they may authenticate the caller
+            // (claims feed the header templates below) or answer directly
+            let mut req = req.map(boxed);
+            let mut filters = None;
+            let mut answered = None;
+            if !route.filters.is_empty() {
+                let ctx = FilterContext::new(&route, &path, &req);
+                match state.filters.chain(&route.filters, ctx) {
+                    Ok(mut chain) => {
+                        answered = chain.on_request(&mut req).await.unwrap_or_else(|e| Some(build_error_response(e)));
+                        filters = Some(chain);
+                    }
+                    Err(e) => answered = Some(build_error_response(e)),
+                }
+            }
+
+            let mut script = hooks.map(|hooks| hooks.start(&route, &req));
+            if let (None, Some(run)) = (&answered, &mut script) {
+                answered = run.on_request(&mut req).await.unwrap_or_else(|e| Some(build_error_response(e)));
+                if let Some(upstream) = run.upstream() {
+                    // The script's choice replaces the route's targets
+                    route.upstream = upstream.to_string();
+                    route.upstreams.clear();
+                }
+            }
+
+            let client_ip = req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip());
+            let claims = req.extensions().get::<JwtClaims>().cloned();
+            let trace_id = trace_id(req.headers());
+            let tls = req.extensions().get::<TlsConnection>().cloned();
+            let proto = match &tls {
+                Some(_) => "https".to_string(),
+                None => req.uri().scheme_str().unwrap_or("http").to_string(),
+            };
+            let ctx = HeaderContext {
+                client_ip,
+                route_id: &route.id,
+                trace_id: &trace_id,
+                claims: claims.as_ref(),
+                client_cert: tls.as_ref().and_then(|tls| tls.client_cert.as_ref()),
+            };
+
+            // Detected before `Upgrade` is stripped as a hop-by-hop header
+            let upgrade = route.websocket.as_ref().and_then(|_| websocket::upgrade_kind(&req));
+            let encoding = route.compression.as_ref().and_then(|c| negotiate(req.headers(), &c.encodings));
+
+            strip_hop_by_hop(req.headers_mut());
+            add_forwarding_headers(req.headers_mut(), client_ip, &proto);
+            apply_policy(req.headers_mut(), &route.request_headers, &ctx);
+
+            let forwarded = answered.is_none();
+            let mut result = if let Some(response) = answered {
+                Ok(response)
+            } else if let Some(kind) = upgrade {
+                proxy_websocket(req, kind, &route, &state).await
+            } else if let Some(cache) = route.cache.as_ref().filter(|_| is_cacheable(&method, &route)) {
+                serve_cached(req, cache, &route, &state)
+                    .await
+                    .and_then(|response| limit_response(response, &route))
             } else {
-                // Route matched - in Phase 1, we return a stub response
-                // Phase 2 will implement actual upstream forwarding
-                Ok(build_stub_response(&route.upstream, &path, &method))
+                forward_request(req, &route, &state)
+                    .await
+                    .and_then(|response| limit_response(response, &route))
+            };
+
+            if let (true, Some(run), Ok(response)) = (forwarded, &mut script, &mut result) {
+                if let Err(e) = run.on_response(response).await {
+                    result = Err(e);
+                }
             }
+
+            let mut response = result.unwrap_or_else(build_error_response);
+            if let Some(chain) = &mut filters {
+                if let Err(e) = chain.on_response(&mut response).await {
+                    response = build_error_response(e);
+                }
+            }
+            // Compressors hold data back, which would stall streamed bodies
+            if let (Some(config), None) = (&route.compression, &route.streaming) {
+                response = compress_response(response, config, encoding, &method);
+            }
+            if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+                strip_hop_by_hop(response.headers_mut());
+            }
+            apply_policy(response.headers_mut(), &route.response_headers, &ctx);
+            response
         }
-        None => Err(GatewayError::RouteNotFound { path: path.clone() }),
+        None => build_error_response(GatewayError::RouteNotFound { path: path.clone() }),
     };
 
-    // Convert result to HTTP response
-    let response = match result {
-        Ok(resp) => resp,
-        Err(e) => build_error_response(e),
+    Ok(response)
+}
+
+/// Enforce the route's response body limit.
+///
+/// A declared `Content-Length` over the limit fails the request outright;
+/// otherwise the body is streamed and cut off once it goes over.
+fn limit_response(response: Response<GatewayBody>, route: &Route) -> Result<Response<GatewayBody>, GatewayError> {
+    let Some(limit) = route.max_response_body_bytes else {
+        return Ok(response);
     };
 
-    Ok(response)
+    if let Some(size) = content_length(response.headers()).filter(|&size| size > limit as u64) {
+        return Err(GatewayError::ResponseTooLarge {
+            size,
+            limit: limit as u64,
+        });
+    }
+    Ok(response.map(|body| stream_limited(body, limit).0))
 }
 
-/// Build a stub response indicating successful routing.
+/// Replace the path of a request URI, keeping its query string.
+fn rewrite_path(uri: &Uri, path: &str) -> Uri {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    match path_and_query.parse() {
+        Ok(pq) => parts.path_and_query = Some(pq),
+        Err(e) => {
+            tracing::warn!(path = %path, error = %e, "Rewritten path is not a valid URI path, keeping original");
+            return uri.clone();
+        }
+    }
+    Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+/// Whether a request may be served through the route's cache. Streaming
+/// routes never are.
+fn is_cacheable(method: &Method, route: &Route) -> bool {
+    route.streaming.is_none() && (*method == Method::GET || *method == Method::HEAD)
+}
+
+/// Serve a GET or HEAD request through the response cache, forwarding
+/// misses and revalidations as usual.
+async fn serve_cached<B>(
+    req: Request<B>,
+    config: &CacheConfig,
+    route: &Route,
+    state: &Arc<GatewayState>,
+) -> Result<Response<GatewayBody>, GatewayError> {
+    // GET and HEAD bodies carry no meaning and are not forwarded
+    let (parts, _) = req.into_parts();
+    let client = parts.extensions.get::<ClientAddr>().copied();
+
+    let fetch: Fetch = {
+        let route = route.clone();
+        let state = state.clone();
+        Arc::new(move |mut req: Request<GatewayBody>| {
+            if let Some(client) = client {
+                req.extensions_mut().insert(client);
+            }
+            let route = route.clone();
+            let state = state.clone();
+            Box::pin(async move { forward_request(req, &route, &state).await })
+        })
+    };
+
+    state.cache.serve(&parts, route, config, fetch).await
+}
+
+/// Forward the request, retrying per the route's retry policy.
 ///
-/// In Phase 1, we don't actually forward to upstream services.
-/// This response confirms the route was matched successfully.
-fn build_stub_response(upstream: &str, path: &str, method: &Method) -> Response<Full<Bytes>> {
-    let body = serde_json::json!({
-        "status": "routed",
-        "upstream": upstream,
-        "path": path,
-        "method": method.as_str(),
-        "message": "Phase 1: Route matched. Upstream forwarding will be implemented in Phase 2."
+/// The body is streamed to the upstream unless the route needs it buffered
+/// (see [`Route::needs_buffered_body`]); the size limit applies either way.
+/// Bodies buffered for inspection are also decoded, and the limit then
+/// applies to the decoded size too.
+async fn forward_request<B>(
+    req: Request<B>,
+    route: &Route,
+    state: &GatewayState,
+) -> Result<Response<GatewayBody>, GatewayError>
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let client_ip = req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip());
+    let key = hash_key(&route.load_balancing, req.headers(), client_ip);
+
+    let (mut parts, body) = req.into_parts();
+    let max_body = route.max_request_body();
+    check_content_length(&parts.headers, max_body)?;
+    check_content_type(&parts.headers, &route.allowed_content_types, !body.is_end_stream())?;
+
+    if !route.needs_buffered_body() {
+        let (body, status) = stream_limited(body, max_body);
+        parts.extensions.insert(status);
+        let req = Request::from_parts(parts, body);
+        return send_upstream(req, route, state, key.as_deref()).await;
+    }
+
+    let mut body = collect_body_limited(body, max_body).await?;
+    if route.buffer_body {
+        body = decode_body(&mut parts.headers, body, max_body).await?;
+    }
+
+    let Some(policy) = &route.retry else {
+        let req = Request::from_parts(parts, full(body));
+        return send_upstream(req, route, state, key.as_deref()).await;
+    };
+
+    state.retry_budget.deposit();
+
+    let mut attempt = 1;
+    loop {
+        let result = send_hedged(&parts, &body, route, state, key.as_deref(), policy).await;
+
+        let (status, error) = outcome(&result);
+        if attempt >= policy.max_attempts || !should_retry(policy, &parts.method, status, error) {
+            return result;
+        }
+        if !state.retry_budget.try_withdraw() {
+            tracing::debug!(route_id = %route.id, "Retry budget exhausted");
+            return result;
+        }
+
+        let delay = backoff(policy, attempt);
+        tracing::debug!(
+            route_id = %route.id,
+            attempt = attempt,
+            status = ?status,
+            error = ?error.map(|e| e.to_string()),
+            delay_ms = delay.as_millis() as u64,
+            "Retrying upstream request"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Proxy a WebSocket upgrade.
+///
+/// The upstream handshake goes through the usual target selection; if the
+/// upstream does not switch protocols its response is returned as is.
+/// Otherwise the client's handshake is answered and messages are relayed on
+/// a background task, counted as an open connection on the route.
+async fn proxy_websocket<B>(
+    mut req: Request<B>,
+    kind: UpgradeKind,
+    route: &Route,
+    state: &GatewayState,
+) -> Result<Response<GatewayBody>, GatewayError> {
+    let config = route.websocket.clone().unwrap_or_default();
+    let (upstream_req, accept) = websocket::upstream_handshake(&req, kind)?;
+
+    let client_ip = req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip());
+    let key = hash_key(&route.load_balancing, req.headers(), client_ip);
+    let client_upgrade = hyper::upgrade::on(&mut req);
+
+    let handshake = upstream_req.headers().clone();
+    let mut upstream_resp = send_upstream(upstream_req, route, state, key.as_deref()).await?;
+    if upstream_resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(upstream_resp);
+    }
+    websocket::verify_upstream_accept(&handshake, &upstream_resp)?;
+    let upstream_upgrade = hyper::upgrade::on(&mut upstream_resp);
+    let response = websocket::client_response(kind, accept, &upstream_resp);
+
+    let guard = state.websockets.open(&route.id);
+    let route_id = route.id.clone();
+    tokio::spawn(async move {
+        let _guard = guard;
+        match tokio::try_join!(client_upgrade, upstream_upgrade) {
+            Ok((client, upstream)) => websocket::relay(client, upstream, &config, &route_id).await,
+            Err(e) => tracing::debug!(route_id = %route_id, error = %e, "WebSocket upgrade failed"),
+        }
     });
 
-    let body_bytes = Bytes::from(serde_json::to_vec(&body).unwrap_or_default());
+    Ok(response)
+}
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .header("X-Gateway-Phase", "1")
-        .header("X-Gateway-Upstream", upstream)
-        .body(Full::new(body_bytes))
-        .unwrap()
+/// Send one attempt, hedging it with a second request when the policy
+/// allows it and the first has not answered within the hedge delay.
+///
+/// Hedging only applies to GET and HEAD, and each hedge spends retry budget.
+/// Streaming routes are never hedged, since their upstreams answer slowly
+/// by design.
+async fn send_hedged(
+    parts: &Parts,
+    body: &Bytes,
+    route: &Route,
+    state: &GatewayState,
+    key: Option<&str>,
+    policy: &RetryPolicy,
+) -> Result<Response<GatewayBody>, GatewayError> {
+    let first = send_upstream(rebuild_request(parts, body), route, state, key);
+
+    let hedgeable =
+        route.streaming.is_none() && (parts.method == Method::GET || parts.method == Method::HEAD);
+    let hedge_delay = match policy.hedge_delay_ms {
+        Some(ms) if hedgeable => ms,
+        _ => return first.await,
+    };
+
+    tokio::pin!(first);
+    tokio::select! {
+        result = &mut first => return result,
+        _ = tokio::time::sleep(Duration::from_millis(hedge_delay)) => {}
+    }
+
+    if !state.retry_budget.try_withdraw() {
+        return first.await;
+    }
+    tracing::debug!(route_id = %route.id, hedge_delay_ms = hedge_delay, "Sending hedged request");
+
+    let second = send_upstream(rebuild_request(parts, body), route, state, key);
+    tokio::pin!(second);
+
+    // First usable answer wins; the other request is cancelled on drop
+    match future::select(first, second).await {
+        Either::Left((result, other)) | Either::Right((result, other)) => {
+            let (status, error) = outcome(&result);
+            if is_upstream_failure(status, error) {
+                other.await
+            } else {
+                result
+            }
+        }
+    }
+}
+
+/// Rebuild a request from buffered parts so it can be sent more than once.
+fn rebuild_request(parts: &Parts, body: &Bytes) -> Request<GatewayBody> {
+    let mut req = Request::new(full(body.clone()));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+/// Split a proxy result into upstream status and error.
+fn outcome(result: &Result<Response<GatewayBody>, GatewayError>) -> (Option<u16>, Option<&GatewayError>) {
+    match result {
+        Ok(resp) => (Some(resp.status().as_u16()), None),
+        Err(e) => (None, Some(e)),
+    }
+}
+
+/// Pick an upstream target and send a single request to it.
+///
+/// The outcome is reported to the health registry and, when configured,
+/// the target's circuit breaker.
+async fn send_upstream(
+    req: Request<GatewayBody>,
+    route: &Route,
+    state: &GatewayState,
+    key: Option<&str>,
+) -> Result<Response<GatewayBody>, GatewayError> {
+    // Held until the response body is done so it counts as in flight
+    let selected = state
+        .balancer
+        .select(route, key, |url| {
+            state.health.is_available(url)
+                && !(route.circuit_breaker.is_some() && state.breakers.is_open(&route.id, url))
+        })
+        .ok_or_else(|| {
+            GatewayError::ConfigError(format!("Route '{}' has no upstream targets", route.id))
+        })?;
+
+    let permit = match &route.circuit_breaker {
+        Some(config) => Some(state.breakers.acquire(&route.id, &selected.url, config)?),
+        None => None,
+    };
+
+    let limit = req.extensions().get::<LimitStatus>().cloned();
+    let result = state.client.forward(route, &selected.url, req).await;
+
+    // A streamed body cut off at its limit is the client's fault; don't
+    // count it against the upstream.
+    if let Some(limit) = limit.filter(LimitStatus::exceeded) {
+        return Err(limit.to_error());
+    }
+
+    // Passive health: report the outcome against the selected target
+    let (status, error) = outcome(&result);
+    let failed = is_upstream_failure(status, error);
+    let reason = failed.then(|| match error {
+        Some(e) => e.to_string(),
+        None => format!("upstream returned {}", status.unwrap_or_default()),
+    });
+    state
+        .health
+        .record_request(&selected.url, !failed, reason, &route.outlier_detection);
+    if let Some(permit) = permit {
+        permit.record(!failed);
+    }
+
+    result.map(|resp| resp.map(|body| hold(body, selected)))
 }
 
 /// Build an error response from a GatewayError.
-fn build_error_response(error: GatewayError) -> Response<Full<Bytes>> {
+fn build_error_response(error: GatewayError) -> Response<GatewayBody> {
     let status_code = error.status_code();
     let category = error.category();
 
@@ -120,38 +526,37 @@ fn build_error_response(error: GatewayError) -> Response<Full<Bytes>> {
         }
     });
 
-    let body_bytes = Bytes::from(serde_json::to_vec(&body).unwrap_or_default());
-
     Response::builder()
         .status(StatusCode::from_u16(status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
         .header("Content-Type", "application/json")
         .header("X-Gateway-Error-Category", category)
-        .body(Full::new(body_bytes))
+        .extension(GatewayResponse)
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
         .unwrap()
 }
 
 /// Health check handler for the gateway.
-pub fn health_check() -> Response<Full<Bytes>> {
+pub fn health_check() -> Response<GatewayBody> {
     let body = serde_json::json!({
         "status": "healthy",
         "version": env!("CARGO_PKG_VERSION"),
     });
 
-    let body_bytes = Bytes::from(serde_json::to_vec(&body).unwrap_or_default());
-
     Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
-        .body(Full::new(body_bytes))
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
         .unwrap()
 }
 
-/// Readiness check - confirms the gateway has loaded configuration.
-pub fn readiness_check(config: &Arc<ArcSwap<RouterMap>>) -> Response<Full<Bytes>> {
-    let router_map = config.load();
+/// Readiness check - confirms the gateway has loaded configuration and is
+/// not draining for shutdown.
+pub fn readiness_check(state: &GatewayState) -> Response<GatewayBody> {
+    let router_map = state.config.load();
     let route_count = router_map.len();
+    let draining = state.is_draining();
 
-    let (status, ready) = if route_count > 0 {
+    let (status, ready) = if route_count > 0 && !draining {
         (StatusCode::OK, true)
     } else {
         (StatusCode::SERVICE_UNAVAILABLE, false)
@@ -160,37 +565,107 @@ pub fn readiness_check(config: &Arc<ArcSwap<RouterMap>>) -> Response<Full<Bytes>
     let body = serde_json::json!({
         "ready": ready,
         "routes_loaded": route_count,
+        "draining": draining,
     });
 
-    let body_bytes = Bytes::from(serde_json::to_vec(&body).unwrap_or_default());
-
     Response::builder()
         .status(status)
         .header("Content-Type", "application/json")
-        .body(Full::new(body_bytes))
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
+        .unwrap()
+}
+
+/// Route statistics endpoint - routing table summary and open WebSocket
+/// connections.
+pub fn routes_status(state: &GatewayState) -> Response<GatewayBody> {
+    let body = serde_json::to_vec(&state.router_stats()).unwrap_or_default();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(full(body))
+        .unwrap()
+}
+
+/// Script metrics endpoint - runs of each script stopped by a time,
+/// operation, size or depth limit.
+pub fn scripts_status() -> Response<GatewayBody> {
+    let body = serde_json::json!({
+        "limit_hits": script_metrics().snapshot(),
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
+        .unwrap()
+}
+
+/// Cache purge endpoint - removes cached responses, optionally only those
+/// of the `route` query parameter's route and with keys starting with the
+/// `prefix` parameter (e.g. `/_gateway/cache/purge?route=users&prefix=/api/users/`).
+pub async fn cache_purge(state: &GatewayState, uri: &Uri) -> Response<GatewayBody> {
+    let param = |name: &str| {
+        form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let route_id = param("route");
+    let prefix = param("prefix").unwrap_or_default();
+
+    let purged = state.cache.purge(route_id.as_deref(), &prefix).await;
+    let body = serde_json::json!({
+        "purged": purged,
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
+        .unwrap()
+}
+
+/// Upstream status endpoint - health, ejection and load of every target.
+pub fn upstreams_status(state: &GatewayState) -> Response<GatewayBody> {
+    let mut upstreams = state.health.snapshot(&state.config.load());
+    for upstream in &mut upstreams {
+        upstream.in_flight = state.balancer.in_flight(&upstream.url);
+    }
+
+    let body = serde_json::json!({
+        "upstreams": upstreams,
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(full(serde_json::to_vec(&body).unwrap_or_default()))
         .unwrap()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::Route;
-    use std::collections::HashMap;
+    use crate::body::DEFAULT_MAX_BODY_SIZE;
+    use crate::config::{CircuitBreaker, LoadBalancing, RouterMap, UpstreamTarget};
+    use arc_swap::ArcSwap;
+    use crate::retry::RetryBudget;
+    use crate::router::build_router_map;
+    use http_body_util::{BodyExt, Full};
+    use futures::{SinkExt, StreamExt};
+    use hyper::body::Incoming;
+    use hyper::body::Frame;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
     fn create_test_config() -> Arc<ArcSwap<RouterMap>> {
-        let mut map = HashMap::new();
-        map.insert(
-            "/api/users".to_string(),
+        let map = build_router_map(vec![
             Route::new("1", "/api/users", "http://user-service:8080"),
-        );
-        map.insert(
-            "/api/posts".to_string(),
             {
                 let mut r = Route::new("2", "/api/posts", "http://post-service:8080");
                 r.methods = vec!["GET".to_string(), "POST".to_string()];
                 r
             },
-        );
+        ]);
         Arc::new(ArcSwap::from_pointee(map))
     }
 
@@ -212,15 +687,1060 @@ mod tests {
 
     #[test]
     fn test_readiness_with_routes() {
-        let config = create_test_config();
-        let response = readiness_check(&config);
+        let state = GatewayState::new(create_test_config());
+        let response = readiness_check(&state);
         assert_eq!(response.status(), StatusCode::OK);
     }
 
     #[test]
     fn test_readiness_without_routes() {
-        let config = Arc::new(ArcSwap::from_pointee(HashMap::new()));
-        let response = readiness_check(&config);
+        let state = GatewayState::new(Arc::new(ArcSwap::from_pointee(RouterMap::new())));
+        let response = readiness_check(&state);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_readiness_while_draining() {
+        let state = GatewayState::new(create_test_config());
+        state.start_draining();
+        assert!(state.is_draining());
+
+        let response = readiness_check(&state);
         assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
     }
+
+    fn create_test_state(routes: Vec<Route>) -> Arc<GatewayState> {
+        let config = Arc::new(ArcSwap::from_pointee(build_router_map(routes)));
+        Arc::new(GatewayState::new(config))
+    }
+
+    /// Start a throwaway upstream that echoes the request back as JSON.
+    async fn spawn_echo_upstream(delay: Duration) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |req: Request<Incoming>| async move {
+                        tokio::time::sleep(delay).await;
+                        let header = |name: &str| {
+                            req.headers()
+                                .get(name)
+                                .and_then(|v| v.to_str().ok())
+                                .unwrap_or_default()
+                                .to_string()
+                        };
+                        let mut echo = serde_json::json!({
+                            "method": req.method().as_str(),
+                            "uri": req.uri().to_string(),
+                            "host": header("host"),
+                            "x_test": header("x-test"),
+                            "x_forwarded_for": header("x-forwarded-for"),
+                            "x_forwarded_host": header("x-forwarded-host"),
+                            "forwarded": header("forwarded"),
+                            "x_hop": header("x-hop"),
+                            "content_encoding": header("content-encoding"),
+                        });
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        echo["body"] = String::from_utf8_lossy(&body).into();
+
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(StatusCode::CREATED)
+                                .header("x-upstream", "echo")
+                                .header("content-type", "application/json")
+                                .body(full(serde_json::to_vec(&echo).unwrap()))
+                                .unwrap(),
+                        )
+                    });
+                    let io = hyper_util::rt::TokioIo::new(stream);
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(io, service)
+                        .await;
+                });
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    /// Start an upstream whose n-th request gets the n-th scripted
+    /// (status, delay); the last entry repeats. Returns the URL and a
+    /// counter of received requests.
+    async fn spawn_scripted_upstream(script: Vec<(StatusCode, Duration)>) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script = Arc::new(script);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let script = script.clone();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |_req: Request<Incoming>| {
+                        let n = counter.fetch_add(1, Ordering::SeqCst);
+                        let (status, delay) = script[n.min(script.len() - 1)];
+                        async move {
+                            tokio::time::sleep(delay).await;
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(full(format!("response {}", n)))
+                                    .unwrap(),
+                            )
+                        }
+                    });
+                    let io = hyper_util::rt::TokioIo::new(stream);
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(io, service)
+                        .await;
+                });
+            }
+        });
+
+        (format!("http://{}", addr), hits)
+    }
+
+    async fn json_body(response: Response<GatewayBody>) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forwards_request_to_upstream() {
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let state = create_test_state(vec![Route::new("echo", "/api/*", upstream.clone())]);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/items?page=2")
+            .header("host", "gateway.local")
+            .header("x-test", "forwarded")
+            .body(Full::new(Bytes::from("payload")))
+            .unwrap();
+
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let echo = json_body(response).await;
+        assert_eq!(echo["method"], "POST");
+        assert_eq!(echo["uri"], "/api/items?page=2");
+        assert_eq!(echo["host"], upstream.trim_start_matches("http://"));
+        assert_eq!(echo["x_test"], "forwarded");
+        assert_eq!(echo["body"], "payload");
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_path_onto_upstream_base() {
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let mut route = Route::new("users", "/api/users/{id}/{*rest}", format!("{}/internal/", upstream));
+        route.rewrite = Some(crate::config::PathRewrite {
+            template: Some("/accounts/{id}/{rest}".into()),
+            ..Default::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let req = Request::builder()
+            .uri("/api/users/42/orders/7?expand=items")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+
+        let response = handle_request(req, state).await.unwrap();
+        let echo = json_body(response).await;
+        assert_eq!(echo["uri"], "/internal/accounts/42/orders/7?expand=items");
+    }
+
+    #[tokio::test]
+    async fn test_header_policies_and_forwarding() {
+        use crate::config::HeaderPolicy;
+
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let mut route = Route::new("echo", "/api/*", upstream);
+        route.request_headers = HeaderPolicy {
+            set: [("x-test".to_string(), "${route_id}:${client_ip}:${jwt.sub}".to_string())].into(),
+            ..Default::default()
+        };
+        route.response_headers = HeaderPolicy {
+            remove: vec!["x-upstream".to_string()],
+            set: [("x-trace".to_string(), "${trace_id}".to_string())].into(),
+            ..Default::default()
+        };
+        let state = create_test_state(vec![route]);
+
+        let mut req = Request::builder()
+            .uri("/api/items")
+            .header("host", "gateway.local")
+            .header("x-request-id", "req-7")
+            .header("connection", "x-hop")
+            .header("x-hop", "1")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        req.extensions_mut().insert(ClientAddr("192.0.2.1:4000".parse().unwrap()));
+        req.extensions_mut().insert(JwtClaims(
+            serde_json::json!({"sub": "alice"}).as_object().unwrap().clone(),
+        ));
+
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.headers()["x-trace"], "req-7");
+        assert!(response.headers().get("x-upstream").is_none());
+
+        let echo = json_body(response).await;
+        assert_eq!(echo["x_test"], "echo:192.0.2.1:alice");
+        assert_eq!(echo["x_forwarded_for"], "192.0.2.1");
+        assert_eq!(echo["x_forwarded_host"], "gateway.local");
+        assert_eq!(echo["forwarded"], "for=192.0.2.1;proto=http;host=gateway.local");
+        assert_eq!(echo["x_hop"], "");
+
+        // Requests arriving over TLS are forwarded as https
+        let mut req = Request::get("/api/items")
+            .header("host", "gateway.local")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        req.extensions_mut().insert(TlsConnection::default());
+        let echo = json_body(handle_request(req, state).await.unwrap()).await;
+        assert_eq!(echo["forwarded"], "proto=https;host=gateway.local");
+    }
+
+    /// A body sent in `chunks` chunks of `size` bytes, without a length.
+    fn chunked_body(chunks: usize, size: usize) -> GatewayBody {
+        let frames = futures::stream::iter(
+            (0..chunks).map(move |_| Ok::<_, BoxError>(hyper::body::Frame::data(Bytes::from(vec![b'x'; size])))),
+        );
+        crate::body::boxed(http_body_util::StreamBody::new(frames))
+    }
+
+    #[tokio::test]
+    async fn test_streams_request_body() {
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let state = create_test_state(vec![Route::new("echo", "/api/*", upstream)]);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .body(chunked_body(64, 1024))
+            .unwrap();
+
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(response).await["body"].as_str().unwrap().len(), 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body_over_limit() {
+        let (upstream, hits) = spawn_scripted_upstream(vec![(StatusCode::OK, Duration::ZERO)]).await;
+        let echo = spawn_echo_upstream(Duration::ZERO).await;
+        let state = create_test_state(vec![
+            Route::new("upload", "/upload", upstream),
+            Route::new("echo", "/echo", echo),
+        ]);
+
+        // Declared too large: rejected before the upstream is contacted
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("content-length", (DEFAULT_MAX_BODY_SIZE + 1).to_string())
+            .body(chunked_body(0, 0))
+            .unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+        // No length: cut off mid-stream, and the upstream is not blamed
+        let req = Request::builder()
+            .method("POST")
+            .uri("/echo")
+            .body(chunked_body(DEFAULT_MAX_BODY_SIZE / 1024 + 1, 1024))
+            .unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(state.health.snapshot(&state.config.load()).iter().all(|h| h.consecutive_failures == 0));
+    }
+
+    #[tokio::test]
+    async fn test_route_body_limits_and_content_types() {
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let mut small = Route::new("small", "/small", upstream.clone());
+        small.max_request_body_bytes = Some(1000);
+        small.max_response_body_bytes = Some(64);
+        let mut json = Route::new("json", "/json", upstream);
+        json.allowed_content_types = vec!["application/json".to_string()];
+        let state = create_test_state(vec![small, json]);
+
+        // The error reports how much was actually received
+        let req = Request::builder()
+            .method("POST")
+            .uri("/small")
+            .body(chunked_body(3, 600))
+            .unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let error = json_body(response).await;
+        assert_eq!(
+            error["error"]["message"],
+            "Payload too large: received 1200 bytes, limit is 1000 bytes"
+        );
+
+        // The echoed response is larger than 64 bytes
+        let req = Request::builder().uri("/small").body(chunked_body(0, 0)).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let post = |content_type: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/json")
+                .header("content-type", content_type)
+                .body(full("{}"))
+                .unwrap()
+        };
+        let response = handle_request(post("text/xml"), state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(response.headers()["x-gateway-error-category"], "unsupported_media_type");
+
+        let response = handle_request(post("application/json; charset=utf-8"), state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Bodyless requests are not subject to the allowlist
+        let req = Request::builder().uri("/json").body(crate::body::empty()).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_balances_across_upstreams() {
+        let first = spawn_echo_upstream(Duration::ZERO).await;
+        let second = spawn_echo_upstream(Duration::ZERO).await;
+        let route = Route::with_upstreams(
+            "lb",
+            "/lb",
+            vec![UpstreamTarget::new(first.clone(), 1), UpstreamTarget::new(second.clone(), 1)],
+            LoadBalancing::WeightedRoundRobin,
+        );
+        let state = create_test_state(vec![route]);
+
+        let mut hosts = Vec::new();
+        for _ in 0..4 {
+            let req = Request::get("/lb").body(Full::new(Bytes::new())).unwrap();
+            let response = handle_request(req, state.clone()).await.unwrap();
+            // In flight until the body has been read, not just the head
+            assert_eq!(state.balancer.in_flight_snapshot().values().sum::<usize>(), 1);
+            hosts.push(json_body(response).await["host"].as_str().unwrap().to_string());
+        }
+
+        let first_host = first.trim_start_matches("http://");
+        assert_eq!(hosts.iter().filter(|h| *h == first_host).count(), 2);
+        assert_eq!(state.balancer.in_flight(&first), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ejects_failing_upstream() {
+        let healthy = spawn_echo_upstream(Duration::ZERO).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let mut route = Route::with_upstreams(
+            "lb",
+            "/lb",
+            vec![UpstreamTarget::new(down.clone(), 1), UpstreamTarget::new(healthy.clone(), 1)],
+            LoadBalancing::WeightedRoundRobin,
+        );
+        route.outlier_detection.consecutive_errors = 1;
+        let state = create_test_state(vec![route]);
+
+        let mut statuses = Vec::new();
+        for _ in 0..4 {
+            let req = Request::get("/lb").body(Full::new(Bytes::new())).unwrap();
+            statuses.push(handle_request(req, state.clone()).await.unwrap().status());
+        }
+        // Only the first request reaches the dead target before it is ejected
+        assert_eq!(statuses.iter().filter(|s| **s == StatusCode::BAD_GATEWAY).count(), 1);
+        assert!(!state.health.is_available(&down));
+
+        let report = json_body(upstreams_status(&state)).await;
+        let entry = report["upstreams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|u| u["url"] == down.as_str())
+            .unwrap()
+            .clone();
+        assert_eq!(entry["available"], false);
+        assert_eq!(entry["ejections"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_short_circuits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let mut route = Route::new("cb", "/cb", down);
+        route.outlier_detection.consecutive_errors = 0;
+        route.circuit_breaker = Some(CircuitBreaker {
+            minimum_calls: 2,
+            ..CircuitBreaker::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let req = Request::get("/cb").body(Full::new(Bytes::new())).unwrap();
+            let response = handle_request(req, state.clone()).await.unwrap();
+            statuses.push((response.status(), response.headers()["X-Gateway-Error-Category"].clone()));
+        }
+
+        assert_eq!(statuses[0].0, StatusCode::BAD_GATEWAY);
+        assert_eq!(statuses[1].0, StatusCode::BAD_GATEWAY);
+        assert_eq!(statuses[2].0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(statuses[2].1, "circuit_open");
+    }
+
+    #[tokio::test]
+    async fn test_retries_retryable_status() {
+        let (upstream, hits) = spawn_scripted_upstream(vec![
+            (StatusCode::SERVICE_UNAVAILABLE, Duration::ZERO),
+            (StatusCode::SERVICE_UNAVAILABLE, Duration::ZERO),
+            (StatusCode::OK, Duration::ZERO),
+        ])
+        .await;
+        let mut route = Route::new("retry", "/retry", upstream);
+        route.retry = Some(RetryPolicy {
+            base_backoff_ms: 1,
+            ..RetryPolicy::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/retry").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // POST is not idempotent: the 503 is returned as-is
+        let req = Request::post("/retry").body(Full::new(Bytes::new())).unwrap();
+        hits.store(0, Ordering::SeqCst);
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_respects_max_attempts_and_budget() {
+        let (upstream, hits) = spawn_scripted_upstream(vec![(StatusCode::BAD_GATEWAY, Duration::ZERO)]).await;
+        let mut route = Route::new("retry", "/retry", upstream);
+        route.outlier_detection.consecutive_errors = 0;
+        route.retry = Some(RetryPolicy {
+            max_attempts: 2,
+            base_backoff_ms: 1,
+            ..RetryPolicy::default()
+        });
+        let config = Arc::new(ArcSwap::from_pointee(build_router_map(vec![route])));
+        let mut state = GatewayState::new(config);
+        state.retry_budget = RetryBudget::new(0.0, 0);
+        let state = Arc::new(state);
+
+        // No budget: no retries at all
+        let req = Request::get("/retry").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let state = create_test_state(vec![state.config.load().get("/retry").unwrap().clone()]);
+        let req = Request::get("/retry").body(Full::new(Bytes::new())).unwrap();
+        handle_request(req, state).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_hedged_request() {
+        let (upstream, hits) = spawn_scripted_upstream(vec![
+            (StatusCode::OK, Duration::from_secs(5)),
+            (StatusCode::OK, Duration::ZERO),
+        ])
+        .await;
+        let mut route = Route::new("hedge", "/hedge", upstream);
+        route.retry = Some(RetryPolicy {
+            hedge_delay_ms: Some(20),
+            ..RetryPolicy::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/hedge").body(Full::new(Bytes::new())).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(2), handle_request(req, state))
+            .await
+            .expect("hedged request should not wait for the slow attempt")
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"response 1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout() {
+        let upstream = spawn_echo_upstream(Duration::from_millis(500)).await;
+        let mut route = Route::new("slow", "/slow", upstream);
+        route.timeout_ms = 50;
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/slow").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_connection_refused() {
+        // Reserve a port, then release it so nothing is listening
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let state = create_test_state(vec![Route::new("down", "/down", format!("http://{}", addr))]);
+
+        let req = Request::get("/down").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()["X-Gateway-Error-Category"], "upstream");
+    }
+
+    #[tokio::test]
+    async fn test_route_and_method_errors() {
+        let mut route = Route::new("ro", "/readonly", "http://127.0.0.1:1");
+        route.methods = vec!["GET".to_string()];
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/missing").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let req = Request::delete("/readonly").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        // Checked before the route's filters and scripts get to run
+        let mut route = Route::new("ro", "/readonly", "http://127.0.0.1:1");
+        route.methods = vec!["GET".to_string()];
+        route.filters = vec!["auth".to_string()];
+        route.script = Some(crate::config::ScriptConfig {
+            request: Some("response = #{ status: 200 };".to_string()),
+            ..Default::default()
+        });
+        let mut state = GatewayState::new(Arc::new(ArcSwap::from_pointee(build_router_map(vec![route]))));
+        state.filters.register("auth", HeaderAuth);
+        let req = Request::delete("/readonly").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, Arc::new(state)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    /// Authenticates callers from an `x-user` header, turning away requests
+    /// without one.
+    struct HeaderAuth;
+
+    impl crate::filter::Filter for HeaderAuth {
+        fn on_request<'a>(
+            &'a self,
+            req: &'a mut Request<GatewayBody>,
+            ctx: &'a mut FilterContext,
+        ) -> futures::future::BoxFuture<'a, Result<crate::filter::FilterAction, GatewayError>> {
+            use crate::filter::{reject, FilterAction};
+
+            let action = match req.headers().get("x-user").and_then(|v| v.to_str().ok()) {
+                Some(user) => {
+                    let claims = JwtClaims(serde_json::json!({"sub": user}).as_object().unwrap().clone());
+                    req.extensions_mut().insert(claims.clone());
+                    ctx.extensions.insert(claims);
+                    FilterAction::Continue
+                }
+                None => FilterAction::Respond(reject(StatusCode::UNAUTHORIZED, "auth", "missing x-user")),
+            };
+            Box::pin(future::ready(Ok(action)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_filters() {
+        use crate::config::HeaderPolicy;
+        use crate::filter::TransformFilter;
+        use crate::transform::RhaiTransformer;
+
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let mut route = Route::new("echo", "/api/*", upstream.clone());
+        route.filters = vec!["auth".to_string(), "transform".to_string()];
+        route.request_headers = HeaderPolicy {
+            set: [("x-test".to_string(), "${jwt.sub}".to_string())].into(),
+            ..Default::default()
+        };
+        let mut unknown = Route::new("unknown", "/other", upstream);
+        unknown.filters = vec!["missing".to_string()];
+        let mut down = Route::new("down", "/down", "http://127.0.0.1:1");
+        down.filters = vec!["upper_response".to_string()];
+
+        let routes = vec![route, unknown, down];
+        let mut state = GatewayState::new(Arc::new(ArcSwap::from_pointee(build_router_map(routes))));
+        state.filters.register("auth", HeaderAuth);
+        state.filters.register(
+            "transform",
+            TransformFilter::new(Some(RhaiTransformer::new("output = upper(input);").unwrap()), None),
+        );
+        state.filters.register(
+            "upper_response",
+            TransformFilter::new(None, Some(RhaiTransformer::new("output = upper(input);").unwrap())),
+        );
+        let state = Arc::new(state);
+
+        // Authenticated: claims reach the header templates, the body is transformed
+        let req = Request::post("/api/items")
+            .header("x-user", "bob")
+            .body(Full::new(Bytes::from("payload")))
+            .unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let echo = json_body(response).await;
+        assert_eq!(echo["x_test"], "bob");
+        assert_eq!(echo["body"], "PAYLOAD");
+
+        // Unauthenticated: answered by the filter, later filters skipped
+        let req = Request::post("/api/items").body(Full::new(Bytes::from("payload"))).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["x-gateway-error-category"], "auth");
+
+        // A filter nobody registered fails the request
+        let req = Request::get("/other").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // The gateway's own errors are not run through response transforms
+        let req = Request::get("/down").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(json_body(response).await["error"]["category"], "upstream");
+    }
+
+    #[tokio::test]
+    async fn test_route_scripts() {
+        use crate::config::ScriptConfig;
+
+        let primary = spawn_echo_upstream(Duration::ZERO).await;
+        let canary = spawn_echo_upstream(Duration::ZERO).await;
+        let script = |request: &str| ScriptConfig {
+            request: Some(request.to_string()),
+            response: Some(r#"response.headers["x-script"] = request.params.id; response.body.seen = true;"#.into()),
+            ..Default::default()
+        };
+        let mut route = Route::new("orders", "/orders/{id}", primary.clone());
+        route.script = Some(script(&format!(
+            r#"
+            if request.method == "DELETE" {{ response = #{{ status: 403, body: "read only" }}; return; }}
+            request.headers["x-test"] = "order-" + request.params.id;
+            if request.query.canary == "1" {{ request.upstream = "{}"; }}
+            "#,
+            canary
+        )));
+        let state = create_test_state(vec![route.clone()]);
+
+        let req = Request::get("/orders/42").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-script"], "42");
+        let echo = json_body(response).await;
+        assert_eq!(echo["x_test"], "order-42");
+        assert_eq!(echo["host"], primary.trim_start_matches("http://"));
+        assert_eq!(echo["seen"], true);
+
+        // The script picks the upstream
+        let req = Request::get("/orders/42?canary=1").body(Full::new(Bytes::new())).unwrap();
+        let echo = json_body(handle_request(req, state.clone()).await.unwrap()).await;
+        assert_eq!(echo["host"], canary.trim_start_matches("http://"));
+
+        // ...or answers itself, without the response script
+        let req = Request::delete("/orders/42").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers().get("x-script").is_none());
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "read only");
+
+        // A reload recompiles the scripts
+        route.script = Some(script(r#"request.headers["x-test"] = "reloaded";"#));
+        state.config.store(Arc::new(build_router_map(vec![route])));
+        let req = Request::get("/orders/42").body(Full::new(Bytes::new())).unwrap();
+        let echo = json_body(handle_request(req, state).await.unwrap()).await;
+        assert_eq!(echo["x_test"], "reloaded");
+    }
+
+    #[tokio::test]
+    async fn test_route_script_limits() {
+        use crate::config::{ScriptConfig, ScriptLimits};
+
+        let mut route = Route::new("spin", "/spin", spawn_echo_upstream(Duration::ZERO).await);
+        route.script = Some(ScriptConfig {
+            request: Some("loop { }".to_string()),
+            limits: ScriptLimits {
+                timeout_ms: 20,
+                max_operations: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/spin").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()["x-gateway-error-category"], "transform");
+
+        let metrics = json_body(scripts_status()).await;
+        assert_eq!(metrics["limit_hits"]["spin.request"]["timeout"], 1);
+    }
+
+    /// Start an upstream that answers after `head_delay`, then sends
+    /// `events` Server-Sent Events `interval` apart and holds the stream
+    /// open. The flag is set once nobody reads the stream anymore.
+    async fn spawn_sse_upstream(head_delay: Duration, events: usize, interval: Duration) -> (String, Arc<AtomicBool>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = closed.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let flag = flag.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |_req: Request<Incoming>| {
+                        let flag = flag.clone();
+                        async move {
+                            tokio::time::sleep(head_delay).await;
+                            let (tx, rx) = tokio::sync::mpsc::channel::<Result<Frame<Bytes>, Infallible>>(1);
+                            tokio::spawn(async move {
+                                for i in 0..events {
+                                    tokio::time::sleep(interval).await;
+                                    if tx.send(Ok(Frame::data(format!("data: {}\n\n", i).into()))).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                tx.closed().await;
+                                flag.store(true, Ordering::SeqCst);
+                            });
+                            let stream = futures::stream::unfold(rx, |mut rx| async move {
+                                rx.recv().await.map(|frame| (frame, rx))
+                            });
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .header("content-type", "text/event-stream")
+                                    .body(http_body_util::StreamBody::new(stream))
+                                    .unwrap(),
+                            )
+                        }
+                    });
+                    let io = hyper_util::rt::TokioIo::new(stream);
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(io, service)
+                        .await;
+                });
+            }
+        });
+
+        (format!("http://{}", addr), closed)
+    }
+
+    #[tokio::test]
+    async fn test_streams_server_sent_events() {
+        let (upstream, closed) = spawn_sse_upstream(Duration::from_millis(100), 3, Duration::from_millis(20)).await;
+        let mut route = Route::new("events", "/events", upstream);
+        // A long poll answering after 100ms would time out without streaming
+        route.timeout_ms = 50;
+        route.streaming = Some(crate::config::StreamingConfig { idle_timeout_ms: 1_000 });
+        // Response scripts would buffer the endless body, so they are skipped
+        route.script = Some(crate::config::ScriptConfig {
+            response: Some("response.status = 500;".to_string()),
+            ..Default::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/events").body(Full::new(Bytes::new())).unwrap();
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        // Events arrive while the upstream stream is still open
+        let mut body = response.into_body();
+        let mut received = String::new();
+        while received.len() < "data: 0\n\n".len() * 3 {
+            let frame = body.frame().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(frame.data_ref().unwrap()).unwrap());
+        }
+        assert_eq!(received, "data: 0\n\ndata: 1\n\ndata: 2\n\n");
+        assert!(!closed.load(Ordering::SeqCst));
+
+        // The client going away cancels the upstream request
+        drop(body);
+        for _ in 0..50 {
+            if closed.load(Ordering::SeqCst) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(closed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_streaming_idle_timeout() {
+        let (upstream, _) = spawn_sse_upstream(Duration::ZERO, 1, Duration::from_millis(10)).await;
+        let mut route = Route::new("events", "/events", upstream);
+        route.streaming = Some(crate::config::StreamingConfig { idle_timeout_ms: 100 });
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/events").body(Full::new(Bytes::new())).unwrap();
+        let mut body = handle_request(req, state).await.unwrap().into_body();
+
+        assert!(body.frame().await.unwrap().is_ok());
+        assert!(body.frame().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use async_compression::tokio::bufread::GzipEncoder;
+        use tokio::io::AsyncReadExt;
+
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let mut route = Route::new("compressed", "/api/*", upstream);
+        route.buffer_body = true;
+        route.compression = Some(crate::config::CompressionConfig {
+            min_size_bytes: 0,
+            ..Default::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(&b"payload"[..]).read_to_end(&mut gzipped).await.unwrap();
+        let req = Request::post("/api/items")
+            .header("content-encoding", "gzip")
+            .header("accept-encoding", "gzip;q=0.5, br")
+            .body(Full::new(Bytes::from(gzipped)))
+            .unwrap();
+
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "br");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+
+        let (mut parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        let body = decode_body(&mut parts.headers, body, DEFAULT_MAX_BODY_SIZE).await.unwrap();
+        let echo: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // The upstream got the decoded body
+        assert_eq!(echo["body"], "payload");
+        assert_eq!(echo["content_encoding"], "");
+
+        // Streaming routes are never compressed, whatever the media type
+        let mut route = Route::new("streamed", "/stream/*", spawn_echo_upstream(Duration::ZERO).await);
+        route.streaming = Some(crate::config::StreamingConfig { idle_timeout_ms: 1_000 });
+        route.compression = Some(crate::config::CompressionConfig {
+            min_size_bytes: 0,
+            ..Default::default()
+        });
+        let state = create_test_state(vec![route]);
+        let req = Request::get("/stream/items")
+            .header("accept-encoding", "gzip")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = handle_request(req, state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers().get("content-encoding").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serves_cached_responses() {
+        let (upstream, hits) = spawn_scripted_upstream(vec![(StatusCode::OK, Duration::ZERO)]).await;
+        let mut route = Route::new("cached", "/items/*", upstream);
+        route.cache = Some(CacheConfig {
+            default_ttl_secs: 60,
+            ..Default::default()
+        });
+        let state = create_test_state(vec![route]);
+
+        let get = || Request::get("/items/1").body(Full::new(Bytes::new())).unwrap();
+        let first = handle_request(get(), state.clone()).await.unwrap();
+        assert_eq!(first.headers()["x-cache"], "MISS");
+        let second = handle_request(get(), state.clone()).await.unwrap();
+        assert_eq!(second.headers()["x-cache"], "HIT");
+        assert_eq!(second.into_body().collect().await.unwrap().to_bytes(), "response 0");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let req = Request::post("/items/1").body(Full::new(Bytes::new())).unwrap();
+        let post = handle_request(req, state.clone()).await.unwrap();
+        assert!(post.headers().get("x-cache").is_none());
+
+        // Query values are percent-decoded
+        let uri = Uri::from_static("/_gateway/cache/purge?route=cached&prefix=%2Fitems%2F");
+        let purge = cache_purge(&state, &uri).await;
+        assert_eq!(purge.into_body().collect().await.unwrap().to_bytes(), r#"{"purged":1}"#);
+        let third = handle_request(get(), state).await.unwrap();
+        assert_eq!(third.headers()["x-cache"], "MISS");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    /// Start an upstream that echoes WebSocket messages back.
+    async fn spawn_websocket_upstream() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if msg.is_text() || msg.is_binary() {
+                            let _ = ws.send(msg).await;
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    /// Serve `handle_request` over HTTP/1.1 with upgrades and return the
+    /// gateway address.
+    async fn spawn_gateway(state: Arc<GatewayState>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+                        handle_request(req, state.clone())
+                    });
+                    let io = hyper_util::rt::TokioIo::new(stream);
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(io, service)
+                        .with_upgrades()
+                        .await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_proxies_websocket() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let upstream = spawn_websocket_upstream().await;
+        let mut route = Route::new("chat", "/ws", upstream);
+        route.websocket = Some(crate::config::WebSocketConfig {
+            idle_timeout_ms: 60_000,
+            max_message_bytes: 16,
+        });
+        let state = create_test_state(vec![route]);
+        let gateway = spawn_gateway(state.clone()).await;
+
+        let stream = tokio::net::TcpStream::connect(gateway).await.unwrap();
+        let (mut ws, response) = tokio_tungstenite::client_async(format!("ws://{}/ws", gateway), stream)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        ws.send(Message::text("hello")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("hello"));
+        assert_eq!(state.websockets.count("chat"), 1);
+        assert_eq!(state.router_stats().websocket_connections["chat"], 1);
+
+        // Over the 16 byte limit: closed with 1009
+        ws.send(Message::binary(vec![0; 64])).await.unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 1009),
+            other => panic!("expected close frame, got {:?}", other),
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.websockets.count("chat"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_idle_timeout() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let upstream = spawn_websocket_upstream().await;
+        let mut route = Route::new("chat", "/ws", upstream);
+        route.websocket = Some(crate::config::WebSocketConfig {
+            idle_timeout_ms: 50,
+            ..Default::default()
+        });
+        let gateway = spawn_gateway(create_test_state(vec![route])).await;
+
+        let stream = tokio::net::TcpStream::connect(gateway).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}/ws", gateway), stream)
+            .await
+            .unwrap();
+
+        match ws.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 1001),
+            other => panic!("expected close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upstream_with_bad_accept() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers any upgrade with 101 but the wrong accept value
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\
+                          Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+                    )
+                    .await;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        let mut route = Route::new("chat", "/ws", upstream);
+        route.websocket = Some(crate::config::WebSocketConfig::default());
+        let state = create_test_state(vec![route]);
+
+        let req = Request::get("/ws")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = handle_request(req, state.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(state.websockets.count("chat"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_on_plain_route_is_forwarded() {
+        let upstream = spawn_echo_upstream(Duration::ZERO).await;
+        let state = create_test_state(vec![Route::new("plain", "/ws", upstream)]);
+
+        let req = Request::get("/ws")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = handle_request(req, state).await.unwrap();
+
+        // Without a websocket config the upgrade headers are stripped
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
 }
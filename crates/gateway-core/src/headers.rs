@@ -0,0 +1,376 @@
+//! Header policies, forwarding headers and hop-by-hop stripping.
+//!
+//! Policy values may reference request context with `${...}` placeholders:
+//!
+//! | Placeholder      | Value                                          |
+//! |------------------|------------------------------------------------|
+//! | `${client_ip}`   | IP address of the downstream client            |
+//! | `${route_id}`    | ID of the matched route                        |
+//! | `${trace_id}`    | W3C trace id, `X-Request-Id`, or a generated id |
+//! | `${jwt.<claim>}` | a claim of the caller's validated JWT          |
+//! | `${client_cert.subject}` | subject DN of the client's TLS certificate |
+//! | `${client_cert.cn}`      | its subject common name                    |
+//! | `${client_cert.sans}`    | its subject alternative names, comma-separated |
+//! | `${client_cert.issuer}`  | its issuer DN                              |
+//! | `${client_cert.serial}`  | its serial number                          |
+//!
+//! Unknown placeholders render as an empty string.
+
+use std::net::IpAddr;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, HOST};
+
+use crate::config::HeaderPolicy;
+use crate::tls::ClientCertificate;
+
+/// Headers that only apply to a single connection (RFC 9110 section 7.6.1)
+/// and must not be forwarded by a proxy.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Claims of a validated JWT, attached to requests as an extension by the
+/// authentication layer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JwtClaims(pub serde_json::Map<String, serde_json::Value>);
+
+/// Request context available to header templates.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderContext<'a> {
+    /// IP address of the downstream client
+    pub client_ip: Option<IpAddr>,
+    /// ID of the matched route
+    pub route_id: &'a str,
+    /// Trace id of the request
+    pub trace_id: &'a str,
+    /// Claims of the caller's JWT, if authenticated
+    pub claims: Option<&'a JwtClaims>,
+    /// Verified TLS client certificate, if the client presented one
+    pub client_cert: Option<&'a ClientCertificate>,
+}
+
+impl HeaderContext<'_> {
+    /// Expand `${...}` placeholders in a header value template.
+    pub fn render(&self, template: &str) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find("${") {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            self.push_var(&rest[start + 2..start + len], &mut out);
+            rest = &rest[start + len + 1..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn push_var(&self, name: &str, out: &mut String) {
+        match name {
+            "client_ip" => {
+                if let Some(ip) = self.client_ip {
+                    out.push_str(&ip.to_string());
+                }
+            }
+            "route_id" => out.push_str(self.route_id),
+            "trace_id" => out.push_str(self.trace_id),
+            _ if name.starts_with("client_cert.") => {
+                let Some(cert) = self.client_cert else {
+                    return;
+                };
+                match &name["client_cert.".len()..] {
+                    "subject" => out.push_str(&cert.subject),
+                    "cn" => out.push_str(cert.common_name.as_deref().unwrap_or_default()),
+                    "sans" => out.push_str(&cert.sans.join(",")),
+                    "issuer" => out.push_str(&cert.issuer),
+                    "serial" => out.push_str(&cert.serial),
+                    _ => {}
+                }
+            }
+            _ => {
+                let claim = name
+                    .strip_prefix("jwt.")
+                    .and_then(|claim| self.claims?.0.get(claim));
+                match claim {
+                    Some(serde_json::Value::String(s)) => out.push_str(s),
+                    Some(value) => out.push_str(&value.to_string()),
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+/// Apply a header policy: rename, then remove, then set, then add.
+///
+/// Entries with an invalid header name, or whose rendered value is not a
+/// valid header value, are skipped with a warning.
+pub fn apply_policy(headers: &mut HeaderMap, policy: &HeaderPolicy, ctx: &HeaderContext<'_>) {
+    for (from, to) in &policy.rename {
+        let (Some(from), Some(to)) = (header_name(from), header_name(to)) else {
+            continue;
+        };
+        let values: Vec<HeaderValue> = match headers.entry(from) {
+            hyper::header::Entry::Occupied(entry) => entry.remove_entry_mult().1.collect(),
+            hyper::header::Entry::Vacant(_) => continue,
+        };
+        for value in values {
+            headers.append(to.clone(), value);
+        }
+    }
+
+    for name in policy.remove.iter().filter_map(|n| header_name(n)) {
+        headers.remove(name);
+    }
+
+    for (name, template) in &policy.set {
+        if let Some((name, value)) = header_pair(name, template, ctx) {
+            headers.insert(name, value);
+        }
+    }
+
+    for (name, template) in &policy.add {
+        if let Some((name, value)) = header_pair(name, template, ctx) {
+            headers.append(name, value);
+        }
+    }
+}
+
+fn header_name(name: &str) -> Option<HeaderName> {
+    match HeaderName::try_from(name) {
+        Ok(name) => Some(name),
+        Err(_) => {
+            tracing::warn!(header = %name, "Skipping invalid header name in header policy");
+            None
+        }
+    }
+}
+
+fn header_pair(name: &str, template: &str, ctx: &HeaderContext<'_>) -> Option<(HeaderName, HeaderValue)> {
+    let name = header_name(name)?;
+    match HeaderValue::try_from(ctx.render(template)) {
+        Ok(value) => Some((name, value)),
+        Err(_) => {
+            tracing::warn!(header = %name, "Skipping header policy entry with an invalid value");
+            None
+        }
+    }
+}
+
+/// Remove hop-by-hop headers, including any listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+/// Append the client to `X-Forwarded-For` and `Forwarded`, and record the
+/// original protocol and host in `X-Forwarded-Proto` / `X-Forwarded-Host`.
+///
+/// Must run before the `Host` header is replaced with the upstream's.
+pub fn add_forwarding_headers(headers: &mut HeaderMap, client_ip: Option<IpAddr>, proto: &str) {
+    let host = headers
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let mut forwarded = Vec::new();
+    if let Some(ip) = client_ip {
+        // Proxies before us may each have added their own header line
+        let mut chain: Vec<String> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(str::to_string)
+            .collect();
+        chain.push(ip.to_string());
+        set(headers, "x-forwarded-for", chain.join(", "));
+
+        forwarded.push(match ip {
+            IpAddr::V4(ip) => format!("for={}", ip),
+            IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+        });
+    }
+
+    set(headers, "x-forwarded-proto", proto.to_string());
+    forwarded.push(format!("proto={}", proto));
+
+    if let Some(host) = host {
+        forwarded.push(format!("host={}", quote_if_needed(&host)));
+        set(headers, "x-forwarded-host", host);
+    }
+
+    if let Ok(value) = HeaderValue::try_from(forwarded.join(";")) {
+        headers.append("forwarded", value);
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: &'static str, value: String) {
+    if let Ok(value) = HeaderValue::try_from(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// Quote a `Forwarded` parameter value unless it is a plain token.
+fn quote_if_needed(value: &str) -> String {
+    let token = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Trace id for a request: the W3C `traceparent` trace id, else
+/// `X-Request-Id`, else a fresh random id.
+pub fn trace_id(headers: &HeaderMap) -> String {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    header("traceparent")
+        .and_then(|tp| tp.split('-').nth(1))
+        .or_else(|| header("x-request-id"))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx<'a>(claims: Option<&'a JwtClaims>) -> HeaderContext<'a> {
+        HeaderContext {
+            client_ip: Some("10.0.0.7".parse().unwrap()),
+            route_id: "users",
+            trace_id: "abc123",
+            claims,
+            client_cert: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let claims = JwtClaims(serde_json::json!({"sub": "alice", "tier": 2}).as_object().unwrap().clone());
+        let ctx = ctx(Some(&claims));
+
+        assert_eq!(ctx.render("${client_ip}"), "10.0.0.7");
+        assert_eq!(ctx.render("route=${route_id}; trace=${trace_id}"), "route=users; trace=abc123");
+        assert_eq!(ctx.render("${jwt.sub}/${jwt.tier}"), "alice/2");
+        assert_eq!(ctx.render("${jwt.missing}${unknown}x"), "x");
+        assert_eq!(ctx.render("plain ${unterminated"), "plain ${unterminated");
+        assert_eq!(ctx.render("[${client_cert.subject}]"), "[]");
+
+        let cert = ClientCertificate {
+            subject: "O=Naseej, CN=orders".to_string(),
+            common_name: Some("orders".to_string()),
+            sans: vec!["DNS:orders.internal".to_string(), "URI:spiffe://naseej/orders".to_string()],
+            ..Default::default()
+        };
+        let ctx = HeaderContext { client_cert: Some(&cert), ..ctx };
+        assert_eq!(ctx.render("${client_cert.cn}|${client_cert.subject}"), "orders|O=Naseej, CN=orders");
+        assert_eq!(ctx.render("${client_cert.sans}"), "DNS:orders.internal,URI:spiffe://naseej/orders");
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-old", HeaderValue::from_static("1"));
+        headers.insert("x-secret", HeaderValue::from_static("s"));
+        headers.insert("x-set", HeaderValue::from_static("before"));
+        headers.insert("x-add", HeaderValue::from_static("first"));
+
+        let policy = HeaderPolicy {
+            rename: [("x-old".to_string(), "x-new".to_string())].into(),
+            remove: vec!["x-secret".to_string()],
+            set: [("x-set".to_string(), "${route_id}".to_string())].into(),
+            add: [("x-add".to_string(), "second".to_string())].into(),
+        };
+        apply_policy(&mut headers, &policy, &ctx(None));
+
+        assert!(headers.get("x-old").is_none());
+        assert_eq!(headers["x-new"], "1");
+        assert!(headers.get("x-secret").is_none());
+        assert_eq!(headers["x-set"], "users");
+        let added: Vec<_> = headers.get_all("x-add").iter().collect();
+        assert_eq!(added, ["first", "second"]);
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, x-private"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-private", HeaderValue::from_static("1"));
+        headers.insert("te", HeaderValue::from_static("trailers"));
+        headers.insert("x-kept", HeaderValue::from_static("1"));
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("x-kept"));
+    }
+
+    #[test]
+    fn test_forwarding_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static("api.example.com:8443"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.9"));
+
+        add_forwarding_headers(&mut headers, Some("10.0.0.7".parse().unwrap()), "https");
+
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.9, 10.0.0.7");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "api.example.com:8443");
+        assert_eq!(headers["forwarded"], "for=10.0.0.7;proto=https;host=\"api.example.com:8443\"");
+
+        let mut headers = HeaderMap::new();
+        add_forwarding_headers(&mut headers, Some("::1".parse().unwrap()), "http");
+        assert_eq!(headers["forwarded"], "for=\"[::1]\";proto=http");
+
+        // Every earlier line of the chain is kept, in order
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("203.0.113.9, 198.51.100.2"));
+        headers.append("x-forwarded-for", HeaderValue::from_static("192.0.2.44"));
+        add_forwarding_headers(&mut headers, Some("10.0.0.7".parse().unwrap()), "http");
+        assert_eq!(headers.get_all("x-forwarded-for").iter().count(), 1);
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.9, 198.51.100.2, 192.0.2.44, 10.0.0.7");
+    }
+
+    #[test]
+    fn test_trace_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        assert_eq!(trace_id(&headers), "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static("req-1"));
+        assert_eq!(trace_id(&headers), "req-1");
+
+        assert_eq!(trace_id(&HeaderMap::new()).len(), 32);
+    }
+}
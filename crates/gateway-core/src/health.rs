@@ -0,0 +1,419 @@
+//! Active and passive upstream health tracking.
+//!
+//! Two independent signals decide whether a target receives traffic:
+//!
+//! - **Active**: a background task probes targets of routes that configure a
+//!   `HealthCheck`, flipping them unhealthy/healthy after the configured
+//!   number of consecutive failed/successful probes.
+//! - **Passive**: every proxied request reports its outcome. A target that
+//!   keeps failing (5xx, connect errors, timeouts) is ejected for a while
+//!   according to the route's `OutlierDetection`.
+//!
+//! The load balancer skips targets that are unhealthy or ejected.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::{HealthCheck, OutlierDetection, RouterMap, UpstreamTlsConfig};
+use crate::error::GatewayError;
+use crate::state::GatewayState;
+
+/// How often the checker looks for targets that are due for a probe
+pub const CHECK_TICK: Duration = Duration::from_millis(250);
+
+/// Health state of every upstream target seen by the gateway.
+#[derive(Default)]
+pub struct HealthRegistry {
+    upstreams: Mutex<HashMap<String, TargetHealth>>,
+}
+
+/// Mutable health state of a single target.
+#[derive(Debug, Default)]
+struct TargetHealth {
+    /// Marked unhealthy by active probing
+    unhealthy: bool,
+    /// Consecutive successful probes
+    probe_successes: u32,
+    /// Consecutive failed probes
+    probe_failures: u32,
+    /// When the last probe was started
+    last_probe: Option<Instant>,
+    /// Most recent probe or request failure
+    last_error: Option<String>,
+    /// Consecutive failed requests (passive)
+    request_failures: u32,
+    /// Ejected by outlier detection until this instant
+    ejected_until: Option<Instant>,
+    /// Number of times this target has been ejected
+    ejections: u64,
+}
+
+impl TargetHealth {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Point-in-time health report for one upstream target.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamHealth {
+    /// Upstream base URL
+    pub url: String,
+    /// Ids of the routes that use this target
+    pub routes: Vec<String>,
+    /// Whether the target currently receives traffic
+    pub available: bool,
+    /// Active health check verdict
+    pub healthy: bool,
+    /// Whether active probing is configured for this target
+    pub active_checks: bool,
+    /// Remaining ejection time, if ejected by outlier detection
+    pub ejected_for_ms: Option<u64>,
+    /// Number of ejections so far
+    pub ejections: u64,
+    /// Consecutive failed requests
+    pub consecutive_failures: u32,
+    /// Most recent failure message
+    pub last_error: Option<String>,
+    /// Requests currently in flight to this target
+    pub in_flight: usize,
+}
+
+impl HealthRegistry {
+    /// Create an empty registry; unknown targets are considered healthy
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a target may receive traffic
+    pub fn is_available(&self, url: &str) -> bool {
+        let upstreams = self.upstreams.lock().unwrap();
+        match upstreams.get(url) {
+            Some(health) => !health.unhealthy && !health.is_ejected(Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Record the outcome of a proxied request (passive health).
+    pub fn record_request(&self, url: &str, success: bool, error: Option<String>, outlier: &OutlierDetection) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+
+        if success {
+            // Avoid creating entries for the common all-good case
+            if let Some(health) = upstreams.get_mut(url) {
+                health.request_failures = 0;
+            }
+            return;
+        }
+
+        let health = upstreams.entry(url.to_string()).or_default();
+        health.request_failures += 1;
+        health.last_error = error;
+
+        let now = Instant::now();
+        if outlier.consecutive_errors > 0
+            && health.request_failures >= outlier.consecutive_errors
+            && !health.is_ejected(now)
+        {
+            health.ejected_until = Some(now + Duration::from_millis(outlier.ejection_ms));
+            health.request_failures = 0;
+            health.ejections += 1;
+
+            tracing::warn!(
+                upstream = %url,
+                ejection_ms = outlier.ejection_ms,
+                "Upstream ejected by outlier detection"
+            );
+        }
+    }
+
+    /// Record the result of an active probe.
+    pub fn record_probe(&self, url: &str, result: Result<(), String>, check: &HealthCheck) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let health = upstreams.entry(url.to_string()).or_default();
+
+        match result {
+            Ok(()) => {
+                health.probe_failures = 0;
+                health.probe_successes += 1;
+                if health.unhealthy && health.probe_successes >= check.healthy_threshold {
+                    health.unhealthy = false;
+                    health.last_error = None;
+                    tracing::info!(upstream = %url, "Upstream marked healthy");
+                }
+            }
+            Err(error) => {
+                health.probe_successes = 0;
+                health.probe_failures += 1;
+                if !health.unhealthy && health.probe_failures >= check.unhealthy_threshold {
+                    health.unhealthy = true;
+                    tracing::warn!(upstream = %url, error = %error, "Upstream marked unhealthy");
+                }
+                health.last_error = Some(error);
+            }
+        }
+    }
+
+    /// Report on every target referenced by the routing table.
+    pub fn snapshot(&self, map: &RouterMap) -> Vec<UpstreamHealth> {
+        let checked = probe_targets(map);
+        let mut routes_by_target: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for route in map.values() {
+            for target in route.targets().iter() {
+                routes_by_target
+                    .entry(target.url.clone())
+                    .or_default()
+                    .insert(route.id.clone());
+            }
+        }
+
+        let upstreams = self.upstreams.lock().unwrap();
+        let now = Instant::now();
+
+        let mut report: Vec<UpstreamHealth> = routes_by_target
+            .into_iter()
+            .map(|(url, routes)| {
+                let health = upstreams.get(&url);
+                let healthy = health.is_none_or(|h| !h.unhealthy);
+                let ejected_for = health
+                    .and_then(|h| h.ejected_until)
+                    .and_then(|until| until.checked_duration_since(now))
+                    .filter(|d| !d.is_zero());
+
+                UpstreamHealth {
+                    active_checks: checked.contains_key(&url),
+                    routes: routes.into_iter().collect(),
+                    available: healthy && ejected_for.is_none(),
+                    healthy,
+                    ejected_for_ms: ejected_for.map(|d| d.as_millis() as u64),
+                    ejections: health.map_or(0, |h| h.ejections),
+                    consecutive_failures: health.map_or(0, |h| h.request_failures),
+                    last_error: health.and_then(|h| h.last_error.clone()),
+                    in_flight: 0,
+                    url,
+                }
+            })
+            .collect();
+
+        report.sort_by(|a, b| a.url.cmp(&b.url));
+        report
+    }
+
+    /// Mark due targets as probed and return them.
+    fn take_due(&self, targets: &HashMap<String, HealthCheck>) -> Vec<(String, HealthCheck)> {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let now = Instant::now();
+
+        targets
+            .iter()
+            .filter_map(|(url, check)| {
+                let health = upstreams.entry(url.clone()).or_default();
+                let due = health
+                    .last_probe
+                    .is_none_or(|last| now.duration_since(last) >= Duration::from_millis(check.interval_ms));
+                if due {
+                    health.last_probe = Some(now);
+                    Some((url.clone(), check.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Forget targets that are no longer referenced by any route.
+    fn retain(&self, urls: &HashSet<String>) {
+        self.upstreams.lock().unwrap().retain(|url, _| urls.contains(url));
+    }
+}
+
+/// Collect the targets that have active health checks configured.
+///
+/// When several routes share a target with different settings, the one
+/// with the shortest interval wins.
+fn probe_targets(map: &RouterMap) -> HashMap<String, HealthCheck> {
+    let mut targets: HashMap<String, HealthCheck> = HashMap::new();
+
+    for route in map.values() {
+        let Some(check) = &route.health_check else {
+            continue;
+        };
+        for target in route.targets().iter() {
+            match targets.get(&target.url) {
+                Some(existing) if existing.interval_ms <= check.interval_ms => {}
+                _ => {
+                    targets.insert(target.url.clone(), check.clone());
+                }
+            }
+        }
+    }
+
+    targets
+}
+
+/// TLS settings to probe each target with, taken from a route that
+/// references it.
+fn target_tls(map: &RouterMap) -> HashMap<String, UpstreamTlsConfig> {
+    let mut targets = HashMap::new();
+
+    for route in map.values() {
+        let Some(tls) = &route.upstream_tls else {
+            continue;
+        };
+        for target in route.targets().iter() {
+            targets.entry(target.url.clone()).or_insert_with(|| tls.clone());
+        }
+    }
+
+    targets
+}
+
+/// Whether a proxy result should count against the target's health.
+///
+/// Connection failures, timeouts and 5xx responses count; client errors do not.
+pub fn is_upstream_failure(status: Option<u16>, error: Option<&GatewayError>) -> bool {
+    match (status, error) {
+        (_, Some(e)) => e.category() == "upstream",
+        (Some(code), None) => code >= 500,
+        (None, None) => false,
+    }
+}
+
+/// Run the active health checker until the task is dropped.
+///
+/// Re-reads the routing table every tick, so targets added or removed by a
+/// configuration reload are picked up without restarting the checker.
+pub async fn run_health_checker(state: Arc<GatewayState>) {
+    tracing::info!("Starting upstream health checker");
+
+    let mut ticker = tokio::time::interval(CHECK_TICK);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let map = state.config.load();
+        let referenced: HashSet<String> = map
+            .values()
+            .flat_map(|r| r.targets().iter().map(|t| t.url.clone()).collect::<Vec<_>>())
+            .collect();
+        state.health.retain(&referenced);
+        let mut tls = target_tls(&map);
+
+        for (url, check) in state.health.take_due(&probe_targets(&map)) {
+            let state = state.clone();
+            let tls = tls.remove(&url);
+            tokio::spawn(async move {
+                let timeout = Duration::from_millis(check.timeout_ms);
+                let result = match state.client.probe(&url, &check.path, tls.as_ref(), timeout).await {
+                    Ok(status) if (200..400).contains(&status) => Ok(()),
+                    Ok(status) => Err(format!("health check returned {}", status)),
+                    Err(e) => Err(e.to_string()),
+                };
+
+                tracing::trace!(upstream = %url, ok = result.is_ok(), "Health probe finished");
+                state.health.record_probe(&url, result, &check);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Route;
+    use crate::router::build_router_map;
+
+    const URL: &str = "http://backend:8080";
+
+    #[test]
+    fn test_active_thresholds() {
+        let registry = HealthRegistry::new();
+        let check = HealthCheck {
+            healthy_threshold: 2,
+            unhealthy_threshold: 2,
+            ..HealthCheck::default()
+        };
+
+        registry.record_probe(URL, Err("refused".into()), &check);
+        assert!(registry.is_available(URL));
+        registry.record_probe(URL, Err("refused".into()), &check);
+        assert!(!registry.is_available(URL));
+
+        registry.record_probe(URL, Ok(()), &check);
+        assert!(!registry.is_available(URL));
+        registry.record_probe(URL, Ok(()), &check);
+        assert!(registry.is_available(URL));
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let registry = HealthRegistry::new();
+        let outlier = OutlierDetection {
+            consecutive_errors: 3,
+            ejection_ms: 60_000,
+        };
+
+        registry.record_request(URL, false, None, &outlier);
+        registry.record_request(URL, false, None, &outlier);
+        // A success resets the streak
+        registry.record_request(URL, true, None, &outlier);
+        registry.record_request(URL, false, None, &outlier);
+        registry.record_request(URL, false, None, &outlier);
+        assert!(registry.is_available(URL));
+
+        registry.record_request(URL, false, Some("502".into()), &outlier);
+        assert!(!registry.is_available(URL));
+    }
+
+    #[test]
+    fn test_ejection_expires() {
+        let registry = HealthRegistry::new();
+        let outlier = OutlierDetection {
+            consecutive_errors: 1,
+            ejection_ms: 0,
+        };
+
+        registry.record_request(URL, false, None, &outlier);
+        assert!(registry.is_available(URL));
+    }
+
+    #[test]
+    fn test_snapshot_lists_route_targets() {
+        let mut checked = Route::new("a", "/a", URL);
+        checked.health_check = Some(HealthCheck::default());
+        let map = build_router_map(vec![checked, Route::new("b", "/b", URL), Route::new("c", "/c", "http://other:80")]);
+
+        let registry = HealthRegistry::new();
+        registry.record_request(
+            "http://other:80",
+            false,
+            Some("connection refused".into()),
+            &OutlierDetection { consecutive_errors: 1, ejection_ms: 60_000 },
+        );
+
+        let report = registry.snapshot(&map);
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].url, URL);
+        assert_eq!(report[0].routes, vec!["a", "b"]);
+        assert!(report[0].active_checks && report[0].available);
+
+        assert!(!report[1].available);
+        assert!(report[1].ejected_for_ms.is_some());
+        assert_eq!(report[1].last_error.as_deref(), Some("connection refused"));
+    }
+
+    #[test]
+    fn test_is_upstream_failure() {
+        assert!(is_upstream_failure(Some(503), None));
+        assert!(!is_upstream_failure(Some(404), None));
+        assert!(is_upstream_failure(
+            None,
+            Some(&GatewayError::RequestTimeout { upstream: URL.into(), timeout_ms: 5 })
+        ));
+        assert!(!is_upstream_failure(None, Some(&GatewayError::PayloadTooLarge { size: 1, limit: 0 })));
+    }
+}
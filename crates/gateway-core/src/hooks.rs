@@ -0,0 +1,603 @@
+//! Route scripts on live traffic.
+//!
+//! A route's [`ScriptConfig`] is compiled into [`RouteHooks`] when the
+//! routing table is built, so each configuration reload compiles its
+//! scripts once and requests only run them. A [`HookRun`] carries one
+//! request through the route's request and response scripts.
+//!
+//! Scripts see requests and responses as maps:
+//!
+//! ```text
+//! request  = #{ method, path, params, query, headers, body }
+//! response = #{ status, headers, body }
+//! ```
+//!
+//! Header names are lower case. A header or query parameter that appears
+//! once is a string and one that is repeated is an array of its values, so
+//! e.g. every `Set-Cookie` survives a script changing the headers. Bodies
+//! that parse as JSON are maps, arrays or values; other bodies are strings
+//! and empty bodies are `()`. Only the parts a script changes are written
+//! back: a changed string body is sent as is and any other changed body as
+//! JSON, and the query string is only rebuilt when `query` itself changed.
+//!
+//! Response scripts are skipped on streaming routes and for
+//! `text/event-stream` responses, whose bodies are never buffered.
+
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use rhai::{Dynamic, Scope};
+use serde_json::{json, Map, Value as JsonValue};
+
+use crate::body::{collect_body_limited, empty, full, GatewayBody, DEFAULT_MAX_BODY_SIZE};
+use crate::cache::is_event_stream;
+use crate::compression::decode_body;
+use crate::config::{Route, ScriptConfig};
+use crate::error::GatewayError;
+use crate::filter::buffered_body;
+use crate::path::PathParams;
+use crate::tls::{ClientCertificate, TlsConnection};
+use crate::scripting::{client_cert_map, dynamic_to_json, json_to_dynamic, Script, ScriptEngine, ScriptError};
+
+/// A route's compiled request and response scripts.
+pub struct RouteHooks {
+    request: Option<Script>,
+    response: Option<Script>,
+}
+
+impl fmt::Debug for RouteHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RouteHooks")
+            .field("request", &self.request.as_ref().map(Script::source))
+            .field("response", &self.response.as_ref().map(Script::source))
+            .finish()
+    }
+}
+
+impl RouteHooks {
+    /// Compile a route's scripts. Their runs are counted as
+    /// `<route_id>.request` and `<route_id>.response` in the script metrics.
+    pub fn compile(route_id: &str, config: &ScriptConfig) -> Result<Self, ScriptError> {
+        let engine = ScriptEngine::new(config.limits.clone());
+        let compile = |source: &Option<String>, phase: &str| {
+            source
+                .as_deref()
+                .map(|source| engine.compile(source))
+                .transpose()
+                .map(|script| script.map(|script| script.named(format!("{}.{}", route_id, phase))))
+        };
+        Ok(Self {
+            request: compile(&config.request, "request")?,
+            response: compile(&config.response, "response")?,
+        })
+    }
+
+    /// Start running the hooks for a request on `route`
+    pub fn start(self: &Arc<Self>, route: &Route, req: &Request<GatewayBody>) -> HookRun {
+        let params = req.extensions().get::<PathParams>().map(|params| {
+            params
+                .iter()
+                .map(|(name, value)| (name.to_string(), JsonValue::from(value)))
+                .collect::<Map<_, _>>()
+        });
+        let query = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .map(|(name, value)| (name.into_owned(), value.into_owned()));
+
+        let request = json!({
+            "method": req.method().as_str(),
+            "path": req.uri().path(),
+            "params": params.unwrap_or_default(),
+            "query": pairs_to_json(query),
+            "headers": headers_to_json(req.headers()),
+            "body": null,
+        });
+
+        HookRun {
+            hooks: self.clone(),
+            request,
+            client_cert: req.extensions().get::<TlsConnection>().and_then(|tls| tls.client_cert.clone()),
+            upstream: None,
+            streaming: route.streaming.is_some(),
+            max_request_body: route.max_request_body(),
+            max_response_body: route.max_response_body_bytes.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+        }
+    }
+}
+
+/// One request's pass through a route's scripts.
+pub struct HookRun {
+    hooks: Arc<RouteHooks>,
+    /// The request as the scripts see it, after the request script
+    request: JsonValue,
+    client_cert: Option<ClientCertificate>,
+    upstream: Option<String>,
+    /// Whether the route streams responses, which are then left alone
+    streaming: bool,
+    max_request_body: usize,
+    max_response_body: usize,
+}
+
+impl HookRun {
+    /// Upstream URL chosen by the request script, replacing the route's
+    /// targets
+    pub fn upstream(&self) -> Option<&str> {
+        self.upstream.as_deref()
+    }
+
+    /// Run the request script and apply its changes to the request.
+    /// Returns the response to answer with if the script set one.
+    pub async fn on_request(
+        &mut self,
+        req: &mut Request<GatewayBody>,
+    ) -> Result<Option<Response<GatewayBody>>, GatewayError> {
+        let Some(script) = &self.hooks.request else {
+            return Ok(None);
+        };
+
+        let body = std::mem::replace(req.body_mut(), empty());
+        let bytes = collect_body_limited(body, self.max_request_body).await?;
+        let bytes = decode_body(req.headers_mut(), bytes, self.max_request_body).await?;
+        self.request["body"] = body_to_json(&bytes);
+
+        let mut scope = self.scope();
+        scope.push("response", Dynamic::UNIT);
+        run(script, &mut scope)?;
+
+        let response = scope.get_value::<Dynamic>("response").unwrap_or_default();
+        if !response.is_unit() {
+            let response = script_map(&response, "response")?;
+            let mut answer = Response::new(empty());
+            apply_response(&mut answer, &JsonValue::Null, &response)?;
+            tracing::debug!(status = answer.status().as_u16(), "Request answered by route script");
+            return Ok(Some(answer));
+        }
+
+        let after = script_map(&scope.get_value::<Dynamic>("request").unwrap_or_default(), "request")?;
+        let before = std::mem::replace(&mut self.request, after);
+        let after = &self.request;
+
+        if let Some(method) = changed(&before, after, "method") {
+            let method = method.as_str().and_then(|m| Method::from_bytes(m.as_bytes()).ok());
+            *req.method_mut() = method.ok_or_else(|| invalid("request.method must be an HTTP method"))?;
+        }
+        let query_changed = changed(&before, after, "query").is_some();
+        if query_changed || changed(&before, after, "path").is_some() {
+            *req.uri_mut() = script_uri(req.uri(), after, query_changed)?;
+        }
+        if let Some(headers) = changed(&before, after, "headers") {
+            set_headers(req.headers_mut(), headers)?;
+        }
+        *req.body_mut() = match changed(&before, after, "body") {
+            Some(body) => set_body(req.headers_mut(), body),
+            None => full(bytes),
+        };
+
+        self.upstream = match after.get("upstream") {
+            None | Some(JsonValue::Null) => None,
+            Some(JsonValue::String(url)) if url.starts_with("http://") || url.starts_with("https://") => {
+                Some(url.clone())
+            }
+            Some(_) => return Err(invalid("request.upstream must be an http:// or https:// URL")),
+        };
+        Ok(None)
+    }
+
+    /// Run the response script and apply its changes to the response.
+    /// Protocol switches and streamed responses are passed through
+    /// untouched.
+    pub async fn on_response(&mut self, response: &mut Response<GatewayBody>) -> Result<(), GatewayError> {
+        let Some(script) = &self.hooks.response else {
+            return Ok(());
+        };
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            return Ok(());
+        }
+        if self.streaming || is_event_stream(response.headers()) {
+            tracing::debug!("Skipping response script for a streamed response");
+            return Ok(());
+        }
+
+        let body = std::mem::replace(response.body_mut(), empty());
+        let bytes = collect_body_limited(body, self.max_response_body)
+            .await
+            .map_err(GatewayError::for_response_body)?;
+        let bytes = decode_body(response.headers_mut(), bytes, self.max_response_body)
+            .await
+            .map_err(GatewayError::for_response_body)?;
+
+        let before = json!({
+            "status": response.status().as_u16(),
+            "headers": headers_to_json(response.headers()),
+            "body": body_to_json(&bytes),
+        });
+        *response.body_mut() = full(bytes);
+
+        let mut scope = self.scope();
+        scope.push("response", json_to_dynamic(&before));
+        run(script, &mut scope)?;
+
+        let after = script_map(&scope.get_value::<Dynamic>("response").unwrap_or_default(), "response")?;
+        apply_response(response, &before, &after)
+    }
+
+    /// Scope shared by both scripts
+    fn scope(&self) -> Scope<'static> {
+        let client_cert = match &self.client_cert {
+            Some(cert) => Dynamic::from_map(client_cert_map(cert)),
+            None => Dynamic::UNIT,
+        };
+
+        let mut scope = Scope::new();
+        scope.push("request", json_to_dynamic(&self.request));
+        scope.push("client_cert", client_cert);
+        scope
+    }
+}
+
+fn run(script: &Script, scope: &mut Scope) -> Result<(), GatewayError> {
+    script.run_with_scope(scope).map_err(|e| GatewayError::TransformFailed(e.to_string()))
+}
+
+fn invalid(message: &str) -> GatewayError {
+    GatewayError::TransformFailed(format!("route script: {}", message))
+}
+
+/// A map the script left in scope, as JSON
+fn script_map(value: &Dynamic, name: &str) -> Result<JsonValue, GatewayError> {
+    dynamic_to_json(value)
+        .filter(JsonValue::is_object)
+        .ok_or_else(|| invalid(&format!("{} must be a map", name)))
+}
+
+/// The value under `key` in `after` if the script changed it
+fn changed<'a>(before: &JsonValue, after: &'a JsonValue, key: &str) -> Option<&'a JsonValue> {
+    let value = after.get(key)?;
+    (before.get(key) != Some(value)).then_some(value)
+}
+
+/// Apply a script's response map. Everything in it counts as changed when
+/// `before` is null.
+fn apply_response(
+    response: &mut Response<GatewayBody>,
+    before: &JsonValue,
+    after: &JsonValue,
+) -> Result<(), GatewayError> {
+    if let Some(status) = changed(before, after, "status") {
+        let status = status.as_u64().and_then(|s| StatusCode::from_u16(u16::try_from(s).ok()?).ok());
+        *response.status_mut() = status.ok_or_else(|| invalid("response.status must be an HTTP status code"))?;
+    }
+    if let Some(headers) = changed(before, after, "headers") {
+        set_headers(response.headers_mut(), headers)?;
+    }
+    if let Some(body) = changed(before, after, "body") {
+        *response.body_mut() = set_body(response.headers_mut(), body);
+    }
+    Ok(())
+}
+
+/// Request URI with the script's path, and its query when `query_changed`
+/// (the original query string otherwise)
+fn script_uri(uri: &Uri, request: &JsonValue, query_changed: bool) -> Result<Uri, GatewayError> {
+    let path = request
+        .get("path")
+        .and_then(JsonValue::as_str)
+        .filter(|path| path.starts_with('/'))
+        .ok_or_else(|| invalid("request.path must start with '/'"))?;
+
+    let query = if query_changed {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in request.get("query").and_then(JsonValue::as_object).into_iter().flatten() {
+            let values = match value {
+                JsonValue::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    JsonValue::Null => {}
+                    JsonValue::String(value) => {
+                        query.append_pair(name, value);
+                    }
+                    value => {
+                        query.append_pair(name, &value.to_string());
+                    }
+                }
+            }
+        }
+        query.finish()
+    } else {
+        uri.query().unwrap_or_default().to_string()
+    };
+
+    let path_and_query = if query.is_empty() { path.to_string() } else { format!("{}?{}", path, query) };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().map_err(|_| invalid("request.path is not a valid URI path"))?);
+    Uri::from_parts(parts).map_err(|_| invalid("request.path is not a valid URI path"))
+}
+
+/// Headers as a map of lower-case names to values
+fn headers_to_json(headers: &HeaderMap) -> JsonValue {
+    pairs_to_json(
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned())),
+    )
+}
+
+/// Name/value pairs as a map, with the values of repeated names in arrays
+fn pairs_to_json(pairs: impl IntoIterator<Item = (String, String)>) -> JsonValue {
+    let mut map = Map::new();
+    for (name, value) in pairs {
+        match map.get_mut(&name) {
+            Some(JsonValue::Array(values)) => values.push(JsonValue::String(value)),
+            Some(first) => *first = JsonValue::Array(vec![first.take(), JsonValue::String(value)]),
+            None => {
+                map.insert(name, JsonValue::String(value));
+            }
+        }
+    }
+    JsonValue::Object(map)
+}
+
+/// Replace all headers with a script's header map. Values are strings or
+/// arrays of strings; `()` drops the header.
+fn set_headers(headers: &mut HeaderMap, map: &JsonValue) -> Result<(), GatewayError> {
+    let map = map.as_object().ok_or_else(|| invalid("headers must be a map"))?;
+
+    let mut replaced = HeaderMap::with_capacity(map.len());
+    for (name, value) in map {
+        let name =
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid(&format!("bad header name '{}'", name)))?;
+        let values = match value {
+            JsonValue::Null => continue,
+            JsonValue::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let text = match value {
+                JsonValue::String(text) => text.clone(),
+                value => value.to_string(),
+            };
+            let value = HeaderValue::try_from(text).map_err(|_| invalid(&format!("bad value for header '{}'", name)))?;
+            replaced.append(name.clone(), value);
+        }
+    }
+    *headers = replaced;
+    Ok(())
+}
+
+/// A body as scripts see it
+fn body_to_json(bytes: &Bytes) -> JsonValue {
+    if bytes.is_empty() {
+        return JsonValue::Null;
+    }
+    serde_json::from_slice(bytes).unwrap_or_else(|_| JsonValue::String(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+/// Body for a script's value: strings as is, anything else as JSON.
+/// A content type is added when there is none.
+fn set_body(headers: &mut HeaderMap, body: &JsonValue) -> GatewayBody {
+    let (bytes, content_type) = match body {
+        JsonValue::Null => (Bytes::new(), None),
+        JsonValue::String(text) => (Bytes::from(text.clone()), Some("text/plain; charset=utf-8")),
+        value => (Bytes::from(value.to_string()), Some("application/json")),
+    };
+    if let Some(content_type) = content_type.filter(|_| !headers.contains_key(CONTENT_TYPE)) {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    buffered_body(headers, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http_body_util::BodyExt;
+
+    fn hooks(request: Option<&str>, response: Option<&str>) -> Arc<RouteHooks> {
+        let config = ScriptConfig {
+            request: request.map(str::to_string),
+            response: response.map(str::to_string),
+            ..Default::default()
+        };
+        Arc::new(RouteHooks::compile("orders", &config).unwrap())
+    }
+
+    fn request(uri: &str, body: &str) -> Request<GatewayBody> {
+        let mut req = Request::post(uri)
+            .header("content-type", "application/json")
+            .header("x-tenant", "acme")
+            .body(full(body.to_string()))
+            .unwrap();
+        let pattern = crate::path::PathPattern::parse("/orders/{id}").unwrap();
+        let params = pattern.matches(req.uri().path()).unwrap();
+        req.extensions_mut().insert(params);
+        req
+    }
+
+    async fn text(body: GatewayBody) -> String {
+        String::from_utf8(body.collect().await.unwrap().to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_script() {
+        let orders = hooks(
+            Some(
+                r#"
+                request.method = "PUT";
+                request.path = "/v2/orders/" + request.params.id;
+                request.query.expand = "items";
+                request.headers["x-tenant"] = upper(request.headers["x-tenant"]);
+                request.body.total = request.body.qty * 2;
+                if request.query.region == "eu" { request.upstream = "http://orders-eu:8080"; }
+                "#,
+            ),
+            None,
+        );
+        let route = Route::new("orders", "/orders/{id}", "http://orders:8080");
+
+        let mut req = request("/orders/42?region=eu", r#"{"qty": 3}"#);
+        let mut run = orders.start(&route, &req);
+        assert!(run.on_request(&mut req).await.unwrap().is_none());
+
+        assert_eq!(req.method(), Method::PUT);
+        assert_eq!(req.uri(), "/v2/orders/42?expand=items&region=eu");
+        assert_eq!(req.headers()["x-tenant"], "ACME");
+        assert_eq!(run.upstream(), Some("http://orders-eu:8080"));
+        let body: JsonValue = serde_json::from_str(&text(req.into_body()).await).unwrap();
+        assert_eq!(body, json!({"qty": 3, "total": 6}));
+
+        // Untouched requests pass through as they came
+        let mut req = request("/orders/7?region=us", "not json");
+        let mut run = hooks(Some("let seen = request.body;"), None).start(&route, &req);
+        assert!(run.on_request(&mut req).await.unwrap().is_none());
+        assert_eq!(req.uri(), "/orders/7?region=us");
+        assert_eq!(run.upstream(), None);
+        assert_eq!(text(req.into_body()).await, "not json");
+    }
+
+    #[tokio::test]
+    async fn test_short_circuit() {
+        let hooks = hooks(
+            Some(
+                r#"
+                if request.headers["x-tenant"] != "internal" {
+                    response = #{ status: 403, body: #{ denied: request.params.id } };
+                }
+                "#,
+            ),
+            None,
+        );
+        let route = Route::new("orders", "/orders/{id}", "http://orders:8080");
+
+        let mut req = request("/orders/42", "");
+        let answer = hooks.start(&route, &req).on_request(&mut req).await.unwrap().unwrap();
+        assert_eq!(answer.status(), StatusCode::FORBIDDEN);
+        assert_eq!(answer.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(text(answer.into_body()).await, r#"{"denied":"42"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_response_script() {
+        let hooks = hooks(
+            Some(r#"request.headers["x-seen"] = "yes";"#),
+            Some(
+                r#"
+                response.status = 201;
+                response.headers["x-order"] = request.params.id + "/" + request.headers["x-seen"];
+                response.body = #{ data: response.body, wrapped: true };
+                "#,
+            ),
+        );
+        let route = Route::new("orders", "/orders/{id}", "http://orders:8080");
+
+        let mut req = request("/orders/42", "");
+        let mut run = hooks.start(&route, &req);
+        assert!(run.on_request(&mut req).await.unwrap().is_none());
+
+        let mut response = Response::builder()
+            .header("content-type", "application/json")
+            .body(full(r#"[1, 2]"#))
+            .unwrap();
+        run.on_response(&mut response).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-order"], "42/yes");
+        let body: JsonValue = serde_json::from_str(&text(response.into_body()).await).unwrap();
+        assert_eq!(body, json!({"data": [1, 2], "wrapped": true}));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_headers_and_query() {
+        let hooks = hooks(
+            Some(
+                r#"
+                request.path = "/v2" + request.path;
+                request.headers["x-tags"] = request.query.tag.len().to_string();
+                "#,
+            ),
+            Some(r#"response.headers["x-cookies"] = response.headers["set-cookie"].len().to_string();"#),
+        );
+        let route = Route::new("orders", "/orders/{id}", "http://orders:8080");
+
+        // Changing the path keeps the query string as it was
+        let mut req = request("/orders/42?tag=b&tag=a&page=2", "");
+        let mut run = hooks.start(&route, &req);
+        assert!(run.on_request(&mut req).await.unwrap().is_none());
+        assert_eq!(req.uri(), "/v2/orders/42?tag=b&tag=a&page=2");
+        assert_eq!(req.headers()["x-tags"], "2");
+
+        let mut response = Response::builder()
+            .header("set-cookie", "a=1; Path=/")
+            .header("set-cookie", "b=2, c; Path=/")
+            .body(full(""))
+            .unwrap();
+        run.on_response(&mut response).await.unwrap();
+        assert_eq!(response.headers()["x-cookies"], "2");
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, ["a=1; Path=/", "b=2, c; Path=/"]);
+
+        // A changed query is rebuilt, arrays as repeated parameters
+        let mut req = request("/orders/42?tag=b", "");
+        let mut run = self::hooks(Some(r#"request.query.tag = ["b", "c"];"#), None).start(&route, &req);
+        assert!(run.on_request(&mut req).await.unwrap().is_none());
+        assert_eq!(req.uri(), "/orders/42?tag=b&tag=c");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_responses_skip_script() {
+        let hooks = hooks(None, Some("response.status = 500;"));
+        let endless = || {
+            let stream = futures::stream::pending::<Result<hyper::body::Frame<Bytes>, crate::body::BoxError>>();
+            http_body_util::StreamBody::new(stream).boxed_unsync()
+        };
+
+        let mut route = Route::new("events", "/events", "http://events:8080");
+        let req = request("/orders/1", "");
+        let mut response = Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(endless())
+            .unwrap();
+        hooks.start(&route, &req).on_response(&mut response).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        route.streaming = Some(crate::config::StreamingConfig { idle_timeout_ms: 1_000 });
+        let mut response = Response::new(endless());
+        hooks.start(&route, &req).on_response(&mut response).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_script_errors() {
+        let route = Route::new("orders", "/orders/{id}", "http://orders:8080");
+
+        let broken = ScriptConfig {
+            request: Some("let x = ;".to_string()),
+            ..Default::default()
+        };
+        assert!(RouteHooks::compile("orders", &broken).is_err());
+
+        for script in [r#"throw "nope";"#, r#"request.upstream = "ftp://x";"#, r#"request.method = "NO PE";"#] {
+            let mut req = request("/orders/1", "");
+            let result = hooks(Some(script), None).start(&route, &req).on_request(&mut req).await;
+            assert!(matches!(result, Err(GatewayError::TransformFailed(_))), "{}", script);
+        }
+
+        let mut route = route;
+        route.max_response_body_bytes = Some(4);
+        let req = request("/orders/1", "");
+        let mut run = hooks(None, Some("response.status = 200;")).start(&route, &req);
+        let mut response = Response::new(full("too long"));
+        let result = run.on_response(&mut response).await;
+        assert!(matches!(result, Err(GatewayError::ResponseTooLarge { .. })));
+
+        // So is a body the upstream encoded badly
+        let mut run = hooks(None, Some("response.status = 200;")).start(&route, &req);
+        let mut response = Response::new(full("bad"));
+        response.headers_mut().insert("content-encoding", HeaderValue::from_static("gzip"));
+        let error = run.on_response(&mut response).await.unwrap_err();
+        assert!(matches!(error, GatewayError::InvalidResponseBody(_)));
+        assert_eq!(error.status_code(), 502);
+    }
+}
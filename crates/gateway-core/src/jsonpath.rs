@@ -0,0 +1,493 @@
+//! JSONPath queries over JSON values.
+//!
+//! Scripts use these through `json_path` and `json_path_first`. The
+//! supported syntax covers what integrations usually need:
+//!
+//! | Syntax                  | Selects                                         |
+//! |-------------------------|-------------------------------------------------|
+//! | `$`                     | the root (may be left out: `a.b` is `$.a.b`)    |
+//! | `.name`, `['name']`     | a member of an object                           |
+//! | `[0]`, `[-1]`           | an array element, negative from the end         |
+//! | `.*`, `[*]`             | every member or element                         |
+//! | `[1:3]`, `[:2]`, `[-2:]`| a slice of an array                             |
+//! | `['a','b']`, `[0,2]`    | several members or elements                     |
+//! | `..name`, `..*`         | the selector applied at every depth             |
+//! | `[?(@.price < 10)]`     | elements whose `@` path compares with a literal |
+//! | `[?(@.isbn)]`           | elements where the `@` path exists              |
+//!
+//! Filters compare with `==`, `!=`, `<`, `<=`, `>` and `>=` against a
+//! number, a quoted string, `true`, `false` or `null`.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde_json::Value as JsonValue;
+
+/// Error for malformed JSONPath expressions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPathError(pub String);
+
+impl fmt::Display for JsonPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid JSONPath: {}", self.0)
+    }
+}
+
+impl std::error::Error for JsonPathError {}
+
+/// A parsed JSONPath expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// Apply the selector to the current values
+    Child(Selector),
+    /// Apply the selector to the current values and all their descendants
+    Descendant(Selector),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Selector {
+    Name(String),
+    Index(i64),
+    Wildcard,
+    Slice(Option<i64>, Option<i64>),
+    Union(Vec<Selector>),
+    Filter(Filter),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    /// Names and indexes under `@`
+    path: Vec<Selector>,
+    /// Comparison with a literal, or `None` for an existence test
+    test: Option<(Op, JsonValue)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl JsonPath {
+    /// Parse an expression
+    pub fn parse(path: &str) -> Result<Self, JsonPathError> {
+        let trimmed = path.trim();
+        let rest = match trimmed.strip_prefix('$') {
+            Some(rest) => rest,
+            None if trimmed.starts_with('[') || trimmed.starts_with('.') => trimmed,
+            None => return Self::parse(&format!("$.{}", trimmed)),
+        };
+
+        let segments = Parser { path, rest }.segments()?;
+        Ok(Self { segments })
+    }
+
+    /// Every value the expression selects in `root`, in document order
+    pub fn query<'a>(&self, root: &'a JsonValue) -> Vec<&'a JsonValue> {
+        let mut current = vec![root];
+        for segment in &self.segments {
+            let mut next = Vec::new();
+            for value in current {
+                match segment {
+                    Segment::Child(selector) => selector.select(value, &mut next),
+                    Segment::Descendant(selector) => selector.descend(value, &mut next),
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    /// The first value the expression selects in `root`
+    pub fn first<'a>(&self, root: &'a JsonValue) -> Option<&'a JsonValue> {
+        self.query(root).into_iter().next()
+    }
+}
+
+impl Selector {
+    fn select<'a>(&self, value: &'a JsonValue, out: &mut Vec<&'a JsonValue>) {
+        match (self, value) {
+            (Selector::Name(name), JsonValue::Object(object)) => out.extend(object.get(name)),
+            (Selector::Index(index), JsonValue::Array(array)) => out.extend(element(array, *index)),
+            (Selector::Wildcard, JsonValue::Array(array)) => out.extend(array),
+            (Selector::Wildcard, JsonValue::Object(object)) => out.extend(object.values()),
+            (Selector::Slice(start, end), JsonValue::Array(array)) => {
+                let len = array.len() as i64;
+                let clamp = |i: i64| if i < 0 { (len + i).max(0) } else { i.min(len) };
+                let start = start.map_or(0, clamp);
+                let end = end.map_or(len, clamp);
+                if start < end {
+                    out.extend(&array[start as usize..end as usize]);
+                }
+            }
+            (Selector::Union(selectors), _) => {
+                for selector in selectors {
+                    selector.select(value, out);
+                }
+            }
+            (Selector::Filter(filter), JsonValue::Array(array)) => {
+                out.extend(array.iter().filter(|item| filter.matches(item)))
+            }
+            (Selector::Filter(filter), JsonValue::Object(object)) => {
+                out.extend(object.values().filter(|item| filter.matches(item)))
+            }
+            _ => {}
+        }
+    }
+
+    fn descend<'a>(&self, value: &'a JsonValue, out: &mut Vec<&'a JsonValue>) {
+        self.select(value, out);
+        match value {
+            JsonValue::Array(array) => array.iter().for_each(|item| self.descend(item, out)),
+            JsonValue::Object(object) => object.values().for_each(|item| self.descend(item, out)),
+            _ => {}
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, item: &JsonValue) -> bool {
+        let mut current = item;
+        for selector in &self.path {
+            let next = match (selector, current) {
+                (Selector::Name(name), JsonValue::Object(object)) => object.get(name),
+                (Selector::Index(index), JsonValue::Array(array)) => element(array, *index),
+                _ => None,
+            };
+            match next {
+                Some(next) => current = next,
+                None => return false,
+            }
+        }
+
+        let Some((op, literal)) = &self.test else {
+            return true;
+        };
+        let ordering = match (current, literal) {
+            (JsonValue::Number(a), JsonValue::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+            (JsonValue::String(a), JsonValue::String(b)) => Some(a.cmp(b)),
+            (a, b) => (a == b).then_some(Ordering::Equal),
+        };
+        match op {
+            Op::Eq => ordering == Some(Ordering::Equal),
+            Op::Ne => ordering != Some(Ordering::Equal),
+            Op::Lt => ordering == Some(Ordering::Less),
+            Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => ordering == Some(Ordering::Greater),
+            Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Array element at `index`, counting from the end when negative
+fn element(array: &[JsonValue], index: i64) -> Option<&JsonValue> {
+    let index = if index < 0 { array.len() as i64 + index } else { index };
+    usize::try_from(index).ok().and_then(|index| array.get(index))
+}
+
+struct Parser<'a> {
+    path: &'a str,
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonPathError {
+        JsonPathError(format!("{} at offset {} in '{}'", message, self.path.len() - self.rest.len(), self.path))
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), JsonPathError> {
+        self.skip_whitespace();
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", token)))
+        }
+    }
+
+    fn segments(mut self) -> Result<Vec<Segment>, JsonPathError> {
+        let mut segments = Vec::new();
+        while !self.rest.is_empty() {
+            if self.eat("..") {
+                let selector = if self.rest.starts_with('[') { self.bracket()? } else { self.dotted()? };
+                segments.push(Segment::Descendant(selector));
+            } else if self.eat(".") {
+                segments.push(Segment::Child(self.dotted()?));
+            } else if self.rest.starts_with('[') {
+                segments.push(Segment::Child(self.bracket()?));
+            } else {
+                return Err(self.error("expected '.' or '['"));
+            }
+        }
+        Ok(segments)
+    }
+
+    /// `*` or a name after a dot
+    fn dotted(&mut self) -> Result<Selector, JsonPathError> {
+        if self.eat("*") {
+            Ok(Selector::Wildcard)
+        } else {
+            self.name().map(Selector::Name)
+        }
+    }
+
+    fn name(&mut self) -> Result<String, JsonPathError> {
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '$' || c == '@'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return Err(self.error("expected a name"));
+        }
+        let (name, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(name.to_string())
+    }
+
+    fn bracket(&mut self) -> Result<Selector, JsonPathError> {
+        self.expect("[")?;
+        self.skip_whitespace();
+        let selector = if self.eat("*") {
+            Selector::Wildcard
+        } else if self.eat("?") {
+            self.filter()?
+        } else {
+            self.union()?
+        };
+        self.expect("]")?;
+        Ok(selector)
+    }
+
+    fn union(&mut self) -> Result<Selector, JsonPathError> {
+        let mut selectors = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let selector = if self.rest.starts_with(['\'', '"']) {
+                Selector::Name(self.string()?)
+            } else {
+                let start = self.integer()?;
+                self.skip_whitespace();
+                if self.eat(":") {
+                    self.skip_whitespace();
+                    Selector::Slice(start, self.integer()?)
+                } else {
+                    Selector::Index(start.ok_or_else(|| self.error("expected a name, index or slice"))?)
+                }
+            };
+            selectors.push(selector);
+
+            self.skip_whitespace();
+            if !self.eat(",") {
+                break;
+            }
+        }
+        Ok(match selectors.len() {
+            1 => selectors.remove(0),
+            _ => Selector::Union(selectors),
+        })
+    }
+
+    fn integer(&mut self) -> Result<Option<i64>, JsonPathError> {
+        let end = self
+            .rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+            .map_or(self.rest.len(), |(i, _)| i);
+        if end == 0 {
+            return Ok(None);
+        }
+        let number = self.rest[..end].parse().map_err(|_| self.error("expected an integer"))?;
+        self.rest = &self.rest[end..];
+        Ok(Some(number))
+    }
+
+    fn string(&mut self) -> Result<String, JsonPathError> {
+        let rest = self.rest;
+        let mut chars = rest.char_indices();
+        let Some((_, quote)) = chars.next() else {
+            return Err(self.error("expected a string"));
+        };
+
+        let mut value = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                c if c == quote => {
+                    self.rest = &rest[i + 1..];
+                    return Ok(value);
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    /// A filter, after its `?`
+    fn filter(&mut self) -> Result<Selector, JsonPathError> {
+        self.skip_whitespace();
+        let parenthesized = self.eat("(");
+        self.expect("@")?;
+
+        let mut path = Vec::new();
+        loop {
+            if self.eat(".") {
+                path.push(Selector::Name(self.name()?));
+            } else if self.eat("[") {
+                self.skip_whitespace();
+                let selector = if self.rest.starts_with(['\'', '"']) {
+                    Selector::Name(self.string()?)
+                } else {
+                    Selector::Index(self.integer()?.ok_or_else(|| self.error("expected a name or index"))?)
+                };
+                self.expect("]")?;
+                path.push(selector);
+            } else {
+                break;
+            }
+        }
+
+        self.skip_whitespace();
+        let ops = [("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)];
+        let op = ops.into_iter().find(|(token, _)| self.eat(token)).map(|(_, op)| op);
+        let test = match op {
+            Some(op) => {
+                self.skip_whitespace();
+                Some((op, self.literal()?))
+            }
+            None => None,
+        };
+
+        if parenthesized {
+            self.expect(")")?;
+        }
+        Ok(Selector::Filter(Filter { path, test }))
+    }
+
+    fn literal(&mut self) -> Result<JsonValue, JsonPathError> {
+        if self.rest.starts_with(['\'', '"']) {
+            return self.string().map(JsonValue::String);
+        }
+        let keywords = [("true", JsonValue::Bool(true)), ("false", JsonValue::Bool(false)), ("null", JsonValue::Null)];
+        for (keyword, value) in keywords {
+            if self.eat(keyword) {
+                return Ok(value);
+            }
+        }
+
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(self.rest.len());
+        match serde_json::from_str::<JsonValue>(&self.rest[..end]) {
+            Ok(number @ JsonValue::Number(_)) => {
+                self.rest = &self.rest[end..];
+                Ok(number)
+            }
+            _ => Err(self.error("expected a number, string, true, false or null")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn store() -> JsonValue {
+        json!({
+            "store": {
+                "book": [
+                    {"title": "Sayings", "price": 8.95, "category": "reference"},
+                    {"title": "Sword", "price": 12.99, "category": "fiction"},
+                    {"title": "Moby Dick", "price": 8.99, "category": "fiction", "isbn": "0-553-21311-3"},
+                    {"title": "The Lord", "price": 22.99, "category": "fiction", "isbn": "0-395-19395-8"}
+                ],
+                "bicycle": {"color": "red", "price": 19.95}
+            }
+        })
+    }
+
+    fn titles(path: &str) -> Vec<JsonValue> {
+        let store = store();
+        JsonPath::parse(path)
+            .unwrap()
+            .query(&store)
+            .into_iter()
+            .map(|book| book["title"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_names_and_indexes() {
+        let store = store();
+        let first = |path: &str| JsonPath::parse(path).unwrap().first(&store).cloned();
+
+        assert_eq!(first("$.store.bicycle.color"), Some(json!("red")));
+        assert_eq!(first("$['store']['bicycle']['price']"), Some(json!(19.95)));
+        assert_eq!(first("store.book[0].title"), Some(json!("Sayings")));
+        assert_eq!(first("$.store.book[-1].title"), Some(json!("The Lord")));
+        assert_eq!(first("$.store.book[9]"), None);
+        assert_eq!(first("$.store.missing"), None);
+        assert_eq!(first("$"), Some(store.clone()));
+    }
+
+    #[test]
+    fn test_wildcards_slices_and_unions() {
+        assert_eq!(titles("$.store.book[*]").len(), 4);
+        assert_eq!(titles("$.store.book[1:3]"), vec![json!("Sword"), json!("Moby Dick")]);
+        assert_eq!(titles("$.store.book[-2:]"), vec![json!("Moby Dick"), json!("The Lord")]);
+        assert_eq!(titles("$.store.book[:1]"), vec![json!("Sayings")]);
+        assert_eq!(titles("$.store.book[0, 3]"), vec![json!("Sayings"), json!("The Lord")]);
+
+        let store = store();
+        let colors = JsonPath::parse("$.store.bicycle['color', 'price']").unwrap();
+        assert_eq!(colors.query(&store), vec![&json!("red"), &json!(19.95)]);
+        assert_eq!(JsonPath::parse("$.store.*").unwrap().query(&store).len(), 2);
+    }
+
+    #[test]
+    fn test_descendants() {
+        let store = store();
+        let prices = JsonPath::parse("$..price").unwrap().query(&store);
+        assert_eq!(prices.len(), 5);
+        assert_eq!(JsonPath::parse("$..book[0].title").unwrap().first(&store), Some(&json!("Sayings")));
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(titles("$.store.book[?(@.price < 10)]"), vec![json!("Sayings"), json!("Moby Dick")]);
+        assert_eq!(titles("$..book[?(@.isbn)]"), vec![json!("Moby Dick"), json!("The Lord")]);
+        assert_eq!(titles("$.store.book[?(@.category == 'reference')]"), vec![json!("Sayings")]);
+        assert_eq!(titles("$.store.book[?@.category != \"fiction\"]"), vec![json!("Sayings")]);
+        assert_eq!(titles("$.store.book[?(@.price >= 22.99)]"), vec![json!("The Lord")]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for path in ["$.", "$[", "$.store[?(@.price <)]", "$.store['book", "$store", "$[1:x]"] {
+            assert!(JsonPath::parse(path).is_err(), "{}", path);
+        }
+    }
+}
@@ -5,19 +5,55 @@
 //! compilation and easier testing.
 
 pub mod body;
+pub mod cache;
+pub mod circuit;
+pub mod compression;
 pub mod config;
+pub mod connector;
 pub mod auth;
+pub mod balancer;
 pub mod error;
 pub mod executor;
+pub mod filter;
 pub mod handler;
+pub mod headers;
+pub mod hooks;
+pub mod health;
+pub mod jsonpath;
+pub mod path;
+pub mod proxy;
+pub mod radix;
+pub mod retry;
+pub mod rewrite;
 pub mod router;
+pub mod scripting;
+pub mod state;
+pub mod stdlib;
+pub mod tls;
 pub mod transform;
+pub mod websocket;
+pub mod xml;
 
-pub use config::{Route, RouterMap};
+pub use balancer::LoadBalancer;
+pub use cache::ResponseCache;
+pub use circuit::CircuitBreakers;
+pub use config::{
+    CacheConfig, CertificateConfig, CompressionConfig, ContentEncoding, LoadBalancing, MatchConditions, Route,
+    RouterMap, ScriptConfig, StreamingConfig, UpstreamTarget, UpstreamTlsConfig, WebSocketConfig,
+};
 pub use auth::{User, Role, ApiKey};
 pub use error::GatewayError;
 pub use executor::TokioExecutor;
+pub use filter::{Filter, FilterAction, FilterChain, FilterContext, FilterRegistry, TransformFilter};
 pub use handler::handle_request;
-pub use router::{build_router_map, match_route};
+pub use health::HealthRegistry;
+pub use hooks::{HookRun, RouteHooks};
+pub use proxy::UpstreamClient;
+pub use retry::RetryBudget;
+pub use path::{PathParams, PathPattern};
+pub use router::{build_router_map, find_route, find_route_for, match_route, RequestInfo, RouteError, RouteMatch};
+pub use scripting::{Script, ScriptContext, ScriptEngine, ScriptError, ScriptOutcome};
+pub use state::GatewayState;
+pub use tls::{CertStore, ClientAuth, ClientCertificate, TlsConnection};
 pub use transform::{RhaiTransformer, TransformError, TransformResult, simulate, validate_script};
 
@@ -0,0 +1,334 @@
+//! Route path templates.
+//!
+//! `Route.path` is parsed into a sequence of segments:
+//!
+//! | Syntax              | Matches                                       |
+//! |---------------------|-----------------------------------------------|
+//! | `users`             | the literal segment `users`                   |
+//! | `{id}`              | any non-empty segment, captured as `id`       |
+//! | `{id:[0-9]+}`       | a segment matching the regex, captured as `id`|
+//! | `*` (not last)      | any non-empty segment, not captured           |
+//! | `{*rest}` (last)    | zero or more remaining segments, as `rest`    |
+//! | `*` (last)          | zero or more remaining segments               |
+//! | trailing `/`        | same as a trailing `*`                        |
+//!
+//! The last two rows keep the original `/api/*` and `/api/v2/` prefix
+//! semantics. When several templates match a path, the most specific one
+//! wins (see [`PathPattern::cmp_specificity`]).
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use regex::Regex;
+
+/// Parameters captured from the request path, in template order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathParams(Vec<(String, String)>);
+
+impl PathParams {
+    /// Value of a captured parameter
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterate over `(name, value)` pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Number of captured parameters
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether nothing was captured
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+}
+
+/// Error for malformed path templates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError(pub String);
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid path template: {}", self.0)
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// One segment of a path template.
+#[derive(Debug, Clone)]
+pub enum Segment {
+    /// Exact text
+    Literal(String),
+    /// Named single-segment parameter, optionally regex-constrained
+    Param { name: String, constraint: Option<Regex> },
+    /// Anonymous single-segment wildcard
+    Wildcard,
+    /// Zero or more trailing segments, optionally captured
+    CatchAll { name: Option<String> },
+}
+
+/// Specificity rank of a segment position: higher is more specific.
+///
+/// `None` means the template ended here, which is more specific than a
+/// catch-all matching zero segments.
+fn rank(segment: Option<&Segment>) -> u8 {
+    match segment {
+        Some(Segment::Literal(_)) => 5,
+        Some(Segment::Param { constraint: Some(_), .. }) => 4,
+        Some(Segment::Param { constraint: None, .. }) => 3,
+        Some(Segment::Wildcard) => 2,
+        None => 1,
+        Some(Segment::CatchAll { .. }) => 0,
+    }
+}
+
+/// A compiled path template.
+#[derive(Debug, Clone)]
+pub struct PathPattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    /// Parse a path template.
+    pub fn parse(template: &str) -> Result<Self, PatternError> {
+        let rest = template
+            .strip_prefix('/')
+            .ok_or_else(|| PatternError(format!("'{}' must start with '/'", template)))?;
+
+        let raw: Vec<&str> = rest.split('/').collect();
+        let mut segments = Vec::with_capacity(raw.len());
+
+        for (i, part) in raw.iter().enumerate() {
+            let last = i == raw.len() - 1;
+
+            let segment = match *part {
+                // Trailing slash: prefix match on everything before it
+                "" if last => Segment::CatchAll { name: None },
+                "*" if last => Segment::CatchAll { name: None },
+                "*" => Segment::Wildcard,
+                p if p.starts_with('{') && p.ends_with('}') => {
+                    let inner = &p[1..p.len() - 1];
+                    if let Some(name) = inner.strip_prefix('*') {
+                        if !last {
+                            return Err(PatternError(format!(
+                                "catch-all '{}' must be the last segment of '{}'",
+                                p, template
+                            )));
+                        }
+                        Segment::CatchAll {
+                            name: Some(check_name(name, template)?.to_string()),
+                        }
+                    } else {
+                        let (name, constraint) = match inner.split_once(':') {
+                            Some((name, re)) => {
+                                let regex = Regex::new(&format!("^(?:{})$", re)).map_err(|e| {
+                                    PatternError(format!("bad constraint for '{}' in '{}': {}", name, template, e))
+                                })?;
+                                (name, Some(regex))
+                            }
+                            None => (inner, None),
+                        };
+                        Segment::Param {
+                            name: check_name(name, template)?.to_string(),
+                            constraint,
+                        }
+                    }
+                }
+                p if p.contains(['{', '}']) => {
+                    return Err(PatternError(format!(
+                        "parameters must span a whole segment, got '{}' in '{}'",
+                        p, template
+                    )));
+                }
+                p => Segment::Literal(p.to_string()),
+            };
+            segments.push(segment);
+        }
+
+        Ok(Self {
+            source: template.to_string(),
+            segments,
+        })
+    }
+
+    /// The original template text
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// The parsed segments
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Whether the template only contains literal segments
+    pub fn is_static(&self) -> bool {
+        self.segments.iter().all(|s| matches!(s, Segment::Literal(_)))
+    }
+
+    /// Match a request path, returning captured parameters.
+    pub fn matches(&self, path: &str) -> Option<PathParams> {
+        let rest = path.strip_prefix('/')?;
+        let parts: Vec<&str> = rest.split('/').collect();
+        let mut params = PathParams::default();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::CatchAll { name } => {
+                    if let Some(name) = name {
+                        params.push(name, &parts.get(i..).map(|p| p.join("/")).unwrap_or_default());
+                    }
+                    return Some(params);
+                }
+                _ => {
+                    let part = parts.get(i)?;
+                    if !match_segment(segment, part) {
+                        return None;
+                    }
+                    if let Segment::Param { name, .. } = segment {
+                        params.push(name, part);
+                    }
+                }
+            }
+        }
+
+        (parts.len() == self.segments.len()).then_some(params)
+    }
+
+    /// Order templates from most to least specific.
+    ///
+    /// Segments are compared left to right: literal beats a constrained
+    /// parameter, which beats a plain parameter, then `*`, then the end of
+    /// the template, then a catch-all. The template text breaks any
+    /// remaining tie, so ordering is deterministic.
+    pub fn cmp_specificity(&self, other: &Self) -> Ordering {
+        let len = self.segments.len().max(other.segments.len());
+        for i in 0..len {
+            match rank(other.segments.get(i)).cmp(&rank(self.segments.get(i))) {
+                Ordering::Equal => {}
+                ord => return ord,
+            }
+        }
+        self.source.cmp(&other.source)
+    }
+}
+
+/// Match a single non-catch-all segment against a path segment.
+pub(crate) fn match_segment(segment: &Segment, part: &str) -> bool {
+    match segment {
+        Segment::Literal(text) => text == part,
+        Segment::Param { constraint, .. } => {
+            !part.is_empty() && constraint.as_ref().is_none_or(|re| re.is_match(part))
+        }
+        Segment::Wildcard => !part.is_empty(),
+        Segment::CatchAll { .. } => true,
+    }
+}
+
+fn check_name<'a>(name: &'a str, template: &str) -> Result<&'a str, PatternError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(PatternError(format!("bad parameter name '{}' in '{}'", name, template)));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(template: &str, path: &str) -> Option<Vec<(String, String)>> {
+        PathPattern::parse(template)
+            .unwrap()
+            .matches(path)
+            .map(|p| p.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect())
+    }
+
+    #[test]
+    fn test_named_params() {
+        let params = matches("/users/{id}/orders/{order_id}", "/users/42/orders/7").unwrap();
+        assert_eq!(
+            params,
+            vec![("id".into(), "42".into()), ("order_id".into(), "7".into())]
+        );
+
+        assert!(matches("/users/{id}", "/users/").is_none());
+        assert!(matches("/users/{id}", "/users/1/extra").is_none());
+    }
+
+    #[test]
+    fn test_regex_constraints() {
+        assert!(matches("/users/{id:[0-9]+}", "/users/42").is_some());
+        assert!(matches("/users/{id:[0-9]+}", "/users/abc").is_none());
+        // Constraints are anchored to the whole segment
+        assert!(matches("/users/{id:[0-9]+}", "/users/42abc").is_none());
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("/api/*/status", "/api/orders/status").is_some());
+        assert!(matches("/api/*/status", "/api/status").is_none());
+
+        let params = matches("/files/{*path}", "/files/a/b/c.txt").unwrap();
+        assert_eq!(params, vec![("path".into(), "a/b/c.txt".into())]);
+        let params = matches("/files/{*path}", "/files").unwrap();
+        assert_eq!(params, vec![("path".into(), "".into())]);
+    }
+
+    #[test]
+    fn test_legacy_prefix_semantics() {
+        assert!(matches("/api/*", "/api").is_some());
+        assert!(matches("/api/*", "/api/anything/deep").is_some());
+        assert!(matches("/api/*", "/apix").is_none());
+
+        assert!(matches("/api/v2/", "/api/v2").is_some());
+        assert!(matches("/api/v2/", "/api/v2/resources").is_some());
+
+        assert!(matches("/health", "/health").is_some());
+        assert!(matches("/health", "/health/").is_none());
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(PathPattern::parse("no-slash").is_err());
+        assert!(PathPattern::parse("/users/{id").is_err());
+        assert!(PathPattern::parse("/users/id-{id}").is_err());
+        assert!(PathPattern::parse("/files/{*rest}/more").is_err());
+        assert!(PathPattern::parse("/users/{id:[0-9}").is_err());
+        assert!(PathPattern::parse("/users/{}").is_err());
+    }
+
+    #[test]
+    fn test_specificity_order() {
+        let mut patterns: Vec<PathPattern> = [
+            "/api/*",
+            "/api/{resource}",
+            "/api/users",
+            "/api/{id:[0-9]+}",
+            "/api/*/x",
+            "/api/users/",
+        ]
+        .iter()
+        .map(|p| PathPattern::parse(p).unwrap())
+        .collect();
+        patterns.sort_by(|a, b| a.cmp_specificity(b));
+
+        let order: Vec<&str> = patterns.iter().map(|p| p.as_str()).collect();
+        assert_eq!(
+            order,
+            vec!["/api/users", "/api/users/", "/api/{id:[0-9]+}", "/api/{resource}", "/api/*/x", "/api/*"]
+        );
+    }
+}
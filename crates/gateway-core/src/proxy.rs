@@ -0,0 +1,363 @@
+//! Upstream forwarding over a pooled HTTP client.
+//!
+//! Matched requests are re-targeted at the route's upstream and sent through
+//! a shared hyper-util connection pool. Upstream responses are streamed back
+//! to the caller without buffering.
+//!
+//! `https://` upstreams are reached over TLS. Routes with their own
+//! [`UpstreamTlsConfig`] get a separate pool per distinct setting, so a
+//! connection opened with one client certificate is never reused for a
+//! route that presents another. CA bundles and client certificates read
+//! from files are checked for changes every [`TLS_FILE_CHECK_INTERVAL`],
+//! and a new pool is built when a file was renewed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::header::HOST;
+use hyper::{Request, Response, Uri, Version};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioTimer;
+
+use crate::body::{self, GatewayBody};
+use crate::config::{Route, UpstreamTlsConfig};
+use crate::connector::HttpsConnector;
+use crate::error::GatewayError;
+use crate::executor::TokioExecutor;
+
+/// Maximum time to establish a TCP connection to an upstream
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an idle pooled connection is kept open
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Maximum idle connections kept per upstream host
+pub const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 64;
+
+/// How often the files behind upstream TLS settings are checked for changes
+pub const TLS_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pooled HTTP client used to reach upstream services.
+///
+/// Cloning is cheap: all clones share the same connection pool.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    client: Client<HttpsConnector, GatewayBody>,
+    /// Clients for routes with their own upstream TLS settings
+    tls_clients: Arc<Mutex<HashMap<UpstreamTlsConfig, TlsClient>>>,
+}
+
+/// A client for upstream TLS settings and the state of the files it was
+/// built from.
+#[derive(Clone)]
+struct TlsClient {
+    client: Client<HttpsConnector, GatewayBody>,
+    /// Modification times of the CA, certificate and key files
+    files: Vec<Option<SystemTime>>,
+    checked: Instant,
+}
+
+impl UpstreamClient {
+    /// Create a client with the default pool settings
+    pub fn new() -> Self {
+        let connector = HttpsConnector::new(None).expect("default upstream TLS settings are valid");
+        Self {
+            client: pooled_client(connector),
+            tls_clients: Arc::default(),
+        }
+    }
+
+    /// The client for upstream TLS settings, built on first use and
+    /// rebuilt when one of its files changed.
+    ///
+    /// Pools are kept for as long as the gateway runs; settings are few and
+    /// change rarely, and an idle pool holds no connections. Files that
+    /// fail to load after a change leave the previous client in use.
+    fn client_for(&self, tls: Option<&UpstreamTlsConfig>) -> Result<Client<HttpsConnector, GatewayBody>, GatewayError> {
+        let Some(tls) = tls else {
+            return Ok(self.client.clone());
+        };
+
+        let cached = self.tls_clients.lock().unwrap_or_else(|e| e.into_inner()).get(tls).cloned();
+        if let Some(cached) = cached.as_ref().filter(|c| c.checked.elapsed() < TLS_FILE_CHECK_INTERVAL) {
+            return Ok(cached.client.clone());
+        }
+
+        // Files are read without holding the lock
+        let files = file_stamps(tls);
+        let entry = match cached {
+            Some(cached) if cached.files == files => cached,
+            cached => match (HttpsConnector::new(Some(tls)), cached) {
+                (Ok(connector), _) => TlsClient {
+                    client: pooled_client(connector),
+                    files,
+                    checked: Instant::now(),
+                },
+                (Err(e), Some(cached)) => {
+                    tracing::warn!(error = %e, "Upstream TLS files changed but failed to load, keeping previous");
+                    cached
+                }
+                (Err(e), None) => return Err(e),
+            },
+        };
+        let entry = TlsClient {
+            checked: Instant::now(),
+            ..entry
+        };
+
+        let client = entry.client.clone();
+        self.tls_clients.lock().unwrap_or_else(|e| e.into_inner()).insert(tls.clone(), entry);
+        Ok(client)
+    }
+
+    /// Forward a request to one of the route's upstream targets.
+    ///
+    /// The request URI is rewritten to target `upstream`, joining the
+    /// request path and query onto the upstream's base path. Method, headers and body are passed
+    /// through unchanged apart from `Host`, which is re-derived from the
+    /// upstream authority.
+    ///
+    /// `route.timeout_ms` bounds the time until the upstream response head
+    /// arrives; the body is then streamed back as it is received. Streaming
+    /// routes use their idle timeout instead, both for the head and between
+    /// body chunks.
+    ///
+    /// Dropping the returned future or response body (e.g. because the
+    /// client went away) closes the upstream connection, cancelling the
+    /// upstream request.
+    pub async fn forward(
+        &self,
+        route: &Route,
+        upstream: &str,
+        req: Request<GatewayBody>,
+    ) -> Result<Response<GatewayBody>, GatewayError> {
+        let (mut parts, body) = req.into_parts();
+
+        parts.uri = upstream_uri(upstream, &parts.uri)?;
+        parts.version = Version::HTTP_11;
+        parts.headers.remove(HOST);
+
+        let upstream_req = Request::from_parts(parts, body);
+        let timeout = Duration::from_millis(route.response_timeout_ms());
+        let client = self.client_for(route.upstream_tls.as_ref())?;
+
+        tracing::debug!(
+            route_id = %route.id,
+            uri = %upstream_req.uri(),
+            "Forwarding request upstream"
+        );
+
+        let response = tokio::time::timeout(timeout, client.request(upstream_req))
+            .await
+            .map_err(|_| GatewayError::RequestTimeout {
+                upstream: upstream.to_string(),
+                timeout_ms: route.response_timeout_ms(),
+            })?
+            .map_err(|e| map_client_error(upstream, e))?;
+
+        Ok(match &route.streaming {
+            Some(streaming) => {
+                let idle = Duration::from_millis(streaming.idle_timeout_ms);
+                response.map(|b| body::idle_timeout(b, idle))
+            }
+            None => response.map(body::boxed),
+        })
+    }
+
+    /// Send a health probe (`GET upstream + path`) and return the status code.
+    ///
+    /// Only the response head is awaited; the body is discarded. `tls` is
+    /// the route's upstream TLS settings.
+    pub async fn probe(
+        &self,
+        upstream: &str,
+        path: &str,
+        tls: Option<&UpstreamTlsConfig>,
+        timeout: Duration,
+    ) -> Result<u16, GatewayError> {
+        let client = self.client_for(tls)?;
+        let uri = upstream_uri(upstream, &path.parse().unwrap_or_else(|_| Uri::from_static("/")))?;
+        let req = Request::get(uri)
+            .header(hyper::header::USER_AGENT, concat!("naseejmesh-health/", env!("CARGO_PKG_VERSION")))
+            .body(body::empty())
+            .map_err(|e| GatewayError::InternalError(e.to_string()))?;
+
+        let response = tokio::time::timeout(timeout, client.request(req))
+            .await
+            .map_err(|_| GatewayError::RequestTimeout {
+                upstream: upstream.to_string(),
+                timeout_ms: timeout.as_millis() as u64,
+            })?
+            .map_err(|e| map_client_error(upstream, e))?;
+
+        Ok(response.status().as_u16())
+    }
+}
+
+impl Default for UpstreamClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Modification times of the files named in upstream TLS settings
+fn file_stamps(tls: &UpstreamTlsConfig) -> Vec<Option<SystemTime>> {
+    [&tls.ca_path, &tls.client_cert_path, &tls.client_key_path]
+        .into_iter()
+        .map(|path| path.as_ref().and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok()))
+        .collect()
+}
+
+fn pooled_client(connector: HttpsConnector) -> Client<HttpsConnector, GatewayBody> {
+    Client::builder(TokioExecutor::new())
+        .pool_timer(TokioTimer::new())
+        .pool_idle_timeout(DEFAULT_POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(DEFAULT_POOL_MAX_IDLE_PER_HOST)
+        .build(connector)
+}
+
+/// Build the upstream URI for a request.
+///
+/// The request path is appended to the upstream's base path and the query
+/// strings of both are combined, e.g. `http://svc:8080/v1?key=k` +
+/// `/users?page=2` gives `http://svc:8080/v1/users?key=k&page=2`.
+pub fn upstream_uri(upstream: &str, original: &Uri) -> Result<Uri, GatewayError> {
+    let invalid = |reason: String| GatewayError::ConfigError(format!("Invalid upstream URL '{}': {}", upstream, reason));
+
+    let base: Uri = upstream.parse().map_err(|e: hyper::http::uri::InvalidUri| invalid(e.to_string()))?;
+    let (Some(scheme), Some(authority)) = (base.scheme(), base.authority()) else {
+        return Err(invalid("scheme and host are required".to_string()));
+    };
+
+    let mut path_and_query = format!("{}{}", base.path().trim_end_matches('/'), original.path());
+    if path_and_query.is_empty() {
+        path_and_query.push('/');
+    }
+    match (base.query(), original.query()) {
+        (Some(a), Some(b)) => path_and_query.push_str(&format!("?{}&{}", a, b)),
+        (Some(q), None) | (None, Some(q)) => path_and_query.push_str(&format!("?{}", q)),
+        (None, None) => {}
+    }
+
+    Uri::builder()
+        .scheme(scheme.clone())
+        .authority(authority.clone())
+        .path_and_query(path_and_query)
+        .build()
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// Map a client failure onto the gateway error taxonomy.
+fn map_client_error(upstream: &str, err: hyper_util::client::legacy::Error) -> GatewayError {
+    if err.is_connect() {
+        GatewayError::UpstreamConnectionFailed {
+            upstream: upstream.to_string(),
+            reason: error_chain(&err),
+        }
+    } else {
+        tracing::debug!(upstream = %upstream, error = %error_chain(&err), "Upstream exchange failed");
+        GatewayError::UpstreamError {
+            upstream: upstream.to_string(),
+            status_code: 502,
+        }
+    }
+}
+
+/// Render an error together with its sources.
+///
+/// hyper-util wraps the interesting cause (e.g. "connection refused"), so the
+/// top-level message alone is rarely useful.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_client_follows_renewed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_path = dir.path().join("ca.pem");
+        let ca = std::fs::read(format!("{}/testdata/tls/ca.pem", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let write_ca = |content: &[u8], age: u64| {
+            std::fs::write(&ca_path, content).unwrap();
+            let file = std::fs::File::options().write(true).open(&ca_path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+        };
+        write_ca(&ca, 120);
+
+        let client = UpstreamClient::new();
+        let tls = UpstreamTlsConfig {
+            ca_path: Some(ca_path.display().to_string()),
+            ..Default::default()
+        };
+        let entry = |client: &UpstreamClient| client.tls_clients.lock().unwrap()[&tls].clone();
+        let expire = |client: &UpstreamClient| {
+            let mut clients = client.tls_clients.lock().unwrap();
+            let entry = clients.get_mut(&tls).unwrap();
+            entry.checked = Instant::now().checked_sub(TLS_FILE_CHECK_INTERVAL).unwrap();
+        };
+
+        client.client_for(Some(&tls)).unwrap();
+        let first = entry(&client).files;
+
+        // A renewed file is picked up once the check interval has passed
+        write_ca(&ca, 60);
+        client.client_for(Some(&tls)).unwrap();
+        assert_eq!(entry(&client).files, first);
+        expire(&client);
+        client.client_for(Some(&tls)).unwrap();
+        let renewed = entry(&client).files;
+        assert_ne!(renewed, first);
+
+        // A broken renewal keeps the working client
+        write_ca(b"not a certificate", 0);
+        expire(&client);
+        assert!(client.client_for(Some(&tls)).is_ok());
+        assert_eq!(entry(&client).files, renewed);
+    }
+
+    #[test]
+    fn test_upstream_uri_keeps_path_and_query() {
+        let original: Uri = "/api/users?page=2".parse().unwrap();
+        let uri = upstream_uri("http://user-service:8080", &original).unwrap();
+        assert_eq!(uri.to_string(), "http://user-service:8080/api/users?page=2");
+    }
+
+    #[test]
+    fn test_upstream_uri_trims_trailing_slash() {
+        let original: Uri = "/health".parse().unwrap();
+        let uri = upstream_uri("http://localhost:3000/", &original).unwrap();
+        assert_eq!(uri.to_string(), "http://localhost:3000/health");
+    }
+
+    #[test]
+    fn test_upstream_uri_joins_base_path_and_query() {
+        let original: Uri = "/users?page=2".parse().unwrap();
+        let uri = upstream_uri("http://svc:8080/v1/?key=k", &original).unwrap();
+        assert_eq!(uri.to_string(), "http://svc:8080/v1/users?key=k&page=2");
+
+        let original: Uri = "/".parse().unwrap();
+        let uri = upstream_uri("http://svc:8080/v1", &original).unwrap();
+        assert_eq!(uri.to_string(), "http://svc:8080/v1/");
+    }
+
+    #[test]
+    fn test_upstream_uri_invalid() {
+        let original: Uri = "/health".parse().unwrap();
+        let result = upstream_uri("not a url", &original);
+        assert!(matches!(result, Err(GatewayError::ConfigError(_))));
+
+        let result = upstream_uri("svc:8080", &original);
+        assert!(matches!(result, Err(GatewayError::ConfigError(_))));
+    }
+}
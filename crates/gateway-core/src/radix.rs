@@ -0,0 +1,225 @@
+//! Segment radix tree for route matching.
+//!
+//! Templates are stored by segment, so a lookup only walks the branches
+//! that can match the request path instead of testing every template.
+//! Children are tried in the same order [`PathPattern::cmp_specificity`]
+//! ranks segments (literal, constrained parameter, parameter, `*`, end of
+//! template, catch-all), which makes the first hit the same template a
+//! linear scan over the sorted templates would return.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::path::{PathParams, PathPattern, Segment};
+
+/// A tree of compiled path templates.
+#[derive(Debug, Clone, Default)]
+pub struct RadixTree {
+    root: Node,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    /// Regex-constrained parameters, one child per distinct regex
+    constrained: Vec<(Regex, Node)>,
+    /// Unconstrained parameters, whatever their name
+    param: Option<Box<Node>>,
+    wildcard: Option<Box<Node>>,
+    /// Templates that end at this node
+    ends: Vec<PathPattern>,
+    /// Templates with a catch-all at this node
+    catch_alls: Vec<PathPattern>,
+}
+
+impl Node {
+    fn child_mut(&mut self, segment: &Segment) -> &mut Node {
+        match segment {
+            Segment::Literal(text) => self.literals.entry(text.clone()).or_default(),
+            Segment::Param { constraint: Some(re), .. } => {
+                let at = match self
+                    .constrained
+                    .iter()
+                    .position(|(r, _)| r.as_str() == re.as_str())
+                {
+                    Some(at) => at,
+                    None => {
+                        self.constrained.push((re.clone(), Node::default()));
+                        self.constrained.len() - 1
+                    }
+                };
+                &mut self.constrained[at].1
+            }
+            Segment::Param { constraint: None, .. } => self.param.get_or_insert_with(Default::default),
+            Segment::Wildcard => self.wildcard.get_or_insert_with(Default::default),
+            Segment::CatchAll { .. } => unreachable!("catch-all is stored as a leaf"),
+        }
+    }
+
+    fn leaves_mut(&mut self, pattern: &PathPattern) -> &mut Vec<PathPattern> {
+        let mut node = self;
+        for segment in pattern.segments() {
+            if let Segment::CatchAll { .. } = segment {
+                return &mut node.catch_alls;
+            }
+            node = node.child_mut(segment);
+        }
+        &mut node.ends
+    }
+
+    fn find<'t>(&'t self, parts: &[&str], depth: usize, accept: Accept<'_>) -> Option<&'t PathPattern> {
+        if let Some(part) = parts.get(depth) {
+            if let Some(child) = self.literals.get(*part) {
+                if let Some(found) = child.find(parts, depth + 1, accept) {
+                    return Some(found);
+                }
+            }
+
+            // Distinct constraints share a rank, so every matching branch
+            // is searched and the most specific hit wins.
+            let mut best: Option<&PathPattern> = None;
+            for (_, child) in self
+                .constrained
+                .iter()
+                .filter(|(re, _)| !part.is_empty() && re.is_match(part))
+            {
+                if let Some(found) = child.find(parts, depth + 1, accept) {
+                    best = Some(most_specific(best, found));
+                }
+            }
+            if best.is_some() {
+                return best;
+            }
+
+            for child in [&self.param, &self.wildcard].into_iter().flatten() {
+                if !part.is_empty() {
+                    if let Some(found) = child.find(parts, depth + 1, accept) {
+                        return Some(found);
+                    }
+                }
+            }
+        } else if let Some(found) = best_leaf(&self.ends, accept) {
+            return Some(found);
+        }
+
+        best_leaf(&self.catch_alls, accept)
+    }
+}
+
+/// Filter deciding whether a matching template is usable
+pub type Accept<'a> = &'a dyn Fn(&PathPattern) -> bool;
+
+fn best_leaf<'t>(leaves: &'t [PathPattern], accept: Accept<'_>) -> Option<&'t PathPattern> {
+    leaves
+        .iter()
+        .filter(|p| accept(p))
+        .reduce(|a, b| most_specific(Some(a), b))
+}
+
+fn most_specific<'t>(current: Option<&'t PathPattern>, candidate: &'t PathPattern) -> &'t PathPattern {
+    match current {
+        Some(current) if current.cmp_specificity(candidate) != Ordering::Greater => current,
+        _ => candidate,
+    }
+}
+
+impl RadixTree {
+    /// Create an empty tree
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a template. Adding the same template twice is a no-op.
+    pub fn insert(&mut self, pattern: PathPattern) {
+        let leaves = self.root.leaves_mut(&pattern);
+        if !leaves.iter().any(|p| p.as_str() == pattern.as_str()) {
+            leaves.push(pattern);
+        }
+    }
+
+    /// Find the most specific template matching `path`.
+    pub fn find(&self, path: &str) -> Option<(&PathPattern, PathParams)> {
+        self.find_by(path, &|_| true)
+    }
+
+    /// Find the most specific template matching `path` that `accept`
+    /// allows, falling back to less specific templates otherwise.
+    pub fn find_by(&self, path: &str, accept: Accept<'_>) -> Option<(&PathPattern, PathParams)> {
+        let parts: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        let pattern = self.root.find(&parts, 0, accept)?;
+
+        // Parameters are captured under the winning template's own names,
+        // since templates sharing a branch may name them differently.
+        let params = pattern.matches(path)?;
+        Some((pattern, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(templates: &[&str]) -> RadixTree {
+        let mut tree = RadixTree::new();
+        for t in templates {
+            tree.insert(PathPattern::parse(t).unwrap());
+        }
+        tree
+    }
+
+    fn find<'t>(tree: &'t RadixTree, path: &str) -> Option<&'t str> {
+        tree.find(path).map(|(p, _)| p.as_str())
+    }
+
+    #[test]
+    fn test_rank_order() {
+        let tree = tree(&["/api/*", "/api/{resource}", "/api/users", "/api/{id:[0-9]+}", "/api/*/x"]);
+
+        assert_eq!(find(&tree, "/api/users"), Some("/api/users"));
+        assert_eq!(find(&tree, "/api/42"), Some("/api/{id:[0-9]+}"));
+        assert_eq!(find(&tree, "/api/orders"), Some("/api/{resource}"));
+        assert_eq!(find(&tree, "/api/orders/x"), Some("/api/*/x"));
+        assert_eq!(find(&tree, "/api/orders/y"), Some("/api/*"));
+        assert_eq!(find(&tree, "/api"), Some("/api/*"));
+        assert_eq!(find(&tree, "/other"), None);
+    }
+
+    #[test]
+    fn test_backtracks_to_less_specific_branch() {
+        let tree = tree(&["/users/admin/settings", "/users/{id}/orders"]);
+
+        // The literal branch is tried first but dead-ends
+        assert_eq!(find(&tree, "/users/admin/orders"), Some("/users/{id}/orders"));
+    }
+
+    #[test]
+    fn test_constrained_ties_use_later_segments() {
+        let tree = tree(&["/a/{x:[0-9]+}/*", "/a/{y:[0-9a-f]+}/b"]);
+
+        // Both constraints match "12"; the literal in the next segment decides
+        assert_eq!(find(&tree, "/a/12/b"), Some("/a/{y:[0-9a-f]+}/b"));
+        assert_eq!(find(&tree, "/a/12/c"), Some("/a/{x:[0-9]+}/*"));
+    }
+
+    #[test]
+    fn test_find_by_falls_back() {
+        let tree = tree(&["/api/users", "/api/{resource}", "/api/*"]);
+
+        let found = tree.find_by("/api/users", &|p| p.as_str() != "/api/users");
+        assert_eq!(found.map(|(p, _)| p.as_str()), Some("/api/{resource}"));
+
+        let found = tree.find_by("/api/users", &|p| p.as_str() == "/api/*");
+        assert_eq!(found.map(|(p, _)| p.as_str()), Some("/api/*"));
+    }
+
+    #[test]
+    fn test_params_use_winning_names() {
+        let tree = tree(&["/u/{id}/x", "/u/{name}/y"]);
+
+        let (_, params) = tree.find("/u/alice/y").unwrap();
+        assert_eq!(params.get("name"), Some("alice"));
+        assert_eq!(params.get("id"), None);
+    }
+}
@@ -0,0 +1,236 @@
+//! Retry decisions, backoff and the global retry budget.
+//!
+//! The handler drives the retry loop; this module decides *whether* a
+//! failed attempt may be retried, *how long* to wait before the next one,
+//! and whether the gateway can afford another retry at all.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::Method;
+use rand::Rng;
+
+use crate::config::RetryPolicy;
+use crate::error::GatewayError;
+
+/// Length of the window the retry budget is computed over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Number of buckets the budget window is divided into
+const BUDGET_BUCKETS: usize = 10;
+
+/// Default share of requests that may be retried
+pub const DEFAULT_BUDGET_RATIO: f64 = 0.2;
+
+/// Default number of retries per second that is always allowed
+pub const DEFAULT_MIN_RETRIES_PER_SEC: u32 = 10;
+
+/// Gateway-wide cap on retries and hedged requests.
+///
+/// Retries are allowed while they stay below `ratio` of the requests seen
+/// over the last 10 seconds, with a floor of `min_per_sec` retries per
+/// second so low-traffic routes can still retry. During an outage this
+/// keeps retries from multiplying the load on struggling upstreams.
+pub struct RetryBudget {
+    ratio: f64,
+    min_per_sec: u32,
+    window: Mutex<BudgetWindow>,
+}
+
+#[derive(Default)]
+struct BudgetWindow {
+    origin: Option<Instant>,
+    /// (bucket epoch, requests, retries)
+    buckets: [(u64, u64, u64); BUDGET_BUCKETS],
+}
+
+impl BudgetWindow {
+    fn bucket(&mut self, now: Instant) -> &mut (u64, u64, u64) {
+        let origin = *self.origin.get_or_insert(now);
+        let bucket_ms = BUDGET_WINDOW.as_millis() as u64 / BUDGET_BUCKETS as u64;
+        let epoch = now.duration_since(origin).as_millis() as u64 / bucket_ms;
+
+        let bucket = &mut self.buckets[epoch as usize % BUDGET_BUCKETS];
+        if bucket.0 != epoch {
+            *bucket = (epoch, 0, 0);
+        }
+        bucket
+    }
+
+    fn totals(&mut self, now: Instant) -> (u64, u64) {
+        let current = self.bucket(now).0;
+        self.buckets
+            .iter()
+            .filter(|b| current.saturating_sub(b.0) < BUDGET_BUCKETS as u64)
+            .fold((0, 0), |(requests, retries), b| (requests + b.1, retries + b.2))
+    }
+}
+
+impl RetryBudget {
+    /// Create a budget allowing `ratio` retries per request, and at least
+    /// `min_per_sec` retries per second.
+    pub fn new(ratio: f64, min_per_sec: u32) -> Self {
+        Self {
+            ratio,
+            min_per_sec,
+            window: Mutex::new(BudgetWindow::default()),
+        }
+    }
+
+    /// Count an original (non-retry) request.
+    pub fn deposit(&self) {
+        let mut window = self.window.lock().unwrap();
+        window.bucket(Instant::now()).1 += 1;
+    }
+
+    /// Try to spend budget on one retry or hedged request.
+    pub fn try_withdraw(&self) -> bool {
+        // Read the clock under the lock so buckets are filled in order
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+
+        let (requests, retries) = window.totals(now);
+        let floor = self.min_per_sec as u64 * BUDGET_WINDOW.as_secs();
+        let allowed = ((requests as f64 * self.ratio) as u64).max(floor);
+
+        if retries >= allowed {
+            return false;
+        }
+        window.bucket(now).2 += 1;
+        true
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUDGET_RATIO, DEFAULT_MIN_RETRIES_PER_SEC)
+    }
+}
+
+/// Methods that can safely be sent more than once (RFC 9110 section 9.2.2)
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Decide whether a failed attempt may be retried under `policy`.
+///
+/// `status` is the upstream status when a response was received, `error`
+/// the failure otherwise. A failed connection is retried for any method,
+/// since the request never reached the upstream; everything else requires
+/// an idempotent method unless `retry_non_idempotent` is set.
+pub fn should_retry(
+    policy: &RetryPolicy,
+    method: &Method,
+    status: Option<u16>,
+    error: Option<&GatewayError>,
+) -> bool {
+    if let Some(GatewayError::UpstreamConnectionFailed { .. }) = error {
+        return true;
+    }
+
+    if !policy.retry_non_idempotent && !is_idempotent(method) {
+        return false;
+    }
+
+    match (status, error) {
+        (_, Some(e)) => e.is_retryable(),
+        (Some(code), None) => policy.retry_on_status.contains(&code),
+        (None, None) => false,
+    }
+}
+
+/// Backoff before retry number `retry` (1-based).
+///
+/// Exponential (`base * 2^(retry - 1)`, capped at `max_backoff_ms`) with
+/// full jitter, so concurrent retries do not arrive in lockstep.
+pub fn backoff(policy: &RetryPolicy, retry: u32) -> Duration {
+    let exp = policy
+        .base_backoff_ms
+        .saturating_mul(1u64 << retry.saturating_sub(1).min(20))
+        .min(policy.max_backoff_ms);
+
+    Duration::from_millis(rand::thread_rng().gen_range(0..=exp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::default();
+        let connect = GatewayError::UpstreamConnectionFailed {
+            upstream: "u".into(),
+            reason: "refused".into(),
+        };
+        let timeout = GatewayError::RequestTimeout {
+            upstream: "u".into(),
+            timeout_ms: 10,
+        };
+
+        assert!(should_retry(&policy, &Method::GET, Some(503), None));
+        assert!(!should_retry(&policy, &Method::GET, Some(500), None));
+        assert!(!should_retry(&policy, &Method::GET, Some(404), None));
+        assert!(should_retry(&policy, &Method::GET, None, Some(&timeout)));
+
+        // Non-idempotent: only when the request never left the gateway
+        assert!(!should_retry(&policy, &Method::POST, Some(503), None));
+        assert!(!should_retry(&policy, &Method::POST, None, Some(&timeout)));
+        assert!(should_retry(&policy, &Method::POST, None, Some(&connect)));
+
+        let lenient = RetryPolicy {
+            retry_non_idempotent: true,
+            ..RetryPolicy::default()
+        };
+        assert!(should_retry(&lenient, &Method::POST, Some(503), None));
+    }
+
+    #[test]
+    fn test_backoff_bounds() {
+        let policy = RetryPolicy {
+            base_backoff_ms: 100,
+            max_backoff_ms: 250,
+            ..RetryPolicy::default()
+        };
+
+        for _ in 0..50 {
+            assert!(backoff(&policy, 1) <= Duration::from_millis(100));
+            assert!(backoff(&policy, 2) <= Duration::from_millis(200));
+            assert!(backoff(&policy, 10) <= Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn test_budget_floor() {
+        let budget = RetryBudget::new(0.0, 1);
+        // 1 retry/sec over a 10s window
+        for _ in 0..10 {
+            assert!(budget.try_withdraw());
+        }
+        assert!(!budget.try_withdraw());
+    }
+
+    #[test]
+    fn test_budget_ratio() {
+        let budget = RetryBudget::new(0.5, 0);
+        assert!(!budget.try_withdraw());
+
+        for _ in 0..4 {
+            budget.deposit();
+        }
+        assert!(budget.try_withdraw());
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+    }
+
+    #[test]
+    fn test_budget_tolerates_out_of_order_times() {
+        let mut window = BudgetWindow::default();
+        let now = Instant::now();
+        window.bucket(now + Duration::from_secs(3)).1 += 1;
+        assert_eq!(window.totals(now), (1, 0));
+    }
+}
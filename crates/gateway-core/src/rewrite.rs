@@ -0,0 +1,230 @@
+//! Request path rewriting.
+//!
+//! A route's [`PathRewrite`] is compiled once when the routing table is
+//! built. Rewrites only touch the path; the query string is kept and the
+//! result is joined onto the upstream base URL by
+//! [`upstream_uri`](crate::proxy::upstream_uri).
+
+use regex::Regex;
+
+use crate::config::PathRewrite;
+use crate::path::{PathParams, PathPattern, PatternError, Segment};
+
+/// A compiled [`PathRewrite`].
+#[derive(Debug, Clone)]
+pub struct PathRewriter {
+    template: Option<Vec<Piece>>,
+    strip_prefix: Option<String>,
+    replace: Vec<(Regex, String)>,
+    add_prefix: Option<String>,
+}
+
+/// Part of a rewrite template
+#[derive(Debug, Clone)]
+enum Piece {
+    Text(String),
+    Param(String),
+}
+
+impl PathRewriter {
+    /// Compile a rewrite for a route whose path template is `pattern`.
+    ///
+    /// Fails if a regex does not compile or the template refers to a
+    /// parameter `pattern` does not capture.
+    pub fn compile(rewrite: &PathRewrite, pattern: &PathPattern) -> Result<Self, PatternError> {
+        let template = rewrite
+            .template
+            .as_deref()
+            .map(|t| parse_template(t, pattern))
+            .transpose()?;
+
+        let replace = rewrite
+            .replace
+            .iter()
+            .map(|r| {
+                Regex::new(&r.pattern)
+                    .map(|re| (re, r.replacement.clone()))
+                    .map_err(|e| PatternError(format!("bad rewrite regex '{}': {}", r.pattern, e)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            template,
+            strip_prefix: normalize_prefix(rewrite.strip_prefix.as_deref()),
+            replace,
+            add_prefix: normalize_prefix(rewrite.add_prefix.as_deref()),
+        })
+    }
+
+    /// Rewrite a request path.
+    ///
+    /// Steps run in order: template, strip prefix, regex replacements,
+    /// add prefix. The result always starts with `/`.
+    pub fn rewrite(&self, path: &str, params: &PathParams) -> String {
+        let mut path = match &self.template {
+            Some(pieces) => pieces
+                .iter()
+                .map(|p| match p {
+                    Piece::Text(text) => text.as_str(),
+                    Piece::Param(name) => params.get(name).unwrap_or_default(),
+                })
+                .collect(),
+            None => path.to_string(),
+        };
+
+        if let Some(prefix) = &self.strip_prefix {
+            if path == *prefix {
+                path = "/".to_string();
+            } else if path.starts_with(prefix.as_str()) && path[prefix.len()..].starts_with('/') {
+                path.drain(..prefix.len());
+            }
+        }
+
+        for (re, replacement) in &self.replace {
+            path = re.replace_all(&path, replacement.as_str()).into_owned();
+        }
+
+        if let Some(prefix) = &self.add_prefix {
+            path.insert_str(0, prefix);
+        }
+
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        path
+    }
+}
+
+/// Parse `/v2/users/{id}` into text and parameter pieces.
+fn parse_template(template: &str, pattern: &PathPattern) -> Result<Vec<Piece>, PatternError> {
+    let known = |name: &str| {
+        pattern.segments().iter().any(|s| match s {
+            Segment::Param { name: n, .. } => n == name,
+            Segment::CatchAll { name: Some(n) } => n == name,
+            _ => false,
+        })
+    };
+
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|e| start + e)
+            .ok_or_else(|| PatternError(format!("unclosed '{{' in rewrite '{}'", template)))?;
+
+        let name = &rest[start + 1..end];
+        if !known(name) {
+            return Err(PatternError(format!(
+                "rewrite '{}' uses '{{{}}}', which '{}' does not capture",
+                template,
+                name,
+                pattern.as_str()
+            )));
+        }
+        if start > 0 {
+            pieces.push(Piece::Text(rest[..start].to_string()));
+        }
+        pieces.push(Piece::Param(name.to_string()));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest.to_string()));
+    }
+
+    Ok(pieces)
+}
+
+/// Ensure a leading `/` and drop trailing ones; an empty prefix is no prefix.
+fn normalize_prefix(prefix: Option<&str>) -> Option<String> {
+    let prefix = prefix?.trim_end_matches('/');
+    if prefix.is_empty() {
+        return None;
+    }
+    Some(if prefix.starts_with('/') {
+        prefix.to_string()
+    } else {
+        format!("/{}", prefix)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RegexReplace;
+
+    fn rewrite(route_path: &str, rewrite: PathRewrite, path: &str) -> String {
+        let pattern = PathPattern::parse(route_path).unwrap();
+        let params = pattern.matches(path).unwrap();
+        PathRewriter::compile(&rewrite, &pattern).unwrap().rewrite(path, &params)
+    }
+
+    #[test]
+    fn test_strip_and_add_prefix() {
+        let strip = PathRewrite {
+            strip_prefix: Some("/api/".into()),
+            ..Default::default()
+        };
+        assert_eq!(rewrite("/api/*", strip.clone(), "/api/users/1"), "/users/1");
+        assert_eq!(rewrite("/api/*", strip.clone(), "/api"), "/");
+        // Only whole segments are stripped
+        assert_eq!(rewrite("/{*all}", strip, "/apiary"), "/apiary");
+
+        let swap = PathRewrite {
+            strip_prefix: Some("/api".into()),
+            add_prefix: Some("internal/v2".into()),
+            ..Default::default()
+        };
+        assert_eq!(rewrite("/api/*", swap, "/api/users"), "/internal/v2/users");
+    }
+
+    #[test]
+    fn test_regex_replace() {
+        let rewrite_rule = PathRewrite {
+            replace: vec![RegexReplace {
+                pattern: "^/v([0-9]+)/(.*)$".into(),
+                replacement: "/$2/version/$1".into(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(rewrite("/{*all}", rewrite_rule, "/v3/orders"), "/orders/version/3");
+    }
+
+    #[test]
+    fn test_template() {
+        let rule = PathRewrite {
+            template: Some("/accounts/{id}/orders/{rest}".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewrite("/users/{id}/{*rest}", rule, "/users/42/recent/all"),
+            "/accounts/42/orders/recent/all"
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let pattern = PathPattern::parse("/users/{id}").unwrap();
+
+        let unknown = PathRewrite {
+            template: Some("/u/{name}".into()),
+            ..Default::default()
+        };
+        assert!(PathRewriter::compile(&unknown, &pattern).is_err());
+
+        let unclosed = PathRewrite {
+            template: Some("/u/{id".into()),
+            ..Default::default()
+        };
+        assert!(PathRewriter::compile(&unclosed, &pattern).is_err());
+
+        let bad_regex = PathRewrite {
+            replace: vec![RegexReplace {
+                pattern: "(".into(),
+                replacement: String::new(),
+            }],
+            ..Default::default()
+        };
+        assert!(PathRewriter::compile(&bad_regex, &pattern).is_err());
+    }
+}
@@ -1,68 +1,383 @@
 //! Router implementation with path matching and route lookup.
 //!
 //! Provides efficient route matching with exact match priority followed
-//! by longest-prefix matching. Designed to work with ArcSwap for
+//! by a radix tree walk over path templates in specificity order. Several
+//! routes may share a path template and are told apart by their host,
+//! header, query and cookie conditions. Designed to work with ArcSwap for
 //! wait-free reads.
 
-use crate::config::{Route, RouterMap};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+use hyper::header::{HeaderMap, COOKIE, HOST};
+use hyper::Request;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::config::{MatchConditions, Route};
+use crate::hooks::RouteHooks;
+use crate::path::{PathParams, PathPattern, PatternError};
+use crate::radix::RadixTree;
+use crate::rewrite::PathRewriter;
+use crate::scripting::ScriptError;
+
+/// The routing table.
+///
+/// Routes are keyed by their path template. Templates are compiled once
+/// when the table is built into a radix tree, so the table can be swapped
+/// atomically via ArcSwap and read without any per-request parsing.
+#[derive(Debug, Clone, Default)]
+pub struct RouterMap {
+    /// Routes per path template, most specific conditions first
+    routes: HashMap<String, Vec<Entry>>,
+    tree: RadixTree,
+    /// Compiled templates, most specific first
+    patterns: Vec<PathPattern>,
+}
+
+/// A route together with its compiled rewrite and scripts
+#[derive(Debug, Clone)]
+struct Entry {
+    route: Route,
+    rewriter: Option<PathRewriter>,
+    hooks: Option<Arc<RouteHooks>>,
+}
+
+/// Why a route could not be added to the routing table
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Pattern(#[from] PatternError),
+
+    #[error("invalid route script: {0}")]
+    Script(#[from] ScriptError),
+}
+
+impl RouterMap {
+    /// Create an empty routing table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route, replacing any route with the same path template and
+    /// the same conditions.
+    pub fn insert(&mut self, route: Route) -> Result<Option<Route>, RouteError> {
+        let (pattern, replaced) = self.add(route)?;
+
+        if let Some(pattern) = pattern {
+            let at = self
+                .patterns
+                .partition_point(|p| p.cmp_specificity(&pattern).is_lt());
+            self.patterns.insert(at, pattern);
+        }
+        Ok(replaced)
+    }
+
+    /// Add a route without keeping `patterns` sorted; callers must sort
+    /// once they are done.
+    fn insert_unsorted(&mut self, route: Route) -> Result<Option<Route>, RouteError> {
+        let (pattern, replaced) = self.add(route)?;
+        self.patterns.extend(pattern);
+        Ok(replaced)
+    }
+
+    /// Store a route among the variants of its template. Returns the
+    /// compiled template if it is new to the table, and any replaced route.
+    fn add(&mut self, route: Route) -> Result<(Option<PathPattern>, Option<Route>), RouteError> {
+        let pattern = PathPattern::parse(&route.path)?;
+        let rewriter = route
+            .rewrite
+            .as_ref()
+            .map(|r| PathRewriter::compile(r, &pattern))
+            .transpose()?;
+        let hooks = route
+            .script
+            .as_ref()
+            .map(|script| RouteHooks::compile(&route.id, script))
+            .transpose()?
+            .map(Arc::new);
+        let entry = Entry { route, rewriter, hooks };
+        let route = &entry.route;
+
+        let variants = self.routes.entry(route.path.clone()).or_default();
+        let pattern = if variants.is_empty() {
+            self.tree.insert(pattern.clone());
+            Some(pattern)
+        } else {
+            None
+        };
+
+        if let Some(existing) = variants
+            .iter_mut()
+            .find(|e| e.route.conditions == route.conditions)
+        {
+            return Ok((pattern, Some(std::mem::replace(existing, entry).route)));
+        }
+        let specificity = route.conditions.specificity();
+        let at = variants.partition_point(|e| e.route.conditions.specificity() >= specificity);
+        variants.insert(at, entry);
+
+        Ok((pattern, None))
+    }
+
+    /// Number of routes
+    pub fn len(&self) -> usize {
+        self.routes.values().map(Vec::len).sum()
+    }
+
+    /// Whether the table has no routes
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Route without extra conditions registered under an exact path template
+    pub fn get(&self, path: &str) -> Option<&Route> {
+        self.variants(path).find(|r| r.conditions.is_empty())
+    }
+
+    /// All routes registered under an exact path template, most specific
+    /// conditions first
+    pub fn variants(&self, path: &str) -> impl Iterator<Item = &Route> {
+        self.entries(path).iter().map(|e| &e.route)
+    }
+
+    fn entries(&self, path: &str) -> &[Entry] {
+        self.routes.get(path).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Whether a route is registered under this path template
+    pub fn contains_key(&self, path: &str) -> bool {
+        self.routes.contains_key(path)
+    }
+
+    /// Iterate over all routes
+    pub fn values(&self) -> impl Iterator<Item = &Route> {
+        self.routes.values().flatten().map(|e| &e.route)
+    }
+
+    /// Iterate over `(path template, route)` pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Route)> {
+        self.routes
+            .iter()
+            .flat_map(|(path, variants)| variants.iter().map(move |e| (path, &e.route)))
+    }
+
+    /// First route under `template` whose conditions hold for the request
+    fn select(&self, template: &str, req: &RequestInfo<'_>) -> Option<&Entry> {
+        self.entries(template).iter().find(|e| req.satisfies(&e.route.conditions))
+    }
+}
+
+/// The parts of a request that route conditions look at.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestInfo<'a> {
+    /// Request path
+    pub path: &'a str,
+    /// Host name without port, from the URI authority or `Host` header
+    pub host: Option<&'a str>,
+    /// Raw query string
+    pub query: Option<&'a str>,
+    /// Request headers
+    pub headers: Option<&'a HeaderMap>,
+}
+
+impl<'a> RequestInfo<'a> {
+    /// A request known only by its path. Only routes without conditions
+    /// match it.
+    pub fn from_path(path: &'a str) -> Self {
+        Self {
+            path,
+            host: None,
+            query: None,
+            headers: None,
+        }
+    }
+
+    /// Extract the routing-relevant parts of a request
+    pub fn from_request<B>(req: &'a Request<B>) -> Self {
+        let host = req
+            .uri()
+            .host()
+            .or_else(|| req.headers().get(HOST)?.to_str().ok().map(strip_port));
+
+        Self {
+            path: req.uri().path(),
+            host,
+            query: req.uri().query(),
+            headers: Some(req.headers()),
+        }
+    }
+
+    /// Whether every condition holds for this request
+    pub fn satisfies(&self, conditions: &MatchConditions) -> bool {
+        if let Some(pattern) = &conditions.host {
+            if !self.host.is_some_and(|host| host_matches(pattern, host)) {
+                return false;
+            }
+        }
+
+        let headers = self.headers;
+        let header_ok = |name: &str, value: &str| {
+            headers.is_some_and(|h| h.get_all(name).iter().any(|v| v.as_bytes() == value.as_bytes()))
+        };
+        if !conditions.headers.iter().all(|(n, v)| header_ok(n, v)) {
+            return false;
+        }
+
+        let query_ok = |name: &str, value: &str| {
+            self.query
+                .into_iter()
+                .flat_map(|q| q.split('&'))
+                .any(|pair| pair.split_once('=').unwrap_or((pair, "")) == (name, value))
+        };
+        if !conditions.query.iter().all(|(n, v)| query_ok(n, v)) {
+            return false;
+        }
+
+        let cookie_ok = |name: &str, value: &str| {
+            headers
+                .into_iter()
+                .flat_map(|h| h.get_all(COOKIE))
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|c| c.trim().split_once('='))
+                .any(|pair| pair == (name, value))
+        };
+        conditions.cookies.iter().all(|(n, v)| cookie_ok(n, v))
+    }
+}
+
+/// Strip an optional port from a `Host` header value
+fn strip_port(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        // IPv6 literal: keep the brackets, drop what follows them
+        Some(rest) => rest.find(']').map_or(host, |end| &host[..end + 2]),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// Match a host against an exact name or a `*.example.com` wildcard.
+/// Comparison is case-insensitive; wildcards do not match the bare domain.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => {
+            host.len() > suffix.len()
+                && host
+                    .get(host.len() - suffix.len()..)
+                    .is_some_and(|tail| tail.eq_ignore_ascii_case(suffix))
+        }
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// A matched route together with the parameters captured from the path.
+#[derive(Debug, Clone)]
+pub struct RouteMatch<'a> {
+    /// The matched route
+    pub route: &'a Route,
+    /// Parameters captured by the route's path template
+    pub params: PathParams,
+    /// The route's compiled path rewrite, if it has one
+    pub rewriter: Option<&'a PathRewriter>,
+    /// The route's compiled scripts, if it has any
+    pub hooks: Option<&'a Arc<RouteHooks>>,
+}
+
+impl<'a> RouteMatch<'a> {
+    fn new(entry: &'a Entry, params: PathParams) -> Self {
+        Self {
+            route: &entry.route,
+            params,
+            rewriter: entry.rewriter.as_ref(),
+            hooks: entry.hooks.as_ref(),
+        }
+    }
+}
 
 /// Build an optimized router map from a list of routes.
 ///
-/// Only active routes are included in the map. The map is keyed by
-/// the exact path pattern for O(1) exact-match lookups.
+/// Only active routes are included in the map. Routes whose path template,
+/// rewrite or scripts do not compile are skipped with a warning rather than
+/// failing the whole reload. Scripts are compiled here, once per reload.
 pub fn build_router_map(routes: Vec<Route>) -> RouterMap {
-    routes
-        .into_iter()
-        .filter(|r| r.active)
-        .map(|r| (r.path.clone(), r))
-        .collect()
+    let mut map = RouterMap::new();
+
+    for route in routes.into_iter().filter(|r| r.active) {
+        let id = route.id.clone();
+        if let Err(e) = map.insert_unsorted(route) {
+            tracing::warn!(route_id = %id, error = %e, "Skipping route with invalid path, rewrite or script");
+        }
+    }
+    map.patterns.sort_by(|a, b| a.cmp_specificity(b));
+
+    map
 }
 
 /// Match a request path against the routing table.
 ///
+/// Only routes without host, header, query or cookie conditions are
+/// considered. Returns `None` if no route matches.
+pub fn match_route<'a>(path: &str, map: &'a RouterMap) -> Option<&'a Route> {
+    find_route(path, map).map(|m| m.route)
+}
+
+/// Match a request path and capture its path parameters.
+///
+/// Only routes without host, header, query or cookie conditions are
+/// considered; see [`find_route_for`].
+pub fn find_route<'a>(path: &str, map: &'a RouterMap) -> Option<RouteMatch<'a>> {
+    find_route_for(&RequestInfo::from_path(path), map)
+}
+
+/// Match a request and capture its path parameters.
+///
 /// Matching strategy:
-/// 1. Exact match (O(1) HashMap lookup)
-/// 2. Longest prefix match (O(n) scan, but typically small n)
+/// 1. Exact match on a static template (O(1) HashMap lookup)
+/// 2. Most specific matching template, found by walking the radix tree
+///    (proportional to the path depth, not the number of routes)
 ///
-/// Returns `None` if no route matches.
-pub fn match_route<'a>(path: &str, map: &'a RouterMap) -> Option<&'a Route> {
-    // Try exact match first (fast path)
-    if let Some(route) = map.get(path) {
-        return Some(route);
+/// Within a template, the first route whose conditions hold wins. If none
+/// do, matching falls back to the next most specific template.
+pub fn find_route_for<'a>(req: &RequestInfo<'_>, map: &'a RouterMap) -> Option<RouteMatch<'a>> {
+    if let Some(matched) = find_exact(req, map) {
+        return Some(matched);
     }
 
-    // Fall back to prefix matching
-    // Find the longest matching prefix
-    map.iter()
-        .filter(|(pattern, _)| path_matches(path, pattern))
-        .max_by_key(|(pattern, _)| pattern.len())
-        .map(|(_, route)| route)
+    let (pattern, params) = map
+        .tree
+        .find_by(req.path, &|p| map.select(p.as_str(), req).is_some())?;
+    let entry = map.select(pattern.as_str(), req)?;
+    Some(RouteMatch::new(entry, params))
 }
 
-/// Check if a path matches a pattern.
+/// Reference matcher: tests every template in specificity order.
 ///
-/// Currently supports:
-/// - Exact match: "/api/users" matches "/api/users"
-/// - Prefix match: "/api/" matches "/api/users", "/api/posts"
-/// - Wildcard suffix: "/api/*" matches "/api/anything"
-fn path_matches(path: &str, pattern: &str) -> bool {
-    // Wildcard pattern
-    if let Some(prefix) = pattern.strip_suffix("/*") {
-        return path.starts_with(prefix) && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'));
+/// Returns the same result as [`find_route_for`] in O(n) per lookup. Kept
+/// for equivalence tests and benchmarks.
+pub fn find_route_linear<'a>(req: &RequestInfo<'_>, map: &'a RouterMap) -> Option<RouteMatch<'a>> {
+    if let Some(matched) = find_exact(req, map) {
+        return Some(matched);
     }
 
-    // Prefix pattern (ends with /)
-    if pattern.ends_with('/') {
-        return path.starts_with(pattern) || path == pattern.trim_end_matches('/');
-    }
+    map.patterns.iter().find_map(|pattern| {
+        let params = pattern.matches(req.path)?;
+        let entry = map.select(pattern.as_str(), req)?;
+        Some(RouteMatch::new(entry, params))
+    })
+}
 
-    // Exact match
-    path == pattern
+/// Exact match on a static template. Templates containing parameters or
+/// wildcards must not match their own literal text.
+fn find_exact<'a>(req: &RequestInfo<'_>, map: &'a RouterMap) -> Option<RouteMatch<'a>> {
+    if req.path.contains(['{', '*']) {
+        return None;
+    }
+    let entry = map.select(req.path, req)?;
+    Some(RouteMatch::new(entry, PathParams::default()))
 }
 
 /// Statistics about the current routing table
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct RouterStats {
     /// Total number of routes in the table
     pub total_routes: usize,
@@ -70,17 +385,40 @@ pub struct RouterStats {
     pub active_routes: usize,
     /// Number of unique upstream services
     pub unique_upstreams: usize,
+    /// Number of upstream targets across all routes
+    pub total_targets: usize,
+    /// Number of routes using each load balancing policy
+    pub balancing_policies: HashMap<&'static str, usize>,
+    /// Number of routes proxying WebSocket upgrades
+    pub websocket_routes: usize,
+    /// Open WebSocket connections per route id. Only filled in by
+    /// [`GatewayState::router_stats`](crate::state::GatewayState::router_stats).
+    pub websocket_connections: HashMap<String, usize>,
 }
 
 /// Calculate statistics about a router map
 pub fn router_stats(map: &RouterMap) -> RouterStats {
-    let unique_upstreams: std::collections::HashSet<_> =
-        map.values().map(|r| &r.upstream).collect();
+    let mut unique_upstreams = HashSet::new();
+    let mut total_targets = 0;
+    let mut balancing_policies = HashMap::new();
+    let mut websocket_routes = 0;
+
+    for route in map.values() {
+        let targets = route.targets();
+        total_targets += targets.len();
+        unique_upstreams.extend(targets.iter().map(|t| t.url.clone()));
+        *balancing_policies.entry(route.load_balancing.name()).or_insert(0) += 1;
+        websocket_routes += usize::from(route.websocket.is_some());
+    }
 
     RouterStats {
         total_routes: map.len(),
         active_routes: map.len(), // All routes in map are active (filtered during build)
         unique_upstreams: unique_upstreams.len(),
+        total_targets,
+        balancing_policies,
+        websocket_routes,
+        websocket_connections: HashMap::new(),
     }
 }
 
@@ -149,6 +487,180 @@ mod tests {
         assert_eq!(route.upstream, "http://api-catchall:8080");
     }
 
+    #[test]
+    fn test_path_params() {
+        let mut routes = create_test_routes();
+        routes.push(Route::new("7", "/users/{id}/orders/{order_id}", "http://orders:8080"));
+        routes.push(Route::new("8", "/users/{id:[0-9]+}", "http://users-by-id:8080"));
+        routes.push(Route::new("9", "/users/{name}", "http://users-by-name:8080"));
+        let map = build_router_map(routes);
+
+        let matched = find_route("/users/42/orders/7", &map).unwrap();
+        assert_eq!(matched.route.id, "7");
+        assert_eq!(matched.params.get("id"), Some("42"));
+        assert_eq!(matched.params.get("order_id"), Some("7"));
+
+        // Constrained parameter beats the unconstrained one
+        assert_eq!(match_route("/users/42", &map).unwrap().id, "8");
+        assert_eq!(match_route("/users/alice", &map).unwrap().id, "9");
+    }
+
+    #[test]
+    fn test_specificity_beats_pattern_length() {
+        let map = build_router_map(vec![
+            Route::new("catchall", "/api/{*rest}", "http://catchall:8080"),
+            Route::new("param", "/api/{version}/items", "http://items:8080"),
+        ]);
+
+        // "/api/{*rest}" is the longer string, but the literal-heavy template wins
+        assert_eq!(match_route("/api/v1/items", &map).unwrap().id, "param");
+        assert_eq!(find_route("/api/v1/other", &map).unwrap().params.get("rest"), Some("v1/other"));
+    }
+
+    #[test]
+    fn test_invalid_template_skipped() {
+        let map = build_router_map(vec![
+            Route::new("bad", "/users/{id", "http://bad:8080"),
+            Route::new("good", "/users", "http://good:8080"),
+        ]);
+
+        assert_eq!(map.len(), 1);
+        assert!(match_route("/users/{id", &map).is_none());
+    }
+
+    #[test]
+    fn test_tree_matches_linear_scan() {
+        let mut routes = create_test_routes();
+        for (i, path) in [
+            "/users/{id}/orders/{order_id}",
+            "/users/{id:[0-9]+}",
+            "/users/{name}",
+            "/users/admin/settings",
+            "/api/{version}/items",
+            "/api/{*rest}",
+            "/files/*/meta",
+            "/files/",
+            "/a/{x:[0-9]+}/*",
+            "/a/{y:[0-9a-f]+}/b",
+        ]
+        .iter()
+        .enumerate()
+        {
+            routes.push(Route::new(format!("t{}", i), *path, "http://t:8080"));
+        }
+        let map = build_router_map(routes);
+
+        for path in [
+            "/api/users",
+            "/api/v2",
+            "/api/v2/x/y",
+            "/api/v1/items",
+            "/api",
+            "/users/42",
+            "/users/bob",
+            "/users/admin/settings",
+            "/users/admin/orders/3",
+            "/files",
+            "/files/x/meta",
+            "/files/x/other",
+            "/a/12/b",
+            "/a/12/c",
+            "/a/zz/b",
+            "/health",
+            "/health/",
+            "/",
+            "",
+            "/nope",
+        ] {
+            let req = RequestInfo::from_path(path);
+            let tree = find_route_for(&req, &map).map(|m| (m.route.id.clone(), m.params));
+            let linear = find_route_linear(&req, &map).map(|m| (m.route.id.clone(), m.params));
+            assert_eq!(tree, linear, "mismatch for {:?}", path);
+        }
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn route_id(map: &RouterMap, req: &Request<()>) -> Option<String> {
+        find_route_for(&RequestInfo::from_request(req), map).map(|m| m.route.id.clone())
+    }
+
+    #[test]
+    fn test_host_conditions() {
+        let mut exact = Route::new("beta", "/api/*", "http://beta:8080");
+        exact.conditions.host = Some("beta.example.com".into());
+        let mut wildcard = Route::new("tenant", "/api/*", "http://tenant:8080");
+        wildcard.conditions.host = Some("*.example.com".into());
+        let map = build_router_map(vec![Route::new("default", "/api/*", "http://default:8080"), wildcard, exact]);
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get("/api/*").unwrap().id, "default");
+
+        let id = |host: &str| route_id(&map, &request("/api/x", &[("host", host)]));
+        assert_eq!(id("beta.example.com:8443").as_deref(), Some("beta"));
+        assert_eq!(id("BETA.example.com").as_deref(), Some("beta"));
+        assert_eq!(id("acme.example.com").as_deref(), Some("tenant"));
+        assert_eq!(id("example.com").as_deref(), Some("default"));
+        assert_eq!(id("other.org").as_deref(), Some("default"));
+
+        // HTTP/2 requests carry the host in the URI authority
+        let req = request("https://a.example.com/api/x", &[]);
+        assert_eq!(route_id(&map, &req).as_deref(), Some("tenant"));
+
+        // Path-only lookups ignore conditional routes
+        assert_eq!(match_route("/api/x", &map).unwrap().id, "default");
+    }
+
+    #[test]
+    fn test_header_query_cookie_conditions() {
+        let mut v2 = Route::new("v2", "/users/{id}", "http://v2:8080");
+        v2.conditions.headers.insert("x-api-version".into(), "2".into());
+        let mut preview = Route::new("preview", "/users/{id}", "http://preview:8080");
+        preview.conditions.query.insert("preview".into(), "true".into());
+        let mut canary = Route::new("canary", "/users/{id}", "http://canary:8080");
+        canary.conditions.cookies.insert("canary".into(), "1".into());
+        let map = build_router_map(vec![Route::new("v1", "/users/{id}", "http://v1:8080"), v2, preview, canary]);
+
+        let id = |uri: &str, headers: &[(&str, &str)]| route_id(&map, &request(uri, headers));
+        assert_eq!(id("/users/1", &[("X-Api-Version", "2")]).as_deref(), Some("v2"));
+        assert_eq!(id("/users/1", &[("x-api-version", "3")]).as_deref(), Some("v1"));
+        assert_eq!(id("/users/1?a=b&preview=true", &[]).as_deref(), Some("preview"));
+        assert_eq!(id("/users/1?preview=false", &[]).as_deref(), Some("v1"));
+        assert_eq!(id("/users/1", &[("cookie", "session=x; canary=1")]).as_deref(), Some("canary"));
+        assert_eq!(id("/users/1", &[("cookie", "canary=0")]).as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn test_conditions_fall_back_to_less_specific_template() {
+        let mut beta = Route::new("beta", "/api/users", "http://beta:8080");
+        beta.conditions.host = Some("beta.example.com".into());
+        let map = build_router_map(vec![beta, Route::new("catchall", "/api/*", "http://api:8080")]);
+
+        let id = |host: &str| route_id(&map, &request("/api/users", &[("host", host)]));
+        assert_eq!(id("beta.example.com").as_deref(), Some("beta"));
+        assert_eq!(id("www.example.com").as_deref(), Some("catchall"));
+
+        let req = request("/api/users", &[("host", "www.example.com")]);
+        let linear = find_route_linear(&RequestInfo::from_request(&req), &map).map(|m| m.route.id.clone());
+        assert_eq!(linear.as_deref(), Some("catchall"));
+    }
+
+    #[test]
+    fn test_same_conditions_replace() {
+        let mut map = RouterMap::new();
+        assert!(map.insert(Route::new("a", "/x", "http://a:8080")).unwrap().is_none());
+        let replaced = map.insert(Route::new("b", "/x", "http://b:8080")).unwrap();
+        assert_eq!(replaced.unwrap().id, "a");
+        assert_eq!(map.len(), 1);
+        assert_eq!(match_route("/x", &map).unwrap().id, "b");
+    }
+
     #[test]
     fn test_no_match() {
         let routes = create_test_routes();
@@ -167,5 +679,31 @@ mod tests {
         assert_eq!(stats.total_routes, 5);
         assert_eq!(stats.active_routes, 5);
         assert_eq!(stats.unique_upstreams, 5);
+        assert_eq!(stats.total_targets, 5);
+        assert_eq!(stats.balancing_policies["weighted_round_robin"], 5);
+        assert_eq!(stats.websocket_routes, 0);
+    }
+
+    #[test]
+    fn test_router_stats_multi_upstream() {
+        use crate::config::{LoadBalancing, UpstreamTarget};
+
+        let mut routes = create_test_routes();
+        routes.push(Route::with_upstreams(
+            "7",
+            "/orders",
+            vec![
+                UpstreamTarget::new("http://orders-1:8080", 50),
+                UpstreamTarget::new("http://orders-2:8080", 50),
+                UpstreamTarget::new("http://user-service:8080", 10),
+            ],
+            LoadBalancing::LeastOutstanding,
+        ));
+        let stats = router_stats(&build_router_map(routes));
+
+        assert_eq!(stats.total_routes, 6);
+        assert_eq!(stats.total_targets, 8);
+        assert_eq!(stats.unique_upstreams, 7);
+        assert_eq!(stats.balancing_policies["least_outstanding"], 1);
     }
 }
@@ -0,0 +1,685 @@
+//! Shared Rhai scripting runtime.
+//!
+//! Every script in NaseejMesh — route scripts, the `transform` filter, the
+//! console's dry runs and the scripts the AI Architect validates — runs on
+//! a [`ScriptEngine`] from this module, so every script gets the same
+//! syntax, helpers and limits.
+//!
+//! # Context contract
+//!
+//! Transforms, dry runs and validation use the message contract below.
+//! Route scripts are the exception: they see the HTTP exchange as
+//! `request` and `response` maps instead (see [`crate::hooks`]), so a
+//! message script has to be adapted before it can run on a route.
+//!
+//! A run gets a [`ScriptContext`] and sees it as these variables:
+//!
+//! | Variable | Contents |
+//! |----------|----------|
+//! | `payload` | The message, parsed: JSON bodies are maps and arrays, other text a string |
+//! | `input` | The message as text |
+//! | `output` | Empty; text assigned here replaces the message |
+//! | `metadata` | String map of headers or message properties |
+//! | `params` | Path parameters captured by the route |
+//! | `protocol`, `destination` | Where the message came from and is going |
+//! | `client_cert` | The caller's TLS client certificate, or `()` |
+//!
+//! Scripts change the message either by assigning text to `output` or by
+//! changing `payload`; see [`ScriptOutcome`].
+//!
+//! # Helpers
+//!
+//! One helper library is registered on every engine. Names from the older
+//! engines are kept as aliases: `uppercase`/`lowercase` for `upper`/`lower`,
+//! `now_utc` for `now_iso`, and `log_info`/`log_debug`/`log_warn` for
+//! `log`/`debug`/`warn`. `timestamp` returns seconds and `timestamp_ms`
+//! milliseconds.
+//!
+//! On top of these, the [`stdlib`](crate::stdlib) module registers helpers
+//! for integration work: JSONPath, XML, encodings, hashes, URLs, dates,
+//! regexes, CSV and number formatting.
+//!
+//! # Limits
+//!
+//! Every run is bounded by [`ScriptLimits`]: a wall-clock deadline checked
+//! from Rhai's progress callback, an operation budget, caps on string,
+//! array and map sizes and a nesting depth checked when a script compiles.
+//! Runs stopped by a limit are counted per script in [`script_metrics`].
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rhai::{Dynamic, Engine, EvalAltResult, ImmutableString, Scope, AST};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::config::ScriptLimits;
+use crate::path::PathParams;
+use crate::tls::ClientCertificate;
+
+/// Scripting errors
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    #[error("Script compilation failed: {0}")]
+    CompileError(String),
+
+    #[error("Script execution failed: {0}")]
+    ExecutionError(String),
+
+    #[error("Timeout exceeded")]
+    Timeout,
+
+    #[error("Script limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    #[error("Output conversion failed: {0}")]
+    OutputError(String),
+}
+
+/// A Rhai engine with the helper library and run limits.
+///
+/// Cheap to clone; clones share the engine.
+#[derive(Clone)]
+pub struct ScriptEngine {
+    engine: Arc<Engine>,
+    limits: ScriptLimits,
+}
+
+impl Default for ScriptEngine {
+    fn default() -> Self {
+        Self::new(ScriptLimits::default())
+    }
+}
+
+impl ScriptEngine {
+    /// Create an engine whose runs are bounded by `limits`
+    pub fn new(limits: ScriptLimits) -> Self {
+        let mut engine = Engine::new();
+        apply_limits(&mut engine, &limits);
+        register_helpers(&mut engine);
+
+        Self {
+            engine: Arc::new(engine),
+            limits,
+        }
+    }
+
+    /// Limits on each run
+    pub fn limits(&self) -> &ScriptLimits {
+        &self.limits
+    }
+
+    /// Compile a script for this engine
+    pub fn compile(&self, source: &str) -> Result<Script, ScriptError> {
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|e| ScriptError::CompileError(e.to_string()))?;
+
+        info!(script_len = source.len(), "Compiled Rhai script");
+
+        Ok(Script {
+            engine: self.clone(),
+            ast,
+            source: source.to_string(),
+            name: "script".to_string(),
+        })
+    }
+
+    /// Check that a script compiles
+    pub fn validate(&self, source: &str) -> Result<(), ScriptError> {
+        self.engine
+            .compile(source)
+            .map(|_| ())
+            .map_err(|e| ScriptError::CompileError(e.to_string()))
+    }
+}
+
+/// A compiled script, ready to run any number of times.
+pub struct Script {
+    engine: ScriptEngine,
+    ast: AST,
+    source: String,
+    name: String,
+}
+
+impl Script {
+    /// Set the name runs are logged and counted under, e.g. the route and
+    /// phase the script belongs to
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Name runs are logged and counted under
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Script source
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Run the script on a message
+    pub fn run(&self, ctx: &ScriptContext) -> Result<ScriptOutcome, ScriptError> {
+        let start = Instant::now();
+
+        let mut scope = ctx.scope();
+        self.run_with_scope(&mut scope)?;
+
+        let output = scope.get_value::<String>("output").unwrap_or_default();
+        let payload = scope
+            .get_value::<Dynamic>("payload")
+            .and_then(|payload| dynamic_to_json(&payload))
+            .unwrap_or(JsonValue::Null);
+        let metadata = scope
+            .get_value::<rhai::Map>("metadata")
+            .map(|metadata| {
+                metadata
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect()
+            })
+            .unwrap_or_else(|| ctx.metadata.clone());
+        let destination = scope
+            .get_value::<String>("destination")
+            .unwrap_or_else(|| ctx.destination.clone());
+
+        let outcome = ScriptOutcome::new(ctx, output, payload, metadata, destination, start.elapsed());
+        debug!(
+            script = %self.name,
+            output_len = outcome.output.len(),
+            execution_us = outcome.execution_us,
+            "Script run complete"
+        );
+        Ok(outcome)
+    }
+
+    /// Run the script against a scope the caller has filled in, for
+    /// callers with their own contract
+    pub fn run_with_scope(&self, scope: &mut Scope) -> Result<(), ScriptError> {
+        let engine = &self.engine.engine;
+        with_deadline(self.engine.limits.timeout(), || engine.run_ast_with_scope(scope, &self.ast)).map_err(|e| {
+            match limit_hit(&e) {
+                Some(limit) => {
+                    script_metrics().record(&self.name, limit);
+                    warn!(script = %self.name, limit = ?limit, error = %e, "Rhai script stopped by a limit");
+                    match limit {
+                        ScriptLimit::Timeout => ScriptError::Timeout,
+                        _ => ScriptError::LimitExceeded(e.to_string()),
+                    }
+                }
+                None => ScriptError::ExecutionError(e.to_string()),
+            }
+        })
+    }
+}
+
+/// The message and its surroundings, as handed to a script run.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptContext {
+    /// The message, parsed
+    pub payload: JsonValue,
+
+    /// The message as text
+    pub input: String,
+
+    /// Headers or message properties
+    pub metadata: HashMap<String, String>,
+
+    /// Path parameters captured by the route
+    pub params: PathParams,
+
+    /// Source protocol, e.g. `http` or `mqtt`
+    pub protocol: String,
+
+    /// Destination path or topic
+    pub destination: String,
+
+    /// The caller's verified TLS client certificate
+    pub client_cert: Option<ClientCertificate>,
+}
+
+impl ScriptContext {
+    /// A message given as text; JSON text is also parsed into `payload`
+    pub fn from_text(input: &str) -> Self {
+        Self {
+            payload: serde_json::from_str(input).unwrap_or_else(|_| JsonValue::String(input.to_string())),
+            input: input.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A structured message; `input` holds its JSON text
+    pub fn from_payload(payload: JsonValue) -> Self {
+        Self {
+            input: payload_text(&payload),
+            payload,
+            ..Default::default()
+        }
+    }
+
+    fn scope(&self) -> Scope<'static> {
+        let metadata: rhai::Map = self
+            .metadata
+            .iter()
+            .map(|(name, value)| (name.into(), Dynamic::from(value.clone())))
+            .collect();
+        let params: rhai::Map = self
+            .params
+            .iter()
+            .map(|(name, value)| (name.into(), Dynamic::from(value.to_string())))
+            .collect();
+        let client_cert = match &self.client_cert {
+            Some(cert) => Dynamic::from_map(client_cert_map(cert)),
+            None => Dynamic::UNIT,
+        };
+
+        let mut scope = Scope::new();
+        scope.push("payload", json_to_dynamic(&self.payload));
+        scope.push("input", self.input.clone());
+        scope.push("output", String::new());
+        scope.push("metadata", metadata);
+        scope.push("params", params);
+        scope.push("protocol", self.protocol.clone());
+        scope.push("destination", self.destination.clone());
+        scope.push("client_cert", client_cert);
+        scope
+    }
+}
+
+/// What a run left behind.
+///
+/// Text assigned to `output` wins: it is the new message text and, parsed,
+/// the new payload. Otherwise a changed `payload` is the new message and
+/// `output` its text. A script that changed neither leaves `output` empty.
+#[derive(Debug, Clone)]
+pub struct ScriptOutcome {
+    /// The message after the run
+    pub payload: JsonValue,
+
+    /// The message text after the run, or empty if the script left the
+    /// message alone
+    pub output: String,
+
+    /// Headers or message properties after the run
+    pub metadata: HashMap<String, String>,
+
+    /// Destination after the run
+    pub destination: String,
+
+    /// Execution time in microseconds
+    pub execution_us: u64,
+}
+
+impl ScriptOutcome {
+    fn new(
+        ctx: &ScriptContext,
+        output: String,
+        payload: JsonValue,
+        metadata: HashMap<String, String>,
+        destination: String,
+        elapsed: Duration,
+    ) -> Self {
+        let (payload, output) = if !output.is_empty() {
+            let parsed = serde_json::from_str(&output).unwrap_or_else(|_| JsonValue::String(output.clone()));
+            (parsed, output)
+        } else if Some(&payload) != dynamic_to_json(&json_to_dynamic(&ctx.payload)).as_ref() {
+            // Compared with the original as it came back from Rhai, since
+            // numbers Rhai can't hold exactly (u64 above i64::MAX) change on
+            // the way through even when the script never touches them
+            let text = payload_text(&payload);
+            (payload, text)
+        } else {
+            (ctx.payload.clone(), output)
+        };
+
+        Self {
+            payload,
+            output,
+            metadata,
+            destination,
+            execution_us: elapsed.as_micros() as u64,
+        }
+    }
+}
+
+/// Text of a payload: strings as they are, anything else as JSON
+fn payload_text(payload: &JsonValue) -> String {
+    match payload {
+        JsonValue::String(text) => text.clone(),
+        payload => payload.to_string(),
+    }
+}
+
+/// Register the helper library
+fn register_helpers(engine: &mut Engine) {
+    // JSON parsing
+    engine.register_fn("parse_json", |s: &str| -> Dynamic {
+        match serde_json::from_str::<JsonValue>(s) {
+            Ok(v) => json_to_dynamic(&v),
+            Err(_) => Dynamic::UNIT,
+        }
+    });
+
+    // JSON stringification
+    engine.register_fn("to_json", |d: Dynamic| -> ImmutableString {
+        dynamic_to_json(&d)
+            .map(|v| serde_json::to_string(&v).unwrap_or_default())
+            .unwrap_or_default()
+            .into()
+    });
+
+    // Pretty JSON
+    engine.register_fn("to_json_pretty", |d: Dynamic| -> ImmutableString {
+        dynamic_to_json(&d)
+            .map(|v| serde_json::to_string_pretty(&v).unwrap_or_default())
+            .unwrap_or_default()
+            .into()
+    });
+
+    // XML wrapping (simple)
+    engine.register_fn("wrap_xml", |tag: &str, content: &str| -> ImmutableString {
+        format!("<{}>{}</{}>", tag, escape_xml(content), tag).into()
+    });
+
+    // String utilities
+    engine.register_fn("trim", |s: &str| -> ImmutableString { s.trim().into() });
+    engine.register_fn("upper", |s: &str| -> ImmutableString { s.to_uppercase().into() });
+    engine.register_fn("lower", |s: &str| -> ImmutableString { s.to_lowercase().into() });
+    engine.register_fn("uppercase", |s: &str| -> ImmutableString { s.to_uppercase().into() });
+    engine.register_fn("lowercase", |s: &str| -> ImmutableString { s.to_lowercase().into() });
+
+    // Number conversions (f64)
+    engine.register_fn("celsius_to_fahrenheit", |c: f64| -> f64 { c * 9.0 / 5.0 + 32.0 });
+    engine.register_fn("fahrenheit_to_celsius", |f: f64| -> f64 { (f - 32.0) * 5.0 / 9.0 });
+
+    // Number conversions (i64 -> f64 for JSON compatibility)
+    engine.register_fn("celsius_to_fahrenheit", |c: i64| -> f64 { (c as f64) * 9.0 / 5.0 + 32.0 });
+    engine.register_fn("fahrenheit_to_celsius", |f: i64| -> f64 { ((f as f64) - 32.0) * 5.0 / 9.0 });
+
+    // Timestamps
+    engine.register_fn("now_iso", || -> ImmutableString { chrono::Utc::now().to_rfc3339().into() });
+    engine.register_fn("now_utc", || -> ImmutableString { chrono::Utc::now().to_rfc3339().into() });
+    engine.register_fn("timestamp", || -> i64 { chrono::Utc::now().timestamp() });
+    engine.register_fn("timestamp_ms", || -> i64 { chrono::Utc::now().timestamp_millis() });
+
+    // UUID
+    engine.register_fn("uuid", || -> ImmutableString { uuid::Uuid::new_v4().to_string().into() });
+
+    // Logging
+    engine.register_fn("log", |msg: &str| info!(rhai = true, "{}", msg));
+    engine.register_fn("debug", |msg: &str| debug!(rhai = true, "{}", msg));
+    engine.register_fn("warn", |msg: &str| warn!(rhai = true, "{}", msg));
+    engine.register_fn("log_info", |msg: &str| info!(rhai = true, "{}", msg));
+    engine.register_fn("log_debug", |msg: &str| debug!(rhai = true, "{}", msg));
+    engine.register_fn("log_warn", |msg: &str| warn!(rhai = true, "{}", msg));
+
+    crate::stdlib::register(engine);
+}
+
+/// Which limit stopped a script run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptLimit {
+    /// The wall-clock deadline passed
+    Timeout,
+    /// The operation budget ran out
+    Operations,
+    /// A string, array or map grew past its cap
+    Size,
+    /// Calls or expressions nested too deeply
+    Depth,
+}
+
+thread_local! {
+    /// Deadline of the script run in progress on this thread
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Operations between two checks of the deadline
+const DEADLINE_CHECK_INTERVAL: u64 = 256;
+
+/// Apply run limits to an engine, including the progress callback that
+/// stops runs past the deadline set by [`with_deadline`].
+fn apply_limits(engine: &mut Engine, limits: &ScriptLimits) {
+    engine.set_max_operations(limits.max_operations);
+    engine.set_max_expr_depths(limits.max_expr_depth, limits.max_expr_depth);
+    engine.set_max_string_size(limits.max_string_size);
+    engine.set_max_array_size(limits.max_array_size);
+    engine.set_max_map_size(limits.max_map_size);
+
+    engine.on_progress(|operations| {
+        if operations % DEADLINE_CHECK_INTERVAL != 0 {
+            return None;
+        }
+        let expired = DEADLINE.with(|deadline| deadline.get().is_some_and(|at| Instant::now() >= at));
+        expired.then(|| Dynamic::from("timeout"))
+    });
+}
+
+/// Run `f` with a deadline, or none, for the scripts it runs on this thread
+fn with_deadline<T>(timeout: Option<Duration>, f: impl FnOnce() -> T) -> T {
+    let previous = DEADLINE.with(|deadline| deadline.replace(timeout.map(|timeout| Instant::now() + timeout)));
+    let result = f();
+    DEADLINE.with(|deadline| deadline.set(previous));
+    result
+}
+
+/// The limit that stopped a run, if one did
+fn limit_hit(error: &EvalAltResult) -> Option<ScriptLimit> {
+    match error.unwrap_inner() {
+        EvalAltResult::ErrorTerminated(..) => Some(ScriptLimit::Timeout),
+        EvalAltResult::ErrorTooManyOperations(_) => Some(ScriptLimit::Operations),
+        EvalAltResult::ErrorDataTooLarge(..) => Some(ScriptLimit::Size),
+        EvalAltResult::ErrorStackOverflow(_) => Some(ScriptLimit::Depth),
+        _ => None,
+    }
+}
+
+/// Counts of script runs stopped by each limit, per script name.
+#[derive(Debug, Default)]
+pub struct ScriptMetrics {
+    hits: Mutex<HashMap<String, HashMap<ScriptLimit, u64>>>,
+}
+
+impl ScriptMetrics {
+    /// Count a run of `script` stopped by `limit`
+    pub fn record(&self, script: &str, limit: ScriptLimit) {
+        let mut hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        *hits.entry(script.to_string()).or_default().entry(limit).or_insert(0) += 1;
+    }
+
+    /// Runs of `script` stopped by `limit` so far
+    pub fn count(&self, script: &str, limit: ScriptLimit) -> u64 {
+        let hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        hits.get(script).and_then(|limits| limits.get(&limit)).copied().unwrap_or(0)
+    }
+
+    /// All counts, ordered by script name
+    pub fn snapshot(&self) -> BTreeMap<String, BTreeMap<ScriptLimit, u64>> {
+        let hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        hits.iter()
+            .map(|(script, limits)| (script.clone(), limits.iter().map(|(l, n)| (*l, *n)).collect()))
+            .collect()
+    }
+}
+
+/// Process-wide counts of script runs stopped by a limit
+pub fn script_metrics() -> &'static ScriptMetrics {
+    static METRICS: OnceLock<ScriptMetrics> = OnceLock::new();
+    METRICS.get_or_init(ScriptMetrics::default)
+}
+
+/// Expose a client certificate to scripts
+pub fn client_cert_map(cert: &ClientCertificate) -> rhai::Map {
+    let sans: rhai::Array = cert.sans.iter().map(|san| Dynamic::from(san.clone())).collect();
+
+    let mut map = rhai::Map::new();
+    map.insert("subject".into(), cert.subject.clone().into());
+    map.insert("cn".into(), cert.common_name.clone().map_or(Dynamic::UNIT, Dynamic::from));
+    map.insert("sans".into(), sans.into());
+    map.insert("issuer".into(), cert.issuer.clone().into());
+    map.insert("serial".into(), cert.serial.clone().into());
+    map
+}
+
+/// Convert JSON to Rhai Dynamic
+pub fn json_to_dynamic(value: &JsonValue) -> Dynamic {
+    match value {
+        JsonValue::Null => Dynamic::UNIT,
+        JsonValue::Bool(b) => Dynamic::from(*b),
+        JsonValue::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Dynamic::from(i)
+            } else if let Some(f) = n.as_f64() {
+                Dynamic::from(f)
+            } else {
+                Dynamic::UNIT
+            }
+        }
+        JsonValue::String(s) => Dynamic::from(s.clone()),
+        JsonValue::Array(arr) => {
+            let vec: Vec<Dynamic> = arr.iter().map(json_to_dynamic).collect();
+            Dynamic::from(vec)
+        }
+        JsonValue::Object(obj) => {
+            let map: rhai::Map = obj
+                .iter()
+                .map(|(k, v)| (k.clone().into(), json_to_dynamic(v)))
+                .collect();
+            Dynamic::from(map)
+        }
+    }
+}
+
+/// Convert Rhai Dynamic to JSON. Values with no JSON form (functions,
+/// custom types) are `None` and left out of arrays and maps.
+pub fn dynamic_to_json(value: &Dynamic) -> Option<JsonValue> {
+    if value.is_unit() {
+        return Some(JsonValue::Null);
+    }
+
+    if let Some(b) = value.clone().try_cast::<bool>() {
+        return Some(JsonValue::Bool(b));
+    }
+
+    if let Some(i) = value.clone().try_cast::<i64>() {
+        return Some(JsonValue::Number(i.into()));
+    }
+
+    if let Some(f) = value.clone().try_cast::<f64>() {
+        return serde_json::Number::from_f64(f).map(JsonValue::Number);
+    }
+
+    if let Some(s) = value.clone().try_cast::<ImmutableString>() {
+        return Some(JsonValue::String(s.to_string()));
+    }
+
+    if let Some(arr) = value.clone().try_cast::<Vec<Dynamic>>() {
+        let json_arr: Vec<JsonValue> = arr.iter().filter_map(dynamic_to_json).collect();
+        return Some(JsonValue::Array(json_arr));
+    }
+
+    if let Some(map) = value.clone().try_cast::<rhai::Map>() {
+        let json_obj: serde_json::Map<String, JsonValue> = map
+            .iter()
+            .filter_map(|(k, v)| dynamic_to_json(v).map(|jv| (k.to_string(), jv)))
+            .collect();
+        return Some(JsonValue::Object(json_obj));
+    }
+
+    None
+}
+
+/// Escape XML special characters
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn run(source: &str, ctx: &ScriptContext) -> ScriptOutcome {
+        ScriptEngine::default().compile(source).unwrap().run(ctx).unwrap()
+    }
+
+    #[test]
+    fn test_text_contract() {
+        let outcome = run("output = upper(input);", &ScriptContext::from_text("hello"));
+        assert_eq!(outcome.output, "HELLO");
+        assert_eq!(outcome.payload, json!("HELLO"));
+
+        // JSON text is parsed into the payload as well
+        let outcome = run(
+            "let data = parse_json(input); data.seen = payload.n + 1; output = to_json(data);",
+            &ScriptContext::from_text(r#"{"n": 1}"#),
+        );
+        assert_eq!(outcome.payload, json!({"n": 1, "seen": 2}));
+    }
+
+    #[test]
+    fn test_payload_contract() {
+        let mut ctx = ScriptContext::from_payload(json!({"temp": 20}));
+        ctx.metadata.insert("device".to_string(), "d1".to_string());
+        ctx.protocol = "mqtt".to_string();
+        ctx.destination = "/sensors".to_string();
+
+        let outcome = run(
+            r#"
+            payload.temp_f = celsius_to_fahrenheit(payload.temp);
+            metadata.unit = "F";
+            destination = destination + "/" + metadata.device + "/" + protocol;
+            "#,
+            &ctx,
+        );
+        assert_eq!(outcome.payload, json!({"temp": 20, "temp_f": 68.0}));
+        assert_eq!(outcome.output, r#"{"temp":20,"temp_f":68.0}"#);
+        assert_eq!(outcome.metadata["unit"], "F");
+        assert_eq!(outcome.destination, "/sensors/d1/mqtt");
+
+        // Nothing changed: no output
+        let outcome = run("let x = payload.temp;", &ctx);
+        assert_eq!(outcome.output, "");
+        assert_eq!(outcome.payload, ctx.payload);
+
+        // Numbers Rhai can't hold exactly don't count as a change
+        let ctx = ScriptContext::from_payload(json!({"id": u64::MAX, "n": 1}));
+        let outcome = run("let x = payload.n;", &ctx);
+        assert_eq!(outcome.output, "");
+        assert_eq!(outcome.payload, json!({"id": u64::MAX, "n": 1}));
+    }
+
+    #[test]
+    fn test_helper_aliases() {
+        let ctx = ScriptContext::default();
+        let outcome = run(
+            r#"
+            log_info("info"); log("info"); log_warn("warn"); warn("warn");
+            output = uppercase("a") + upper("b") + lowercase("C") + lower("D");
+            if now_utc().len() < 20 || now_iso().len() < 20 { throw "bad date"; }
+            if timestamp_ms() / 1000 - timestamp() > 1 { throw "bad timestamp"; }
+            "#,
+            &ctx,
+        );
+        assert_eq!(outcome.output, "ABcd");
+    }
+
+    #[test]
+    fn test_validate() {
+        let engine = ScriptEngine::default();
+        assert!(engine.validate("payload.x = 1;").is_ok());
+        assert!(matches!(engine.validate("let x = ;"), Err(ScriptError::CompileError(_))));
+    }
+}
@@ -0,0 +1,91 @@
+//! Shared data-plane state.
+//!
+//! Groups the hot-swappable routing table with the long-lived runtime
+//! components that must survive configuration reloads (connection pools,
+//! counters). A single `Arc<GatewayState>` is cloned into every request.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::balancer::LoadBalancer;
+use crate::cache::ResponseCache;
+use crate::circuit::CircuitBreakers;
+use crate::config::RouterMap;
+use crate::filter::FilterRegistry;
+use crate::health::HealthRegistry;
+use crate::proxy::UpstreamClient;
+use crate::retry::RetryBudget;
+use crate::router::{router_stats, RouterStats};
+use crate::websocket::WebSocketConnections;
+
+/// Runtime state shared by all request handlers.
+pub struct GatewayState {
+    /// Routing configuration, swapped atomically on reload
+    pub config: Arc<ArcSwap<RouterMap>>,
+
+    /// Pooled client for upstream requests
+    pub client: UpstreamClient,
+
+    /// Upstream target selection and in-flight tracking
+    pub balancer: LoadBalancer,
+
+    /// Active and passive health of upstream targets
+    pub health: HealthRegistry,
+
+    /// Per-route, per-target circuit breakers
+    pub breakers: CircuitBreakers,
+
+    /// Gateway-wide cap on retries and hedged requests
+    pub retry_budget: RetryBudget,
+
+    /// Cached upstream responses, shared by all routes
+    pub cache: ResponseCache,
+
+    /// Open WebSocket connections per route
+    pub websockets: WebSocketConnections,
+
+    /// Filters routes can opt into by name, registered at startup
+    pub filters: FilterRegistry,
+
+    /// Set once shutdown begins; readiness then reports unavailable
+    draining: AtomicBool,
+}
+
+impl GatewayState {
+    /// Create gateway state around an existing routing configuration
+    pub fn new(config: Arc<ArcSwap<RouterMap>>) -> Self {
+        Self {
+            config,
+            client: UpstreamClient::new(),
+            balancer: LoadBalancer::new(),
+            health: HealthRegistry::new(),
+            breakers: CircuitBreakers::new(),
+            retry_budget: RetryBudget::default(),
+            cache: ResponseCache::default(),
+            websockets: WebSocketConnections::new(),
+            filters: FilterRegistry::new(),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Mark the gateway as shutting down, so load balancers stop sending
+    /// it new traffic while in-flight requests drain
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether shutdown has begun
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Statistics about the current routing table, including open
+    /// WebSocket connections
+    pub fn router_stats(&self) -> RouterStats {
+        let mut stats = router_stats(&self.config.load());
+        stats.websocket_connections = self.websockets.snapshot();
+        stats
+    }
+}
@@ -0,0 +1,796 @@
+//! Script standard library for integration work.
+//!
+//! Registered on every [`ScriptEngine`](crate::scripting::ScriptEngine)
+//! next to the basic helpers. Helpers that can fail (bad input, a bad
+//! pattern or format) raise a script error, which fails the run like a
+//! `throw` would.
+//!
+//! - **Querying**: `json_path(value, path)` returns every match of a
+//!   [JSONPath](crate::jsonpath) expression, `json_path_first(value, path)`
+//!   the first one or `()`
+//! - **XML**: `parse_xml(text)`, `to_xml(value, root)`
+//! - **Encoding**: `base64_encode`, `base64_decode`, `base64url_encode`,
+//!   `base64url_decode`, `hex_encode`, `hex_decode`; the `_decode_bytes`
+//!   variants return a blob for data that is not text, and the encoders
+//!   also take blobs
+//! - **Hashing**: `sha256`, `sha512`, `hmac_sha256(key, message)` and
+//!   `hmac_sha512` as hex, `hmac_sha256_base64` as base64, and
+//!   `hmac_sha256_bytes` / `hmac_sha512_bytes` as blobs. Keys may be text
+//!   or blobs, so signing keys can be chained as in AWS Signature V4.
+//! - **URLs**: `url_encode`, `url_decode`, `parse_query(text)` into a map,
+//!   `to_query(map)`
+//! - **Dates**: `parse_date(text)`, `parse_date(text, format)`,
+//!   `format_date(ts, format)`, `format_date(ts, format, tz)`,
+//!   `to_timezone(text, tz)`, with `tz` e.g. `+04:00` or `Europe/London`
+//! - **Regex**: `regex_match`, `regex_find`, `regex_captures(text, pattern)`,
+//!   `regex_replace(text, pattern, with)`
+//! - **CSV**: `parse_csv(text)` and `parse_csv(text, delimiter)` into maps
+//!   keyed by the header row, `parse_csv_rows(text)` into arrays,
+//!   `to_csv(rows)` and `to_csv(rows, columns)`
+//! - **Numbers**: `format_number(n, decimals)`, `format_currency(n, code)`
+//!
+//! Dates are Unix timestamps in seconds, as returned by `timestamp()`.
+//! Formats use `strftime` syntax (`%Y-%m-%d %H:%M`) and time zones are
+//! `UTC`, fixed offsets such as `+04:00` or `-0500`, or IANA names such as
+//! `Europe/London`, whose offset follows daylight saving time (see
+//! [`timezone`](crate::timezone)). XML follows the
+//! SOAP adapter's mapping: attributes are `@name` keys and mixed text is
+//! `#text`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine as _;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::{Regex, RegexBuilder};
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, ImmutableString, Map};
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::jsonpath::JsonPath;
+use crate::scripting::{dynamic_to_json, json_to_dynamic};
+use crate::xml::{JsonToXml, XmlToJson};
+
+type HelperResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Characters `url_encode` leaves alone: the RFC 3986 unreserved set
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// Compiled patterns kept for reuse across runs
+const REGEX_CACHE_SIZE: usize = 256;
+
+/// Largest compiled pattern, so scripts cannot build huge automata
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Register the library on an engine
+pub(crate) fn register(engine: &mut Engine) {
+    register_query(engine);
+    register_xml(engine);
+    register_encoding(engine);
+    register_hashing(engine);
+    register_urls(engine);
+    register_dates(engine);
+    register_regex(engine);
+    register_csv(engine);
+    register_numbers(engine);
+}
+
+fn failed(helper: &str, error: impl std::fmt::Display) -> Box<EvalAltResult> {
+    format!("{}: {}", helper, error).into()
+}
+
+/// A script value as JSON, failing for values with no JSON form
+fn to_json_value(helper: &str, value: &Dynamic) -> HelperResult<JsonValue> {
+    dynamic_to_json(value).ok_or_else(|| failed(helper, format!("cannot convert {} to JSON", value.type_name())))
+}
+
+fn register_query(engine: &mut Engine) {
+    engine.register_fn("json_path", |value: Dynamic, path: &str| -> HelperResult<Array> {
+        let path = JsonPath::parse(path).map_err(|e| failed("json_path", e))?;
+        let value = to_json_value("json_path", &value)?;
+        Ok(path.query(&value).into_iter().map(json_to_dynamic).collect())
+    });
+
+    engine.register_fn("json_path_first", |value: Dynamic, path: &str| -> HelperResult<Dynamic> {
+        let path = JsonPath::parse(path).map_err(|e| failed("json_path_first", e))?;
+        let value = to_json_value("json_path_first", &value)?;
+        Ok(path.first(&value).map_or(Dynamic::UNIT, json_to_dynamic))
+    });
+}
+
+fn register_xml(engine: &mut Engine) {
+    engine.register_fn("parse_xml", |text: &str| -> HelperResult<Dynamic> {
+        let json = XmlToJson::new().transcode_str(text).map_err(|e| failed("parse_xml", e))?;
+        Ok(json_to_dynamic(&json))
+    });
+
+    engine.register_fn("to_xml", |value: Dynamic, root: &str| -> HelperResult<ImmutableString> {
+        let json = to_json_value("to_xml", &value)?;
+        let xml = JsonToXml::new(root)
+            .with_declaration(false)
+            .transcode(&json)
+            .map_err(|e| failed("to_xml", e))?;
+        String::from_utf8(xml.to_vec()).map(Into::into).map_err(|e| failed("to_xml", e))
+    });
+}
+
+fn register_encoding(engine: &mut Engine) {
+    fn text(helper: &str, bytes: Vec<u8>) -> HelperResult<ImmutableString> {
+        String::from_utf8(bytes).map(Into::into).map_err(|_| failed(helper, "decoded bytes are not UTF-8 text"))
+    }
+
+    engine.register_fn("base64_encode", |s: &str| -> ImmutableString { STANDARD.encode(s).into() });
+    engine.register_fn("base64_decode", |s: &str| -> HelperResult<ImmutableString> {
+        let bytes = STANDARD.decode(s.trim()).map_err(|e| failed("base64_decode", e))?;
+        text("base64_decode", bytes)
+    });
+    engine.register_fn("base64url_encode", |s: &str| -> ImmutableString { URL_SAFE_NO_PAD.encode(s).into() });
+    engine.register_fn("base64url_decode", |s: &str| -> HelperResult<ImmutableString> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(s.trim().trim_end_matches('='))
+            .map_err(|e| failed("base64url_decode", e))?;
+        text("base64url_decode", bytes)
+    });
+    engine.register_fn("hex_encode", |s: &str| -> ImmutableString { hex::encode(s).into() });
+    engine.register_fn("hex_decode", |s: &str| -> HelperResult<ImmutableString> {
+        let bytes = hex::decode(s.trim()).map_err(|e| failed("hex_decode", e))?;
+        text("hex_decode", bytes)
+    });
+
+    engine.register_fn("base64_encode", |b: Blob| -> ImmutableString { STANDARD.encode(b).into() });
+    engine.register_fn("base64_decode_bytes", |s: &str| -> HelperResult<Blob> {
+        STANDARD.decode(s.trim()).map_err(|e| failed("base64_decode_bytes", e))
+    });
+    engine.register_fn("base64url_encode", |b: Blob| -> ImmutableString { URL_SAFE_NO_PAD.encode(b).into() });
+    engine.register_fn("base64url_decode_bytes", |s: &str| -> HelperResult<Blob> {
+        URL_SAFE_NO_PAD
+            .decode(s.trim().trim_end_matches('='))
+            .map_err(|e| failed("base64url_decode_bytes", e))
+    });
+    engine.register_fn("hex_encode", |b: Blob| -> ImmutableString { hex::encode(b).into() });
+    engine.register_fn("hex_decode_bytes", |s: &str| -> HelperResult<Blob> {
+        hex::decode(s.trim()).map_err(|e| failed("hex_decode_bytes", e))
+    });
+}
+
+fn register_hashing(engine: &mut Engine) {
+    engine.register_fn("sha256", |s: &str| -> ImmutableString { hex::encode(Sha256::digest(s)).into() });
+    engine.register_fn("sha256", |b: Blob| -> ImmutableString { hex::encode(Sha256::digest(b)).into() });
+    engine.register_fn("sha512", |s: &str| -> ImmutableString { hex::encode(Sha512::digest(s)).into() });
+    engine.register_fn("sha512", |b: Blob| -> ImmutableString { hex::encode(Sha512::digest(b)).into() });
+
+    engine.register_fn("hmac_sha256", |key: &str, message: &str| -> ImmutableString {
+        hex::encode(hmac_sha256(key.as_bytes(), message)).into()
+    });
+    engine.register_fn("hmac_sha256", |key: Blob, message: &str| -> ImmutableString {
+        hex::encode(hmac_sha256(&key, message)).into()
+    });
+    engine.register_fn("hmac_sha512", |key: &str, message: &str| -> ImmutableString {
+        hex::encode(hmac_sha512(key.as_bytes(), message)).into()
+    });
+    engine.register_fn("hmac_sha512", |key: Blob, message: &str| -> ImmutableString {
+        hex::encode(hmac_sha512(&key, message)).into()
+    });
+    engine.register_fn("hmac_sha256_base64", |key: &str, message: &str| -> ImmutableString {
+        STANDARD.encode(hmac_sha256(key.as_bytes(), message)).into()
+    });
+    engine.register_fn("hmac_sha256_base64", |key: Blob, message: &str| -> ImmutableString {
+        STANDARD.encode(hmac_sha256(&key, message)).into()
+    });
+
+    engine.register_fn("hmac_sha256_bytes", |key: &str, message: &str| -> Blob {
+        hmac_sha256(key.as_bytes(), message)
+    });
+    engine.register_fn("hmac_sha256_bytes", |key: Blob, message: &str| -> Blob { hmac_sha256(&key, message) });
+    engine.register_fn("hmac_sha512_bytes", |key: &str, message: &str| -> Blob {
+        hmac_sha512(key.as_bytes(), message)
+    });
+    engine.register_fn("hmac_sha512_bytes", |key: Blob, message: &str| -> Blob { hmac_sha512(&key, message) });
+}
+
+fn hmac_sha256(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hmac_sha512(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn register_urls(engine: &mut Engine) {
+    engine.register_fn("url_encode", |s: &str| -> ImmutableString {
+        utf8_percent_encode(s, URL_COMPONENT).to_string().into()
+    });
+    engine.register_fn("url_decode", |s: &str| -> HelperResult<ImmutableString> {
+        let decoded = percent_decode_str(s).decode_utf8().map_err(|e| failed("url_decode", e))?;
+        Ok(decoded.as_ref().into())
+    });
+
+    // Repeated names keep the last value, as route scripts' `request.query` does
+    engine.register_fn("parse_query", |s: &str| -> Map {
+        form_urlencoded::parse(s.trim_start_matches('?').as_bytes())
+            .map(|(name, value)| (name.as_ref().into(), Dynamic::from(value.into_owned())))
+            .collect()
+    });
+    engine.register_fn("to_query", |params: Map| -> ImmutableString {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in &params {
+            query.append_pair(name, &value.to_string());
+        }
+        query.finish().into()
+    });
+}
+
+fn register_dates(engine: &mut Engine) {
+    engine.register_fn("parse_date", |text: &str| -> HelperResult<i64> {
+        parse_date(text, None).map(|date| date.timestamp())
+    });
+    engine.register_fn("parse_date", |text: &str, format: &str| -> HelperResult<i64> {
+        parse_date(text, Some(format)).map(|date| date.timestamp())
+    });
+    engine.register_fn("format_date", |timestamp: i64, format: &str| -> HelperResult<ImmutableString> {
+        format_date(timestamp, format, "UTC")
+    });
+    engine.register_fn(
+        "format_date",
+        |timestamp: i64, format: &str, tz: &str| -> HelperResult<ImmutableString> {
+            format_date(timestamp, format, tz)
+        },
+    );
+    engine.register_fn("to_timezone", |text: &str, tz: &str| -> HelperResult<ImmutableString> {
+        let date = parse_date(text, None)?;
+        let offset = zone_offset(tz, date.timestamp())?;
+        Ok(date.with_timezone(&offset).to_rfc3339().into())
+    });
+}
+
+/// Parse a date with a `strftime` format, or else as RFC 3339, RFC 2822,
+/// `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD`. Dates without an offset are UTC.
+fn parse_date(text: &str, format: Option<&str>) -> HelperResult<DateTime<FixedOffset>> {
+    let text = text.trim();
+    let utc = |naive: NaiveDateTime| Utc.fix().from_utc_datetime(&naive);
+    let date = match format {
+        Some(format) => DateTime::parse_from_str(text, format)
+            .ok()
+            .or_else(|| NaiveDateTime::parse_from_str(text, format).ok().map(utc))
+            .or_else(|| NaiveDate::parse_from_str(text, format).ok().and_then(|d| d.and_hms_opt(0, 0, 0)).map(utc)),
+        None => DateTime::parse_from_rfc3339(text)
+            .ok()
+            .or_else(|| DateTime::parse_from_rfc2822(text).ok())
+            .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").ok().map(utc))
+            .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)).map(utc)),
+    };
+    date.ok_or_else(|| match format {
+        Some(format) => failed("parse_date", format!("'{}' does not match the format '{}'", text, format)),
+        None => failed("parse_date", format!("'{}' is not a date", text)),
+    })
+}
+
+fn format_date(timestamp: i64, format: &str, tz: &str) -> HelperResult<ImmutableString> {
+    let offset = zone_offset(tz, timestamp)?;
+    let date = DateTime::from_timestamp(timestamp, 0)
+        .ok_or_else(|| failed("format_date", format!("{} is out of range", timestamp)))?
+        .with_timezone(&offset);
+
+    // Bad format specifiers surface as a formatting error rather than a panic
+    let mut formatted = String::new();
+    write!(formatted, "{}", date.format(format))
+        .map_err(|_| failed("format_date", format!("bad format '{}'", format)))?;
+    Ok(formatted.into())
+}
+
+/// The offset of a fixed offset or IANA zone at a Unix timestamp. Zone
+/// data is compiled in, so this works on hosts without tzdata.
+fn zone_offset(tz: &str, timestamp: i64) -> HelperResult<FixedOffset> {
+    parse_offset(tz).or_else(|e| {
+        let zone: Tz = tz.trim().parse().map_err(|_| e)?;
+        let date = DateTime::from_timestamp(timestamp, 0).ok_or_else(|| failed("time zone", "timestamp out of range"))?;
+        Ok(zone.offset_from_utc_datetime(&date.naive_utc()).fix())
+    })
+}
+
+/// `UTC`, `Z` or an offset such as `+04:00`, `-0500` or `+03`
+fn parse_offset(tz: &str) -> HelperResult<FixedOffset> {
+    let tz = tz.trim();
+    if tz.eq_ignore_ascii_case("utc") || tz.eq_ignore_ascii_case("gmt") || tz == "Z" {
+        return Ok(Utc.fix());
+    }
+
+    let bad = || failed("time zone", format!("'{}' is not UTC, an offset such as +04:00 or a zone name", tz));
+    let (sign, digits) = match tz.as_bytes().first() {
+        Some(b'+') => (1, &tz[1..]),
+        Some(b'-') => (-1, &tz[1..]),
+        _ => return Err(bad()),
+    };
+    let digits = digits.replace(':', "");
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (&digits[..], "0"),
+        4 => (&digits[..2], &digits[2..]),
+        _ => return Err(bad()),
+    };
+    let seconds = hours.parse::<i32>().map_err(|_| bad())? * 3600 + minutes.parse::<i32>().map_err(|_| bad())? * 60;
+    FixedOffset::east_opt(sign * seconds).ok_or_else(bad)
+}
+
+fn register_regex(engine: &mut Engine) {
+    engine.register_fn("regex_match", |text: &str, pattern: &str| -> HelperResult<bool> {
+        Ok(regex(pattern)?.is_match(text))
+    });
+    engine.register_fn("regex_find", |text: &str, pattern: &str| -> HelperResult<Dynamic> {
+        Ok(regex(pattern)?.find(text).map_or(Dynamic::UNIT, |m| m.as_str().into()))
+    });
+    engine.register_fn("regex_captures", |text: &str, pattern: &str| -> HelperResult<Dynamic> {
+        let captures = regex(pattern)?.captures(text).map(|captures| {
+            captures
+                .iter()
+                .map(|group| group.map_or(Dynamic::UNIT, |m| m.as_str().into()))
+                .collect::<Array>()
+        });
+        Ok(captures.map_or(Dynamic::UNIT, Dynamic::from_array))
+    });
+    engine.register_fn(
+        "regex_replace",
+        |text: &str, pattern: &str, with: &str| -> HelperResult<ImmutableString> {
+            Ok(regex(pattern)?.replace_all(text, with).as_ref().into())
+        },
+    );
+}
+
+/// A compiled pattern, from the cache when a script used it before
+fn regex(pattern: &str) -> HelperResult<Regex> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Regex>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    if let Some(regex) = cache.lock().unwrap_or_else(|e| e.into_inner()).get(pattern) {
+        return Ok(regex.clone());
+    }
+
+    let regex = RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|e| failed("regex", e))?;
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    if cache.len() >= REGEX_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
+
+fn register_csv(engine: &mut Engine) {
+    engine.register_fn("parse_csv", |text: &str| -> HelperResult<Array> { csv_records(text, ',') });
+    engine.register_fn("parse_csv", |text: &str, delimiter: &str| -> HelperResult<Array> {
+        csv_records(text, delimiter_char(delimiter)?)
+    });
+    engine.register_fn("parse_csv_rows", |text: &str| -> HelperResult<Array> {
+        let rows = parse_csv(text, ',').map_err(|e| failed("parse_csv_rows", e))?;
+        Ok(rows.into_iter().map(|row| Dynamic::from_array(row.into_iter().map(Dynamic::from).collect())).collect())
+    });
+    engine.register_fn("to_csv", |rows: Array| -> HelperResult<ImmutableString> { to_csv(&rows, None) });
+    engine.register_fn("to_csv", |rows: Array, columns: Array| -> HelperResult<ImmutableString> {
+        let columns = columns.iter().map(|column| column.to_string()).collect::<Vec<_>>();
+        to_csv(&rows, Some(columns))
+    });
+}
+
+fn delimiter_char(delimiter: &str) -> HelperResult<char> {
+    let mut chars = delimiter.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c != '"' && c != '\n' && c != '\r' => Ok(c),
+        _ => Err(failed("parse_csv", format!("bad delimiter '{}'", delimiter))),
+    }
+}
+
+/// Rows as maps keyed by the header row
+fn csv_records(text: &str, delimiter: char) -> HelperResult<Array> {
+    let mut rows = parse_csv(text, delimiter).map_err(|e| failed("parse_csv", e))?.into_iter();
+    let Some(header) = rows.next() else {
+        return Ok(Array::new());
+    };
+    Ok(rows
+        .map(|row| {
+            let record: Map = header
+                .iter()
+                .zip(row.into_iter().chain(std::iter::repeat(String::new())))
+                .map(|(name, value)| (name.as_str().into(), Dynamic::from(value)))
+                .collect();
+            Dynamic::from_map(record)
+        })
+        .collect())
+}
+
+/// Split RFC 4180 CSV into rows of fields. Quoted fields may hold the
+/// delimiter, line breaks and `""` for a quote; blank lines are skipped.
+fn parse_csv(text: &str, delimiter: char) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                row.push(std::mem::take(&mut field));
+                if row.len() > 1 || !row[0].is_empty() {
+                    rows.push(std::mem::take(&mut row));
+                } else {
+                    row.clear();
+                }
+            }
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Write rows of maps (columns from `columns`, or every key in order) or
+/// rows of arrays as CSV
+fn to_csv(rows: &Array, columns: Option<Vec<String>>) -> HelperResult<ImmutableString> {
+    let columns = columns.or_else(|| {
+        let maps = rows.iter().filter_map(|row| row.read_lock::<Map>().map(|map| map.clone()));
+        let mut columns: Vec<String> = Vec::new();
+        for map in maps {
+            for name in map.keys() {
+                if !columns.iter().any(|column| column == name.as_str()) {
+                    columns.push(name.to_string());
+                }
+            }
+        }
+        (!columns.is_empty()).then_some(columns)
+    });
+
+    let mut out = String::new();
+    if let Some(columns) = &columns {
+        write_csv_row(&mut out, columns.iter().map(String::as_str));
+    }
+    for row in rows {
+        if let Some(map) = row.read_lock::<Map>() {
+            let columns = columns.as_deref().unwrap_or_default();
+            let fields: Vec<String> = columns.iter().map(|column| csv_field(map.get(column.as_str()))).collect();
+            write_csv_row(&mut out, fields.iter().map(String::as_str));
+        } else if let Some(array) = row.read_lock::<Array>() {
+            let fields: Vec<String> = array.iter().map(|value| csv_field(Some(value))).collect();
+            write_csv_row(&mut out, fields.iter().map(String::as_str));
+        } else {
+            return Err(failed("to_csv", format!("rows must be maps or arrays, not {}", row.type_name())));
+        }
+    }
+    Ok(out.into())
+}
+
+fn csv_field(value: Option<&Dynamic>) -> String {
+    match value {
+        None => String::new(),
+        Some(value) if value.is_unit() => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn write_csv_row<'a>(out: &mut String, fields: impl Iterator<Item = &'a str>) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+fn register_numbers(engine: &mut Engine) {
+    engine.register_fn("format_number", |n: f64, decimals: i64| -> ImmutableString {
+        format_number(n, decimals.clamp(0, 12) as usize).into()
+    });
+    engine.register_fn("format_number", |n: i64, decimals: i64| -> ImmutableString {
+        format_number(n as f64, decimals.clamp(0, 12) as usize).into()
+    });
+    engine.register_fn("format_currency", |n: f64, code: &str| -> HelperResult<ImmutableString> {
+        format_currency(n, code)
+    });
+    engine.register_fn("format_currency", |n: i64, code: &str| -> HelperResult<ImmutableString> {
+        format_currency(n as f64, code)
+    });
+}
+
+/// `1234567.891` with 2 decimals is `1,234,567.89`
+fn format_number(n: f64, decimals: usize) -> String {
+    let fixed = format!("{:.*}", decimals, n.abs());
+    let (whole, fraction) = match fixed.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (fixed.as_str(), None),
+    };
+
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        grouped.push('.');
+        grouped.push_str(fraction);
+    }
+
+    let negative = n < 0.0 && fixed.bytes().any(|b| (b'1'..=b'9').contains(&b));
+    if negative {
+        format!("-{}", grouped)
+    } else {
+        grouped
+    }
+}
+
+/// An amount with the currency's symbol (or code) and minor units, e.g.
+/// `$1,234.50`, `¥1,234` or `KWD 1,234.500`
+fn format_currency(n: f64, code: &str) -> HelperResult<ImmutableString> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(failed("format_currency", format!("'{}' is not an ISO 4217 currency code", code)));
+    }
+
+    let decimals = match code.as_str() {
+        "JPY" | "KRW" | "VND" | "CLP" | "ISK" => 0,
+        "BHD" | "KWD" | "OMR" | "JOD" | "IQD" | "LYD" | "TND" => 3,
+        _ => 2,
+    };
+    let symbol = match code.as_str() {
+        "USD" => Some("$"),
+        "EUR" => Some("€"),
+        "GBP" => Some("£"),
+        "JPY" => Some("¥"),
+        "INR" => Some("₹"),
+        _ => None,
+    };
+
+    let amount = format_number(n, decimals);
+    let (sign, amount) = match amount.strip_prefix('-') {
+        Some(amount) => ("-", amount),
+        None => ("", amount.as_str()),
+    };
+    Ok(match symbol {
+        Some(symbol) => format!("{}{}{}", sign, symbol, amount),
+        None => format!("{}{} {}", sign, code, amount),
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        register(&mut engine);
+        engine.register_fn("parse_json", |s: &str| -> Dynamic {
+            serde_json::from_str::<JsonValue>(s).map_or(Dynamic::UNIT, |v| json_to_dynamic(&v))
+        });
+        engine
+    }
+
+    fn eval<T: Clone + Send + Sync + 'static>(script: &str) -> T {
+        engine().eval::<T>(script).unwrap_or_else(|e| panic!("{}: {}", script, e))
+    }
+
+    fn eval_json(script: &str) -> JsonValue {
+        dynamic_to_json(&eval::<Dynamic>(script)).unwrap()
+    }
+
+    fn fails(script: &str) -> String {
+        match engine().eval::<Dynamic>(script) {
+            Ok(value) => panic!("{} returned {}", script, value),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_json_path() {
+        let doc = r#"let doc = parse_json(`{"orders": [{"id": 1, "total": 25}, {"id": 2, "total": 5}]}`);"#;
+        assert_eq!(eval_json(&format!("{} json_path(doc, \"$.orders[*].id\")", doc)), json!([1, 2]));
+        assert_eq!(eval_json(&format!("{} json_path(doc, \"$.orders[?(@.total > 10)].id\")", doc)), json!([1]));
+        assert_eq!(eval_json(&format!("{} json_path_first(doc, \"orders[-1].total\")", doc)), json!(5));
+        assert_eq!(eval_json(&format!("{} json_path_first(doc, \"$.missing\")", doc)), JsonValue::Null);
+        assert!(fails(r#"json_path(#{}, "$[")"#).contains("json_path: invalid JSONPath"));
+    }
+
+    #[test]
+    fn test_xml() {
+        let order = eval_json(r#"parse_xml("<order id='7'><item>tea</item><qty>2</qty></order>")"#);
+        assert_eq!(order["order"]["@id"], "7");
+        assert_eq!(order["order"]["item"], "tea");
+
+        let xml = eval::<ImmutableString>(r#"to_xml(#{ item: "a & b", qty: 2 }, "order")"#);
+        assert_eq!(xml, "<order><item>a &amp; b</item><qty>2</qty></order>");
+        assert!(fails(r#"parse_xml("<order><item></order>")"#).contains("parse_xml"));
+    }
+
+    #[test]
+    fn test_base64_and_hex() {
+        assert_eq!(eval::<ImmutableString>(r#"base64_encode("naseej:mesh")"#), "bmFzZWVqOm1lc2g=");
+        assert_eq!(eval::<ImmutableString>(r#"base64_decode("bmFzZWVqOm1lc2g=")"#), "naseej:mesh");
+        assert_eq!(eval::<ImmutableString>(r#"base64url_encode("??>")"#), "Pz8-");
+        assert_eq!(eval::<ImmutableString>(r#"base64url_decode("Pz8-")"#), "??>");
+        assert_eq!(eval::<ImmutableString>(r#"hex_encode("Hi!")"#), "486921");
+        assert_eq!(eval::<ImmutableString>(r#"hex_decode("486921")"#), "Hi!");
+        assert!(fails(r#"base64_decode("***")"#).contains("base64_decode"));
+        assert!(fails(r#"hex_decode("ff")"#).contains("not UTF-8"));
+
+        // Binary data round-trips through blobs
+        assert_eq!(eval::<Blob>(r#"hex_decode_bytes("00ff")"#), vec![0x00, 0xff]);
+        assert_eq!(eval::<ImmutableString>(r#"base64_encode(hex_decode_bytes("00ff"))"#), "AP8=");
+        assert_eq!(eval::<Blob>(r#"base64_decode_bytes("AP8=")"#), vec![0x00, 0xff]);
+        assert_eq!(eval::<ImmutableString>(r#"base64url_encode(base64url_decode_bytes("AP8"))"#), "AP8");
+        assert_eq!(eval::<ImmutableString>(r#"hex_encode(base64_decode_bytes("AP8="))"#), "00ff");
+        assert!(fails(r#"hex_decode_bytes("f")"#).contains("hex_decode_bytes"));
+    }
+
+    #[test]
+    fn test_hashing() {
+        assert_eq!(
+            eval::<ImmutableString>(r#"sha256("abc")"#),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(eval::<ImmutableString>(r#"sha512("abc")"#).starts_with("ddaf35a193617aba"));
+        // RFC 4231 test case 2
+        assert_eq!(
+            eval::<ImmutableString>(r#"hmac_sha256("Jefe", "what do ya want for nothing?")"#),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(eval::<ImmutableString>(r#"hmac_sha512("Jefe", "what do ya want for nothing?")"#)
+            .starts_with("164b7a7bfcf819e2"));
+        assert_eq!(
+            eval::<ImmutableString>(r#"hmac_sha256_base64("Jefe", "what do ya want for nothing?")"#),
+            "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM="
+        );
+
+        // AWS Signature V4 signing key, from the AWS documentation example
+        let signing_key = eval::<ImmutableString>(
+            r#"
+            let key = hmac_sha256_bytes("AWS4wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215");
+            key = hmac_sha256_bytes(key, "us-east-1");
+            key = hmac_sha256_bytes(key, "iam");
+            hmac_sha256(key, "aws4_request")
+            "#,
+        );
+        assert_eq!(signing_key, "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+        assert_eq!(
+            eval::<ImmutableString>(r#"sha256(hex_decode_bytes("616263"))"#),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let mac = eval::<Blob>(r#"hmac_sha512_bytes(hex_decode_bytes("4a656665"), "what do ya want for nothing?")"#);
+        assert!(hex::encode(mac).starts_with("164b7a7bfcf819e2"));
+    }
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(eval::<ImmutableString>(r#"url_encode("a b&c=d/é~")"#), "a%20b%26c%3Dd%2F%C3%A9~");
+        assert_eq!(eval::<ImmutableString>(r#"url_decode("a%20b%26c%3Dd%2F%C3%A9~")"#), "a b&c=d/é~");
+        assert_eq!(eval_json(r#"parse_query("?q=tea+pot&page=2&page=3")"#), json!({"q": "tea pot", "page": "3"}));
+        assert_eq!(eval::<ImmutableString>(r#"to_query(#{ page: 2, q: "tea pot" })"#), "page=2&q=tea+pot");
+        assert!(fails(r#"url_decode("%C3")"#).contains("url_decode"));
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(eval::<i64>(r#"parse_date("2024-03-01T12:00:00+02:00")"#), 1_709_287_200);
+        assert_eq!(eval::<i64>(r#"parse_date("Fri, 01 Mar 2024 10:00:00 GMT")"#), 1_709_287_200);
+        assert_eq!(eval::<i64>(r#"parse_date("2024-03-01")"#), 1_709_251_200);
+        assert_eq!(eval::<i64>(r#"parse_date("01/03/2024 10:00", "%d/%m/%Y %H:%M")"#), 1_709_287_200);
+        assert_eq!(eval::<i64>(r#"parse_date("01/03/2024", "%d/%m/%Y")"#), 1_709_251_200);
+
+        assert_eq!(eval::<ImmutableString>(r#"format_date(1709287200, "%Y-%m-%d %H:%M")"#), "2024-03-01 10:00");
+        assert_eq!(
+            eval::<ImmutableString>(r#"format_date(1709287200, "%H:%M %:z", "+04:00")"#),
+            "14:00 +04:00"
+        );
+        assert_eq!(eval::<ImmutableString>(r#"format_date(1709287200, "%H:%M", "-0530")"#), "04:30");
+        assert_eq!(
+            eval::<ImmutableString>(r#"to_timezone("2024-03-01T10:00:00Z", "+03")"#),
+            "2024-03-01T13:00:00+03:00"
+        );
+
+        assert!(fails(r#"parse_date("soon")"#).contains("parse_date"));
+        assert!(fails(r#"format_date(0, "%Q")"#).contains("bad format"));
+        assert!(fails(r#"format_date(0, "%Y", "Mars/Olympus")"#).contains("time zone"));
+        assert!(fails(r#"format_date(0, "%Y", "Mars")"#).contains("time zone"));
+
+        // IANA zones follow daylight saving time
+        assert_eq!(
+            eval::<ImmutableString>(r#"format_date(1705320000, "%H:%M %:z", "Europe/London")"#),
+            "12:00 +00:00"
+        );
+        assert_eq!(
+            eval::<ImmutableString>(r#"format_date(1721044800, "%H:%M %:z", "Europe/London")"#),
+            "13:00 +01:00"
+        );
+        assert_eq!(
+            eval::<ImmutableString>(r#"to_timezone("2024-07-15T12:00:00Z", "America/New_York")"#),
+            "2024-07-15T08:00:00-04:00"
+        );
+    }
+
+    #[test]
+    fn test_regex() {
+        assert!(eval::<bool>(r#"regex_match("order-42", "^order-\\d+$")"#));
+        assert!(!eval::<bool>(r#"regex_match("order-x", "^order-\\d+$")"#));
+        assert_eq!(eval::<ImmutableString>(r#"regex_find("id=42;id=7", "\\d+")"#), "42");
+        assert_eq!(eval_json(r#"regex_find("none", "\\d+")"#), JsonValue::Null);
+        assert_eq!(
+            eval_json(r#"regex_captures("v2.10", "v(\\d+)\\.(\\d+)(-rc)?")"#),
+            json!(["v2.10", "2", "10", null])
+        );
+        assert_eq!(
+            eval::<ImmutableString>(r#"regex_replace("2024-03-01", "(\\d+)-(\\d+)-(\\d+)", "$3/$2/$1")"#),
+            "01/03/2024"
+        );
+        assert!(fails(r#"regex_match("x", "(")"#).contains("regex"));
+    }
+
+    #[test]
+    fn test_csv() {
+        let records = eval_json(r#"parse_csv("id,name\r\n1,\"Tea, green\"\n2,\"Say \"\"hi\"\"\"\n")"#);
+        assert_eq!(records, json!([{"id": "1", "name": "Tea, green"}, {"id": "2", "name": "Say \"hi\""}]));
+        assert_eq!(eval_json(r#"parse_csv("a;b\n1;2", ";")"#), json!([{"a": "1", "b": "2"}]));
+        assert_eq!(eval_json(r#"parse_csv_rows("a,b\n\n1,2")"#), json!([["a", "b"], ["1", "2"]]));
+        assert!(fails(r#"parse_csv("a\n\"open")"#).contains("unterminated"));
+
+        assert_eq!(
+            eval::<ImmutableString>(r#"to_csv([#{ id: 1, name: "Tea, green" }, #{ id: 2 }])"#),
+            "id,name\r\n1,\"Tea, green\"\r\n2,\r\n"
+        );
+        assert_eq!(eval::<ImmutableString>(r#"to_csv([#{ id: 1, name: "x" }], ["name", "id"])"#), "name,id\r\nx,1\r\n");
+        assert_eq!(eval::<ImmutableString>(r#"to_csv([[1, "a"], [2, "b"]])"#), "1,a\r\n2,b\r\n");
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(eval::<ImmutableString>("format_number(1234567.891, 2)"), "1,234,567.89");
+        assert_eq!(eval::<ImmutableString>("format_number(-1234, 0)"), "-1,234");
+        assert_eq!(eval::<ImmutableString>("format_number(999.996, 2)"), "1,000.00");
+        assert_eq!(eval::<ImmutableString>("format_number(-0.001, 2)"), "0.00");
+        assert_eq!(eval::<ImmutableString>(r#"format_currency(1234.5, "USD")"#), "$1,234.50");
+        assert_eq!(eval::<ImmutableString>(r#"format_currency(-1234.5, "eur")"#), "-€1,234.50");
+        assert_eq!(eval::<ImmutableString>(r#"format_currency(1234.5, "JPY")"#), "¥1,234");
+        assert_eq!(eval::<ImmutableString>(r#"format_currency(1234, "KWD")"#), "KWD 1,234.000");
+        assert_eq!(eval::<ImmutableString>(r#"format_currency(10, "SAR")"#), "SAR 10.00");
+        assert!(fails(r#"format_currency(1, "dollars")"#).contains("ISO 4217"));
+    }
+}
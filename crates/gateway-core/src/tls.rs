@@ -0,0 +1,599 @@
+//! TLS termination with certificates selected by SNI.
+//!
+//! Certificates are compiled into a [`CertStore`] that is published through
+//! an `ArcSwap`, the same way the routing table is. The rustls server
+//! config built by [`server_config`] looks the store up on every handshake,
+//! so storing a new one changes what new connections are served while
+//! established connections carry on with the certificate they negotiated.
+//!
+//! ALPN offers `h2` and `http/1.1`.
+//!
+//! Listeners can also ask clients for a certificate ([`ClientAuth`]); the
+//! verified certificate is described by [`ClientCertificate`] on the
+//! connection's [`TlsConnection`]. [`client_config`] builds the other side:
+//! the rustls config for connections to `https://` upstreams.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use serde::Serialize;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::{CertificateConfig, UpstreamTlsConfig};
+use crate::error::GatewayError;
+
+/// ALPN protocols offered to clients, most preferred first
+pub const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// TLS details of a downstream connection, attached to requests as an
+/// extension by the server's accept loop.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConnection {
+    /// Hostname the client asked for via SNI
+    pub server_name: Option<String>,
+    /// Negotiated ALPN protocol
+    pub alpn: Option<String>,
+    /// Certificate the client authenticated with, verified against the
+    /// listener's client CA
+    pub client_cert: Option<ClientCertificate>,
+}
+
+impl TlsConnection {
+    /// Read the negotiated details off an accepted stream.
+    pub fn from_stream<IO>(stream: &TlsStream<IO>) -> Self {
+        let (_, conn) = stream.get_ref();
+        Self {
+            server_name: conn.server_name().map(str::to_string),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+            client_cert: conn
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|leaf| ClientCertificate::from_der(leaf)),
+        }
+    }
+}
+
+/// Identity details of a verified client certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ClientCertificate {
+    /// Subject distinguished name, e.g. `O=Naseej, CN=orders-service`
+    pub subject: String,
+    /// Common name of the subject
+    pub common_name: Option<String>,
+    /// Subject alternative names, prefixed with their type as in
+    /// `DNS:orders.internal`, `URI:spiffe://naseej/orders`,
+    /// `email:ops@example.com` or `IP:10.0.0.7`
+    pub sans: Vec<String>,
+    /// Issuer distinguished name
+    pub issuer: String,
+    /// Serial number as colon-separated hex
+    pub serial: String,
+}
+
+impl ClientCertificate {
+    /// Parse a DER certificate; `None` if it is malformed.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext.value.general_names.iter().filter_map(format_general_name).collect(),
+            _ => Vec::new(),
+        };
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            sans,
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+        })
+    }
+}
+
+fn format_general_name(name: &GeneralName<'_>) -> Option<String> {
+    match name {
+        GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+        GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+        GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+        GeneralName::IPAddress(ip) => match ip.len() {
+            4 => <[u8; 4]>::try_from(*ip).ok().map(|ip| format!("IP:{}", Ipv4Addr::from(ip))),
+            16 => <[u8; 16]>::try_from(*ip).ok().map(|ip| format!("IP:{}", Ipv6Addr::from(ip))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Client certificate authentication on a TLS listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuth {
+    /// Path to the PEM CA bundle client certificates must chain to
+    pub ca_path: String,
+    /// Reject clients that present no certificate. Otherwise a certificate
+    /// is optional, but one that is presented must still verify.
+    pub required: bool,
+}
+
+/// Certificates keyed by the hostnames they are served for.
+#[derive(Debug, Default)]
+pub struct CertStore {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// `*.example.com` certificates, keyed by `example.com`
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    len: usize,
+}
+
+impl CertStore {
+    /// Build a store from certificate configs.
+    ///
+    /// Inactive certificates are left out. Certificates that fail to load
+    /// are skipped with a warning rather than failing the whole reload. If
+    /// none is marked `default` and exactly one loads, it becomes the
+    /// default. When two certificates claim a hostname the later one wins.
+    pub fn build(certs: &[CertificateConfig]) -> Self {
+        let mut store = Self::default();
+        let mut only = None;
+
+        for config in certs.iter().filter(|c| c.active) {
+            let key = match load_certificate(config) {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::warn!(certificate_id = %config.id, error = %e, "Skipping certificate that failed to load");
+                    continue;
+                }
+            };
+
+            for hostname in &config.hostnames {
+                let hostname = normalize_hostname(hostname);
+                let (map, name) = match hostname.strip_prefix("*.") {
+                    Some(parent) => (&mut store.wildcard, parent.to_string()),
+                    None => (&mut store.exact, hostname.clone()),
+                };
+                if map.insert(name, key.clone()).is_some() {
+                    tracing::warn!(
+                        certificate_id = %config.id,
+                        hostname = %hostname,
+                        "Certificate replaces another for the same hostname"
+                    );
+                }
+            }
+            if config.default {
+                store.default = Some(key.clone());
+            }
+
+            store.len += 1;
+            only = Some(key);
+        }
+
+        if store.default.is_none() && store.len == 1 {
+            store.default = only;
+        }
+        store
+    }
+
+    /// Certificate for an SNI hostname: an exact match, then a wildcard
+    /// one label up, then the default.
+    pub fn resolve(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let by_name = server_name.map(normalize_hostname).and_then(|name| {
+            self.exact.get(&name).cloned().or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.wildcard.get(parent).cloned()
+            })
+        });
+        by_name.or_else(|| self.default.clone())
+    }
+
+    /// Number of certificates loaded
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no certificate is loaded
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Lowercase a hostname and drop a trailing dot.
+fn normalize_hostname(hostname: &str) -> String {
+    hostname.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Load and check a certificate chain and its private key.
+///
+/// Fails if either is missing or unreadable, the chain is empty, or the
+/// key does not belong to the leaf certificate.
+pub fn load_certificate(config: &CertificateConfig) -> Result<Arc<CertifiedKey>, GatewayError> {
+    let error = |reason: String| GatewayError::ConfigError(format!("certificate '{}': {}", config.id, reason));
+
+    let chain = read_certs(config.cert_pem.as_deref(), config.cert_path.as_deref())
+        .map_err(error)?
+        .ok_or_else(|| error("no certificate chain given".to_string()))?;
+    let key = read_key(config.key_pem.as_deref(), config.key_path.as_deref())
+        .map_err(error)?
+        .ok_or_else(|| error("no private key given".to_string()))?;
+
+    CertifiedKey::from_der(chain, key, &provider())
+        .map(Arc::new)
+        .map_err(|e| error(e.to_string()))
+}
+
+/// Parse PEM certificates given inline or, failing that, as a file path.
+/// `Ok(None)` when neither is given.
+fn read_certs(pem: Option<&str>, path: Option<&str>) -> Result<Option<Vec<CertificateDer<'static>>>, String> {
+    let certs: Vec<CertificateDer<'static>> = match (pem, path) {
+        (Some(pem), _) => CertificateDer::pem_slice_iter(pem.as_bytes()).collect::<Result<_, _>>(),
+        (None, Some(path)) => CertificateDer::pem_file_iter(path)
+            .map_err(|e| format!("cannot read {}: {}", path, e))?
+            .collect(),
+        (None, None) => return Ok(None),
+    }
+    .map_err(|e| format!("invalid certificate PEM: {}", e))?;
+
+    if certs.is_empty() {
+        return Err("certificate PEM contains no certificates".to_string());
+    }
+    Ok(Some(certs))
+}
+
+/// Parse a PEM private key given inline or, failing that, as a file path.
+fn read_key(pem: Option<&str>, path: Option<&str>) -> Result<Option<PrivateKeyDer<'static>>, String> {
+    match (pem, path) {
+        (Some(pem), _) => PrivateKeyDer::from_pem_slice(pem.as_bytes()),
+        (None, Some(path)) => PrivateKeyDer::from_pem_file(path),
+        (None, None) => return Ok(None),
+    }
+    .map(Some)
+    .map_err(|e| format!("invalid private key PEM: {}", e))
+}
+
+fn root_store(certs: Vec<CertificateDer<'static>>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert).map_err(|e| format!("invalid CA certificate: {}", e))?;
+    }
+    Ok(roots)
+}
+
+fn provider() -> CryptoProvider {
+    rustls::crypto::ring::default_provider()
+}
+
+/// Picks a certificate from the current [`CertStore`] for each handshake.
+#[derive(Debug)]
+pub struct SniResolver {
+    store: Arc<ArcSwap<CertStore>>,
+}
+
+impl SniResolver {
+    pub fn new(store: Arc<ArcSwap<CertStore>>) -> Self {
+        Self { store }
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = self.store.load().resolve(client_hello.server_name());
+        if key.is_none() {
+            tracing::debug!(server_name = ?client_hello.server_name(), "No certificate for TLS handshake");
+        }
+        key
+    }
+}
+
+/// Build the rustls server config serving certificates from `store`,
+/// verifying client certificates when `client_auth` is given.
+///
+/// The client CA bundle is read once here; unlike the served certificates
+/// it is not reloaded.
+pub fn server_config(
+    store: Arc<ArcSwap<CertStore>>,
+    client_auth: Option<&ClientAuth>,
+) -> Result<Arc<ServerConfig>, GatewayError> {
+    let provider = Arc::new(provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| GatewayError::ConfigError(format!("TLS: {}", e)))?;
+
+    let builder = match client_auth {
+        Some(auth) => builder.with_client_cert_verifier(client_verifier(auth, provider)?),
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(Arc::new(SniResolver::new(store)));
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+fn client_verifier(auth: &ClientAuth, provider: Arc<CryptoProvider>) -> Result<Arc<dyn ClientCertVerifier>, GatewayError> {
+    let error = |reason: String| GatewayError::ConfigError(format!("TLS client CA: {}", reason));
+
+    let certs = read_certs(None, Some(&auth.ca_path)).map_err(error)?.unwrap_or_default();
+    let roots = root_store(certs).map_err(error)?;
+
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let builder = if auth.required { builder } else { builder.allow_unauthenticated() };
+    builder.build().map_err(|e| error(e.to_string()))
+}
+
+/// A TLS acceptor serving certificates from `store`.
+pub fn acceptor(store: Arc<ArcSwap<CertStore>>, client_auth: Option<&ClientAuth>) -> Result<TlsAcceptor, GatewayError> {
+    server_config(store, client_auth).map(TlsAcceptor::from)
+}
+
+/// Build the rustls client config for connections to upstreams.
+///
+/// Upstream certificates are checked against the public web roots unless
+/// `tls` names its own CAs. ALPN offers only `http/1.1`, the protocol the
+/// gateway speaks upstream.
+pub fn client_config(tls: Option<&UpstreamTlsConfig>) -> Result<Arc<ClientConfig>, GatewayError> {
+    let error = |reason: String| GatewayError::ConfigError(format!("upstream TLS: {}", reason));
+    let defaults = UpstreamTlsConfig::default();
+    let tls = tls.unwrap_or(&defaults);
+
+    let provider = Arc::new(provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| error(e.to_string()))?;
+
+    let builder = if tls.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+    } else {
+        let roots = match read_certs(tls.ca_pem.as_deref(), tls.ca_path.as_deref()).map_err(error)? {
+            Some(certs) => root_store(certs).map_err(error)?,
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+        builder.with_root_certificates(roots)
+    };
+
+    let chain = read_certs(tls.client_cert_pem.as_deref(), tls.client_cert_path.as_deref()).map_err(error)?;
+    let key = read_key(tls.client_key_pem.as_deref(), tls.client_key_path.as_deref()).map_err(error)?;
+    let mut config = match (chain, key) {
+        (Some(chain), Some(key)) => builder
+            .with_client_auth_cert(chain, key)
+            .map_err(|e| error(format!("client certificate: {}", e)))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(error("a client certificate needs both the chain and its key".to_string())),
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Accepts any upstream certificate, for `insecure_skip_verify`. Handshake
+/// signatures are still checked against the presented certificate.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, RootCertStore};
+    use std::net::SocketAddr;
+    use tokio_rustls::client::TlsStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn cert(id: &str, hostnames: &[&str]) -> CertificateConfig {
+        let (cert, key) = (testdata(&format!("{}.pem", id)), testdata(&format!("{}.key", id)));
+        let mut config = CertificateConfig::from_files(id, cert, key);
+        config.hostnames = hostnames.iter().map(|h| h.to_string()).collect();
+        config
+    }
+
+    fn leaf(id: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(testdata(&format!("{}.pem", id))).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_by_sni() {
+        let mut localhost = cert("localhost", &["localhost"]);
+        localhost.default = true;
+        let store = CertStore::build(&[
+            cert("api", &["api.example.com"]),
+            cert("wildcard", &["*.apps.example.com"]),
+            localhost,
+        ]);
+        assert_eq!(store.len(), 3);
+
+        let served = |name: Option<&str>| store.resolve(name).unwrap().cert[0].clone();
+        assert_eq!(served(Some("API.example.com.")), leaf("api"));
+        assert_eq!(served(Some("shop.apps.example.com")), leaf("wildcard"));
+        // Wildcards cover a single label
+        assert_eq!(served(Some("a.b.apps.example.com")), leaf("localhost"));
+        assert_eq!(served(None), leaf("localhost"));
+    }
+
+    #[test]
+    fn test_default_certificate() {
+        // A lone certificate is the default
+        let store = CertStore::build(&[cert("api", &["api.example.com"])]);
+        assert!(store.resolve(Some("other.example.com")).is_some());
+
+        // Otherwise unknown names get nothing and the handshake fails
+        let store = CertStore::build(&[cert("api", &["api.example.com"]), cert("localhost", &["localhost"])]);
+        assert!(store.resolve(Some("other.example.com")).is_none());
+        assert!(store.resolve(None).is_none());
+    }
+
+    #[test]
+    fn test_invalid_certificates_are_skipped() {
+        let mut mismatched = cert("api", &["api.example.com"]);
+        mismatched.key_path = Some(testdata("localhost.key"));
+        assert!(load_certificate(&mismatched).is_err());
+
+        let mut missing = cert("wildcard", &["*.apps.example.com"]);
+        missing.cert_path = Some(testdata("missing.pem"));
+        assert!(load_certificate(&missing).is_err());
+
+        let mut inline = cert("localhost", &["localhost"]);
+        inline.cert_pem = Some("not a certificate".to_string());
+        assert!(load_certificate(&inline).is_err());
+
+        let mut inactive = cert("localhost", &["localhost"]);
+        inactive.active = false;
+
+        let store = CertStore::build(&[mismatched, missing, inline, inactive]);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_client_certificate_details() {
+        let cert = ClientCertificate::from_der(&leaf("client")).unwrap();
+        assert_eq!(cert.subject, "O=Naseej, CN=orders-service");
+        assert_eq!(cert.common_name.as_deref(), Some("orders-service"));
+        assert_eq!(cert.sans, ["DNS:orders.internal", "URI:spiffe://naseej/orders"]);
+        assert_eq!(cert.issuer, "CN=NaseejMesh Test Client CA");
+        assert!(!cert.serial.is_empty());
+
+        assert!(ClientCertificate::from_der(b"not a certificate").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_auth() {
+        let store = Arc::new(ArcSwap::from_pointee(CertStore::build(&[cert("localhost", &["localhost"])])));
+        let client_auth = ClientAuth {
+            ca_path: testdata("client-ca.pem"),
+            required: false,
+        };
+        let acceptor = acceptor(store, Some(&client_auth)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = acceptor.accept(stream).await.unwrap();
+            let info = TlsConnection::from_stream(&tls);
+            tls.write_all(b"ok").await.unwrap();
+            info
+        });
+
+        let mut client = connect(addr, "localhost").await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(server.await.unwrap().client_cert, None);
+    }
+
+    async fn connect(addr: SocketAddr, server_name: &str) -> std::io::Result<TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(testdata("ca.pem")).unwrap()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let stream = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        TlsConnector::from(Arc::new(config)).connect(name, stream).await
+    }
+
+    #[tokio::test]
+    async fn test_hot_reload_keeps_connections() {
+        let store = Arc::new(ArcSwap::from_pointee(CertStore::build(&[cert("api", &["api.example.com"])])));
+        let acceptor = acceptor(store.clone(), None).unwrap();
+
+        // Echo server that reports the ALPN protocol of each connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (alpn_tx, mut alpn_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let alpn_tx = alpn_tx.clone();
+                tokio::spawn(async move {
+                    let Ok(mut tls) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let _ = alpn_tx.send(TlsConnection::from_stream(&tls).alpn);
+                    let mut buf = [0u8; 64];
+                    while let Ok(n @ 1..) = tls.read(&mut buf).await {
+                        let _ = tls.write_all(&buf[..n]).await;
+                    }
+                });
+            }
+        });
+
+        let mut before = connect(addr, "api.example.com").await.unwrap();
+        assert_eq!(before.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(alpn_rx.recv().await.unwrap().as_deref(), Some("h2"));
+
+        store.store(Arc::new(CertStore::build(&[cert("wildcard", &["*.apps.example.com"])])));
+
+        // New connections see the new certificates...
+        assert!(connect(addr, "shop.apps.example.com").await.is_ok());
+        assert!(connect(addr, "api.example.com").await.is_err());
+
+        // ...while the earlier one keeps working
+        before.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        before.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -2,29 +2,23 @@
 //!
 //! Executes AI-generated Rhai scripts for data transformation.
 //! Scripts are compiled once and executed for each request.
+//!
+//! [`RhaiTransformer`] is the text-in, text-out face of the shared
+//! [`scripting`](crate::scripting) runtime: the request body is `input`
+//! (and, parsed, `payload`) and the new body is whatever the script leaves
+//! in `output`, or the changed `payload`.
 
-use rhai::{Dynamic, Engine, ImmutableString, Scope, AST};
 use serde::{Deserialize, Serialize};
-use serde_json::Value as JsonValue;
-use std::sync::Arc;
-use thiserror::Error;
-use tracing::{debug, info, warn};
-
-/// Transformation errors
-#[derive(Debug, Error)]
-pub enum TransformError {
-    #[error("Script compilation failed: {0}")]
-    CompileError(String),
 
-    #[error("Script execution failed: {0}")]
-    ExecutionError(String),
+use crate::config::ScriptLimits;
+use crate::path::PathParams;
+use crate::scripting::{Script, ScriptContext, ScriptEngine};
+use crate::tls::ClientCertificate;
 
-    #[error("Timeout exceeded")]
-    Timeout,
+pub use crate::scripting::{script_metrics, ScriptLimit, ScriptMetrics};
 
-    #[error("Output conversion failed: {0}")]
-    OutputError(String),
-}
+/// Transformation errors
+pub type TransformError = crate::scripting::ScriptError;
 
 /// Transformation result
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,173 +36,77 @@ pub struct TransformResult {
 
 /// Compiled Rhai transformer
 pub struct RhaiTransformer {
-    /// Shared Rhai engine
-    engine: Arc<Engine>,
-
-    /// Compiled AST (reusable)
-    ast: AST,
-
-    /// Script source (for debugging)
-    source: String,
+    script: Script,
 }
 
 impl RhaiTransformer {
-    /// Create a new transformer from script source
+    /// Create a new transformer from script source, with the default limits
     pub fn new(script: &str) -> Result<Self, TransformError> {
-        let engine = Self::create_engine();
-
-        let ast = engine
-            .compile(script)
-            .map_err(|e| TransformError::CompileError(e.to_string()))?;
-
-        info!(
-            script_len = script.len(),
-            "Compiled Rhai transformation script"
-        );
-
-        Ok(Self {
-            engine: Arc::new(engine),
-            ast,
-            source: script.to_string(),
-        })
+        Self::with_limits(script, &ScriptLimits::default())
     }
 
-    /// Create a configured Rhai engine
-    fn create_engine() -> Engine {
-        let mut engine = Engine::new();
-
-        // Safety limits
-        engine.set_max_operations(100_000);
-        engine.set_max_expr_depths(64, 64);
-        engine.set_max_string_size(1024 * 1024); // 1MB
-        engine.set_max_array_size(10_000);
-        engine.set_max_map_size(10_000);
-
-        // Register helper functions
-        Self::register_helpers(&mut engine);
-
-        engine
+    /// Create a new transformer whose runs are bounded by `limits`
+    pub fn with_limits(script: &str, limits: &ScriptLimits) -> Result<Self, TransformError> {
+        let script = ScriptEngine::new(limits.clone()).compile(script)?.named("transform");
+        Ok(Self { script })
     }
 
-    /// Register helper functions for transformations
-    fn register_helpers(engine: &mut Engine) {
-        // JSON parsing
-        engine.register_fn("parse_json", |s: &str| -> Dynamic {
-            match serde_json::from_str::<JsonValue>(s) {
-                Ok(v) => json_to_dynamic(&v),
-                Err(_) => Dynamic::UNIT,
-            }
-        });
-
-        // JSON stringification
-        engine.register_fn("to_json", |d: Dynamic| -> ImmutableString {
-            dynamic_to_json(&d)
-                .map(|v| serde_json::to_string(&v).unwrap_or_default())
-                .unwrap_or_default()
-                .into()
-        });
-
-        // Pretty JSON
-        engine.register_fn("to_json_pretty", |d: Dynamic| -> ImmutableString {
-            dynamic_to_json(&d)
-                .map(|v| serde_json::to_string_pretty(&v).unwrap_or_default())
-                .unwrap_or_default()
-                .into()
-        });
-
-        // XML wrapping (simple)
-        engine.register_fn("wrap_xml", |tag: &str, content: &str| -> ImmutableString {
-            format!("<{}>{}</{}>", tag, escape_xml(content), tag).into()
-        });
-
-        // String utilities
-        engine.register_fn("trim", |s: &str| -> ImmutableString { s.trim().into() });
-        engine.register_fn("upper", |s: &str| -> ImmutableString { s.to_uppercase().into() });
-        engine.register_fn("lower", |s: &str| -> ImmutableString { s.to_lowercase().into() });
-
-        // Number conversions (f64)
-        engine.register_fn("celsius_to_fahrenheit", |c: f64| -> f64 { c * 9.0 / 5.0 + 32.0 });
-        engine.register_fn("fahrenheit_to_celsius", |f: f64| -> f64 { (f - 32.0) * 5.0 / 9.0 });
-        
-        // Number conversions (i64 -> f64 for JSON compatibility)
-        engine.register_fn("celsius_to_fahrenheit", |c: i64| -> f64 { (c as f64) * 9.0 / 5.0 + 32.0 });
-        engine.register_fn("fahrenheit_to_celsius", |f: i64| -> f64 { ((f as f64) - 32.0) * 5.0 / 9.0 });
-
-        // Timestamp
-        engine.register_fn("now_iso", || -> ImmutableString {
-            chrono::Utc::now().to_rfc3339().into()
-        });
-
-        engine.register_fn("timestamp_ms", || -> i64 {
-            chrono::Utc::now().timestamp_millis()
-        });
-
-        // UUID
-        engine.register_fn("uuid", || -> ImmutableString {
-            uuid::Uuid::new_v4().to_string().into()
-        });
-
-        // Logging
-        engine.register_fn("log", |msg: &str| {
-            info!(rhai = true, "{}", msg);
-        });
-
-        engine.register_fn("debug", |msg: &str| {
-            debug!(rhai = true, "{}", msg);
-        });
-
-        engine.register_fn("warn", |msg: &str| {
-            warn!(rhai = true, "{}", msg);
-        });
+    /// Set the name runs are logged and counted under, e.g. the route and
+    /// phase the script belongs to
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.script = self.script.named(name);
+        self
     }
 
     /// Execute the transformation
     pub fn execute(&self, input: &str) -> Result<TransformResult, TransformError> {
-        let start = std::time::Instant::now();
-
-        let mut scope = Scope::new();
-        scope.push("input", input.to_string());
-        scope.push("output", String::new());
-
-        // Execute script
-        self.engine
-            .run_ast_with_scope(&mut scope, &self.ast)
-            .map_err(|e| TransformError::ExecutionError(e.to_string()))?;
-
-        // Get output
-        let output = scope
-            .get_value::<String>("output")
-            .unwrap_or_default();
+        self.execute_with_params(input, &PathParams::default())
+    }
 
-        let execution_us = start.elapsed().as_micros() as u64;
+    /// Execute the transformation with the route's captured path parameters
+    /// available to the script as the `params` map.
+    pub fn execute_with_params(&self, input: &str, params: &PathParams) -> Result<TransformResult, TransformError> {
+        self.execute_with_context(input, params, None)
+    }
 
-        debug!(
-            input_len = input.len(),
-            output_len = output.len(),
-            execution_us = execution_us,
-            "Transformation complete"
-        );
+    /// Execute the transformation with the request context: path parameters
+    /// as `params`, and the caller's verified TLS client certificate as the
+    /// `client_cert` map (`subject`, `cn`, `sans`, `issuer`, `serial`), or
+    /// `()` when there is none.
+    pub fn execute_with_context(
+        &self,
+        input: &str,
+        params: &PathParams,
+        client_cert: Option<&ClientCertificate>,
+    ) -> Result<TransformResult, TransformError> {
+        let ctx = ScriptContext {
+            params: params.clone(),
+            client_cert: client_cert.cloned(),
+            ..ScriptContext::from_text(input)
+        };
+        let outcome = self.script.run(&ctx)?;
 
         Ok(TransformResult {
-            output,
-            execution_us,
+            output: outcome.output,
+            execution_us: outcome.execution_us,
             warnings: vec![],
         })
     }
 
+    /// Name runs are logged and counted under
+    pub fn name(&self) -> &str {
+        self.script.name()
+    }
+
     /// Get the script source
     pub fn source(&self) -> &str {
-        &self.source
+        self.script.source()
     }
 }
 
 /// Validate a script without executing it
 pub fn validate_script(script: &str) -> Result<(), TransformError> {
-    let engine = RhaiTransformer::create_engine();
-    engine
-        .compile(script)
-        .map_err(|e| TransformError::CompileError(e.to_string()))?;
-    Ok(())
+    ScriptEngine::default().validate(script)
 }
 
 /// Simulate a transformation (dry run)
@@ -217,85 +115,10 @@ pub fn simulate(script: &str, input: &str) -> Result<TransformResult, TransformE
     transformer.execute(input)
 }
 
-/// Convert JSON to Rhai Dynamic
-fn json_to_dynamic(value: &JsonValue) -> Dynamic {
-    match value {
-        JsonValue::Null => Dynamic::UNIT,
-        JsonValue::Bool(b) => Dynamic::from(*b),
-        JsonValue::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                Dynamic::from(i)
-            } else if let Some(f) = n.as_f64() {
-                Dynamic::from(f)
-            } else {
-                Dynamic::UNIT
-            }
-        }
-        JsonValue::String(s) => Dynamic::from(s.clone()),
-        JsonValue::Array(arr) => {
-            let vec: Vec<Dynamic> = arr.iter().map(json_to_dynamic).collect();
-            Dynamic::from(vec)
-        }
-        JsonValue::Object(obj) => {
-            let map: rhai::Map = obj
-                .iter()
-                .map(|(k, v)| (k.clone().into(), json_to_dynamic(v)))
-                .collect();
-            Dynamic::from(map)
-        }
-    }
-}
-
-/// Convert Rhai Dynamic to JSON
-fn dynamic_to_json(value: &Dynamic) -> Option<JsonValue> {
-    if value.is_unit() {
-        return Some(JsonValue::Null);
-    }
-
-    if let Some(b) = value.clone().try_cast::<bool>() {
-        return Some(JsonValue::Bool(b));
-    }
-
-    if let Some(i) = value.clone().try_cast::<i64>() {
-        return Some(JsonValue::Number(i.into()));
-    }
-
-    if let Some(f) = value.clone().try_cast::<f64>() {
-        return serde_json::Number::from_f64(f).map(JsonValue::Number);
-    }
-
-    if let Some(s) = value.clone().try_cast::<ImmutableString>() {
-        return Some(JsonValue::String(s.to_string()));
-    }
-
-    if let Some(arr) = value.clone().try_cast::<Vec<Dynamic>>() {
-        let json_arr: Vec<JsonValue> = arr.iter().filter_map(dynamic_to_json).collect();
-        return Some(JsonValue::Array(json_arr));
-    }
-
-    if let Some(map) = value.clone().try_cast::<rhai::Map>() {
-        let json_obj: serde_json::Map<String, JsonValue> = map
-            .iter()
-            .filter_map(|(k, v)| dynamic_to_json(v).map(|jv| (k.to_string(), jv)))
-            .collect();
-        return Some(JsonValue::Object(json_obj));
-    }
-
-    None
-}
-
-/// Escape XML special characters
-fn escape_xml(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&apos;")
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_simple_transform() {
@@ -339,6 +162,45 @@ mod tests {
         assert_eq!(result.output, "<temperature>25</temperature>");
     }
 
+    #[test]
+    fn test_path_params() {
+        let pattern = crate::path::PathPattern::parse("/users/{id}").unwrap();
+        let params = pattern.matches("/users/42").unwrap();
+
+        let transformer = RhaiTransformer::new(r#"output = "user-" + params["id"];"#).unwrap();
+        let result = transformer.execute_with_params("", &params).unwrap();
+        assert_eq!(result.output, "user-42");
+    }
+
+    #[test]
+    fn test_client_cert() {
+        let script = r#"output = if client_cert == () { "anonymous" } else { client_cert.cn + " " + client_cert.sans[0] };"#;
+        let transformer = RhaiTransformer::new(script).unwrap();
+
+        let result = transformer.execute("").unwrap();
+        assert_eq!(result.output, "anonymous");
+
+        let cert = ClientCertificate {
+            common_name: Some("orders-service".to_string()),
+            sans: vec!["URI:spiffe://naseej/orders".to_string()],
+            ..Default::default()
+        };
+        let result = transformer.execute_with_context("", &PathParams::default(), Some(&cert)).unwrap();
+        assert_eq!(result.output, "orders-service URI:spiffe://naseej/orders");
+    }
+
+    #[test]
+    fn test_payload_script() {
+        // Scripts written against `payload` and `metadata` run here too
+        let script = r#"
+            payload.temp_f = celsius_to_fahrenheit(payload.temp);
+            payload.source = uppercase(metadata.source ?? "gateway");
+        "#;
+        let result = simulate(script, r#"{"temp": 20}"#).unwrap();
+        let output: serde_json::Value = serde_json::from_str(&result.output).unwrap();
+        assert_eq!(output, serde_json::json!({"temp": 20, "temp_f": 68.0, "source": "GATEWAY"}));
+    }
+
     #[test]
     fn test_validate_valid_script() {
         let result = validate_script("let x = 1 + 2;");
@@ -358,6 +220,75 @@ mod tests {
         assert_eq!(result.output, "HELLO");
     }
 
+    #[test]
+    fn test_timeout() {
+        let limits = ScriptLimits {
+            timeout_ms: 20,
+            max_operations: 0,
+            ..Default::default()
+        };
+        let transformer = RhaiTransformer::with_limits("loop { }", &limits).unwrap().named("test.timeout");
+
+        let started = Instant::now();
+        assert!(matches!(transformer.execute(""), Err(TransformError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(script_metrics().count("test.timeout", ScriptLimit::Timeout), 1);
+
+        // The deadline is per run
+        let quick = RhaiTransformer::with_limits("output = input;", &limits).unwrap();
+        assert_eq!(quick.execute("ok").unwrap().output, "ok");
+
+        // 0 means no deadline, as for the other limits
+        let limits = ScriptLimits {
+            timeout_ms: 0,
+            ..Default::default()
+        };
+        let unbounded = RhaiTransformer::with_limits("output = input;", &limits).unwrap();
+        assert_eq!(unbounded.execute("ok").unwrap().output, "ok");
+    }
+
+    #[test]
+    fn test_operation_and_size_limits() {
+        let limits = ScriptLimits {
+            max_operations: 1_000,
+            ..Default::default()
+        };
+        let busy = RhaiTransformer::with_limits("let n = 0; while true { n += 1; }", &limits)
+            .unwrap()
+            .named("test.operations");
+        assert!(matches!(busy.execute(""), Err(TransformError::LimitExceeded(_))));
+        assert_eq!(script_metrics().count("test.operations", ScriptLimit::Operations), 1);
+
+        let limits = ScriptLimits {
+            max_string_size: 64,
+            max_array_size: 8,
+            ..Default::default()
+        };
+        let growing = RhaiTransformer::with_limits("output = input; while true { output += output; }", &limits)
+            .unwrap()
+            .named("test.string");
+        assert!(matches!(growing.execute("abc"), Err(TransformError::LimitExceeded(_))));
+        let array = RhaiTransformer::with_limits("let a = []; for i in 0..100 { a.push(i); }", &limits)
+            .unwrap()
+            .named("test.array");
+        assert!(matches!(array.execute(""), Err(TransformError::LimitExceeded(_))));
+        assert_eq!(script_metrics().count("test.string", ScriptLimit::Size), 1);
+        assert_eq!(script_metrics().count("test.array", ScriptLimit::Size), 1);
+
+        // Nesting deeper than the route allows fails to compile
+        let limits = ScriptLimits {
+            max_expr_depth: 4,
+            ..Default::default()
+        };
+        assert!(RhaiTransformer::with_limits("output = ((((((input))))));", &limits).is_err());
+        assert!(RhaiTransformer::with_limits("output = input;", &limits).is_ok());
+
+        // Plain script errors are not limit hits
+        let failing = RhaiTransformer::new(r#"throw "boom";"#).unwrap().named("test.error");
+        assert!(matches!(failing.execute(""), Err(TransformError::ExecutionError(_))));
+        assert!(!script_metrics().snapshot().contains_key("test.error"));
+    }
+
     #[test]
     fn test_helper_functions() {
         // Test uuid
@@ -0,0 +1,399 @@
+//! WebSocket proxying.
+//!
+//! Upgrade requests on routes with a [`WebSocketConfig`] are not forwarded
+//! as plain HTTP. The gateway performs its own HTTP/1.1 upgrade against the
+//! selected upstream, checks the upstream's answer, answers the client's
+//! handshake (HTTP/1.1 `Upgrade` or HTTP/2 extended CONNECT, RFC 8441),
+//! then relays messages between the two connections until either side
+//! closes, the connection goes idle, or a message exceeds the route's size
+//! limit.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, CONTENT_LENGTH, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::upgrade::Upgraded;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio_tungstenite::tungstenite::handshake::client::generate_key;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, WebSocketConfig as FrameConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::body::{empty, GatewayBody};
+use crate::config::WebSocketConfig;
+use crate::error::GatewayError;
+
+/// How long to wait for a peer to answer a close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a client asked to open a WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeKind {
+    /// HTTP/1.1 `GET` with `Connection: upgrade` and `Upgrade: websocket`
+    Http1,
+    /// HTTP/2 `CONNECT` with the `:protocol` pseudo-header set to `websocket`
+    ExtendedConnect,
+}
+
+/// Detect a WebSocket upgrade request.
+///
+/// Must run before hop-by-hop headers are stripped, since `Connection` and
+/// `Upgrade` are among them.
+pub fn upgrade_kind<B>(req: &Request<B>) -> Option<UpgradeKind> {
+    if req.method() == Method::CONNECT {
+        let protocol = req.extensions().get::<hyper::ext::Protocol>()?;
+        return protocol
+            .as_str()
+            .eq_ignore_ascii_case("websocket")
+            .then_some(UpgradeKind::ExtendedConnect);
+    }
+
+    let upgrade = req.method() == Method::GET
+        && has_token(req.headers(), CONNECTION, "upgrade")
+        && has_token(req.headers(), UPGRADE, "websocket");
+    upgrade.then_some(UpgradeKind::Http1)
+}
+
+fn has_token(headers: &HeaderMap, name: hyper::header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Open WebSocket connections per route.
+#[derive(Debug, Default)]
+pub struct WebSocketConnections {
+    open: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+/// An open proxied connection. Counts towards its route until dropped.
+pub struct ConnectionGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WebSocketConnections {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a new connection on `route_id`
+    pub fn open(&self, route_id: &str) -> ConnectionGuard {
+        let counter = self
+            .open
+            .lock()
+            .unwrap()
+            .entry(route_id.to_string())
+            .or_default()
+            .clone();
+        counter.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { counter }
+    }
+
+    /// Open connections on `route_id`
+    pub fn count(&self, route_id: &str) -> usize {
+        self.open
+            .lock()
+            .unwrap()
+            .get(route_id)
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// Routes with open connections and their connection counts
+    pub fn snapshot(&self) -> HashMap<String, usize> {
+        self.open
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(route_id, c)| (route_id.clone(), c.load(Ordering::Relaxed)))
+            .filter(|(_, n)| *n > 0)
+            .collect()
+    }
+}
+
+/// Headers that belong to the client's handshake and are regenerated for
+/// the upstream one.
+const HANDSHAKE_HEADERS: [hyper::header::HeaderName; 5] = [
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_VERSION,
+    SEC_WEBSOCKET_EXTENSIONS,
+    CONTENT_LENGTH,
+];
+
+/// Build the upstream handshake for a client upgrade request.
+///
+/// Returns the request to send upstream and, for HTTP/1.1 clients, the
+/// `Sec-WebSocket-Accept` value to answer the client with.
+///
+/// Extensions such as permessage-deflate are not negotiated, since the
+/// gateway relays whole messages and would have to decode them anyway.
+pub fn upstream_handshake<B>(
+    req: &Request<B>,
+    kind: UpgradeKind,
+) -> Result<(Request<GatewayBody>, Option<String>), GatewayError> {
+    let accept = match kind {
+        UpgradeKind::Http1 => {
+            let version = req.headers().get(SEC_WEBSOCKET_VERSION);
+            if version.is_none_or(|v| v != "13") {
+                return Err(GatewayError::InvalidUpgrade(
+                    "Sec-WebSocket-Version must be 13".to_string(),
+                ));
+            }
+            let key = req
+                .headers()
+                .get(SEC_WEBSOCKET_KEY)
+                .ok_or_else(|| GatewayError::InvalidUpgrade("missing Sec-WebSocket-Key".to_string()))?;
+            Some(derive_accept_key(key.as_bytes()))
+        }
+        UpgradeKind::ExtendedConnect => None,
+    };
+
+    let mut upstream = Request::new(empty());
+    *upstream.uri_mut() = match req.uri().path_and_query() {
+        Some(pq) => pq.as_str().parse().expect("path and query is a valid URI"),
+        None => "/".parse().expect("'/' is a valid URI"),
+    };
+
+    let headers = upstream.headers_mut();
+    *headers = req.headers().clone();
+    for name in HANDSHAKE_HEADERS {
+        headers.remove(name);
+    }
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+    headers.insert(
+        SEC_WEBSOCKET_KEY,
+        HeaderValue::try_from(generate_key()).expect("generated key is a valid header value"),
+    );
+
+    Ok((upstream, accept))
+}
+
+/// Check that the upstream's 101 answers the handshake the gateway sent:
+/// its `Sec-WebSocket-Accept` must be derived from the `Sec-WebSocket-Key`
+/// in `upstream_req_headers`. Anything else fails with 502 rather than
+/// being spliced to the client.
+pub fn verify_upstream_accept(
+    upstream_req_headers: &HeaderMap,
+    response: &Response<GatewayBody>,
+) -> Result<(), GatewayError> {
+    let expected = upstream_req_headers
+        .get(SEC_WEBSOCKET_KEY)
+        .map(|key| derive_accept_key(key.as_bytes()));
+    match (expected, response.headers().get(SEC_WEBSOCKET_ACCEPT)) {
+        (Some(expected), Some(accept)) if accept.as_bytes() == expected.as_bytes() => Ok(()),
+        (_, Some(_)) => Err(GatewayError::InvalidUpstreamUpgrade(
+            "wrong Sec-WebSocket-Accept".to_string(),
+        )),
+        (_, None) => Err(GatewayError::InvalidUpstreamUpgrade(
+            "missing Sec-WebSocket-Accept".to_string(),
+        )),
+    }
+}
+
+/// Build the response completing the client's handshake.
+pub fn client_response(kind: UpgradeKind, accept: Option<String>, upstream: &Response<GatewayBody>) -> Response<GatewayBody> {
+    let mut response = Response::new(empty());
+    if let Some(protocol) = upstream.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+
+    match kind {
+        UpgradeKind::Http1 => {
+            *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+            let headers = response.headers_mut();
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+            if let Some(accept) = accept.and_then(|a| HeaderValue::try_from(a).ok()) {
+                headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+            }
+        }
+        UpgradeKind::ExtendedConnect => *response.status_mut() = StatusCode::OK,
+    }
+    response
+}
+
+/// Relay messages between an upgraded client and upstream connection.
+///
+/// Runs until either side closes or fails, no message passes in either
+/// direction for the idle timeout (closed with 1001), or a message exceeds
+/// the size limit (closed with 1009).
+pub async fn relay(client: Upgraded, upstream: Upgraded, config: &WebSocketConfig, route_id: &str) {
+    let frames = FrameConfig {
+        max_message_size: Some(config.max_message_bytes),
+        max_frame_size: Some(config.max_message_bytes),
+        ..Default::default()
+    };
+    let mut client = WebSocketStream::from_raw_socket(TokioIo::new(client), Role::Server, Some(frames)).await;
+    let mut upstream = WebSocketStream::from_raw_socket(TokioIo::new(upstream), Role::Client, Some(frames)).await;
+    let idle = Duration::from_millis(config.idle_timeout_ms);
+
+    let close = loop {
+        let next = tokio::time::timeout(idle, async {
+            tokio::select! {
+                msg = client.next() => (msg, true),
+                msg = upstream.next() => (msg, false),
+            }
+        })
+        .await;
+
+        let (msg, from_client) = match next {
+            Ok(next) => next,
+            Err(_) => {
+                tracing::debug!(route_id = %route_id, "Closing idle WebSocket connection");
+                break Some(close_frame(CloseCode::Away, "idle timeout"));
+            }
+        };
+        let (source, sink) = if from_client {
+            (&mut client, &mut upstream)
+        } else {
+            (&mut upstream, &mut client)
+        };
+
+        match msg {
+            // Pings are answered by each side's own stream
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+            Some(Ok(Message::Close(frame))) => {
+                // Pass the close on, then give both streams a chance to
+                // finish the closing handshake
+                let _ = sink.send(Message::Close(frame)).await;
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, source.next()).await;
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, sink.next()).await;
+                break None;
+            }
+            Some(Ok(msg)) => {
+                if sink.send(msg).await.is_err() {
+                    break None;
+                }
+            }
+            Some(Err(WsError::Capacity(e))) => {
+                tracing::debug!(route_id = %route_id, error = %e, "WebSocket message over size limit");
+                break Some(close_frame(CloseCode::Size, "message too big"));
+            }
+            Some(Err(e)) => {
+                tracing::debug!(route_id = %route_id, error = %e, "WebSocket connection failed");
+                break Some(close_frame(CloseCode::Away, ""));
+            }
+            None => break Some(close_frame(CloseCode::Away, "")),
+        }
+    };
+
+    if let Some(frame) = close {
+        let _ = client.close(Some(frame.clone())).await;
+        let _ = upstream.close(Some(frame)).await;
+    }
+}
+
+fn close_frame(code: CloseCode, reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request() -> hyper::http::request::Builder {
+        Request::builder()
+            .uri("/ws?room=1")
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    #[test]
+    fn test_upgrade_kind() {
+        let req = upgrade_request().body(()).unwrap();
+        assert_eq!(upgrade_kind(&req), Some(UpgradeKind::Http1));
+
+        let req = Request::builder().uri("/ws").header(UPGRADE, "websocket").body(()).unwrap();
+        assert_eq!(upgrade_kind(&req), None);
+
+        let req = upgrade_request().method("POST").body(()).unwrap();
+        assert_eq!(upgrade_kind(&req), None);
+
+        let mut req = Request::builder().method("CONNECT").uri("https://gw/ws").body(()).unwrap();
+        assert_eq!(upgrade_kind(&req), None);
+        req.extensions_mut().insert(hyper::ext::Protocol::from_static("websocket"));
+        assert_eq!(upgrade_kind(&req), Some(UpgradeKind::ExtendedConnect));
+    }
+
+    #[test]
+    fn test_upstream_handshake() {
+        let req = upgrade_request()
+            .header(SEC_WEBSOCKET_PROTOCOL, "chat")
+            .header(SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate")
+            .body(())
+            .unwrap();
+        let (upstream, accept) = upstream_handshake(&req, UpgradeKind::Http1).unwrap();
+
+        // RFC 6455 section 1.3 example
+        assert_eq!(accept.as_deref(), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(upstream.uri(), "/ws?room=1");
+        assert_eq!(upstream.headers()[SEC_WEBSOCKET_PROTOCOL], "chat");
+        assert_ne!(upstream.headers()[SEC_WEBSOCKET_KEY], "dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(upstream.headers().get(SEC_WEBSOCKET_EXTENSIONS).is_none());
+
+        // The upstream must answer the key the gateway generated
+        let key = upstream.headers()[SEC_WEBSOCKET_KEY].as_bytes();
+        let answer = |accept: Option<String>| {
+            let mut response = Response::new(empty());
+            *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+            if let Some(accept) = accept {
+                response.headers_mut().insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+            }
+            response
+        };
+        assert!(verify_upstream_accept(upstream.headers(), &answer(Some(derive_accept_key(key)))).is_ok());
+        for wrong in [Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()), None] {
+            let error = verify_upstream_accept(upstream.headers(), &answer(wrong)).unwrap_err();
+            assert_eq!(error.status_code(), 502);
+        }
+
+        let req = Request::builder()
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "8")
+            .body(())
+            .unwrap();
+        assert!(matches!(
+            upstream_handshake(&req, UpgradeKind::Http1),
+            Err(GatewayError::InvalidUpgrade(_))
+        ));
+    }
+
+    #[test]
+    fn test_connection_counts() {
+        let connections = WebSocketConnections::new();
+        let first = connections.open("chat");
+        let _second = connections.open("chat");
+        assert_eq!(connections.count("chat"), 2);
+
+        drop(first);
+        assert_eq!(connections.count("chat"), 1);
+        assert_eq!(connections.snapshot(), HashMap::from([("chat".to_string(), 1)]));
+    }
+}
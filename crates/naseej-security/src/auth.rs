@@ -61,7 +61,8 @@ pub struct Claims {
 }
 
 /// Authentication configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AuthConfig {
     /// Enable/disable authentication
     pub enabled: bool,
@@ -166,6 +167,11 @@ impl JwtValidator {
         })
     }
 
+    /// Whether tokens are checked at all
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
     /// Validate a JWT token
     pub async fn validate(&self, token: &str) -> Result<Claims, AuthError> {
         if !self.config.enabled {
@@ -0,0 +1,453 @@
+//! Gateway Filters
+//!
+//! Wraps the WAF, JWT validation, rate limiting and usage metering as
+//! `gateway_core` filters, so routes opt into them by name.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::future::{self, BoxFuture};
+use gateway_core::body::{boxed, content_length, empty, BoxError, GatewayBody};
+use gateway_core::filter::{read_request_body, reject, Filter, FilterAction, FilterContext};
+use gateway_core::headers::JwtClaims;
+use gateway_core::GatewayError;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{HeaderValue, AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::{Request, Response, StatusCode};
+use tracing::debug;
+
+use crate::auth::{AuthError, JwtValidator};
+use crate::metering::{Meter, UsageEvent};
+use crate::rate_limit::{RateLimitResult, RateLimiter};
+use crate::waf::WafEngine;
+
+/// Scans the path, query and body of requests, turning away those that
+/// match an attack pattern with 403.
+pub struct WafFilter {
+    engine: WafEngine,
+}
+
+impl WafFilter {
+    /// Create a filter scanning with `engine`
+    pub fn new(engine: WafEngine) -> Self {
+        Self { engine }
+    }
+}
+
+impl Filter for WafFilter {
+    fn on_request<'a>(
+        &'a self,
+        req: &'a mut Request<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+        Box::pin(async move {
+            let target = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+            let mut result = self.engine.scan(target);
+
+            if result.allowed && !req.body().is_end_stream() {
+                let body = read_request_body(req, ctx).await?;
+                result = self.engine.scan(&String::from_utf8_lossy(&body));
+            }
+
+            if result.allowed {
+                return Ok(FilterAction::Continue);
+            }
+            let category = result.category.unwrap_or_default();
+            let mut response = reject(StatusCode::FORBIDDEN, "waf", &format!("Request blocked: {}", category));
+            if let Some(rule) = result.triggered_rule.and_then(|rule| HeaderValue::try_from(rule).ok()) {
+                response.headers_mut().insert("x-waf-rule", rule);
+            }
+            Ok(FilterAction::Respond(response))
+        })
+    }
+}
+
+/// Requires a valid bearer token, turning away requests without one with
+/// 401. The token's claims are attached to the request as [`JwtClaims`]
+/// for header templates and later filters. A validator configured with
+/// `enabled = false` lets every request through.
+pub struct JwtFilter {
+    validator: JwtValidator,
+}
+
+impl JwtFilter {
+    /// Create a filter validating tokens with `validator`
+    pub fn new(validator: JwtValidator) -> Self {
+        Self { validator }
+    }
+}
+
+impl Filter for JwtFilter {
+    fn on_request<'a>(
+        &'a self,
+        req: &'a mut Request<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+        Box::pin(async move {
+            if !self.validator.is_enabled() {
+                return Ok(FilterAction::Continue);
+            }
+
+            let header = req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+            let claims = match header {
+                Some(header) => match JwtValidator::extract_token(header) {
+                    Ok(token) => self.validator.validate(token).await,
+                    Err(e) => Err(e),
+                },
+                None => Err(AuthError::TokenMissing),
+            };
+
+            let claims = match claims {
+                Ok(claims) => claims,
+                Err(e) => {
+                    let mut response = reject(StatusCode::UNAUTHORIZED, "auth", &e.to_string());
+                    response
+                        .headers_mut()
+                        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                    return Ok(FilterAction::Respond(response));
+                }
+            };
+
+            let claims = match serde_json::to_value(claims)? {
+                serde_json::Value::Object(map) => JwtClaims(map),
+                _ => JwtClaims::default(),
+            };
+            req.extensions_mut().insert(claims.clone());
+            ctx.extensions.insert(claims);
+            Ok(FilterAction::Continue)
+        })
+    }
+}
+
+/// Limits each caller's requests per route, turning away those over the
+/// limit with 429 and `Retry-After`. Callers are told their remaining
+/// allowance in `X-RateLimit-Limit` and `X-RateLimit-Remaining`.
+///
+/// Callers are told apart by [`FilterContext::client_id`], so listing the
+/// filter after `jwt` limits authenticated users rather than addresses.
+pub struct RateLimitFilter {
+    limiter: RateLimiter,
+}
+
+impl RateLimitFilter {
+    /// Create a filter counting requests in `limiter`
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self { limiter }
+    }
+}
+
+/// Attach the limit headers of a rate limit check to a response
+fn limit_headers(response: &mut Response<GatewayBody>, result: &RateLimitResult) {
+    let headers = response.headers_mut();
+    headers.insert("x-ratelimit-limit", HeaderValue::from(result.limit));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(result.remaining));
+}
+
+impl Filter for RateLimitFilter {
+    fn on_request<'a>(
+        &'a self,
+        _req: &'a mut Request<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+        let key = format!("{}:{}", ctx.route_id, ctx.client_id());
+        let result = self.limiter.check(&key);
+
+        let action = if result.allowed {
+            ctx.extensions.insert(result);
+            FilterAction::Continue
+        } else {
+            let mut response = reject(StatusCode::TOO_MANY_REQUESTS, "rate_limit", "Rate limit exceeded");
+            let retry_after_secs = result.retry_after_ms.unwrap_or_default().div_ceil(1000).max(1);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+            limit_headers(&mut response, &result);
+            FilterAction::Respond(response)
+        };
+        Box::pin(future::ready(Ok(action)))
+    }
+
+    fn on_response<'a>(
+        &'a self,
+        response: &'a mut Response<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), GatewayError>> {
+        if let Some(result) = ctx.extensions.get::<RateLimitResult>() {
+            limit_headers(response, result);
+        }
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Size of the request body as declared by the client
+#[derive(Clone, Copy)]
+struct RequestBytes(u64);
+
+/// Records a usage event for every request, including those turned away
+/// by other filters. List it first to see every outcome.
+///
+/// Response bytes are counted as the body streams through the filter, so
+/// chunked and streamed responses are metered too; the event is recorded
+/// once the body is finished or dropped.
+pub struct MeterFilter {
+    meter: Meter,
+}
+
+impl MeterFilter {
+    /// Create a filter recording usage into `meter`
+    pub fn new(meter: Meter) -> Self {
+        Self { meter }
+    }
+}
+
+impl Filter for MeterFilter {
+    fn on_request<'a>(
+        &'a self,
+        req: &'a mut Request<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<FilterAction, GatewayError>> {
+        let bytes = content_length(req.headers()).or_else(|| req.body().size_hint().exact());
+        ctx.extensions.insert(RequestBytes(bytes.unwrap_or_default()));
+        Box::pin(future::ready(Ok(FilterAction::Continue)))
+    }
+
+    fn on_response<'a>(
+        &'a self,
+        response: &'a mut Response<GatewayBody>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), GatewayError>> {
+        let request_bytes = ctx.extensions.get::<RequestBytes>().map_or(0, |bytes| bytes.0);
+
+        let event = UsageEvent::new(ctx.client_id(), ctx.path.clone())
+            .with_method(ctx.method.as_str())
+            .with_status(response.status().as_u16())
+            .with_request_bytes(request_bytes)
+            .with_latency_us(ctx.started.elapsed().as_micros() as u64)
+            .with_route_id(ctx.route_id.clone());
+        let body = std::mem::replace(response.body_mut(), empty());
+        *response.body_mut() = boxed(MeteredBody {
+            inner: body,
+            event: Some(event),
+            meter: self.meter.clone(),
+        });
+
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Response body counting the bytes sent, recording its usage event when
+/// dropped.
+struct MeteredBody {
+    inner: GatewayBody,
+    event: Option<UsageEvent>,
+    meter: Meter,
+}
+
+impl Body for MeteredBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            let sent = frame.data_ref().map_or(0, |data| data.len() as u64);
+            if let Some(event) = &mut self.event {
+                event.response_bytes += sent;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        if let Some(event) = self.event.take() {
+            debug!(route_id = ?event.route_id, client_id = %event.client_id, "Metering request");
+            self.meter.record(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use gateway_core::body::full;
+    use gateway_core::Route;
+    use http_body_util::{BodyExt, StreamBody};
+
+    use crate::auth::{AuthConfig, JwtIssuer};
+    use crate::metering::{MeterCollector, MeterConfig};
+    use crate::rate_limit::RateLimitConfig;
+    use crate::waf::WafConfig;
+
+    fn request(uri: &str, body: &str) -> Request<GatewayBody> {
+        Request::post(uri).body(full(body.to_string())).unwrap()
+    }
+
+    fn context(req: &Request<GatewayBody>) -> FilterContext {
+        FilterContext::new(&Route::new("orders", "/orders", "http://orders"), req.uri().path(), req)
+    }
+
+    /// Run a filter's request phase, returning the response it answered
+    /// with, if any.
+    async fn run(
+        filter: &impl Filter,
+        req: &mut Request<GatewayBody>,
+        ctx: &mut FilterContext,
+    ) -> Option<Response<GatewayBody>> {
+        match filter.on_request(req, ctx).await.unwrap() {
+            FilterAction::Continue => None,
+            FilterAction::Respond(response) => Some(response),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waf_filter() {
+        let filter = WafFilter::new(WafEngine::new(WafConfig::default()).unwrap());
+
+        let mut req = request("/orders?sort=date", r#"{"item": "widget"}"#);
+        let mut ctx = context(&req);
+        assert!(run(&filter, &mut req, &mut ctx).await.is_none());
+
+        let mut req = request("/orders?file=../../etc/passwd", "");
+        let response = run(&filter, &mut req, &mut ctx).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()["x-waf-rule"], "PATH-1");
+
+        let mut req = request("/orders", "<script>alert(1)</script>");
+        let response = run(&filter, &mut req, &mut ctx).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()["x-waf-rule"], "XSS-1");
+    }
+
+    #[tokio::test]
+    async fn test_jwt_filter() {
+        let config = AuthConfig {
+            secret_or_jwks: "test-secret-key-for-testing-purposes".to_string(),
+            issuers: vec!["naseej-gateway".to_string()],
+            audiences: vec!["orders".to_string()],
+            ..Default::default()
+        };
+        let filter = JwtFilter::new(JwtValidator::new(config.clone()).unwrap());
+        let issuer = JwtIssuer::new(config.clone()).unwrap();
+        let (token, _) = issuer.issue_token("alice", vec!["admin".to_string()]).unwrap();
+
+        let mut req = request("/orders", "");
+        let mut ctx = context(&req);
+        let response = run(&filter, &mut req, &mut ctx).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+
+        let mut req = request("/orders", "");
+        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer not-a-token"));
+        assert!(run(&filter, &mut req, &mut ctx).await.is_some());
+
+        let mut req = request("/orders", "");
+        let bearer = format!("Bearer {}", token);
+        req.headers_mut().insert(AUTHORIZATION, HeaderValue::try_from(bearer).unwrap());
+        assert!(run(&filter, &mut req, &mut ctx).await.is_none());
+
+        let claims = req.extensions().get::<JwtClaims>().unwrap();
+        assert_eq!(claims.0["sub"], "alice");
+        assert_eq!(claims.0["roles"][0], "admin");
+        assert_eq!(ctx.client_id(), "alice");
+
+        // A disabled filter lets requests through untouched
+        let filter = JwtFilter::new(JwtValidator::new(AuthConfig { enabled: false, ..config }).unwrap());
+        let mut req = request("/orders", "");
+        assert!(run(&filter, &mut req, &mut ctx).await.is_none());
+        assert!(req.extensions().get::<JwtClaims>().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_filter() {
+        let filter = RateLimitFilter::new(RateLimiter::new(RateLimitConfig {
+            requests_per_window: 2,
+            window_secs: 60,
+            burst_size: 0,
+            distributed: false,
+        }));
+
+        let mut req = request("/orders", "");
+        let mut ctx = context(&req);
+        for remaining in ["1", "0"] {
+            assert!(run(&filter, &mut req, &mut ctx).await.is_none());
+            let mut response = Response::new(full(""));
+            filter.on_response(&mut response, &mut ctx).await.unwrap();
+            assert_eq!(response.headers()["x-ratelimit-remaining"], remaining);
+        }
+
+        let response = run(&filter, &mut req, &mut ctx).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+
+        // Other callers have their own allowance
+        ctx.client_ip = Some("10.0.0.7".parse().unwrap());
+        assert!(run(&filter, &mut req, &mut ctx).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_meter_filter() {
+        let (meter, collector) = MeterCollector::new(MeterConfig {
+            flush_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let collected = tokio::spawn(collector.run(move |batch| {
+            sink.lock().unwrap().extend(batch);
+            future::ready(Ok(()))
+        }));
+
+        let filter = MeterFilter::new(meter);
+        let mut req = request("/orders", "payload");
+        let mut ctx = context(&req);
+        assert!(run(&filter, &mut req, &mut ctx).await.is_none());
+        let mut response = Response::builder().status(StatusCode::CREATED).body(full("done")).unwrap();
+        filter.on_response(&mut response, &mut ctx).await.unwrap();
+        response.into_body().collect().await.unwrap();
+
+        // A streamed response has no length up front; its bytes are
+        // counted as they are sent
+        let chunks = ["stre", "amed"].map(|chunk| Ok::<_, BoxError>(Frame::data(Bytes::from(chunk))));
+        let streamed = boxed(StreamBody::new(futures::stream::iter(chunks)));
+        assert_eq!(streamed.size_hint().exact(), None);
+        let mut response = Response::new(streamed);
+        filter.on_response(&mut response, &mut ctx).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(events.lock().unwrap().len() < 2, "recorded before the body was sent");
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "streamed");
+
+        for _ in 0..100 {
+            if events.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        collected.abort();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].response_bytes, 8);
+        assert_eq!(events[0].client_id, "anonymous");
+        assert_eq!(events[0].path, "/orders");
+        assert_eq!(events[0].method, "POST");
+        assert_eq!(events[0].status_code, 201);
+        assert_eq!(events[0].request_bytes, 7);
+        assert_eq!(events[0].response_bytes, 4);
+        assert_eq!(events[0].route_id.as_deref(), Some("orders"));
+    }
+}
@@ -5,15 +5,18 @@
 //! - JWT/OIDC Authentication
 //! - Distributed Rate Limiting
 //! - Request/Response validation
+//! - Gateway filters running the above on routes' traffic
 
 pub mod waf;
 pub mod auth;
 pub mod key_manager;
 pub mod rate_limit;
 pub mod metering;
+pub mod filters;
 
 pub use waf::{WafEngine, WafConfig, WafResult};
 pub use auth::{JwtValidator, JwtIssuer, AuthConfig, Claims};
 pub use key_manager::{KeyManager, KeyManagerError};
 pub use rate_limit::{RateLimiter, RateLimitConfig, RateLimitResult};
 pub use metering::{Meter, UsageEvent};
+pub use filters::{JwtFilter, MeterFilter, RateLimitFilter, WafFilter};
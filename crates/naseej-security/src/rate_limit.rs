@@ -41,7 +41,8 @@ pub struct RateLimitResult {
 }
 
 /// Rate limit configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RateLimitConfig {
     /// Requests per window
     pub requests_per_window: u64,
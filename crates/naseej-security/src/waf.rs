@@ -28,7 +28,8 @@ pub struct WafResult {
 }
 
 /// WAF configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WafConfig {
     pub enabled: bool,
     pub mode: WafMode,
@@ -54,7 +55,7 @@ pub enum WafMode {
     DetectOnly,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CustomPattern {
     pub id: String,
     pub pattern: String,
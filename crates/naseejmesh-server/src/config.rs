@@ -0,0 +1,809 @@
+//! Server configuration.
+//!
+//! Settings are layered, each layer overriding the one before:
+//!
+//! 1. built-in defaults
+//! 2. a TOML or YAML file (`--config` / `NASEEJ_CONFIG`), chosen by extension
+//! 3. environment variables (`PORT`, `HOST`, `TLS_PORT`, ...)
+//! 4. command-line flags
+//!
+//! Routes and certificates are not configured here; they live in SurrealDB.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use arc_swap::ArcSwap;
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use gateway_core::config::{CertificateConfig, ScriptLimits};
+use gateway_core::filter::{FilterRegistry, TransformFilter};
+use gateway_core::tls::{self, CertStore, ClientAuth};
+use gateway_core::RhaiTransformer;
+use naseej_security::{
+    AuthConfig, JwtFilter, JwtValidator, Meter, MeterFilter, RateLimitConfig, RateLimitFilter, RateLimiter, WafConfig,
+    WafEngine, WafFilter,
+};
+use surreal_config::DatabaseConfig;
+
+/// Default log filter, applied when neither the file, `RUST_LOG` nor
+/// `--log-filter` gives one
+const DEFAULT_LOG_FILTER: &str = "naseejmesh=info,gateway_core=debug,surreal_config=debug";
+
+/// Command-line arguments
+#[derive(Debug, Default, Parser)]
+#[command(name = "naseejmesh-gateway", version, about = "NaseejMesh API Gateway")]
+pub struct Cli {
+    /// Configuration file, TOML (.toml) or YAML (.yaml, .yml)
+    #[arg(short, long, env = "NASEEJ_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// HTTP listen address, e.g. 0.0.0.0:8080 or [::]:8080
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+
+    /// HTTPS listen address, e.g. [::]:8443
+    #[arg(long, value_name = "ADDR")]
+    pub tls_listen: Option<SocketAddr>,
+
+    /// Path of the embedded database
+    #[arg(long, value_name = "PATH")]
+    pub db_path: Option<String>,
+
+    /// Log filter, in `RUST_LOG` syntax
+    #[arg(long, value_name = "FILTER")]
+    pub log_filter: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Seed default routes (development mode)
+    #[arg(long)]
+    pub dev: bool,
+
+    /// Validate the configuration, print the effective settings with
+    /// secrets masked, and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+/// Complete server configuration
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listeners: ListenersConfig,
+    pub tls: TlsConfig,
+    pub database: DatabaseSettings,
+    pub telemetry: TelemetryConfig,
+    pub security: SecurityConfig,
+    pub limits: LimitsConfig,
+    pub filters: FiltersConfig,
+
+    /// Seed default routes on startup
+    pub dev_mode: bool,
+}
+
+/// Listen addresses and shutdown behaviour
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenersConfig {
+    /// HTTP listen address
+    pub http: SocketAddr,
+
+    /// HTTPS listen address (TLS disabled when absent)
+    pub https: Option<SocketAddr>,
+
+    /// How long the listeners keep accepting after a shutdown signal, with
+    /// readiness failing, so load balancers stop sending traffic first
+    pub drain_grace_secs: u64,
+
+    /// How long in-flight requests get to finish once the listeners close
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ListenersConfig {
+    fn default() -> Self {
+        Self {
+            http: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            https: None,
+            drain_grace_secs: 5,
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+/// TLS termination on the HTTPS listener
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain served as the default certificate, alongside
+    /// those stored in the database
+    pub cert_path: Option<String>,
+
+    /// PEM private key for `cert_path`
+    pub key_path: Option<String>,
+
+    /// How often certificates are reloaded to pick up files renewed on disk
+    pub reload_interval_secs: u64,
+
+    /// PEM CA bundle for client certificate authentication (off when absent)
+    pub client_ca_path: Option<String>,
+
+    /// Whether clients must present a certificate
+    pub client_auth: ClientAuthMode,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            reload_interval_secs: 60,
+            client_ca_path: None,
+            client_auth: ClientAuthMode::Required,
+        }
+    }
+}
+
+/// Client certificate requirement on the HTTPS listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Reject clients without a certificate
+    Required,
+    /// Ask for a certificate but accept clients without one
+    Optional,
+}
+
+impl FromStr for ClientAuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "required" => Ok(Self::Required),
+            "optional" => Ok(Self::Optional),
+            _ => bail!("expected 'required' or 'optional', got '{}'", s),
+        }
+    }
+}
+
+/// Embedded SurrealDB holding routes and certificates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    /// Directory of the RocksDB store
+    pub path: String,
+
+    /// SurrealDB namespace
+    pub namespace: String,
+
+    /// Database name within the namespace
+    pub database: String,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        let defaults = DatabaseConfig::default();
+        Self {
+            path: defaults.connection,
+            namespace: defaults.namespace,
+            database: defaults.database,
+        }
+    }
+}
+
+/// Logging
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// Log filter, in `RUST_LOG` syntax
+    pub log_filter: String,
+
+    /// Log output format
+    pub log_format: LogFormat,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            log_filter: DEFAULT_LOG_FILTER.to_string(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+/// Log output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Access to the gateway's own endpoints
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// Client networks allowed to use the `/_gateway/*` status and cache
+    /// purge endpoints, e.g. "10.0.0.0/8" or "::1/128". Defaults to
+    /// loopback and private networks; empty allows no one. Health and
+    /// readiness probes are always open.
+    pub internal_allow: Vec<IpNet>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        let networks = ["127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "::1/128", "fc00::/7"];
+        Self {
+            internal_allow: networks.iter().map(|net| net.parse().expect("valid network")).collect(),
+        }
+    }
+}
+
+impl SecurityConfig {
+    /// Whether `ip` may use the internal status and purge endpoints. IPv4
+    /// clients of an IPv6 listener are matched by their IPv4 address.
+    pub fn allows_internal(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.internal_allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Connection limits and timeouts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Open client connections across both listeners; further connections
+    /// wait to be accepted (unlimited when absent)
+    pub max_connections: Option<usize>,
+
+    /// How long a client gets to complete the TLS handshake
+    pub tls_handshake_timeout_secs: u64,
+
+    /// How long an HTTP/1 client gets to send the request head
+    pub header_read_timeout_secs: u64,
+
+    /// Concurrent streams per HTTP/2 connection (hyper's default when absent)
+    pub http2_max_concurrent_streams: Option<u32>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            tls_handshake_timeout_secs: 10,
+            header_read_timeout_secs: 30,
+            http2_max_concurrent_streams: None,
+        }
+    }
+}
+
+/// Built-in filters routes opt into by name (`waf`, `jwt`, `ratelimit`,
+/// `transform`, `meter`); each is registered only when configured here
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FiltersConfig {
+    /// Attack pattern scanning of request paths, queries and bodies
+    pub waf: Option<WafConfig>,
+
+    /// Bearer token validation; `secret_or_jwks` must be given
+    pub jwt: Option<AuthConfig>,
+
+    /// Per-caller, per-route request limits
+    pub ratelimit: Option<RateLimitConfig>,
+
+    /// Rhai scripts rewriting request and/or response bodies
+    pub transform: Option<TransformConfig>,
+
+    /// Usage events for every request, written to the log
+    pub meter: bool,
+}
+
+/// Scripts of the `transform` filter
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Rhai source run on request bodies before forwarding
+    pub request_script: Option<String>,
+
+    /// Rhai source run on response bodies before returning them
+    pub response_script: Option<String>,
+
+    /// Time, operation and size limits for each run
+    pub limits: ScriptLimits,
+}
+
+impl FiltersConfig {
+    /// Build the configured filters. The `meter` filter records into
+    /// `meter`, and is left out when there is none.
+    pub fn registry(&self, meter: Option<Meter>) -> anyhow::Result<FilterRegistry> {
+        let mut registry = FilterRegistry::new();
+
+        if let Some(waf) = &self.waf {
+            let engine = WafEngine::new(waf.clone()).context("invalid filters.waf")?;
+            registry.register("waf", WafFilter::new(engine));
+        }
+        if let Some(jwt) = &self.jwt {
+            // AuthConfig's defaults carry a placeholder key anyone could sign with
+            if jwt.enabled && (jwt.secret_or_jwks.trim().is_empty() || jwt.secret_or_jwks == "secret") {
+                bail!("filters.jwt.secret_or_jwks must be set to the real signing key");
+            }
+            let validator = JwtValidator::new(jwt.clone()).context("invalid filters.jwt")?;
+            registry.register("jwt", JwtFilter::new(validator));
+        }
+        if let Some(limit) = &self.ratelimit {
+            if limit.requests_per_window == 0 || limit.window_secs == 0 {
+                bail!("filters.ratelimit needs requests_per_window and window_secs of at least 1");
+            }
+            registry.register("ratelimit", RateLimitFilter::new(RateLimiter::new(limit.clone())));
+        }
+        if let Some(transform) = &self.transform {
+            let compile = |name: &str, phase: &str, script: &Option<String>| {
+                script
+                    .as_deref()
+                    .map(|script| RhaiTransformer::with_limits(script, &transform.limits))
+                    .transpose()
+                    .map(|script| script.map(|script| script.named(format!("filter.transform.{}", phase))))
+                    .with_context(|| format!("invalid filters.transform.{}", name))
+            };
+            let request = compile("request_script", "request", &transform.request_script)?;
+            let response = compile("response_script", "response", &transform.response_script)?;
+            if request.is_none() && response.is_none() {
+                bail!("filters.transform needs a request_script or a response_script");
+            }
+            registry.register("transform", TransformFilter::new(request, response));
+        }
+        if let Some(meter) = meter {
+            registry.register("meter", MeterFilter::new(meter));
+        }
+
+        Ok(registry)
+    }
+}
+
+impl ServerConfig {
+    /// Load the configuration for a command line: file, then environment,
+    /// then flags, then validate the result.
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read a TOML or YAML configuration file.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).with_context(|| format!("invalid TOML in {}", path.display())),
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&content).with_context(|| format!("invalid YAML in {}", path.display()))
+            }
+            _ => bail!("{}: configuration files must end in .toml, .yaml or .yml", path.display()),
+        }
+    }
+
+    /// Apply environment variable overrides, reading them through `var`.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        fn parse<T: FromStr>(name: &str, value: String) -> anyhow::Result<T>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value.parse().map_err(|e| anyhow::anyhow!("invalid {} '{}': {}", name, value, e))
+        }
+
+        if let Some(host) = var("HOST") {
+            let ip: IpAddr = parse("HOST", host)?;
+            self.listeners.http.set_ip(ip);
+            if let Some(https) = &mut self.listeners.https {
+                https.set_ip(ip);
+            }
+        }
+        if let Some(port) = var("PORT") {
+            self.listeners.http.set_port(parse("PORT", port)?);
+        }
+        if let Some(port) = var("TLS_PORT") {
+            let port = parse("TLS_PORT", port)?;
+            let ip = self.listeners.https.map_or(self.listeners.http.ip(), |https| https.ip());
+            self.listeners.https = Some(SocketAddr::new(ip, port));
+        }
+        if let Some(secs) = var("DRAIN_GRACE_SECS") {
+            self.listeners.drain_grace_secs = parse("DRAIN_GRACE_SECS", secs)?;
+        }
+        if let Some(secs) = var("DRAIN_TIMEOUT_SECS") {
+            self.listeners.drain_timeout_secs = parse("DRAIN_TIMEOUT_SECS", secs)?;
+        }
+
+        if let Some(path) = var("TLS_CERT_PATH") {
+            self.tls.cert_path = Some(path);
+        }
+        if let Some(path) = var("TLS_KEY_PATH") {
+            self.tls.key_path = Some(path);
+        }
+        if let Some(secs) = var("TLS_RELOAD_INTERVAL_SECS") {
+            self.tls.reload_interval_secs = parse("TLS_RELOAD_INTERVAL_SECS", secs)?;
+        }
+        if let Some(path) = var("TLS_CLIENT_CA_PATH") {
+            self.tls.client_ca_path = Some(path);
+        }
+        if let Some(mode) = var("TLS_CLIENT_AUTH") {
+            self.tls.client_auth = parse("TLS_CLIENT_AUTH", mode)?;
+        }
+
+        if let Some(path) = var("SURREAL_PATH") {
+            self.database.path = path;
+        }
+        if let Some(namespace) = var("SURREAL_NS") {
+            self.database.namespace = namespace;
+        }
+        if let Some(database) = var("SURREAL_DB") {
+            self.database.database = database;
+        }
+        // Remote connections are only supported by the console
+        let remote = var("SURREAL_EMBEDDED").is_some_and(|v| v == "false" || v == "0");
+        if remote || var("SURREAL_URL").is_some() {
+            bail!("the gateway only runs on its embedded database; use SURREAL_PATH instead of SURREAL_URL");
+        }
+        if let Some(filter) = var("RUST_LOG") {
+            self.telemetry.log_filter = filter;
+        }
+        if var("DEV_MODE").is_some() {
+            self.dev_mode = true;
+        }
+        Ok(())
+    }
+
+    /// Apply command-line flag overrides.
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(addr) = cli.listen {
+            self.listeners.http = addr;
+        }
+        if let Some(addr) = cli.tls_listen {
+            self.listeners.https = Some(addr);
+        }
+        if let Some(path) = &cli.db_path {
+            self.database.path = path.clone();
+        }
+        if let Some(filter) = &cli.log_filter {
+            self.telemetry.log_filter = filter.clone();
+        }
+        if let Some(format) = cli.log_format {
+            self.telemetry.log_format = format;
+        }
+        if cli.dev {
+            self.dev_mode = true;
+        }
+    }
+
+    /// Check the settings, loading the TLS files they name.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.listeners.https == Some(self.listeners.http) {
+            bail!("listeners.http and listeners.https must differ ({})", self.listeners.http);
+        }
+        if self.tls.reload_interval_secs == 0 {
+            bail!("tls.reload_interval_secs must be at least 1");
+        }
+        if self.limits.max_connections == Some(0) {
+            bail!("limits.max_connections must be at least 1");
+        }
+        if self.database.path.is_empty() {
+            bail!("database.path cannot be empty");
+        }
+
+        tracing_subscriber::EnvFilter::try_new(&self.telemetry.log_filter)
+            .with_context(|| format!("invalid telemetry.log_filter '{}'", self.telemetry.log_filter))?;
+
+        match (&self.tls.cert_path, &self.tls.key_path) {
+            (Some(_), Some(_)) => {
+                for cert in self.file_certificates() {
+                    tls::load_certificate(&cert)?;
+                }
+            }
+            (None, None) => {}
+            _ => bail!("tls.cert_path and tls.key_path must be set together"),
+        }
+
+        self.filters.registry(None)?;
+
+        if let Some(client_auth) = self.client_auth() {
+            if self.listeners.https.is_none() {
+                bail!("tls.client_ca_path is set but there is no HTTPS listener");
+            }
+            let store = Arc::new(ArcSwap::from_pointee(CertStore::default()));
+            tls::server_config(store, Some(&client_auth))?;
+        }
+
+        Ok(())
+    }
+
+    /// Certificates configured outside the database
+    pub fn file_certificates(&self) -> Vec<CertificateConfig> {
+        match (&self.tls.cert_path, &self.tls.key_path) {
+            (Some(cert), Some(key)) => vec![CertificateConfig {
+                default: true,
+                ..CertificateConfig::from_files("file-default", cert.clone(), key.clone())
+            }],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Client certificate authentication for the HTTPS listener
+    pub fn client_auth(&self) -> Option<ClientAuth> {
+        self.tls.client_ca_path.as_ref().map(|ca_path| ClientAuth {
+            ca_path: ca_path.clone(),
+            required: self.tls.client_auth == ClientAuthMode::Required,
+        })
+    }
+
+    /// Connection settings for the embedded database
+    pub fn database_config(&self) -> DatabaseConfig {
+        DatabaseConfig {
+            namespace: self.database.namespace.clone(),
+            database: self.database.database.clone(),
+            ..DatabaseConfig::embedded(self.database.path.clone())
+        }
+    }
+
+    pub fn drain_grace(&self) -> Duration {
+        Duration::from_secs(self.listeners.drain_grace_secs)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.listeners.drain_timeout_secs)
+    }
+
+    pub fn tls_reload_interval(&self) -> Duration {
+        Duration::from_secs(self.tls.reload_interval_secs)
+    }
+
+    pub fn tls_handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.limits.tls_handshake_timeout_secs)
+    }
+
+    pub fn header_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.limits.header_read_timeout_secs)
+    }
+
+    /// A copy safe to print, with secrets such as the JWT signing key
+    /// replaced by `***`
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if let Some(jwt) = &mut config.filters.jwt {
+            if !jwt.secret_or_jwks.is_empty() {
+                jwt.secret_or_jwks = "***".to_string();
+            }
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/../gateway-core/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn write_config(dir: &tempfile::TempDir, name: &str, content: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_toml_and_yaml_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = write_config(
+            &dir,
+            "gateway.toml",
+            r#"
+            dev_mode = true
+
+            [listeners]
+            http = "[::]:9080"
+            https = "[::]:9443"
+
+            [security]
+            internal_allow = ["10.0.0.0/8", "::1/128"]
+
+            [limits]
+            max_connections = 1000
+            "#,
+        );
+        let config = ServerConfig::from_file(&toml).unwrap();
+        assert_eq!(config.listeners.http, "[::]:9080".parse().unwrap());
+        assert_eq!(config.listeners.https, Some("[::]:9443".parse().unwrap()));
+        assert_eq!(config.limits.max_connections, Some(1000));
+        assert_eq!(config.limits.tls_handshake_timeout_secs, 10);
+        assert!(config.dev_mode);
+        assert!(config.security.allows_internal("10.1.2.3".parse().unwrap()));
+        assert!(config.security.allows_internal("::1".parse().unwrap()));
+        assert!(!config.security.allows_internal("192.168.0.1".parse().unwrap()));
+
+        let yaml = write_config(
+            &dir,
+            "gateway.yaml",
+            "listeners:\n  http: 127.0.0.1:9080\ntelemetry:\n  log_format: json\ndatabase:\n  path: /var/lib/naseej\n",
+        );
+        let config = ServerConfig::from_file(&yaml).unwrap();
+        assert_eq!(config.listeners.http, "127.0.0.1:9080".parse().unwrap());
+        assert_eq!(config.telemetry.log_format, LogFormat::Json);
+        assert_eq!(config.database.path, "/var/lib/naseej");
+        assert_eq!(config.database.namespace, "gateway");
+
+        // Typos are errors rather than silently ignored
+        let typo = write_config(&dir, "typo.toml", "[listeners]\nhtpp = \"0.0.0.0:80\"\n");
+        assert!(ServerConfig::from_file(&typo).is_err());
+        assert!(ServerConfig::from_file(Path::new("gateway.json")).is_err());
+    }
+
+    #[test]
+    fn test_layering() {
+        let mut config = ServerConfig::default();
+        config
+            .apply_env(env(&[("HOST", "::"), ("PORT", "9000"), ("TLS_PORT", "9443"), ("TLS_CLIENT_AUTH", "optional")]))
+            .unwrap();
+        assert_eq!(config.listeners.http, "[::]:9000".parse().unwrap());
+        assert_eq!(config.listeners.https, Some("[::]:9443".parse().unwrap()));
+        assert_eq!(config.tls.client_auth, ClientAuthMode::Optional);
+
+        // Flags win over the environment
+        let cli = Cli {
+            listen: Some("127.0.0.1:8081".parse().unwrap()),
+            log_format: Some(LogFormat::Json),
+            ..Default::default()
+        };
+        config.apply_cli(&cli);
+        assert_eq!(config.listeners.http, "127.0.0.1:8081".parse().unwrap());
+        assert_eq!(config.telemetry.log_format, LogFormat::Json);
+
+        assert!(config.apply_env(env(&[("PORT", "http")])).is_err());
+        assert!(config.apply_env(env(&[("HOST", "0.0.0")])).is_err());
+        assert!(config.apply_env(env(&[("TLS_CLIENT_AUTH", "sometimes")])).is_err());
+
+        config.apply_env(env(&[("SURREAL_PATH", "/data/db"), ("SURREAL_NS", "edge")])).unwrap();
+        assert_eq!((config.database.path.as_str(), config.database.namespace.as_str()), ("/data/db", "edge"));
+        assert!(config.apply_env(env(&[("SURREAL_URL", "ws://surrealdb:8000")])).is_err());
+        assert!(config.apply_env(env(&[("SURREAL_EMBEDDED", "false")])).is_err());
+    }
+
+    #[test]
+    fn test_internal_allow_defaults_to_private_networks() {
+        let security = SecurityConfig::default();
+        for ip in ["127.0.0.1", "10.1.2.3", "192.168.1.1", "::1", "fd00::1", "::ffff:10.0.0.1"] {
+            assert!(security.allows_internal(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!security.allows_internal(ip.parse().unwrap()), "{}", ip);
+        }
+
+        let closed = SecurityConfig { internal_allow: Vec::new() };
+        assert!(!closed.allows_internal("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            &dir,
+            "filters.toml",
+            r#"
+            [filters]
+            meter = true
+
+            [filters.waf]
+            mode = "DetectOnly"
+
+            [filters.jwt]
+            secret_or_jwks = "change-me"
+            issuers = ["naseej-gateway"]
+
+            [filters.ratelimit]
+            requests_per_window = 50
+            window_secs = 1
+
+            [filters.transform]
+            response_script = "output = upper(input);"
+
+            [filters.transform.limits]
+            timeout_ms = 20
+            "#,
+        );
+        let config = ServerConfig::from_file(&path).unwrap();
+        assert_eq!(config.filters.jwt.as_ref().unwrap().algorithm, "HS256");
+        assert_eq!(config.filters.ratelimit.as_ref().unwrap().burst_size, 10);
+        let limits = &config.filters.transform.as_ref().unwrap().limits;
+        assert_eq!((limits.timeout_ms, limits.max_operations), (20, 100_000));
+        assert!(config.validate().is_ok());
+
+        // --check-config never prints the signing key
+        let printed = toml::to_string_pretty(&config.redacted()).unwrap();
+        assert!(!printed.contains("change-me"));
+        assert!(printed.contains(r#"secret_or_jwks = "***""#));
+        assert_eq!(config.filters.jwt.as_ref().unwrap().secret_or_jwks, "change-me");
+
+        // The meter filter needs a running collector
+        let registry = config.filters.registry(None).unwrap();
+        assert_eq!(registry.names(), ["jwt", "ratelimit", "transform", "waf"]);
+
+        let mut config = ServerConfig::default();
+        config.filters.transform = Some(TransformConfig {
+            request_script: Some("output = ;".to_string()),
+            ..Default::default()
+        });
+        assert!(config.validate().is_err());
+        config.filters.transform = Some(TransformConfig::default());
+        assert!(config.validate().is_err());
+
+        // A [filters.jwt] section without its own key is rejected
+        let mut config = ServerConfig::default();
+        config.filters.jwt = Some(AuthConfig::default());
+        assert!(config.validate().is_err());
+        config.filters.jwt = Some(AuthConfig {
+            secret_or_jwks: String::new(),
+            ..Default::default()
+        });
+        assert!(config.validate().is_err());
+        config.filters.jwt = Some(AuthConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        assert!(config.validate().is_ok());
+
+        let mut config = ServerConfig::default();
+        config.filters.ratelimit = Some(RateLimitConfig {
+            window_secs: 0,
+            ..Default::default()
+        });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ServerConfig::default().validate().is_ok());
+
+        let mut config = ServerConfig::default();
+        config.tls.cert_path = Some(testdata("localhost.pem"));
+        assert!(config.validate().is_err());
+        config.tls.key_path = Some(testdata("localhost.key"));
+        assert!(config.validate().is_ok());
+        config.tls.key_path = Some(testdata("api.key"));
+        assert!(config.validate().is_err());
+
+        let mut config = ServerConfig::default();
+        config.tls.client_ca_path = Some(testdata("client-ca.pem"));
+        assert!(config.validate().is_err());
+        config.listeners.https = Some("0.0.0.0:8443".parse().unwrap());
+        assert!(config.validate().is_ok());
+        config.tls.client_ca_path = Some(testdata("missing.pem"));
+        assert!(config.validate().is_err());
+
+        let mut config = ServerConfig::default();
+        config.listeners.https = Some(config.listeners.http);
+        assert!(config.validate().is_err());
+
+        let mut config = ServerConfig::default();
+        config.telemetry.log_filter = "gateway_core=loud".to_string();
+        assert!(config.validate().is_err());
+    }
+}
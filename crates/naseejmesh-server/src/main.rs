@@ -5,105 +5,85 @@
 //! - Embedded SurrealDB configuration
 //! - Zero-downtime hot reload via ArcSwap
 //! - Live Query reactive updates
+//! - TLS termination with SNI certificate selection and hot reload
+//! - Optional client certificate authentication (mTLS) on the TLS listener
+//! - Graceful shutdown on SIGTERM/SIGINT with connection draining
+//! - Per-route filter chains (WAF, JWT, rate limiting, transforms, metering)
+//!
+//! Settings come from an optional TOML/YAML file, environment variables and
+//! command-line flags; see [`config`].
+
+mod config;
 
-use std::collections::HashMap;
 use std::convert::Infallible;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use arc_swap::ArcSwap;
+use clap::Parser;
 use hyper::body::Incoming;
 use hyper::service::service_fn;
-use hyper::Request;
-use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper::{Method, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
 use hyper_util::server::conn::auto::Builder as AutoBuilder;
-use tokio::net::TcpListener;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
 
 use gateway_core::config::RouterMap;
-use gateway_core::handler::{handle_request, health_check, readiness_check};
-use surreal_config::{init_database, start_config_watcher, seed_default_routes, DatabaseConfig};
-
-/// Server configuration
-#[derive(Debug, Clone)]
-struct ServerConfig {
-    /// HTTP listen address
-    listen_addr: SocketAddr,
-
-    /// Database configuration
-    db_config: DatabaseConfig,
-
-    /// Enable development mode (seeds default routes)
-    dev_mode: bool,
-}
+use gateway_core::handler::{
+    cache_purge, handle_request, health_check, readiness_check, routes_status, scripts_status, upstreams_status,
+    ClientAddr,
+};
+use gateway_core::health::run_health_checker;
+use gateway_core::tls::{self, CertStore, TlsConnection};
+use gateway_core::GatewayState;
+use naseej_security::metering::{MeterCollector, MeterConfig, UsageEvent};
+use surreal_config::{init_database, start_certificate_watcher, start_config_watcher, seed_default_routes};
 
-impl Default for ServerConfig {
-    fn default() -> Self {
-        Self {
-            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
-            db_config: DatabaseConfig::from_env(),
-            dev_mode: std::env::var("DEV_MODE").is_ok(),
-        }
-    }
-}
+use crate::config::{Cli, LogFormat, ServerConfig};
 
-impl ServerConfig {
-    fn from_env() -> Self {
-        let port: u16 = std::env::var("PORT")
-            .ok()
-            .and_then(|p| p.parse().ok())
-            .unwrap_or(8080);
-
-        let host: [u8; 4] = std::env::var("HOST")
-            .ok()
-            .and_then(|h| {
-                let parts: Vec<u8> = h.split('.').filter_map(|p| p.parse().ok()).collect();
-                if parts.len() == 4 {
-                    Some([parts[0], parts[1], parts[2], parts[3]])
-                } else {
-                    None
-                }
-            })
-            .unwrap_or([0, 0, 0, 0]);
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Load configuration: file, then environment, then flags
+    let cli = Cli::parse();
+    let config = ServerConfig::load(&cli)?;
 
-        Self {
-            listen_addr: SocketAddr::from((host, port)),
-            db_config: DatabaseConfig::from_env(),
-            dev_mode: std::env::var("DEV_MODE").is_ok(),
-        }
+    if cli.check_config {
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        eprintln!("Configuration OK");
+        return Ok(());
     }
-}
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
     // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("naseejmesh=info".parse()?)
-                .add_directive("gateway_core=debug".parse()?)
-                .add_directive("surreal_config=debug".parse()?),
-        )
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_new(&config.telemetry.log_filter)?)
         .with_target(true)
-        .with_thread_ids(true)
-        .init();
+        .with_thread_ids(true);
+    match config.telemetry.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 
     tracing::info!(
         version = env!("CARGO_PKG_VERSION"),
         "Starting NaseejMesh API Gateway"
     );
-
-    // Load configuration
-    let config = ServerConfig::from_env();
     tracing::info!(
-        addr = %config.listen_addr,
-        db_path = %config.db_config.connection,
+        addr = %config.listeners.http,
+        tls_addr = ?config.listeners.https,
+        db_path = %config.database.path,
         dev_mode = config.dev_mode,
         "Server configuration loaded"
     );
+    let config = Arc::new(config);
 
     // Initialize embedded SurrealDB
-    let db = init_database(&config.db_config).await?;
+    let db = init_database(&config.database_config()).await?;
     tracing::info!("Database initialized successfully");
 
     // Seed default routes in development mode
@@ -113,13 +93,17 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // Create shared routing configuration with ArcSwap for wait-free reads
-    let router_config: Arc<ArcSwap<RouterMap>> = Arc::new(ArcSwap::from_pointee(HashMap::new()));
+    let router_config: Arc<ArcSwap<RouterMap>> = Arc::new(ArcSwap::from_pointee(RouterMap::new()));
+
+    // Cancelled on SIGTERM/SIGINT to stop the background watchers
+    let shutdown = CancellationToken::new();
 
     // Spawn the configuration watcher task
     let watcher_db = db.clone();
     let watcher_config = router_config.clone();
-    tokio::spawn(async move {
-        if let Err(e) = start_config_watcher(watcher_db, watcher_config).await {
+    let watcher_shutdown = shutdown.clone();
+    let config_watcher = tokio::spawn(async move {
+        if let Err(e) = start_config_watcher(watcher_db, watcher_config, watcher_shutdown).await {
             tracing::error!(error = %e, "Configuration watcher failed");
         }
     });
@@ -131,51 +115,309 @@ async fn main() -> anyhow::Result<()> {
     let initial_routes = router_config.load().len();
     tracing::info!(routes = initial_routes, "Initial configuration loaded");
 
+    // Shared data-plane state (routing table + pooled upstream client),
+    // with the filters routes can opt into
+    let mut gateway_state = GatewayState::new(router_config.clone());
+    let meter = config.filters.meter.then(|| {
+        let (meter, collector) = MeterCollector::new(MeterConfig::default());
+        tokio::spawn(collector.run(log_usage));
+        meter
+    });
+    gateway_state.filters = config.filters.registry(meter)?;
+    tracing::info!(filters = ?gateway_state.filters.names(), "Filters registered");
+    let gateway_state = Arc::new(gateway_state);
+
+    // Spawn the active upstream health checker
+    tokio::spawn(run_health_checker(gateway_state.clone()));
+
     // Bind TCP listener
-    let listener = TcpListener::bind(config.listen_addr).await?;
-    tracing::info!(addr = %config.listen_addr, "Gateway listening for connections");
+    let listener = TcpListener::bind(config.listeners.http).await?;
+    tracing::info!(addr = %config.listeners.http, "Gateway listening for connections");
+
+    // TLS listener, with certificates hot-reloaded into an ArcSwap'd store
+    let mut tls_listener = None;
+    if let Some(tls_addr) = config.listeners.https {
+        let cert_store = Arc::new(ArcSwap::from_pointee(CertStore::default()));
+
+        let watcher_db = db.clone();
+        let watcher_store = cert_store.clone();
+        let file_certs = config.file_certificates();
+        let reload_interval = config.tls_reload_interval();
+        let watcher_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let watcher = start_certificate_watcher(watcher_db, watcher_store, file_certs, reload_interval, watcher_shutdown);
+            if let Err(e) = watcher.await {
+                tracing::error!(error = %e, "Certificate watcher failed");
+            }
+        });
+
+        let acceptor = tls::acceptor(cert_store, config.client_auth().as_ref())?;
+        let listener = TcpListener::bind(tls_addr).await?;
+        tracing::info!(addr = %tls_addr, "Gateway listening for TLS connections");
+        tls_listener = Some((listener, acceptor));
+    }
 
     // Print startup banner
-    print_banner(&config.listen_addr);
+    print_banner(&config.listeners.http);
+
+    // Accept loops run until a shutdown signal; every connection is watched
+    // so it can be drained afterwards
+    let graceful = GracefulShutdown::new();
+    let server = Server {
+        state: gateway_state.clone(),
+        config: config.clone(),
+        connections: config.limits.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+    };
+    serve_until_shutdown(&server, listener, tls_listener, &graceful, shutdown_signal(), config.drain_grace()).await;
+
+    // The listeners are closed now. Stop the watchers and let open
+    // connections finish: HTTP/1 connections close after their current
+    // response, HTTP/2 connections get a GOAWAY. Proxied WebSocket
+    // connections are not drained.
+    gateway_state.start_draining();
+    shutdown.cancel();
+    tracing::info!(
+        connections = graceful.count(),
+        timeout_secs = config.listeners.drain_timeout_secs,
+        "Shutting down, draining connections"
+    );
+
+    match tokio::time::timeout(config.drain_timeout(), graceful.shutdown()).await {
+        Ok(()) => tracing::info!("All connections drained"),
+        Err(_) => tracing::warn!("Drain timeout elapsed, closing remaining connections"),
+    }
+    let _ = config_watcher.await;
+
+    tracing::info!("Gateway stopped");
+    Ok(())
+}
+
+/// Run the accept loops until `signal` resolves. Readiness then fails while
+/// the listeners keep accepting for `grace`, so load balancers take the
+/// gateway out of rotation before connections start being refused.
+async fn serve_until_shutdown(
+    server: &Server,
+    listener: TcpListener,
+    tls_listener: Option<(TcpListener, TlsAcceptor)>,
+    graceful: &GracefulShutdown,
+    signal: impl Future<Output = ()>,
+    grace: Duration,
+) {
+    let serve_tls_listener = async {
+        match tls_listener {
+            Some((listener, acceptor)) => server.serve_tls(listener, acceptor, graceful).await,
+            None => std::future::pending().await,
+        }
+    };
+    let accepting = async {
+        tokio::join!(server.serve_plain(listener, graceful), serve_tls_listener);
+    };
+    tokio::pin!(accepting);
+
+    tokio::select! {
+        _ = &mut accepting => {}
+        _ = signal => {
+            server.state.start_draining();
+            tracing::info!(grace_secs = grace.as_secs(), "Failing readiness, still accepting connections");
+            let _ = tokio::time::timeout(grace, accepting).await;
+        }
+    }
+}
+
+/// Write a batch of usage events from the `meter` filter to the log.
+async fn log_usage(batch: Vec<UsageEvent>) -> Result<(), String> {
+    for event in batch {
+        tracing::info!(
+            client_id = %event.client_id,
+            route_id = event.route_id.as_deref().unwrap_or_default(),
+            method = %event.method,
+            path = %event.path,
+            status = event.status_code,
+            request_bytes = event.request_bytes,
+            response_bytes = event.response_bytes,
+            latency_us = event.latency_us,
+            "Usage"
+        );
+    }
+    Ok(())
+}
+
+/// Resolve on SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Failed to listen for Ctrl+C");
+            std::future::pending::<()>().await;
+        }
+    };
 
-    // Main accept loop
-    loop {
-        let (stream, peer_addr) = match listener.accept().await {
-            Ok(conn) => conn,
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
             Err(e) => {
-                tracing::warn!(error = %e, "Failed to accept connection");
-                continue;
+                tracing::error!(error = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
             }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// What the accept loops share with every connection they serve.
+#[derive(Clone)]
+struct Server {
+    state: Arc<GatewayState>,
+    config: Arc<ServerConfig>,
+    /// Permits for `limits.max_connections`
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Server {
+    /// Accept the next connection, first waiting for a free slot when the
+    /// connection limit is reached. The permit is held until the connection
+    /// closes.
+    async fn accept(&self, listener: &TcpListener) -> std::io::Result<(TcpStream, SocketAddr, Option<OwnedSemaphorePermit>)> {
+        let permit = match &self.connections {
+            Some(connections) => Some(connections.clone().acquire_owned().await.expect("semaphore is never closed")),
+            None => None,
         };
+        let (stream, peer_addr) = listener.accept().await?;
+        Ok((stream, peer_addr, permit))
+    }
 
-        // Wrap stream for Hyper 1.0 compatibility
-        let io = TokioIo::new(stream);
-        let config = router_config.clone();
+    /// Accept plain TCP connections.
+    async fn serve_plain(&self, listener: TcpListener, graceful: &GracefulShutdown) {
+        loop {
+            let (stream, peer_addr, permit) = match self.accept(&listener).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to accept connection");
+                    continue;
+                }
+            };
 
-        // Spawn connection handler
-        tokio::spawn(async move {
-            // Create service with clone-and-move pattern
-            let service = service_fn(move |req: Request<Incoming>| {
-                let config = config.clone();
-                async move {
-                    // Handle gateway-internal endpoints
-                    let path = req.uri().path();
-                    if path == "/_gateway/health" {
-                        return Ok::<_, Infallible>(health_check());
+            // Wrap stream for Hyper 1.0 compatibility
+            let connection = self.serve_connection(TokioIo::new(stream), peer_addr, None, graceful.watcher());
+            tokio::spawn(async move {
+                connection.await;
+                drop(permit);
+            });
+        }
+    }
+
+    /// Accept TLS connections. Handshakes run on each connection's own task
+    /// so a slow client does not hold up the accept loop.
+    async fn serve_tls(&self, listener: TcpListener, acceptor: TlsAcceptor, graceful: &GracefulShutdown) {
+        loop {
+            let (stream, peer_addr, permit) = match self.accept(&listener).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to accept TLS connection");
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let handshake_timeout = self.config.tls_handshake_timeout();
+            let server = self.clone();
+            let watcher = graceful.watcher();
+            tokio::spawn(async move {
+                let stream = match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        tracing::debug!(peer = %peer_addr, error = %e, "TLS handshake failed");
+                        return;
                     }
-                    if path == "/_gateway/ready" {
-                        return Ok(readiness_check(&config));
+                    Err(_) => {
+                        tracing::debug!(peer = %peer_addr, "TLS handshake timed out");
+                        return;
                     }
+                };
 
-                    // Handle regular requests
-                    handle_request(req, config).await
-                }
+                let tls = TlsConnection::from_stream(&stream);
+                server.serve_connection(TokioIo::new(stream), peer_addr, Some(tls), watcher).await;
+                drop(permit);
             });
+        }
+    }
 
-            // Use auto-builder for HTTP/1 + HTTP/2 support
-            let builder = AutoBuilder::new(TokioExecutor::new());
+    /// Serve HTTP on an accepted connection until it closes or, once
+    /// shutdown begins, until its in-flight requests finish.
+    fn serve_connection<I>(
+        &self,
+        io: I,
+        peer_addr: SocketAddr,
+        tls: Option<TlsConnection>,
+        watcher: Watcher,
+    ) -> impl std::future::Future<Output = ()> + Send + 'static
+    where
+        I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+    {
+        let state = self.state.clone();
+        let config = self.config.clone();
+        let internal_allowed = config.security.allows_internal(peer_addr.ip());
 
-            if let Err(e) = builder.serve_connection(io, service).await {
+        // Create service with clone-and-move pattern
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            let state = state.clone();
+            req.extensions_mut().insert(ClientAddr(peer_addr));
+            if let Some(tls) = &tls {
+                req.extensions_mut().insert(tls.clone());
+            }
+            async move {
+                // Handle gateway-internal endpoints. Probes are open to all;
+                // the rest are only served to allowed networks and otherwise
+                // routed like any other path.
+                let path = req.uri().path();
+                if path == "/_gateway/health" {
+                    return Ok::<_, Infallible>(health_check());
+                }
+                if path == "/_gateway/ready" {
+                    return Ok(readiness_check(&state));
+                }
+                if internal_allowed {
+                    if path == "/_gateway/upstreams" {
+                        return Ok(upstreams_status(&state));
+                    }
+                    if path == "/_gateway/routes" {
+                        return Ok(routes_status(&state));
+                    }
+                    if path == "/_gateway/scripts" {
+                        return Ok(scripts_status());
+                    }
+                    if path == "/_gateway/cache/purge" && req.method() == Method::POST {
+                        return Ok(cache_purge(&state, req.uri()).await);
+                    }
+                }
+
+                // Handle regular requests
+                handle_request(req, state).await
+            }
+        });
+
+        async move {
+            // Use auto-builder for HTTP/1 + HTTP/2 support, with HTTP/1.1
+            // upgrades and HTTP/2 extended CONNECT for WebSockets
+            let mut builder = AutoBuilder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(config.header_read_timeout());
+            builder
+                .http2()
+                .enable_connect_protocol()
+                .max_concurrent_streams(config.limits.http2_max_concurrent_streams);
+
+            if let Err(e) = watcher.watch(builder.serve_connection_with_upgrades(io, service)).await {
                 // Only log non-trivial errors
                 let err_str = e.to_string();
                 if !err_str.contains("connection closed") && !err_str.contains("reset by peer") {
@@ -186,7 +428,7 @@ async fn main() -> anyhow::Result<()> {
                     );
                 }
             }
-        });
+        }
     }
 }
 
@@ -220,3 +462,53 @@ fn print_banner(addr: &SocketAddr) {
         addr, addr
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_core::config::Route;
+    use gateway_core::router::build_router_map;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    /// Status line of a readiness probe sent on a new connection
+    async fn probe_ready(addr: SocketAddr) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /_gateway/ready HTTP/1.1\r\nHost: gateway\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_readiness_fails_before_accepting_stops() {
+        let routes = build_router_map(vec![Route::new("api", "/api", "http://127.0.0.1:9")]);
+        let server = Server {
+            state: Arc::new(GatewayState::new(Arc::new(ArcSwap::from_pointee(routes)))),
+            config: Arc::new(ServerConfig::default()),
+            connections: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (signal, signalled) = oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            let graceful = GracefulShutdown::new();
+            let signalled = async {
+                let _ = signalled.await;
+            };
+            serve_until_shutdown(&server, listener, None, &graceful, signalled, Duration::from_millis(300)).await;
+        });
+        assert_eq!(probe_ready(addr).await.unwrap(), "HTTP/1.1 200 OK");
+
+        // Still accepting during the grace period, but no longer ready
+        signal.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(probe_ready(addr).await.unwrap(), "HTTP/1.1 503 Service Unavailable");
+
+        serving.await.unwrap();
+        assert!(probe_ready(addr).await.is_err());
+    }
+}
@@ -21,6 +21,14 @@ pub enum ConfigError {
     #[error("Invalid route configuration: {reason}")]
     InvalidRoute { reason: String },
 
+    /// Certificate not found
+    #[error("Certificate not found: {id}")]
+    CertificateNotFound { id: String },
+
+    /// Invalid or unloadable TLS certificate
+    #[error("Invalid certificate: {reason}")]
+    InvalidCertificate { reason: String },
+
     /// Serialization/deserialization error
     #[error("Serialization error: {0}")]
     Serialization(String),
@@ -13,6 +13,7 @@ pub mod db;
 pub mod error;
 pub mod schema;
 pub mod auth_schema;
+pub mod tls_schema;
 pub mod watcher;
 
 pub use db::{init_database, init_remote_database, DatabaseConfig, EmbeddedDb, RemoteDb};
@@ -23,4 +24,5 @@ pub use auth_schema::{
     create_role, get_role, list_roles,
     create_api_key, get_api_key, list_api_keys, delete_api_key
 };
-pub use watcher::start_config_watcher;
+pub use tls_schema::{create_certificate, delete_certificate, get_certificate, list_certificates, update_certificate};
+pub use watcher::{start_certificate_watcher, start_config_watcher};
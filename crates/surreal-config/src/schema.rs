@@ -4,6 +4,10 @@
 //! with operations that work with both embedded and remote SurrealDB.
 
 use gateway_core::config::Route;
+use gateway_core::connector::HttpsConnector;
+use gateway_core::hooks::RouteHooks;
+use gateway_core::path::PathPattern;
+use gateway_core::rewrite::PathRewriter;
 use surrealdb::Connection;
 use surrealdb::Surreal;
 
@@ -119,17 +123,43 @@ fn validate_route(route: &Route) -> Result<(), ConfigError> {
         });
     }
 
-    if route.upstream.is_empty() {
+    let pattern = PathPattern::parse(&route.path).map_err(|e| ConfigError::InvalidRoute { reason: e.to_string() })?;
+
+    if let Some(rewrite) = &route.rewrite {
+        PathRewriter::compile(rewrite, &pattern).map_err(|e| ConfigError::InvalidRoute { reason: e.to_string() })?;
+    }
+
+    if let Some(host) = &route.conditions.host {
+        let name = host.strip_prefix("*.").unwrap_or(host);
+        if name.is_empty() || name.contains(['*', ':', '/']) {
+            return Err(ConfigError::InvalidRoute {
+                reason: format!("Host condition must be a host name or '*.domain': {}", host),
+            });
+        }
+    }
+
+    let targets = route.targets();
+    if targets.is_empty() {
         return Err(ConfigError::InvalidRoute {
             reason: "Upstream URL cannot be empty".to_string(),
         });
     }
 
     // Validate upstream URL format
-    if !route.upstream.starts_with("http://") && !route.upstream.starts_with("https://") {
-        return Err(ConfigError::InvalidRoute {
-            reason: "Upstream must be a valid HTTP/HTTPS URL".to_string(),
-        });
+    for target in targets.iter() {
+        if !target.url.starts_with("http://") && !target.url.starts_with("https://") {
+            return Err(ConfigError::InvalidRoute {
+                reason: format!("Upstream must be a valid HTTP/HTTPS URL: {}", target.url),
+            });
+        }
+    }
+
+    if let Some(tls) = &route.upstream_tls {
+        HttpsConnector::new(Some(tls)).map_err(|e| ConfigError::InvalidRoute { reason: e.to_string() })?;
+    }
+
+    if let Some(script) = &route.script {
+        RouteHooks::compile(&route.id, script).map_err(|e| ConfigError::InvalidRoute { reason: e.to_string() })?;
     }
 
     Ok(())
@@ -138,14 +168,8 @@ fn validate_route(route: &Route) -> Result<(), ConfigError> {
 /// Create a default route (helper for seed_default_routes)
 fn default_route(id: &str, path: &str, upstream: &str, description: &str) -> Route {
     Route {
-        id: id.to_string(),
-        path: path.to_string(),
-        upstream: upstream.to_string(),
-        weight: 100,
-        active: true,
-        methods: Vec::new(),
-        timeout_ms: 30000,
         description: description.to_string(),
+        ..Route::new(id, path, upstream)
     }
 }
 
@@ -175,18 +199,10 @@ pub async fn seed_default_routes<C: Connection>(db: &Surreal<C>) -> Result<(), C
 #[cfg(test)]
 mod tests {
     use super::*;
+    use gateway_core::config::PathRewrite;
 
     fn test_route(id: &str, path: &str, upstream: &str) -> Route {
-        Route {
-            id: id.to_string(),
-            path: path.to_string(),
-            upstream: upstream.to_string(),
-            weight: 100,
-            active: true,
-            methods: Vec::new(),
-            timeout_ms: 30000,
-            description: String::new(),
-        }
+        Route::new(id, path, upstream)
     }
 
     #[test]
@@ -210,6 +226,44 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_validate_path_template() {
+        let route = test_route("test", "/users/{id:[0-9]+}/orders/{order_id}", "http://localhost:8080");
+        assert!(validate_route(&route).is_ok());
+
+        let route = test_route("test", "/users/{id", "http://localhost:8080");
+        assert!(validate_route(&route).is_err());
+    }
+
+    #[test]
+    fn test_validate_rewrite() {
+        let mut route = test_route("test", "/users/{id}", "http://localhost:8080");
+        route.rewrite = Some(PathRewrite {
+            template: Some("/accounts/{id}".to_string()),
+            ..Default::default()
+        });
+        assert!(validate_route(&route).is_ok());
+
+        route.rewrite = Some(PathRewrite {
+            template: Some("/accounts/{name}".to_string()),
+            ..Default::default()
+        });
+        assert!(validate_route(&route).is_err());
+    }
+
+    #[test]
+    fn test_validate_host_condition() {
+        let mut route = test_route("test", "/api", "http://localhost:8080");
+        route.conditions.host = Some("*.example.com".to_string());
+        assert!(validate_route(&route).is_ok());
+
+        route.conditions.host = Some("api.*.example.com".to_string());
+        assert!(validate_route(&route).is_err());
+
+        route.conditions.host = Some("example.com:8080".to_string());
+        assert!(validate_route(&route).is_err());
+    }
+
     #[test]
     fn test_validate_invalid_upstream() {
         let route = test_route("test", "/test", "invalid-url");
@@ -217,6 +271,61 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_validate_upstream_targets() {
+        use gateway_core::config::{LoadBalancing, UpstreamTarget};
+
+        let mut route = Route::with_upstreams(
+            "lb",
+            "/lb",
+            vec![
+                UpstreamTarget::new("http://a:8080", 1),
+                UpstreamTarget::new("http://b:8080", 1),
+            ],
+            LoadBalancing::RandomTwoChoices,
+        );
+        assert!(validate_route(&route).is_ok());
+
+        route.upstreams.push(UpstreamTarget::new("ftp://c", 1));
+        assert!(validate_route(&route).is_err());
+    }
+
+    #[test]
+    fn test_validate_upstream_tls() {
+        use gateway_core::config::UpstreamTlsConfig;
+
+        let mut route = Route::new("test", "/api", "https://orders.internal:8443");
+        route.upstream_tls = Some(UpstreamTlsConfig {
+            server_name: Some("orders.internal".to_string()),
+            ..Default::default()
+        });
+        assert!(validate_route(&route).is_ok());
+
+        route.upstream_tls = Some(UpstreamTlsConfig {
+            ca_path: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        });
+        assert!(validate_route(&route).is_err());
+    }
+
+    #[test]
+    fn test_validate_script() {
+        use gateway_core::config::ScriptConfig;
+
+        let mut route = Route::new("test", "/api", "http://api:8080");
+        route.script = Some(ScriptConfig {
+            request: Some(r#"request.headers["x-api"] = "1";"#.to_string()),
+            ..Default::default()
+        });
+        assert!(validate_route(&route).is_ok());
+
+        route.script = Some(ScriptConfig {
+            response: Some("response.status = ;".to_string()),
+            ..Default::default()
+        });
+        assert!(validate_route(&route).is_err());
+    }
+
     #[test]
     fn test_validate_valid_route() {
         let route = Route::new("test", "/api/users", "http://user-service:8080");
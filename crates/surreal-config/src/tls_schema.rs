@@ -0,0 +1,147 @@
+//! TLS certificate schema and CRUD operations for SurrealDB.
+//!
+//! Certificates are stored with their PEM inline, or as paths to PEM files
+//! on the gateway host. Either way they are loaded and checked before being
+//! written, so a bad certificate is rejected here rather than skipped at
+//! reload time.
+
+use gateway_core::config::CertificateConfig;
+use gateway_core::tls::load_certificate;
+use surrealdb::Connection;
+use surrealdb::Surreal;
+
+use crate::error::ConfigError;
+
+/// Table name for certificates
+const CERTIFICATES_TABLE: &str = "certificates";
+
+/// Create a new certificate in the database.
+pub async fn create_certificate<C: Connection>(
+    db: &Surreal<C>,
+    cert: CertificateConfig,
+) -> Result<CertificateConfig, ConfigError> {
+    validate_certificate(&cert)?;
+
+    tracing::debug!(id = %cert.id, hostnames = ?cert.hostnames, "Creating certificate");
+
+    let id = cert.id.clone();
+    let created: Option<CertificateConfig> = db
+        .create((CERTIFICATES_TABLE, &id))
+        .content(cert)
+        .await?;
+
+    created.ok_or_else(|| ConfigError::Database("Failed to create certificate".to_string()))
+}
+
+/// Get a specific certificate by ID.
+pub async fn get_certificate<C: Connection>(db: &Surreal<C>, id: &str) -> Result<Option<CertificateConfig>, ConfigError> {
+    let cert: Option<CertificateConfig> = db.select((CERTIFICATES_TABLE, id)).await?;
+    Ok(cert)
+}
+
+/// Get all certificates from the database.
+pub async fn list_certificates<C: Connection>(db: &Surreal<C>) -> Result<Vec<CertificateConfig>, ConfigError> {
+    let certs: Vec<CertificateConfig> = db.select(CERTIFICATES_TABLE).await?;
+    Ok(certs)
+}
+
+/// Replace an existing certificate, e.g. after renewal.
+pub async fn update_certificate<C: Connection>(
+    db: &Surreal<C>,
+    cert: CertificateConfig,
+) -> Result<CertificateConfig, ConfigError> {
+    validate_certificate(&cert)?;
+
+    tracing::debug!(id = %cert.id, hostnames = ?cert.hostnames, "Updating certificate");
+
+    let id = cert.id.clone();
+    let updated: Option<CertificateConfig> = db
+        .update((CERTIFICATES_TABLE, &id))
+        .content(cert)
+        .await?;
+
+    updated.ok_or_else(|| ConfigError::CertificateNotFound { id })
+}
+
+/// Delete a certificate by ID.
+pub async fn delete_certificate<C: Connection>(db: &Surreal<C>, id: &str) -> Result<(), ConfigError> {
+    tracing::debug!(id = %id, "Deleting certificate");
+
+    let deleted: Option<CertificateConfig> = db.delete((CERTIFICATES_TABLE, id)).await?;
+
+    if deleted.is_none() {
+        return Err(ConfigError::CertificateNotFound { id: id.to_string() });
+    }
+
+    Ok(())
+}
+
+/// Validate a certificate configuration.
+fn validate_certificate(cert: &CertificateConfig) -> Result<(), ConfigError> {
+    if cert.id.is_empty() {
+        return Err(ConfigError::InvalidCertificate {
+            reason: "Certificate ID cannot be empty".to_string(),
+        });
+    }
+
+    if cert.hostnames.is_empty() && !cert.default {
+        return Err(ConfigError::InvalidCertificate {
+            reason: "Certificate needs at least one hostname or to be the default".to_string(),
+        });
+    }
+
+    for hostname in &cert.hostnames {
+        let name = hostname.strip_prefix("*.").unwrap_or(hostname);
+        if name.is_empty() || name.contains(['*', ':', '/']) {
+            return Err(ConfigError::InvalidCertificate {
+                reason: format!("Hostname must be a host name or '*.domain': {}", hostname),
+            });
+        }
+    }
+
+    load_certificate(cert).map_err(|e| ConfigError::InvalidCertificate { reason: e.to_string() })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/../gateway-core/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn test_cert(hostnames: &[&str]) -> CertificateConfig {
+        let mut cert = CertificateConfig::from_files("api", testdata("api.pem"), testdata("api.key"));
+        cert.hostnames = hostnames.iter().map(|h| h.to_string()).collect();
+        cert
+    }
+
+    #[test]
+    fn test_validate_valid_certificate() {
+        assert!(validate_certificate(&test_cert(&["api.example.com"])).is_ok());
+    }
+
+    #[test]
+    fn test_validate_hostnames() {
+        assert!(validate_certificate(&test_cert(&[])).is_err());
+        assert!(validate_certificate(&test_cert(&["*.example.com"])).is_ok());
+        assert!(validate_certificate(&test_cert(&["api.*.example.com"])).is_err());
+
+        let mut default = test_cert(&[]);
+        default.default = true;
+        assert!(validate_certificate(&default).is_ok());
+    }
+
+    #[test]
+    fn test_validate_unloadable_certificate() {
+        let mut cert = test_cert(&["api.example.com"]);
+        cert.key_path = Some(testdata("localhost.key"));
+        assert!(validate_certificate(&cert).is_err());
+
+        let mut cert = test_cert(&["api.example.com"]);
+        cert.cert_path = None;
+        assert!(validate_certificate(&cert).is_err());
+    }
+}
@@ -3,19 +3,25 @@
 //! This module implements the reactive configuration update mechanism using
 //! SurrealDB's Live Query feature. Changes to the routes table are detected
 //! in real-time and propagated to the gateway's routing table via ArcSwap.
+//! TLS certificates are watched the same way and swapped into the
+//! gateway's certificate store.
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use arc_swap::ArcSwap;
 use futures::StreamExt;
 use gateway_core::config::RouterMap;
 use gateway_core::router::build_router_map;
+use gateway_core::tls::CertStore;
 use surrealdb::engine::local::Db;
 use surrealdb::Surreal;
-use gateway_core::config::Route;
+use gateway_core::config::{CertificateConfig, Route};
+use tokio_util::sync::CancellationToken;
 
 use crate::error::ConfigError;
 use crate::schema::get_all_routes;
+use crate::tls_schema::list_certificates;
 
 /// Start the configuration watcher task.
 ///
@@ -34,13 +40,16 @@ use crate::schema::get_all_routes;
 ///
 /// * `db` - SurrealDB connection
 /// * `config` - Shared routing configuration wrapped in ArcSwap
+/// * `shutdown` - Cancelled when the gateway shuts down
 ///
 /// # Note
 ///
-/// This function runs indefinitely and should be spawned as a background task.
+/// This function runs until `shutdown` is cancelled, dropping the Live
+/// Query subscription, and should be spawned as a background task.
 pub async fn start_config_watcher(
     db: Surreal<Db>,
     config: Arc<ArcSwap<RouterMap>>,
+    shutdown: CancellationToken,
 ) -> Result<(), ConfigError> {
     tracing::info!("Starting configuration watcher with Live Query subscription");
 
@@ -59,7 +68,23 @@ pub async fn start_config_watcher(
     tracing::info!("Live Query subscription established on 'routes' table");
 
     // Process incoming notifications
-    while let Some(notification) = stream.next().await {
+    loop {
+        let notification = tokio::select! {
+            _ = shutdown.cancelled() => {
+                tracing::info!("Configuration watcher stopped");
+                return Ok(());
+            }
+            notification = async {
+                match &mut stream {
+                    Some(stream) => stream.next().await,
+                    None => std::future::pending().await,
+                }
+            } => match notification {
+                Some(notification) => notification,
+                None => break,
+            },
+        };
+
         match notification {
             Ok(event) => {
                 tracing::info!(
@@ -128,6 +153,129 @@ pub async fn force_reload(
     reload_config(db, config).await
 }
 
+/// Start the certificate watcher task.
+///
+/// Subscribes to the certificates table and rebuilds the whole certificate
+/// store on every notification, the same full-reload strategy as
+/// [`start_config_watcher`]. The store is also rebuilt every
+/// `reload_interval` so that certificate files renewed in place on disk are
+/// picked up. `extra` certificates, such as files given in the server's
+/// environment, are included in every rebuild.
+///
+/// Established TLS connections keep the certificate they negotiated; only
+/// new handshakes see the new store.
+///
+/// The database being unavailable does not stop the watcher: `extra`
+/// certificates are served on their own, the failure is logged, and later
+/// reloads try the database again.
+///
+/// # Note
+///
+/// This function runs until `shutdown` is cancelled and should be spawned
+/// as a background task.
+pub async fn start_certificate_watcher(
+    db: Surreal<Db>,
+    store: Arc<ArcSwap<CertStore>>,
+    extra: Vec<CertificateConfig>,
+    reload_interval: Duration,
+    shutdown: CancellationToken,
+) -> Result<(), ConfigError> {
+    tracing::info!("Starting certificate watcher with Live Query subscription");
+
+    if let Err(e) = reload_certificates(&db, &store, &extra).await {
+        tracing::error!(error = %e, "Failed to load certificates from the database, serving file certificates only");
+        store.store(Arc::new(CertStore::build(&extra)));
+    }
+
+    let subscription = async {
+        db.query("LIVE SELECT * FROM certificates")
+            .await
+            .map_err(|e| ConfigError::LiveQuery(e.to_string()))?
+            .stream::<surrealdb::Notification<CertificateConfig>>(0)
+            .map_err(|e| ConfigError::LiveQuery(e.to_string()))
+    };
+    // Without a subscription, changes are picked up by the periodic reloads
+    let mut stream = match subscription.await {
+        Ok(stream) => {
+            tracing::info!("Live Query subscription established on 'certificates' table");
+            Some(stream)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Certificate Live Query failed, relying on periodic reloads");
+            None
+        }
+    };
+
+    let mut interval = tokio::time::interval(reload_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // The first tick completes immediately; the initial load already ran
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                tracing::info!("Certificate watcher stopped");
+                return Ok(());
+            }
+            notification = async {
+                match &mut stream {
+                    Some(stream) => stream.next().await,
+                    None => std::future::pending().await,
+                }
+            } => match notification {
+                Some(Ok(event)) => {
+                    tracing::info!(
+                        action = ?event.action,
+                        certificate_id = %event.data.id,
+                        "Certificate change detected"
+                    );
+                    if let Err(e) = reload_certificates(&db, &store, &extra).await {
+                        tracing::error!(error = %e, "Failed to reload certificates");
+                    }
+                }
+                Some(Err(e)) => {
+                    tracing::warn!(error = %e, "Error receiving Live Query notification");
+                }
+                None => {
+                    tracing::warn!("Certificate Live Query stream ended, relying on periodic reloads");
+                    stream = None;
+                }
+            },
+            _ = interval.tick() => {
+                if let Err(e) = reload_certificates(&db, &store, &extra).await {
+                    tracing::error!(error = %e, "Failed to reload certificates");
+                }
+            }
+        }
+    }
+}
+
+/// Rebuild the certificate store from the database plus `extra`, and swap
+/// it in.
+///
+/// Certificates that fail to load are skipped, so one bad or missing file
+/// does not take the others down.
+async fn reload_certificates(
+    db: &Surreal<Db>,
+    store: &Arc<ArcSwap<CertStore>>,
+    extra: &[CertificateConfig],
+) -> Result<(), ConfigError> {
+    let mut certs = list_certificates(db).await?;
+    certs.extend_from_slice(extra);
+
+    let new_store = CertStore::build(&certs);
+    let loaded = new_store.len();
+    store.store(Arc::new(new_store));
+
+    tracing::debug!(
+        configured = certs.len(),
+        loaded = loaded,
+        "Certificates reloaded"
+    );
+
+    Ok(())
+}
+
 /// Get statistics about the current configuration.
 #[derive(Debug, Clone)]
 pub struct ConfigStats {
@@ -138,8 +286,10 @@ pub struct ConfigStats {
 
 pub fn get_config_stats(config: &Arc<ArcSwap<RouterMap>>) -> ConfigStats {
     let map = config.load();
-    let unique_upstreams: std::collections::HashSet<_> =
-        map.values().map(|r| &r.upstream).collect();
+    let unique_upstreams: std::collections::HashSet<_> = map
+        .values()
+        .flat_map(|r| r.targets().iter().map(|t| t.url.clone()).collect::<Vec<_>>())
+        .collect();
 
     ConfigStats {
         total_routes: map.len(),
@@ -151,11 +301,10 @@ pub fn get_config_stats(config: &Arc<ArcSwap<RouterMap>>) -> ConfigStats {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
 
     #[test]
     fn test_config_stats_empty() {
-        let config = Arc::new(ArcSwap::from_pointee(HashMap::new()));
+        let config = Arc::new(ArcSwap::from_pointee(RouterMap::new()));
         let stats = get_config_stats(&config);
 
         assert_eq!(stats.total_routes, 0);
@@ -165,19 +314,11 @@ mod tests {
 
     #[test]
     fn test_config_stats_with_routes() {
-        let mut map = HashMap::new();
-        map.insert(
-            "/api/users".to_string(),
+        let map = build_router_map(vec![
             Route::new("1", "/api/users", "http://service-a:8080"),
-        );
-        map.insert(
-            "/api/posts".to_string(),
             Route::new("2", "/api/posts", "http://service-b:8080"),
-        );
-        map.insert(
-            "/api/comments".to_string(),
             Route::new("3", "/api/comments", "http://service-a:8080"), // Same upstream
-        );
+        ]);
 
         let config = Arc::new(ArcSwap::from_pointee(map));
         let stats = get_config_stats(&config);